@@ -0,0 +1,883 @@
+//! `wgsl-pp check`, which finds the shaders used by the macros in a crate or
+//! workspace, and preprocesses and validates them without building it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use wgpu_pp_runtime::{
+    declarations, f16_fallback, parse_defines, preprocess_bundle, register_crate_directives,
+    split_stages, Preprocessor, DIRECTIVES_FILE,
+};
+
+use crate::{diagnostic, validate};
+
+/// How a macro uses its shaders, which decides how they're checked.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Kind {
+    /// Each path is a shader, preprocessed and validated.
+    Shader,
+    /// Like `Shader`, once for each variant.
+    Variants,
+    /// Each `#stage` section is validated on its own.
+    Stages,
+    /// Only the f32 fallback is validated.
+    F16,
+    /// The path is a directory of shaders, validated as one module.
+    Bundle,
+}
+
+/// The macros taking shader paths.
+const MACROS: &[(&str, Kind)] = &[
+    ("include_wgsl", Kind::Shader),
+    ("include_wgsl_pipeline_layout", Kind::Shader),
+    ("include_wgsl_structs", Kind::Shader),
+    ("include_wgsl_entry_points", Kind::Shader),
+    ("include_wgsl_push_constants", Kind::Shader),
+    ("include_wgsl_overrides", Kind::Shader),
+    ("include_wgsl_hash", Kind::Shader),
+    ("include_wgsl_split", Kind::Shader),
+    ("include_wgsl_reloadable", Kind::Shader),
+    ("include_wgsl_module", Kind::Shader),
+    ("include_wgsl_variants", Kind::Variants),
+    ("include_wgsl_stages", Kind::Stages),
+    ("include_wgsl_f16", Kind::F16),
+    ("include_wgsl_bundle", Kind::Bundle),
+];
+
+/// Tokens, with the line each starts on.
+type Tokens = Vec<(usize, Token)>;
+
+/// A token of Rust source, just enough to find macro calls and their arguments.
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    /// A string literal's value.
+    Str(String),
+    Punct(char),
+    /// A delimited group, with its opening delimiter, and the line each token starts on.
+    Group(char, Tokens),
+    /// Numbers and other literals, as written.
+    Other(String),
+}
+
+/// Reads the value of a string literal starting at `i`, after any `r`, `b`
+/// and `#` prefix, returning it and where the literal ends.
+fn string_literal(chars: &[char], mut i: usize, raw: bool) -> (String, usize) {
+    let mut hashes = 0;
+    while raw && chars.get(i) == Some(&'#') {
+        hashes += 1;
+        i += 1;
+    }
+    // Skip the opening quote.
+    i += 1;
+    let mut value = String::new();
+    while let Some(&c) = chars.get(i) {
+        i += 1;
+        match c {
+            '"' if !raw => break,
+            '"' if chars[i..]
+                .iter()
+                .take(hashes)
+                .filter(|&&c| c == '#')
+                .count()
+                == hashes =>
+            {
+                i += hashes;
+                break;
+            }
+            '\\' if !raw => {
+                let escaped = chars.get(i).copied().unwrap_or('\\');
+                i += 1;
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '0' => value.push('\0'),
+                    // A line continuation skips the newline and leading whitespace.
+                    '\n' => {
+                        while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+                            i += 1;
+                        }
+                    }
+                    escaped => value.push(escaped),
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    (value, i)
+}
+
+/// Returns where a byte or raw string literal's `#`s or quote start, and
+/// whether it's raw, if one starts at `i`.
+fn raw_string(chars: &[char], i: usize) -> Option<(usize, bool)> {
+    let (quote, raw) = match (chars[i], chars.get(i + 1)) {
+        ('b', Some('r')) => (i + 2, true),
+        ('r', _) => (i + 1, true),
+        _ => (i + 1, false),
+    };
+    let hashes = match raw {
+        true => chars[quote.min(chars.len())..]
+            .iter()
+            .take_while(|&&c| c == '#')
+            .count(),
+        false => 0,
+    };
+    (chars.get(quote + hashes) == Some(&'"')).then_some((quote, raw))
+}
+
+/// Splits Rust source into tokens, with the line each starts on.
+///
+/// Comments are skipped, so calls in doc comment examples aren't checked.
+fn tokenize(source: &str) -> Tokens {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    let mut line = 1;
+    // The open groups, with their delimiter, line and tokens.
+    let mut stack: Vec<(char, usize, Tokens)> = vec![(' ', 1, vec![])];
+    while i < chars.len() {
+        let start = i;
+        let start_line = line;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let token = match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '/' if next == Some('/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '/' if next == Some('*') => {
+                let mut depth = 0;
+                while i < chars.len() {
+                    match (chars[i], chars.get(i + 1)) {
+                        ('/', Some('*')) => {
+                            depth += 1;
+                            i += 1;
+                        }
+                        ('*', Some('/')) => {
+                            depth -= 1;
+                            i += 1;
+                        }
+                        ('\n', _) => line += 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '"' => {
+                let (value, end) = string_literal(&chars, i, false);
+                i = end;
+                Token::Str(value)
+            }
+            'b' | 'r' if raw_string(&chars, i).is_some() => {
+                let (quote, raw) = raw_string(&chars, i).unwrap();
+                let (value, end) = string_literal(&chars, quote, raw);
+                i = end;
+                Token::Str(value)
+            }
+            '\'' => {
+                // Character literals, as opposed to lifetimes.
+                let end = match (next, chars.get(i + 2)) {
+                    (Some('\\'), _) => chars[i + 2..]
+                        .iter()
+                        .position(|&c| c == '\'')
+                        .map(|end| i + 2 + end + 1),
+                    (Some(_), Some('\'')) => Some(i + 3),
+                    _ => None,
+                };
+                match end {
+                    Some(end) => {
+                        i = end;
+                        Token::Other(chars[start..end].iter().collect())
+                    }
+                    None => {
+                        i += 1;
+                        Token::Punct('\'')
+                    }
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            c if c.is_ascii_digit() => {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                Token::Other(chars[start..i].iter().collect())
+            }
+            '(' | '[' | '{' => {
+                stack.push((c, line, vec![]));
+                i += 1;
+                continue;
+            }
+            ')' | ']' | '}' => {
+                i += 1;
+                if stack.len() == 1 {
+                    continue;
+                }
+                let (open, open_line, tokens) = stack.pop().unwrap();
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .2
+                    .push((open_line, Token::Group(open, tokens)));
+                continue;
+            }
+            c => {
+                i += 1;
+                Token::Punct(c)
+            }
+        };
+        stack.last_mut().unwrap().2.push((start_line, token));
+    }
+
+    // Close groups left open at the end of the file.
+    while stack.len() > 1 {
+        let (open, open_line, tokens) = stack.pop().unwrap();
+        stack
+            .last_mut()
+            .unwrap()
+            .2
+            .push((open_line, Token::Group(open, tokens)));
+    }
+    stack.pop().unwrap().2
+}
+
+/// A call of one of the macros.
+struct Call<'a> {
+    line: usize,
+    name: &'a str,
+    kind: Kind,
+    args: &'a [(usize, Token)],
+}
+
+/// Finds the macro calls in tokens, including inside groups, e.g. function bodies.
+fn calls<'a>(tokens: &'a [(usize, Token)], found: &mut Vec<Call<'a>>) {
+    for (i, (line, token)) in tokens.iter().enumerate() {
+        if let (
+            Token::Ident(name),
+            Some((_, Token::Punct('!'))),
+            Some((_, Token::Group(_, args))),
+        ) = (token, tokens.get(i + 1), tokens.get(i + 2))
+        {
+            if let Some((name, kind)) = MACROS.iter().find(|(macro_name, _)| macro_name == name) {
+                found.push(Call {
+                    line: *line,
+                    name,
+                    kind: *kind,
+                    args,
+                });
+            }
+        }
+        if let Token::Group(_, group) = token {
+            calls(group, found);
+        }
+    }
+}
+
+/// Splits tokens on top-level commas, dropping empty items like after a trailing comma.
+fn split_commas(tokens: &[(usize, Token)]) -> Vec<&[(usize, Token)]> {
+    tokens
+        .split(|(_, token)| *token == Token::Punct(','))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Formats tokens like the macros see them, e.g. for define values.
+fn text(tokens: &[(usize, Token)]) -> String {
+    let mut text = String::new();
+    for (_, token) in tokens {
+        match token {
+            Token::Ident(s) | Token::Other(s) => text += s,
+            Token::Str(s) => text += &format!("{:?}", s),
+            Token::Punct(c) => text.push(*c),
+            Token::Group(open, tokens) => {
+                text.push(*open);
+                text += &self::text(tokens);
+                text.push(match open {
+                    '(' => ')',
+                    '[' => ']',
+                    _ => '}',
+                });
+            }
+        }
+    }
+    text
+}
+
+/// The arguments of a macro call: string literal paths followed by options,
+/// with the tokens after `=` or inside the parenthesis of each.
+struct Args<'a> {
+    paths: Vec<&'a str>,
+    options: Vec<(&'a str, &'a [(usize, Token)])>,
+}
+
+impl<'a> Args<'a> {
+    fn parse(tokens: &'a [(usize, Token)]) -> Result<Self, String> {
+        let mut args = Args {
+            paths: vec![],
+            options: vec![],
+        };
+        for item in split_commas(tokens) {
+            match item {
+                [(_, Token::Str(path))] if args.options.is_empty() => args.paths.push(path),
+                [(_, Token::Ident(name))] => args.options.push((name, &[])),
+                [(_, Token::Ident(name)), (_, Token::Punct('=')), value @ ..] => {
+                    args.options.push((name, value))
+                }
+                [(_, Token::Ident(name)), (_, Token::Group('(', value))] => {
+                    args.options.push((name, value))
+                }
+                _ => return Err(format!("can't parse the argument `{}`", text(item))),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Returns the tokens of an option, if it was passed.
+    fn option(&self, name: &str) -> Option<&'a [(usize, Token)]> {
+        self.options
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Returns the directory the paths are relative to, like `base_dir` in the
+    /// macros: `base = "dir"` relative to the crate calling the macro from
+    /// `file`, or else the directory of `file`.
+    fn base_dir(&self, file: &Path) -> Result<PathBuf, String> {
+        let Some(value) = self.option("base") else {
+            return Ok(file.parent().unwrap_or(Path::new("")).to_path_buf());
+        };
+        let [(_, Token::Str(base))] = value else {
+            return Err("expected `base = \"dir\"`".to_string());
+        };
+        match crate_dir(file) {
+            Some(dir) => Ok(dir.join(base)),
+            None => Err(format!("no Cargo.toml above {}", file.display())),
+        }
+    }
+
+    /// Reads the defines of the `defines = "file.toml"` option, relative to the
+    /// shader's directory `dir`, like `config_defines` in the macros.
+    fn config_defines(&self, dir: &Path) -> Result<Vec<(String, String)>, String> {
+        let Some(value) = self.option("defines") else {
+            return Ok(vec![]);
+        };
+        let [(_, Token::Str(config))] = value else {
+            return Err("expected `defines = \"file.toml\"`".to_string());
+        };
+        let path = dir.join(config);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        parse_defines(extension, &contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Returns the defines of each variant, like `include_wgsl_variants!`.
+    ///
+    /// Each define is either `NAME`, which is `false` or `true`, or
+    /// `NAME = [values]`. Every combination is checked, unless
+    /// `variants([...], ...)` lists them.
+    fn variants(&self) -> Result<Vec<Vec<(String, String)>>, String> {
+        let mut defines: Vec<(&str, Vec<String>)> = vec![];
+        let mut explicit = None;
+        for (name, value) in self.options.iter() {
+            if *name == "variants" {
+                explicit = Some(*value);
+                continue;
+            }
+            // `base = "dir"` is where the shader is, see `base_dir`.
+            if *name == "base" {
+                if let [(_, Token::Str(_))] = value {
+                    continue;
+                }
+                return Err(
+                    "`base` is the `base = \"dir\"` option, so it can't be the name of a define"
+                        .to_string(),
+                );
+            }
+            let values = match value {
+                [] => vec!["false".to_string(), "true".to_string()],
+                [(_, Token::Group('[', values))] => {
+                    split_commas(values).into_iter().map(text).collect()
+                }
+                _ => return Err(format!("expected `{}` or `{} = [values]`", name, name)),
+            };
+            defines.push((name, values));
+        }
+
+        let combinations = match explicit {
+            Some(variants) => split_commas(variants)
+                .into_iter()
+                .map(|variant| {
+                    let [(_, Token::Group('[', items))] = variant else {
+                        return Err("expected variants as `[NAME, NAME = value, ...]`".to_string());
+                    };
+                    // Defines that aren't listed take their first value, `false` for booleans.
+                    let mut combination = vec![0; defines.len()];
+                    for item in split_commas(items) {
+                        let (name, value) = match item {
+                            [(_, Token::Ident(name))] => (name.as_str(), "true".to_string()),
+                            [(_, Token::Ident(name)), (_, Token::Punct('=')), value @ ..] => {
+                                (name.as_str(), text(value))
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "expected `NAME` or `NAME = value`, got `{}`",
+                                    text(item)
+                                ))
+                            }
+                        };
+                        let i = defines
+                            .iter()
+                            .position(|(define, _)| *define == name)
+                            .ok_or_else(|| format!("unknown define: {}", name))?;
+                        combination[i] =
+                            defines[i]
+                                .1
+                                .iter()
+                                .position(|v| *v == value)
+                                .ok_or_else(|| {
+                                    format!("{} is not one of the values of {}", value, name)
+                                })?;
+                    }
+                    Ok(combination)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => {
+                let count = defines.iter().map(|(_, values)| values.len()).product();
+                (0..count)
+                    .map(|mut index: usize| {
+                        defines
+                            .iter()
+                            .map(|(_, values)| {
+                                let value = index % values.len();
+                                index /= values.len();
+                                value
+                            })
+                            .collect()
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(combinations
+            .iter()
+            .map(|combination: &Vec<usize>| {
+                defines
+                    .iter()
+                    .zip(combination.iter())
+                    .map(|((name, values), i)| (name.to_string(), values[*i].clone()))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Returns the directory of the crate `file` is in, i.e. the closest one
+/// above it with a `Cargo.toml`.
+fn crate_dir(file: &Path) -> Option<PathBuf> {
+    file.ancestors()
+        .skip(1)
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .map(Path::to_path_buf)
+}
+
+/// Returns the Rust files in a directory and its subdirectories, sorted,
+/// skipping `target` and hidden directories.
+fn rust_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            if name != "target" && !name.starts_with('.') {
+                files.extend(rust_files(&path)?);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Preprocesses and validates a shader the way a macro of `kind` does,
+/// printing the warnings that weren't `printed` for another shader.
+fn check_shader(
+    preprocessor: &Preprocessor,
+    path: &Path,
+    kind: Kind,
+    printed: &mut HashSet<(PathBuf, usize, String)>,
+) -> Result<(), String> {
+    let (source, info) = preprocessor
+        .process_file_with_info(path)
+        .map_err(|e| diagnostic::preprocessor_error(path, &e).unwrap_or_else(|| e.to_string()))?;
+    diagnostic::print_warnings(&info, printed);
+    match kind {
+        Kind::Stages => {
+            let stages = split_stages(&source);
+            if stages.is_empty() {
+                return Err("no #stage sections".to_string());
+            }
+            for (stage, source) in stages.iter() {
+                validate(source).map_err(|e| format!("in #stage {}: {}", stage, e))?;
+            }
+        }
+        // The fallback drops `enable` lines, so it doesn't match the source map.
+        Kind::F16 => {
+            validate(&f16_fallback(&source))?;
+        }
+        _ => {
+            diagnostic::validate(&source, &info)?;
+        }
+    }
+    Ok(())
+}
+
+/// Preprocesses the shaders directly in a directory and validates them as one
+/// module, like `include_wgsl_bundle!`.
+fn check_bundle(dir: &Path, defines: &[(String, String)]) -> Result<(), String> {
+    let mut filenames = fs::read_dir(dir)
+        .map_err(|_| "directory not found".to_string())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|filename| filename.ends_with(".wgsl"))
+        .collect::<Vec<_>>();
+    filenames.sort();
+    if filenames.is_empty() {
+        return Err("no .wgsl files".to_string());
+    }
+
+    let sources = preprocess_bundle(&filenames, dir, defines).map_err(|e| e.to_string())?;
+    let mut declared: HashMap<String, &str> = HashMap::new();
+    for (filename, source) in filenames.iter().zip(sources.iter()) {
+        for name in declarations(source) {
+            if let Some(other) = declared.insert(name.clone(), filename) {
+                return Err(format!(
+                    "`{}` is declared in both {} and {}",
+                    name, other, filename
+                ));
+            }
+        }
+    }
+    validate(&sources.concat())?;
+    Ok(())
+}
+
+/// Registers the custom directives of the crate `file` is in, from its
+/// `wgpu_pp_directives.toml`, like the macros do, so its commands only run
+/// with `WGPU_PP_ALLOW_COMMANDS=1`.
+fn load_crate_directives(file: &Path) -> Result<(), String> {
+    match crate_dir(file).map(|dir| dir.join(DIRECTIVES_FILE)) {
+        Some(path) if path.is_file() => register_crate_directives(&path),
+        _ => Ok(()),
+    }
+}
+
+/// Finds the macro calls in the Rust files under `paths`, and checks every
+/// shader they use, returning whether they all passed.
+///
+/// Paths in the calls are relative to the file calling the macro, or to its
+/// `base = "dir"` in the crate, like the macros resolve them. `include_dirs`
+/// and `defines` are used on top of what the macros use, e.g. for the profile.
+pub fn run(
+    paths: &[PathBuf],
+    include_dirs: &[PathBuf],
+    defines: &[(String, String)],
+) -> io::Result<bool> {
+    let mut files = vec![];
+    for path in paths {
+        match path.is_dir() {
+            true => files.extend(rust_files(path)?),
+            false => files.push(path.clone()),
+        }
+    }
+
+    let mut call_count = 0;
+    let mut checked = HashSet::new();
+    let mut printed = HashSet::new();
+    let mut failed = 0;
+    for file in files {
+        let source = fs::read_to_string(&file)?;
+        let file = file.strip_prefix(".").unwrap_or(&file);
+        let tokens = tokenize(&source);
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+
+        for call in found {
+            call_count += 1;
+            let location = format!("{}:{}: {}!", file.display(), call.line, call.name);
+            let args = match Args::parse(call.args) {
+                Ok(args) => args,
+                Err(e) => {
+                    diagnostic::print_error(format!("{}: {}", location, e));
+                    failed += 1;
+                    continue;
+                }
+            };
+            let variants = match call.kind {
+                Kind::Variants => args.variants(),
+                _ => Ok(vec![vec![]]),
+            };
+            let resolved = variants.and_then(|variants| {
+                load_crate_directives(file)?;
+                let dir = args.base_dir(file)?;
+                let config = args.config_defines(&dir)?;
+                Ok((variants, dir, config))
+            });
+            let (variants, dir, config) = match resolved {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    diagnostic::print_error(format!("{}: {}", location, e));
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            for path in args.paths.iter() {
+                let path = dir.join(path);
+                for variant in variants.iter() {
+                    // Calls with another `defines` file check the shader again.
+                    let key = (
+                        fs::canonicalize(&path).unwrap_or(path.clone()),
+                        call.kind,
+                        variant
+                            .iter()
+                            .chain(config.iter())
+                            .cloned()
+                            .collect::<Vec<_>>(),
+                    );
+                    if !checked.insert(key) {
+                        continue;
+                    }
+
+                    let result = match call.kind {
+                        Kind::Bundle => check_bundle(&path, defines),
+                        kind => {
+                            let mut preprocessor = Preprocessor::new();
+                            for dir in include_dirs.iter() {
+                                preprocessor = preprocessor.include_dir(dir);
+                            }
+                            let all = variant.iter().chain(config.iter()).chain(defines.iter());
+                            for (name, value) in all {
+                                preprocessor = preprocessor.define(name, value);
+                            }
+                            check_shader(&preprocessor, &path, kind, &mut printed)
+                        }
+                    };
+                    if let Err(e) = result {
+                        let variant = variant
+                            .iter()
+                            .map(|(name, value)| format!("{} = {}", name, value))
+                            .collect::<Vec<_>>();
+                        match variant.is_empty() {
+                            true => diagnostic::print_error(format!(
+                                "{}: {}: {}",
+                                location,
+                                path.display(),
+                                e
+                            )),
+                            false => diagnostic::print_error(format!(
+                                "{}: {} in variant {}: {}",
+                                location,
+                                path.display(),
+                                variant.join(", "),
+                                e
+                            )),
+                        }
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "checked {} shaders from {} macro calls, {} failed",
+        checked.len(),
+        call_count,
+        failed
+    );
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{calls, run, tokenize, Args, Kind};
+
+    #[test]
+    fn test_calls() {
+        let source = r##"
+            // include_wgsl!("commented.wgsl");
+            /* include_wgsl!("commented.wgsl"); */
+            const NOT_A_CALL: &str = "include_wgsl!(\"string.wgsl\")";
+            const RAW: &str = r#"include_wgsl!("raw.wgsl")"#;
+
+            fn shaders<'a>() {
+                let c = '"';
+                let source = wgpu_pp::include_wgsl!("water.wgsl");
+                include_wgsl_variants!(
+                    "uber.wgsl",
+                    USE_FOG,
+                    QUALITY = [1, 2],
+                );
+                include_wgsl_variants!("uber.wgsl", USE_FOG, QUALITY = [1, 2, 3], variants([QUALITY = 2], [USE_FOG, QUALITY = 3]));
+            }
+        "##;
+        let tokens = tokenize(source);
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+
+        let found = found
+            .iter()
+            .map(|call| (call.line, call.name, call.kind))
+            .collect::<Vec<_>>();
+        assert!(
+            found
+                == [
+                    (9, "include_wgsl", Kind::Shader),
+                    (10, "include_wgsl_variants", Kind::Variants),
+                    (15, "include_wgsl_variants", Kind::Variants),
+                ]
+        );
+
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+        let args = Args::parse(found[1].args).unwrap();
+        assert_eq!(args.paths, ["uber.wgsl"]);
+        let variants = args.variants().unwrap();
+        let variant = |defines: &[(&str, &str)]| {
+            defines
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            variants,
+            [
+                variant(&[("USE_FOG", "false"), ("QUALITY", "1")]),
+                variant(&[("USE_FOG", "true"), ("QUALITY", "1")]),
+                variant(&[("USE_FOG", "false"), ("QUALITY", "2")]),
+                variant(&[("USE_FOG", "true"), ("QUALITY", "2")]),
+            ]
+        );
+        let variants = Args::parse(found[2].args).unwrap().variants().unwrap();
+        assert_eq!(
+            variants,
+            [
+                variant(&[("USE_FOG", "false"), ("QUALITY", "2")]),
+                variant(&[("USE_FOG", "true"), ("QUALITY", "3")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_base() {
+        let tokens = tokenize(r#"include_wgsl_variants!("water.wgsl", base = "shaders", FOG)"#);
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+        let args = Args::parse(found[0].args).unwrap();
+        assert_eq!(args.variants().unwrap().len(), 2);
+        // The crate of this file is the cli directory.
+        let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/main.rs");
+        assert_eq!(
+            args.base_dir(&file).unwrap(),
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders")
+        );
+
+        let tokens = tokenize(r#"include_wgsl!("water.wgsl")"#);
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+        let args = Args::parse(found[0].args).unwrap();
+        assert_eq!(args.base_dir(&file).unwrap(), file.parent().unwrap());
+
+        let tokens = tokenize(r#"include_wgsl_variants!("water.wgsl", base, FOG)"#);
+        let mut found = vec![];
+        calls(&tokens, &mut found);
+        let e = Args::parse(found[0].args).unwrap().variants().unwrap_err();
+        assert_eq!(
+            e,
+            "`base` is the `base = \"dir\"` option, so it can't be the name of a define"
+        );
+    }
+
+    #[test]
+    fn test_run_base_and_defines() {
+        let dir = std::env::temp_dir().join(format!("wgsl-pp-check-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("shaders")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "[package]\nname = \"shaders\"\n").unwrap();
+        std::fs::write(
+            dir.join("shaders/water.wgsl"),
+            "const fog: bool = FOG;\nconst density: f32 = DENSITY;\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("shaders/water.toml"), "DENSITY = 0.5\n").unwrap();
+
+        // Both calls need `base` to find the shader, and the second needs
+        // `defines` for DENSITY.
+        let main = dir.join("src/main.rs");
+        std::fs::write(
+            &main,
+            r#"include_wgsl_variants!("water.wgsl", base = "shaders", FOG, DENSITY = [1.0]);
+            include_wgsl!("water.wgsl", base = "shaders", defines = "water.toml");"#,
+        )
+        .unwrap();
+        let passed = run(&[dir.join("src")], &[], &[("FOG".into(), "true".into())]).unwrap();
+        assert!(passed);
+
+        std::fs::write(&main, r#"include_wgsl!("water.wgsl", base = "shaders");"#).unwrap();
+        let passed = run(&[dir.join("src")], &[], &[("FOG".into(), "true".into())]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!passed);
+    }
+
+    #[test]
+    fn test_run_directives() {
+        let dir = std::env::temp_dir().join(format!("wgsl-pp-directives-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "[package]\nname = \"shaders\"\n").unwrap();
+        std::fs::write(dir.join("src/blur.wgsl"), "#check_repeat3 WEIGHTS 0.5\n").unwrap();
+        std::fs::write(dir.join("src/main.rs"), r#"include_wgsl!("blur.wgsl");"#).unwrap();
+        let passed = run(&[dir.join("src")], &[], &[]).unwrap();
+        assert!(!passed);
+
+        // The directive is declared for the crate like the macros read it.
+        std::fs::write(
+            dir.join("wgpu_pp_directives.toml"),
+            "[template]\ncheck_repeat3 = \"const $1 = array($2, $2, $2);\"\n",
+        )
+        .unwrap();
+        let passed = run(&[dir.join("src")], &[], &[]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(passed);
+    }
+}
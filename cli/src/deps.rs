@@ -0,0 +1,122 @@
+//! `wgsl-pp deps`, which prints the include tree of a shader.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use wgpu_pp_runtime::SourceInfo;
+
+/// Returns the names a file defines, joined for printing.
+fn defines(info: &SourceInfo, path: &Path) -> String {
+    let mut names = vec![];
+    for (file, name) in info.defines.iter() {
+        if file == path && !names.contains(&name.as_str()) {
+            names.push(name.as_str());
+        }
+    }
+    names.join(", ")
+}
+
+/// Returns the files `path` includes, in order.
+fn children<'a>(info: &'a SourceInfo, path: &Path) -> impl Iterator<Item = &'a PathBuf> {
+    let path = path.to_path_buf();
+    info.includes
+        .iter()
+        .filter(move |(from, _)| *from == path)
+        .map(|(_, to)| to)
+}
+
+fn tree_node(
+    info: &SourceInfo,
+    path: &Path,
+    prefix: &str,
+    visited: &mut HashSet<PathBuf>,
+    out: &mut String,
+) {
+    let children = children(info, path).collect::<Vec<_>>();
+    for (i, child) in children.iter().enumerate() {
+        let last = i == children.len() - 1;
+        *out += prefix;
+        *out += if last { "└── " } else { "├── " };
+        *out += &child.display().to_string();
+
+        if !visited.insert(child.to_path_buf()) {
+            *out += " (already included)\n";
+            continue;
+        }
+        let defines = defines(info, child);
+        if !defines.is_empty() {
+            *out += &format!("  [defines {}]", defines);
+        }
+        out.push('\n');
+
+        let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        tree_node(info, child, &prefix, visited, out);
+    }
+}
+
+/// Formats the include tree as text, with the names each file defines.
+///
+/// Files are only expanded the first time they're included, later includes
+/// of them are marked as already included.
+pub fn tree(info: &SourceInfo) -> String {
+    let Some(root) = info.dependencies.first() else {
+        return String::new();
+    };
+    let mut out = root.display().to_string();
+    let defines = defines(info, root);
+    if !defines.is_empty() {
+        out += &format!("  [defines {}]", defines);
+    }
+    out.push('\n');
+
+    let mut visited = HashSet::from([root.clone()]);
+    tree_node(info, root, "", &mut visited, &mut out);
+    out
+}
+
+/// Quotes a string for DOT.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Formats the include graph in Graphviz's DOT language, with the names each
+/// file defines under its name.
+pub fn dot(info: &SourceInfo) -> String {
+    let mut files: Vec<&PathBuf> = info.dependencies.iter().take(1).collect();
+    for (from, to) in info.includes.iter() {
+        for path in [from, to] {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut out = "digraph includes {\n    node [shape=box];\n".to_string();
+    for path in files {
+        let name = path.display().to_string();
+        let defines = defines(info, path);
+        let label = match defines.is_empty() {
+            true => name.clone(),
+            false => format!("{}\n{}", name, defines),
+        };
+        out += &format!(
+            "    {} [label={}];\n",
+            quote(&name),
+            quote(&label).replace('\n', "\\n")
+        );
+    }
+    let mut edges = HashSet::new();
+    for (from, to) in info.includes.iter() {
+        if edges.insert((from, to)) {
+            out += &format!(
+                "    {} -> {};\n",
+                quote(&from.display().to_string()),
+                quote(&to.display().to_string())
+            );
+        }
+    }
+    out += "}\n";
+    out
+}
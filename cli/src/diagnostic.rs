@@ -0,0 +1,279 @@
+//! Errors rendered against the files they came from: the offending line with
+//! carets under it, the includes leading to it, and a colored label when
+//! stderr is a terminal. Warnings about defines are rendered the same way.
+
+use std::{
+    collections::HashSet,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use naga::front::wgsl::Frontend;
+use naga::valid::{Capabilities, ModuleInfo, ValidationFlags, Validator};
+use naga::{Module, Span};
+use wgpu_pp_runtime::{PreprocessorError, SourceInfo};
+
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+const BLUE: &str = "1;34";
+
+/// Styles `text` with an ANSI escape code if stderr is a terminal and
+/// `NO_COLOR` isn't set.
+fn paint(text: &str, style: &str) -> String {
+    let colored = std::io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    match colored {
+        true => format!("\x1b[{}m{}\x1b[0m", style, text),
+        false => text.to_string(),
+    }
+}
+
+/// Prints an error to stderr.
+pub fn print_error(message: impl std::fmt::Display) {
+    eprintln!("{}: {}", paint("error", RED), message);
+}
+
+/// Returns the quoted path of an `#include` or `#embed` line.
+pub fn directive_path(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if !line.starts_with("#include") && !line.starts_with("#embed") {
+        return None;
+    }
+    let start = line.find('"')? + 1;
+    let end = start + line[start..].find('"')?;
+    Some(&line[start..end])
+}
+
+/// Returns the index of the line a preprocessor error is most likely about,
+/// since errors don't say where they happened.
+pub fn error_line(e: &PreprocessorError, lines: &[&str]) -> Option<usize> {
+    let find_line = |matches: &dyn Fn(&str) -> bool| lines.iter().position(|line| matches(line));
+    let directive = |name: &str| find_line(&|line| line.trim_start().starts_with(name));
+    match e {
+        PreprocessorError::FileNotFound(filename)
+        | PreprocessorError::FileNotValidUtf8(filename)
+        | PreprocessorError::EmbedInvalidData(filename, _) => {
+            find_line(&|line| directive_path(line) == Some(filename.as_str()))
+        }
+        PreprocessorError::UnknownDirective(name) => directive(name),
+        PreprocessorError::IncludeIncorrectArgs => directive("#include"),
+        PreprocessorError::EmbedIncorrectArgs => directive("#embed"),
+        PreprocessorError::StageIncorrectArgs => directive("#stage"),
+        PreprocessorError::DirectiveFailed(name, _) => directive(name),
+        PreprocessorError::MissingDefine(name, _) => {
+            find_line(&|line| line.split_whitespace().take(2).eq(["#require", name]))
+        }
+        PreprocessorError::MacroWithoutArgs(_, _, usage)
+        | PreprocessorError::DefineCalled(_, _, usage) => {
+            find_line(&|line| line.contains(usage.as_str()))
+        }
+        _ => None,
+    }
+}
+
+/// Renders `message` with line `line_number` of `path`, which is `line`,
+/// underlining `span`, a byte range in the line, or else the whole line.
+#[allow(clippy::too_many_arguments)]
+fn render(
+    message: &str,
+    path: &Path,
+    line_number: usize,
+    line: &str,
+    span: Option<(usize, usize)>,
+    label: &str,
+    style: &str,
+    notes: &[String],
+) -> String {
+    let trimmed = line.trim();
+    let whole_line = (line.len() - line.trim_start().len(), trimmed.len());
+    let (start, len) = span
+        .filter(|&(start, len)| line.get(start..start + len).is_some())
+        .unwrap_or(whole_line);
+
+    // The caret line keeps the tabs of the source, so it lines up with it.
+    let indent = line[..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(line[start..start + len].chars().count().max(1));
+    let number = line_number.to_string();
+    let pad = " ".repeat(number.len());
+    let gutter = paint("|", BLUE);
+
+    let mut out = format!(
+        "{}\n{}{} {}:{}:{}\n{} {}\n{} {} {}\n{} {} {}{} {}",
+        message,
+        pad,
+        paint("-->", BLUE),
+        path.display(),
+        line_number,
+        start + 1,
+        pad,
+        gutter,
+        paint(&number, BLUE),
+        gutter,
+        line,
+        pad,
+        gutter,
+        indent,
+        paint(&carets, style),
+        paint(label, style),
+    )
+    .trim_end()
+    .to_string();
+    for note in notes.iter() {
+        out += &format!("\n{} {} {}", pad, paint("=", BLUE), note);
+    }
+    out
+}
+
+/// Returns the includes leading to `path`, from the file including it up to
+/// the shader, as notes.
+fn include_chain(path: &Path, info: &SourceInfo) -> Vec<String> {
+    let mut notes = vec![];
+    let mut current = path.to_path_buf();
+    // Files are expanded where they're first included, which is listed first.
+    while let Some((including, _)) = info
+        .includes
+        .iter()
+        .find(|(_, included)| *included == current)
+    {
+        let line = std::fs::read_to_string(including).ok().and_then(|text| {
+            text.lines().position(|line| {
+                directive_path(line)
+                    .is_some_and(|included| Path::new(included).file_name() == current.file_name())
+            })
+        });
+        notes.push(match line {
+            Some(line) => format!("included from {}:{}", including.display(), line + 1),
+            None => format!("included from {}", including.display()),
+        });
+        current = including.clone();
+        if notes.len() > info.includes.len() {
+            break;
+        }
+    }
+    notes
+}
+
+/// Renders an error at `span` of the expanded source, against the line of
+/// the file it came from.
+fn source_error(message: &str, label: &str, span: Span, source: &str, info: &SourceInfo) -> String {
+    let location = span.location(source);
+    let line_index = location.line_number as usize - 1;
+    let expanded = source.lines().nth(line_index).unwrap_or("");
+    let column = location.line_position as usize - 1;
+    // Spans of functions and such go on for several lines, only the first is shown.
+    let span_text = expanded
+        .get(column..)
+        .map(|rest| rest.get(..location.length as usize).unwrap_or(rest))
+        .unwrap_or("");
+
+    let Some((path, line_number)) = info.source_map.get(line_index) else {
+        return format!("{}: {}", message, label);
+    };
+    let original = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| text.lines().nth(line_number - 1).map(str::to_string));
+    let mut notes = vec![];
+    let (line, span) = match original {
+        // Usually the line is only indented differently, or had a comment.
+        Some(original) if original.trim().starts_with(expanded.trim()) => {
+            let shift = original.len() - original.trim_start().len();
+            let start =
+                (column + shift).saturating_sub(expanded.len() - expanded.trim_start().len());
+            (original, Some((start, span_text.len())))
+        }
+        // Defines were substituted in the line.
+        Some(original) => {
+            notes.push(format!("expanded to `{}`", expanded.trim()));
+            let span = match span_text.is_empty() {
+                true => None,
+                false => original
+                    .find(span_text)
+                    .map(|start| (start, span_text.len())),
+            };
+            (original, span)
+        }
+        // A virtual header, which isn't on disk.
+        None => (expanded.to_string(), Some((column, span_text.len()))),
+    };
+    notes.extend(include_chain(path, info));
+    render(message, path, *line_number, &line, span, label, RED, &notes)
+}
+
+/// Validates the source like the macros do, rendering errors against the
+/// files they came from, which `info` has the source map of.
+pub fn validate(source: &str, info: &SourceInfo) -> Result<(Module, ModuleInfo), String> {
+    let module = Frontend::new().parse(source).map_err(|e| {
+        let message = format!("failed to parse WGSL: {}", e.message());
+        match e.labels().next() {
+            Some((span, label)) if span.is_defined() => {
+                source_error(&message, label, span, source, info)
+            }
+            _ => message,
+        }
+    })?;
+
+    // Push constants are allowed here, since whether they're supported depends on the device.
+    let capabilities = Capabilities::default() | Capabilities::PUSH_CONSTANT;
+    let module_info = Validator::new(ValidationFlags::all(), capabilities)
+        .validate(&module)
+        .map_err(|e| {
+            let mut message = format!("failed to validate WGSL: {}", e);
+            let mut cause = std::error::Error::source(e.as_inner());
+            while let Some(e) = cause {
+                message += &format!(": {}", e);
+                cause = e.source();
+            }
+            match e.spans().next() {
+                Some((span, label)) if span.is_defined() => {
+                    source_error(&message, label, *span, source, info)
+                }
+                _ => message,
+            }
+        })?;
+    Ok((module, module_info))
+}
+
+/// Renders a preprocessor error against the line of `path` it's most likely
+/// about, if there is one.
+pub fn preprocessor_error(path: &Path, e: &PreprocessorError) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let lines = text.lines().collect::<Vec<_>>();
+    let line = error_line(e, &lines)?;
+    Some(render(
+        &e.to_string(),
+        path,
+        line + 1,
+        lines[line],
+        None,
+        "",
+        RED,
+        &[],
+    ))
+}
+
+/// Prints the warnings about defines found while preprocessing to stderr,
+/// against the lines they're about. Warnings in `printed` are skipped, e.g.
+/// ones from a header shared with a shader checked before.
+pub fn print_warnings(info: &SourceInfo, printed: &mut HashSet<(PathBuf, usize, String)>) {
+    for warning in info.warnings.iter() {
+        if !printed.insert(warning.clone()) {
+            continue;
+        }
+        let (path, line_number, message) = warning;
+        let line = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| text.lines().nth(line_number - 1).map(str::to_string));
+        let rendered = match line {
+            Some(line) => {
+                let notes = include_chain(path, info);
+                render(message, path, *line_number, &line, None, "", YELLOW, &notes)
+            }
+            // A virtual header or stdin, which isn't on disk.
+            None => format!("{}:{}: {}", path.display(), line_number, message),
+        };
+        eprintln!("{}: {}", paint("warning", YELLOW), rendered);
+    }
+}
@@ -0,0 +1,161 @@
+//! `wgsl-pp expand`, which writes the expanded source of every shader in a
+//! directory to another one, for tools like wgsl-analyzer that don't
+//! understand the directives to index.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serde_json::{json, Value};
+use wgpu_pp_runtime::{Preprocessor, SourceInfo};
+
+
+/// How often the shaders are checked for changes with `--watch`.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A shader that was expanded, and the files it was expanded from.
+struct Expanded {
+    dependencies: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// Returns the modification time of each file, `None` for files that don't exist.
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Returns the `.wgsl` files in a directory and its subdirectories, sorted.
+pub(crate) fn wgsl_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(wgsl_files(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "wgsl") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Formats the source map sidecar of an expanded shader.
+///
+/// `sources` are the files lines came from, as absolute paths where they
+/// exist, and `lines` has a `[source, line]` pair for each line of the
+/// expanded shader, with lines starting at 1.
+fn source_map(info: &SourceInfo) -> Value {
+    let mut sources: Vec<PathBuf> = vec![];
+    let mut lines = vec![];
+    for (path, line) in info.source_map.iter() {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        let index = match sources.iter().position(|source| *source == path) {
+            Some(index) => index,
+            None => {
+                sources.push(path);
+                sources.len() - 1
+            }
+        };
+        lines.push(json!([index, line]));
+    }
+
+    json!({
+        "version": 1,
+        "sources": sources
+            .iter()
+            .map(|source| source.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        "lines": lines,
+    })
+}
+
+/// Expands the shaders in `dir` that are new or changed since the last call,
+/// returning how many failed.
+///
+/// Headers are expanded too, so every file can be indexed. Shaders that fail
+/// to expand are reported and tried again when they change.
+fn expand_changed(
+    preprocessor: &Preprocessor,
+    dir: &Path,
+    out_dir: &Path,
+    expanded: &mut HashMap<PathBuf, Expanded>,
+) -> io::Result<usize> {
+    let mut failed = 0;
+    let files = wgsl_files(dir)?;
+
+    // Remove the output of shaders that were deleted.
+    expanded.retain(|path, _| {
+        let exists = files.contains(path);
+        if !exists {
+            let out_path = out_dir.join(path.strip_prefix(dir).unwrap_or(path));
+            let _ = fs::remove_file(&out_path);
+            let _ = fs::remove_file(out_path.with_extension("wgsl.map"));
+        }
+        exists
+    });
+
+    for path in files {
+        if let Some(shader) = expanded.get(&path) {
+            if modified(&shader.dependencies) == shader.modified {
+                continue;
+            }
+        }
+
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let dependencies = match preprocessor.process_file_with_info(&path) {
+            Ok((source, info)) => {
+                let out_path = out_dir.join(relative);
+                if let Some(parent) = out_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&out_path, source)?;
+                fs::write(
+                    out_path.with_extension("wgsl.map"),
+                    source_map(&info).to_string(),
+                )?;
+                eprintln!("expanded {}", relative.display());
+                info.dependencies
+            }
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                failed += 1;
+                vec![path.clone()]
+            }
+        };
+        let shader = Expanded {
+            modified: modified(&dependencies),
+            dependencies,
+        };
+        expanded.insert(path, shader);
+    }
+    Ok(failed)
+}
+
+/// Expands every shader in `dir` into `out_dir`, and with `watch`, keeps
+/// expanding them again as they change.
+///
+/// Without `watch`, returns whether every shader expanded.
+pub fn run(
+    preprocessor: &Preprocessor,
+    dir: &Path,
+    out_dir: &Path,
+    watch: bool,
+) -> io::Result<bool> {
+    let mut expanded = HashMap::new();
+    loop {
+        let failed = expand_changed(preprocessor, dir, out_dir, &mut expanded)?;
+        if !watch {
+            return Ok(failed == 0);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,133 @@
+//! `wgsl-pp fmt`, which formats shaders without touching their directives.
+
+use naga::back::wgsl::{Writer, WriterFlags};
+
+use crate::validate;
+
+const INDENT: &str = "    ";
+
+/// Returns the code of a line outside of comments, and whether a block comment
+/// is still open at the end of it.
+fn code(line: &str, mut in_block_comment: bool) -> (String, bool) {
+    let mut code = String::new();
+    let mut rest = line;
+    loop {
+        if in_block_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    rest = &rest[end + 2..];
+                    in_block_comment = false;
+                }
+                None => return (code, true),
+            }
+        }
+        let line_comment = rest.find("//");
+        match rest.find("/*") {
+            Some(start) if line_comment.is_none_or(|comment| start < comment) => {
+                code += &rest[..start];
+                rest = &rest[start + 2..];
+                in_block_comment = true;
+            }
+            _ => {
+                code += &rest[..line_comment.unwrap_or(rest.len())];
+                return (code, in_block_comment);
+            }
+        }
+    }
+}
+
+/// Formats WGSL source, leaving preprocessor directives untouched.
+///
+/// Lines are indented by four spaces per open bracket, trailing whitespace is
+/// removed, consecutive blank lines are collapsed into one, and the file ends
+/// with a single newline. Directives, the lines they continue onto with `\`,
+/// and the inside of block comments are kept as they are.
+pub fn format(source: &str) -> String {
+    let mut formatted = String::new();
+    let mut depth = 0usize;
+    let mut in_block_comment = false;
+    let mut in_directive = false;
+    let mut blank = false;
+
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if in_directive || (!in_block_comment && trimmed.starts_with('#')) {
+            in_directive = line.ends_with('\\');
+            formatted += line;
+            formatted.push('\n');
+            blank = false;
+            continue;
+        }
+
+        if in_block_comment {
+            formatted += line.trim_end();
+            formatted.push('\n');
+        } else if trimmed.is_empty() {
+            // Blank lines at the start and repeated ones are dropped.
+            if !blank && !formatted.is_empty() {
+                formatted.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+
+        let (code, still_in_block_comment) = code(line, in_block_comment);
+        if !in_block_comment {
+            // Lines starting with closing brackets belong to the outer level.
+            let closing = code
+                .trim_start()
+                .chars()
+                .take_while(|c| matches!(c, '}' | ')' | ']'))
+                .count();
+            formatted += &INDENT.repeat(depth.saturating_sub(closing));
+            formatted += trimmed;
+            formatted.push('\n');
+        }
+        for c in code.chars() {
+            match c {
+                '{' | '(' | '[' => depth += 1,
+                '}' | ')' | ']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        in_block_comment = still_in_block_comment;
+        blank = false;
+    }
+
+    // Remove trailing blank lines.
+    while formatted.ends_with("\n\n") {
+        formatted.pop();
+    }
+    formatted
+}
+
+/// Formats WGSL source by round-tripping it through Naga's WGSL writer.
+///
+/// Naga drops comments and can't parse directives, so this is only for plain WGSL.
+pub fn format_with_naga(source: &str) -> Result<String, String> {
+    if source
+        .lines()
+        .any(|line| line.trim_start().starts_with('#'))
+    {
+        return Err("--naga can't format files with preprocessor directives".to_string());
+    }
+    let (module, info) = validate(source)?;
+    let mut formatted = String::new();
+    Writer::new(&mut formatted, WriterFlags::empty())
+        .write(&module, &info)
+        .map_err(|e| format!("failed to write WGSL: {}", e))?;
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn test_format() {
+        let source = "\n\n#include \"common/light.wgsl\"\n#define SCALE(x, y) \\\n    (x * y)\n\n\n\nstruct Light {\ncolor: vec3<f32>,   \n  }\n/* block\n     comment */\nfn main() {\n        let x = array(\n1.0,\n    2.0);\n    if x[0] > 0.0 { // {\n return;\n}\n}\n\n";
+        let expected = "#include \"common/light.wgsl\"\n#define SCALE(x, y) \\\n    (x * y)\n\nstruct Light {\n    color: vec3<f32>,\n}\n/* block\n     comment */\nfn main() {\n    let x = array(\n        1.0,\n        2.0);\n    if x[0] > 0.0 { // {\n        return;\n    }\n}\n";
+        assert_eq!(format(source), expected);
+        assert_eq!(format(expected), expected);
+    }
+}
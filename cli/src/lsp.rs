@@ -0,0 +1,550 @@
+//! `wgsl-pp lsp`, a minimal language server for the preprocessor's directives.
+//!
+//! It speaks the Language Server Protocol over stdin and stdout, providing:
+//!
+//! - Go to definition on `#include` and `#embed` paths, and on defines.
+//! - Hover on a define, showing it and the line it's used on after expansion.
+//! - Diagnostics from preprocessing the shader and validating it with Naga.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use naga::front::wgsl::Frontend;
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use serde_json::{json, Value};
+use wgpu_pp_runtime::{enables_f16, FileProvider, Preprocessor};
+
+use crate::diagnostic::{directive_path, error_line};
+
+/// Open documents take precedence over the files on disk, so diagnostics follow unsaved edits.
+///
+/// Also used for a shader read from stdin, which isn't on disk either.
+#[derive(Clone, Default)]
+pub struct Overlay {
+    pub documents: HashMap<PathBuf, String>,
+}
+
+impl Overlay {
+    fn exists(&self, path: &Path) -> bool {
+        self.documents.contains_key(path) || path.is_file()
+    }
+}
+
+impl FileProvider for Overlay {
+    fn read(&self, path: &Path) -> Option<Cow<'_, str>> {
+        match self.documents.get(path) {
+            Some(text) => Some(Cow::Borrowed(text)),
+            None => fs::read_to_string(path).ok().map(Cow::Owned),
+        }
+    }
+
+    fn read_bytes(&self, path: &Path) -> Option<Cow<'_, [u8]>> {
+        match self.documents.get(path) {
+            Some(text) => Some(Cow::Borrowed(text.as_bytes())),
+            None => fs::read(path).ok().map(Cow::Owned),
+        }
+    }
+}
+
+struct Server {
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    files: Overlay,
+}
+
+/// A position in a document, as a line and a UTF-16 offset in it, like LSP counts them.
+#[derive(Clone, Copy)]
+struct Position {
+    line: u32,
+    character: u32,
+}
+
+fn position(json: &Value) -> Option<Position> {
+    let number = |key: &str| json[key].as_u64().and_then(|n| u32::try_from(n).ok());
+    Some(Position {
+        line: number("line")?,
+        character: number("character")?,
+    })
+}
+
+fn range(line: u32, start: u32, end: u32) -> Value {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+/// Returns the UTF-16 length of a string.
+fn utf16_len(s: &str) -> u32 {
+    s.encode_utf16().count() as u32
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().replace('\\', "/").bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            // Windows paths start with the drive, e.g. `file:///C:/shaders`.
+            b':' => uri.push(':'),
+            byte => uri += &format!("%{:02X}", byte),
+        }
+    }
+    if !uri.starts_with("file:///") {
+        uri.insert(7, '/');
+    }
+    uri
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = vec![];
+    let mut iter = path.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // `/C:/shaders` on Windows.
+    match path.as_bytes() {
+        [b'/', _, b':', ..] => Some(PathBuf::from(&path[1..])),
+        _ => Some(PathBuf::from(path)),
+    }
+}
+
+/// Returns the identifier at `character`, and its start and end, in UTF-16 offsets.
+fn identifier_at(line: &str, character: u32) -> Option<(&str, u32, u32)> {
+    let is_identifier = |c: char| c == '_' || c.is_alphanumeric();
+    let mut offset = 0;
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        if is_identifier(c) {
+            let (byte_start, utf16_start) = *start.get_or_insert((i, offset));
+            let utf16_end = offset + c.len_utf16() as u32;
+            let is_last = !line[i + c.len_utf8()..].starts_with(is_identifier);
+            if is_last {
+                if (utf16_start..=utf16_end).contains(&character) {
+                    let name = &line[byte_start..i + c.len_utf8()];
+                    // Numbers aren't identifiers.
+                    let is_number = name.starts_with(|c: char| c.is_ascii_digit());
+                    return (!is_number).then_some((name, utf16_start, utf16_end));
+                }
+                start = None;
+            }
+        }
+        offset += c.len_utf16() as u32;
+    }
+    None
+}
+
+/// Returns the name a `#define` or `#embed` line defines.
+fn defined_name(line: &str) -> Option<&str> {
+    let mut args = line.split_whitespace();
+    match args.next()? {
+        "#define" => args.next()?.split('(').next(),
+        "#embed" => args.next(),
+        _ => None,
+    }
+}
+
+/// Whether the message has no id, so doesn't get a response.
+fn is_notification(message: &Value) -> bool {
+    message["id"].is_null()
+}
+
+impl Server {
+    fn text(&self, path: &Path) -> Option<Cow<'_, str>> {
+        self.files.read(path)
+    }
+
+    /// Resolves an include like the preprocessor does, next to the including file first.
+    fn resolve(&self, filename: &str, including: &Path) -> Option<PathBuf> {
+        let basepath = including.parent().unwrap_or(Path::new(""));
+        std::iter::once(basepath)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(filename))
+            .find(|path| self.files.exists(path))
+            .map(|path| fs::canonicalize(&path).unwrap_or(path))
+    }
+
+    /// Finds the line defining `name` in the file or the files it includes.
+    fn find_definition(
+        &self,
+        name: &str,
+        path: &Path,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Option<(PathBuf, u32, String)> {
+        if !visited.insert(path.to_path_buf()) {
+            return None;
+        }
+        let text = self.text(path)?;
+        for (i, line) in text.lines().enumerate() {
+            if defined_name(line) == Some(name) {
+                return Some((path.to_path_buf(), i as u32, line.to_string()));
+            }
+        }
+        for line in text.lines() {
+            let Some(include) =
+                directive_path(line).filter(|_| line.trim_start().starts_with("#include"))
+            else {
+                continue;
+            };
+            if let Some(included) = self.resolve(include, path) {
+                if let Some(definition) = self.find_definition(name, &included, visited) {
+                    return Some(definition);
+                }
+            }
+        }
+        None
+    }
+
+    fn preprocessor(&self) -> Preprocessor {
+        let mut preprocessor = Preprocessor::new();
+        for dir in self.include_dirs.iter() {
+            preprocessor = preprocessor.include_dir(dir);
+        }
+        for (name, value) in self.defines.iter() {
+            preprocessor = preprocessor.define(name, value);
+        }
+        preprocessor.file_provider(self.files.clone())
+    }
+
+    fn definition(&self, path: &Path, at: Position) -> Value {
+        let Some(text) = self.text(path) else {
+            return Value::Null;
+        };
+        let Some(line) = text.lines().nth(at.line as usize) else {
+            return Value::Null;
+        };
+
+        if let Some(filename) = directive_path(line) {
+            return match self.resolve(filename, path) {
+                Some(target) => json!({
+                    "uri": path_to_uri(&target),
+                    "range": range(0, 0, 0),
+                }),
+                None => Value::Null,
+            };
+        }
+
+        let Some((name, _, _)) = identifier_at(line, at.character) else {
+            return Value::Null;
+        };
+        match self.find_definition(name, path, &mut HashSet::new()) {
+            Some((target, line_number, line)) => {
+                let start = line.find(name).unwrap_or(0);
+                let start = utf16_len(&line[..start]);
+                json!({
+                    "uri": path_to_uri(&target),
+                    "range": range(line_number, start, start + utf16_len(name)),
+                })
+            }
+            None => Value::Null,
+        }
+    }
+
+    /// Expands the line at `line_number`, by preprocessing the file up to it.
+    fn expand_line(&self, path: &Path, text: &str, line_number: usize) -> Option<String> {
+        let truncated = text
+            .lines()
+            .take(line_number + 1)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut files = self.files.clone();
+        files.documents.insert(path.to_path_buf(), truncated);
+        let server = Server {
+            include_dirs: self.include_dirs.clone(),
+            defines: self.defines.clone(),
+            files,
+        };
+        let expanded = server.preprocessor().process_file(path).ok()?;
+        expanded
+            .trim_end()
+            .lines()
+            .last()
+            .map(|line| line.trim().to_string())
+    }
+
+    fn hover(&self, path: &Path, at: Position) -> Value {
+        let Some(text) = self.text(path) else {
+            return Value::Null;
+        };
+        let Some(line) = text.lines().nth(at.line as usize) else {
+            return Value::Null;
+        };
+        let Some((name, start, end)) = identifier_at(line, at.character) else {
+            return Value::Null;
+        };
+        let Some((_, _, definition)) = self.find_definition(name, path, &mut HashSet::new()) else {
+            return Value::Null;
+        };
+
+        let mut contents = format!("```wgsl\n{}\n```", definition.trim());
+        // Directives aren't expanded, and neither are defines' own lines.
+        if !line.trim_start().starts_with('#') {
+            if let Some(expanded) = self.expand_line(path, &text, at.line as usize) {
+                if expanded != line.trim() {
+                    contents += &format!("\n\nExpands to:\n\n```wgsl\n{}\n```", expanded);
+                }
+            }
+        }
+        json!({
+            "contents": { "kind": "markdown", "value": contents },
+            "range": range(at.line, start, end),
+        })
+    }
+
+    /// Preprocesses and validates a document, returning its diagnostics.
+    ///
+    /// Errors are reported on the line they come from when it can be found, or
+    /// else the first line, since the expanded source has no source map back
+    /// to the original files. Warnings about the document's defines are
+    /// reported on their lines.
+    fn diagnostics(&self, path: &Path) -> Vec<Value> {
+        let Some(text) = self.text(path) else {
+            return vec![];
+        };
+        let lines = text.lines().collect::<Vec<_>>();
+        let diagnostic = |line: usize, severity: u32, message: String| {
+            let length = lines.get(line).map_or(0, |line| utf16_len(line));
+            json!({
+                "range": range(line as u32, 0, length),
+                "severity": severity,
+                "source": "wgsl-pp",
+                "message": message,
+            })
+        };
+        let find_line =
+            |matches: &dyn Fn(&str) -> bool| lines.iter().position(|line| matches(line));
+
+        let (source, info) = match self.preprocessor().process_file_with_info(path) {
+            Ok(result) => result,
+            Err(e) => {
+                let line = error_line(&e, &lines);
+                return vec![diagnostic(line.unwrap_or(0), 1, e.to_string())];
+            }
+        };
+        let mut diagnostics = info
+            .warnings
+            .iter()
+            .filter(|(file, _, _)| file == path)
+            .map(|(_, line_number, message)| diagnostic(line_number - 1, 2, message.clone()))
+            .collect::<Vec<_>>();
+
+        // Naga can't parse f16 yet.
+        if enables_f16(&source) {
+            return diagnostics;
+        }
+        let (message, location) = match Frontend::new().parse(&source) {
+            Err(e) => (e.message().to_string(), e.location(&source)),
+            Ok(module) => {
+                let capabilities = Capabilities::default() | Capabilities::PUSH_CONSTANT;
+                match Validator::new(ValidationFlags::all(), capabilities).validate(&module) {
+                    Ok(_) => return diagnostics,
+                    Err(e) => {
+                        let mut message = e.to_string();
+                        let mut cause = std::error::Error::source(e.as_inner());
+                        while let Some(e) = cause {
+                            message += &format!(": {}", e);
+                            cause = e.source();
+                        }
+                        (message, e.location(&source))
+                    }
+                }
+            }
+        };
+
+        // Find the line in the document the expanded line came from.
+        let line = location.and_then(|location| {
+            let expanded = source
+                .lines()
+                .nth(location.line_number as usize - 1)?
+                .trim();
+            find_line(&|line| !expanded.is_empty() && line.trim() == expanded)
+        });
+        diagnostics.push(diagnostic(line.unwrap_or(0), 1, message));
+        diagnostics
+    }
+
+    /// Publishes diagnostics for every open document, since a change to one can
+    /// break the others including it.
+    fn publish_diagnostics(&self, out: &mut impl Write) -> io::Result<()> {
+        for path in self.files.documents.keys() {
+            let params = json!({
+                "uri": path_to_uri(path),
+                "diagnostics": self.diagnostics(path),
+            });
+            notify(out, "textDocument/publishDiagnostics", params)?;
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: &str, params: &Value) -> Result<Value, (i32, String)> {
+        let document = || {
+            let uri = params["textDocument"]["uri"].as_str();
+            let path = uri.and_then(uri_to_path);
+            let at = position(&params["position"]);
+            match (path, at) {
+                (Some(path), Some(at)) => Ok((path, at)),
+                _ => Err((-32602, "invalid params".to_string())),
+            }
+        };
+
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // Full documents are sent on every change.
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": {
+                    "name": "wgsl-pp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/definition" => {
+                let (path, at) = document()?;
+                Ok(self.definition(&path, at))
+            }
+            "textDocument/hover" => {
+                let (path, at) = document()?;
+                Ok(self.hover(&path, at))
+            }
+            _ => Err((-32601, format!("unsupported method: {}", method))),
+        }
+    }
+
+    /// Handles a notification, returning whether the documents changed.
+    fn notification(&mut self, method: &str, params: &Value) -> bool {
+        let document = &params["textDocument"];
+        let Some(path) = document["uri"].as_str().and_then(uri_to_path) else {
+            return false;
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = document["text"].as_str().unwrap_or_default();
+                self.files.documents.insert(path, text.to_string());
+                true
+            }
+            "textDocument/didChange" => {
+                let Some(change) = params["contentChanges"].as_array().and_then(|changes| changes.last()) else {
+                    return false;
+                };
+                let text = change["text"].as_str().unwrap_or_default();
+                self.files.documents.insert(path, text.to_string());
+                true
+            }
+            "textDocument/didClose" => {
+                self.files.documents.remove(&path);
+                true
+            }
+            // Files that aren't open may have changed too.
+            "textDocument/didSave" => true,
+            _ => false,
+        }
+    }
+}
+
+fn send(out: &mut impl Write, message: Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn notify(out: &mut impl Write, method: &str, params: Value) -> io::Result<()> {
+    send(
+        out,
+        json!({ "jsonrpc": "2.0", "method": method, "params": params }),
+    )
+}
+
+/// Reads a message, or `None` once the client closes the connection.
+fn receive(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let body =
+        String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    serde_json::from_str(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Runs the language server over stdin and stdout until the client exits.
+pub fn run(include_dirs: Vec<PathBuf>, defines: Vec<(String, String)>) -> io::Result<()> {
+    let mut server = Server {
+        include_dirs,
+        defines,
+        files: Overlay::default(),
+    };
+    let mut input = io::stdin().lock();
+    let mut out = io::stdout().lock();
+
+    while let Some(message) = receive(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        if method == "exit" {
+            break;
+        }
+
+        if is_notification(&message) {
+            if server.notification(method, params) {
+                server.publish_diagnostics(&mut out)?;
+            }
+            if method == "textDocument/didClose" {
+                // Clear the closed document's diagnostics.
+                let uri = params["textDocument"]["uri"].clone();
+                let params = json!({ "uri": uri, "diagnostics": [] });
+                notify(&mut out, "textDocument/publishDiagnostics", params)?;
+            }
+            continue;
+        }
+
+        let id = message["id"].clone();
+        let response = match server.request(method, params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        send(&mut out, response)?;
+    }
+    Ok(())
+}
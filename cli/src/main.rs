@@ -0,0 +1,587 @@
+//! `wgsl-pp`, the preprocessor behind the `include_wgsl!` macros as a command,
+//! for artists and tools outside of cargo.
+
+mod check;
+mod deps;
+mod diagnostic;
+mod expand;
+mod fmt;
+mod lsp;
+
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use naga::back::{glsl, hlsl, msl, pipeline_constants, spv};
+use naga::front::wgsl::Frontend;
+use naga::proc::BoundsCheckPolicies;
+use naga::valid::{Capabilities, ModuleInfo, ValidationFlags, Validator};
+use naga::Module;
+use wgpu_pp_runtime::{
+    f16_fallback, parse_directives, register_directive, split_stages, Directive, Limits,
+    LineEnding, Preprocessor, PROFILE_DEFINE,
+};
+
+const USAGE: &str = "\
+Usage: wgsl-pp [OPTIONS] <FILE>
+       wgsl-pp [OPTIONS] --stdin [--base-path <DIR>]
+       wgsl-pp check [OPTIONS] [PATH]...
+       wgsl-pp deps [OPTIONS] [--dot] <FILE>
+       wgsl-pp expand [OPTIONS] [--watch] <DIR> -o <OUT>
+       wgsl-pp fmt [--check] [--naga] <PATH>...
+       wgsl-pp lsp [OPTIONS]
+
+Preprocesses a WGSL shader like the include_wgsl! macros, validates it, and
+prints the expanded source. `wgsl-pp check` finds the include_wgsl! family of
+macros in the Rust files under PATH, or the current directory, and checks every
+shader they use without building the crate. `wgsl-pp deps` prints the include
+tree of a shader, with the names each file defines, instead. `wgsl-pp expand`
+writes the expanded source of every shader in DIR to OUT, with a source map
+next to each, for tools that don't understand the directives. `wgsl-pp fmt`
+formats shaders, or the shaders in directories, in place. `wgsl-pp lsp` runs a
+language server for the directives over stdin and stdout.
+
+Options:
+  -D <NAME>[=<VALUE>]   Define NAME as VALUE, or 1, as if by #define
+  -I <DIR>              Look up includes in DIR when they're not next to the including file
+  --directive <N>=<CMD> Expand #N by running CMD with its arguments appended, splicing in its output
+  --template <N>=<TEXT> Expand #N into TEXT, with $1 to $9 replaced by its arguments and $* by all
+  --directives <FILE>   Declare the directives in FILE, like the macros do from wgpu_pp_directives.toml
+  --profile <NAME>      Build for a profile, defaults to $WGPU_PP_PROFILE
+  --stage <STAGE>       Print only the #stage section STAGE, like include_wgsl_stages!
+  --f16-fallback        Print the f32 fallback of a shader enabling f16, like include_wgsl_f16!
+  --emit <FORMAT>       Print the shader as wgsl (default), spv, msl, hlsl, glsl or naga-ir
+  --entry-point <NAME>  The entry point to translate, for --emit glsl
+  --no-validate         Don't validate the expanded source with Naga, for --emit wgsl
+  -o <FILE>             Write the output to FILE instead of stdout
+  --stdin               Read the shader from stdin instead of FILE
+  --base-path <DIR>     Resolve includes of the shader read from stdin relative to DIR
+  --max-file-size <N>   Fail on files read larger than N bytes, 16 MiB by default
+  --max-output-size <N> Fail when the expanded source gets larger than N bytes, 64 MiB by default
+  --max-include-depth <N> Fail on includes nested more than N files deep, 256 by default
+  --crlf                Write the expanded source with CRLF line endings, for wgsl-pp and wgsl-pp expand
+  --dot                 Print the include graph in Graphviz's DOT language, for wgsl-pp deps
+  --watch               Keep expanding shaders as they change, for wgsl-pp expand
+  --check               List the shaders that aren't formatted instead, for wgsl-pp fmt
+  --naga                Format by round-tripping through Naga, which drops comments, for wgsl-pp fmt
+  -h, --help            Print this message
+  -V, --version         Print the version";
+
+#[derive(Default)]
+struct Args {
+    paths: Vec<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    directives: Vec<(String, Directive)>,
+    profile: Option<String>,
+    stage: Option<String>,
+    f16_fallback: bool,
+    emit: Emit,
+    entry_point: Option<String>,
+    validate: bool,
+    output: Option<PathBuf>,
+    stdin: bool,
+    base_path: Option<PathBuf>,
+    line_ending: LineEnding,
+    limits: Limits,
+    dot: bool,
+    watch: bool,
+    check: bool,
+    naga: bool,
+}
+
+/// What `--emit` prints the shader as.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Emit {
+    /// The expanded source.
+    #[default]
+    Wgsl,
+    /// SPIR-V, as a binary module.
+    Spv,
+    Msl,
+    Hlsl,
+    Glsl,
+    /// Naga's IR, as its `Debug` output.
+    NagaIr,
+}
+
+impl Emit {
+    fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "wgsl" => Ok(Emit::Wgsl),
+            "spv" => Ok(Emit::Spv),
+            "msl" => Ok(Emit::Msl),
+            "hlsl" => Ok(Emit::Hlsl),
+            "glsl" => Ok(Emit::Glsl),
+            "naga-ir" => Ok(Emit::NagaIr),
+            _ => Err(format!("unknown --emit format: {}", format)),
+        }
+    }
+}
+
+enum Command {
+    Run(Args),
+    Check(Args),
+    Deps(Args),
+    Expand(Args),
+    Fmt(Args),
+    Lsp(Args),
+    Help,
+    Version,
+}
+
+/// Parses the arguments, accepting `-DNAME` and `-IDIR` without a space like C compilers do.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut parsed = Args {
+        validate: true,
+        ..Args::default()
+    };
+    let mut args = args.into_iter().peekable();
+    let subcommand =
+        args.next_if(|arg| matches!(arg.as_str(), "check" | "deps" | "expand" | "fmt" | "lsp"));
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str, attached: &str| match attached {
+            "" => args
+                .next()
+                .ok_or_else(|| format!("{} expects a value", flag)),
+            attached => Ok(attached.to_string()),
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--directive" | "--template" => {
+                let directive = value(&arg, "")?;
+                let Some((name, expansion)) = directive
+                    .split_once('=')
+                    .filter(|(name, _)| !name.is_empty())
+                else {
+                    return Err(format!("invalid {}: {}", arg, directive));
+                };
+                let directive = match arg.as_str() {
+                    "--template" => Directive::Template(expansion.to_string()),
+                    _ => {
+                        let mut command = expansion.split_whitespace().map(str::to_string);
+                        let program = command
+                            .next()
+                            .ok_or_else(|| format!("invalid {}: {}", arg, directive))?;
+                        Directive::Command(program, command.collect())
+                    }
+                };
+                let name = name.strip_prefix('#').unwrap_or(name);
+                parsed.directives.push((name.to_string(), directive));
+            }
+            "--directives" => {
+                let path = PathBuf::from(value("--directives", "")?);
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+                let directives = parse_directives(extension, &contents)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                parsed.directives.extend(directives);
+            }
+            "--profile" => parsed.profile = Some(value("--profile", "")?),
+            "--stage" => parsed.stage = Some(value("--stage", "")?),
+            "--f16-fallback" => parsed.f16_fallback = true,
+            "--emit" => parsed.emit = Emit::parse(&value("--emit", "")?)?,
+            "--entry-point" => parsed.entry_point = Some(value("--entry-point", "")?),
+            "--no-validate" => parsed.validate = false,
+            "-o" => parsed.output = Some(value("-o", "")?.into()),
+            "--stdin" => parsed.stdin = true,
+            "--base-path" => parsed.base_path = Some(value("--base-path", "")?.into()),
+            "--crlf" => parsed.line_ending = LineEnding::CrLf,
+            "--max-file-size" | "--max-output-size" | "--max-include-depth" => {
+                let size = value(&arg, "")?;
+                let size = size
+                    .parse()
+                    .map_err(|_| format!("invalid {}: {}", arg, size))?;
+                match arg.as_str() {
+                    "--max-file-size" => parsed.limits.max_file_size = size,
+                    "--max-include-depth" => parsed.limits.max_include_depth = size,
+                    _ => parsed.limits.max_output_size = size,
+                }
+            }
+            "--dot" => parsed.dot = true,
+            "--watch" => parsed.watch = true,
+            "--check" => parsed.check = true,
+            "--naga" => parsed.naga = true,
+            _ if arg.starts_with("-D") => {
+                let define = value("-D", &arg[2..])?;
+                let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+                if name.is_empty() {
+                    return Err(format!("invalid define: {}", define));
+                }
+                parsed.defines.push((name.to_string(), value.to_string()));
+            }
+            _ if arg.starts_with("-I") => parsed.include_dirs.push(value("-I", &arg[2..])?.into()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ => parsed.paths.push(arg.into()),
+        }
+    }
+
+    match (subcommand.as_deref(), &parsed.paths[..]) {
+        (Some("check"), _) => Ok(Command::Check(parsed)),
+        (Some("deps"), [_]) => Ok(Command::Deps(parsed)),
+        (Some("fmt"), []) => Err("expected a path".to_string()),
+        (Some("fmt"), _) => Ok(Command::Fmt(parsed)),
+        (Some("lsp"), []) => Ok(Command::Lsp(parsed)),
+        (Some("expand"), [_]) if parsed.output.is_none() => Err("expected -o <OUT>".to_string()),
+        (Some("expand"), [_]) => Ok(Command::Expand(parsed)),
+        (Some("expand"), []) => Err("expected a directory".to_string()),
+        (None, [_]) if !parsed.stdin => Ok(Command::Run(parsed)),
+        (None, []) if parsed.stdin => Ok(Command::Run(parsed)),
+        (Some("deps") | None, []) => Err("expected a file".to_string()),
+        (_, paths) => match paths.last() {
+            Some(path) => Err(format!("unexpected argument: {}", path.display())),
+            None => Err("expected a path".to_string()),
+        },
+    }
+}
+
+/// Validates the source like the macros do.
+fn validate(source: &str) -> Result<(Module, ModuleInfo), String> {
+    let module = Frontend::new()
+        .parse(source)
+        .map_err(|e| format!("failed to parse WGSL: {}", e.emit_to_string(source)))?;
+
+    // Push constants are allowed here, since whether they're supported depends on the device.
+    let capabilities = Capabilities::default() | Capabilities::PUSH_CONSTANT;
+    let info = Validator::new(ValidationFlags::all(), capabilities)
+        .validate(&module)
+        .map_err(|e| format!("failed to validate WGSL: {}", e.emit_to_string(source)))?;
+    Ok((module, info))
+}
+
+/// Translates a validated module with one of Naga's backends.
+///
+/// Overrides are replaced with their defaults, since the backends need their values.
+fn translate(
+    module: &Module,
+    info: &ModuleInfo,
+    emit: Emit,
+    entry_point: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    if emit == Emit::NagaIr {
+        return Ok(format!("{:#?}\n", module).into_bytes());
+    }
+    let (module, info) = pipeline_constants::process_overrides(module, info, &Default::default())
+        .map_err(|e| format!("failed to apply overrides: {}", e))?;
+
+    match emit {
+        Emit::Wgsl | Emit::NagaIr => unreachable!(),
+        // SPIR-V is written as its words in little-endian, like `.spv` files.
+        Emit::Spv => spv::write_vec(&module, &info, &Default::default(), None)
+            .map(|words| words.iter().flat_map(|word| word.to_le_bytes()).collect())
+            .map_err(|e| format!("failed to write SPIR-V: {}", e)),
+        Emit::Msl => msl::write_string(&module, &info, &Default::default(), &Default::default())
+            .map(|(source, _)| source.into_bytes())
+            .map_err(|e| format!("failed to write MSL: {}", e)),
+        Emit::Hlsl => {
+            let mut out = String::new();
+            hlsl::Writer::new(&mut out, &Default::default())
+                .write(&module, &info, None)
+                .map_err(|e| format!("failed to write HLSL: {}", e))?;
+            Ok(out.into_bytes())
+        }
+        Emit::Glsl => {
+            // GLSL has a single entry point per shader.
+            let names = || {
+                module
+                    .entry_points
+                    .iter()
+                    .map(|entry_point| entry_point.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let entry = match entry_point {
+                Some(name) => module
+                    .entry_points
+                    .iter()
+                    .find(|entry_point| entry_point.name == name)
+                    .ok_or_else(|| {
+                        format!("no entry point {}, expected one of: {}", name, names())
+                    })?,
+                None => match &module.entry_points[..] {
+                    [entry_point] => entry_point,
+                    [] => return Err("--emit glsl needs an entry point".to_string()),
+                    _ => {
+                        return Err(format!(
+                            "--emit glsl needs --entry-point, one of: {}",
+                            names()
+                        ))
+                    }
+                },
+            };
+            let pipeline_options = glsl::PipelineOptions {
+                shader_stage: entry.stage,
+                entry_point: entry.name.clone(),
+                multiview: None,
+            };
+
+            let mut out = String::new();
+            glsl::Writer::new(
+                &mut out,
+                &module,
+                &info,
+                &Default::default(),
+                &pipeline_options,
+                BoundsCheckPolicies::default(),
+            )
+            .and_then(|mut writer| writer.write())
+            .map_err(|e| format!("failed to write GLSL: {}", e))?;
+            Ok(out.into_bytes())
+        }
+    }
+}
+
+/// Registers the `--directive`, `--template` and `--directives` directives.
+fn register_directives(args: &Args) {
+    for (name, directive) in args.directives.iter() {
+        register_directive(name, directive.clone());
+    }
+}
+
+/// Returns the `-D` defines, and the profile from `--profile` or the environment.
+fn defines(args: &Args) -> Vec<(String, String)> {
+    let mut defines = args.defines.clone();
+    let profile = args
+        .profile
+        .clone()
+        .or_else(|| std::env::var(PROFILE_DEFINE).ok())
+        .filter(|profile| !profile.is_empty());
+    if let Some(profile) = profile {
+        defines.push((PROFILE_DEFINE.to_string(), profile));
+    }
+    defines
+}
+
+/// Returns a preprocessor with the `-I` include directories, the defines and the size limits.
+fn preprocessor(args: &Args) -> Preprocessor {
+    register_directives(args);
+    let mut preprocessor = Preprocessor::new().limits(args.limits);
+    for dir in args.include_dirs.iter() {
+        preprocessor = preprocessor.include_dir(dir);
+    }
+    for (name, value) in defines(args) {
+        preprocessor = preprocessor.define(name, value);
+    }
+    preprocessor
+}
+
+/// The name of the shader read from stdin, in `--base-path`.
+const STDIN_NAME: &str = "<stdin>";
+
+fn run(args: Args) -> Result<(), String> {
+    let mut preprocessor = preprocessor(&args);
+    let stdin_path;
+    let file = match args.stdin {
+        // The shader is read through an overlay, so includes still come from disk.
+        true => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|e| format!("failed to read stdin: {}", e))?;
+            let base_path = args.base_path.clone().unwrap_or_default();
+            stdin_path = base_path.join(STDIN_NAME);
+            let mut overlay = lsp::Overlay::default();
+            overlay.documents.insert(stdin_path.clone(), text);
+            preprocessor = preprocessor.file_provider(overlay);
+            &stdin_path
+        }
+        false => &args.paths[0],
+    };
+
+    let (mut source, info) = preprocessor.process_file_with_info(file).map_err(|e| {
+        diagnostic::preprocessor_error(file, &e)
+            .unwrap_or_else(|| format!("{}: {}", file.display(), e))
+    })?;
+    diagnostic::print_warnings(&info, &mut HashSet::new());
+    // The source map doesn't cover the fallback or a stage split from the source.
+    let mapped = !args.f16_fallback && args.stage.is_none();
+    if args.f16_fallback {
+        source = f16_fallback(&source);
+    }
+    if let Some(stage) = args.stage {
+        source = split_stages(&source)
+            .into_iter()
+            .find_map(|(name, source)| (name == stage).then_some(source))
+            .ok_or_else(|| format!("{}: no #stage {} section", file.display(), stage))?;
+    }
+
+    // Naga can't parse f16 yet, so shaders enabling it are only checked through their fallback.
+    let mut translated = None;
+    if args.emit != Emit::Wgsl || (args.validate && !wgpu_pp_runtime::enables_f16(&source)) {
+        let (module, module_info) = match mapped {
+            true => diagnostic::validate(&source, &info)?,
+            false => validate(&source)?,
+        };
+        if args.emit != Emit::Wgsl {
+            translated = Some(translate(
+                &module,
+                &module_info,
+                args.emit,
+                args.entry_point.as_deref(),
+            )?);
+        }
+    }
+
+    // Line endings are converted last, so the source is split into stages and
+    // translated with LF.
+    let output = match translated {
+        Some(output) => output,
+        None => args.line_ending.apply(source).into_bytes(),
+    };
+    write_output(args.output.as_deref(), &output)
+}
+
+/// Writes the output to `-o` if it was passed, or else stdout.
+fn write_output(output: Option<&Path>, bytes: &[u8]) -> Result<(), String> {
+    match output {
+        Some(output) => std::fs::write(output, bytes)
+            .map_err(|e| format!("failed to write {}: {}", output.display(), e)),
+        // Output piped into e.g. `head` being cut short isn't an error.
+        None => match io::stdout().write_all(bytes) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                Err(format!("failed to write output: {}", e))
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+fn run_deps(args: &Args) -> Result<(), String> {
+    let file = &args.paths[0];
+    let (_, info) = preprocessor(args)
+        .process_file_with_info(file)
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+    let text = match args.dot {
+        true => deps::dot(&info),
+        false => deps::tree(&info),
+    };
+    write_output(args.output.as_deref(), text.as_bytes())
+}
+
+/// Formats the shaders at the paths, returning whether they were all formatted,
+/// or with `--check`, already formatted.
+fn run_fmt(args: &Args) -> Result<bool, String> {
+    let mut files = vec![];
+    for path in args.paths.iter() {
+        match path.is_dir() {
+            true => files.extend(
+                expand::wgsl_files(path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?,
+            ),
+            false => files.push(path.clone()),
+        }
+    }
+
+    let mut ok = true;
+    for file in files {
+        let source = std::fs::read_to_string(&file)
+            .map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+        let formatted = match args.naga {
+            true => fmt::format_with_naga(&source),
+            false => Ok(fmt::format(&source)),
+        };
+        let formatted = match formatted {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                diagnostic::print_error(format!("{}: {}", file.display(), e));
+                ok = false;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+
+        if args.check {
+            println!("{}", file.display());
+            ok = false;
+        } else {
+            std::fs::write(&file, formatted)
+                .map_err(|e| format!("failed to write {}: {}", file.display(), e))?;
+        }
+    }
+    Ok(ok)
+}
+
+fn main() -> ExitCode {
+    match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(args)) => match run(args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                diagnostic::print_error(&e);
+                ExitCode::FAILURE
+            }
+        },
+        Ok(Command::Check(args)) => {
+            register_directives(&args);
+            let paths = match args.paths.is_empty() {
+                true => vec![PathBuf::from(".")],
+                false => args.paths.clone(),
+            };
+            match check::run(&paths, &args.include_dirs, &defines(&args)) {
+                Ok(true) => ExitCode::SUCCESS,
+                Ok(false) => ExitCode::FAILURE,
+                Err(e) => {
+                    diagnostic::print_error(&e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Ok(Command::Deps(args)) => match run_deps(&args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                diagnostic::print_error(&e);
+                ExitCode::FAILURE
+            }
+        },
+        Ok(Command::Expand(args)) => {
+            let (dir, out_dir) = (&args.paths[0], args.output.as_ref().unwrap());
+            let preprocessor = preprocessor(&args).line_ending(args.line_ending);
+            match expand::run(&preprocessor, dir, out_dir, args.watch) {
+                Ok(true) => ExitCode::SUCCESS,
+                Ok(false) => ExitCode::FAILURE,
+                Err(e) => {
+                    diagnostic::print_error(&e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Ok(Command::Fmt(args)) => match run_fmt(&args) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(e) => {
+                diagnostic::print_error(&e);
+                ExitCode::FAILURE
+            }
+        },
+        Ok(Command::Lsp(args)) => {
+            register_directives(&args);
+            let defines = defines(&args);
+            match lsp::run(args.include_dirs, defines) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    diagnostic::print_error(&e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Version) => {
+            println!("wgsl-pp {}", env!("CARGO_PKG_VERSION"));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            diagnostic::print_error(format!("{}\n\nRun `wgsl-pp --help` for usage.", e));
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,2 @@
+@group(0) @binding(0) var albedo: texture_2d<f32>;
+@group(0) @binding(1) var albedo_sampler: sampler;
@@ -0,0 +1,46 @@
+#include "material.wgsl" group_offset 1
+
+struct Light {
+    position: vec3f,
+    /// In lux.
+    intensity: f32,
+}
+
+struct Params {
+    scale: f32,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4f,
+    @location(0) uv: vec2f,
+}
+
+/// The light shading the mesh.
+@group(0) @binding(0) var<uniform> light: Light;
+@group(0) @binding(1) var<storage, read_write> values: array<f32>;
+
+var<push_constant> params: Params;
+
+/// Multiplies the output.
+@id(7) override gain: f32 = 1.0;
+override count: u32;
+
+@vertex
+fn vs_main(@location(0) position: vec3f, @location(1) uv: vec2f) -> VertexOutput {
+    var out: VertexOutput;
+    out.position = vec4f(position * params.scale, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4f {
+    return textureSample(albedo, albedo_sampler, in.uv) * light.intensity * gain;
+}
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3u) {
+    if id.x < count {
+        values[id.x] *= params.scale;
+    }
+}
@@ -0,0 +1,6 @@
+@group(0) @binding(0) var<storage, read> light: array<vec4f>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3u) {
+    _ = light[id.x];
+}
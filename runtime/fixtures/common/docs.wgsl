@@ -0,0 +1,24 @@
+// DOCS
+// This tests collecting doc comments for the generated Rust items.
+
+/// Per-frame camera data.
+struct Camera {
+    /// World to clip space.
+    view_proj: mat4x4<f32>,
+    // Not a doc comment.
+    eye: vec3<f32>,
+}
+
+/// The camera of the current view.
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+/// Fog density, between 0 and 1.
+override fog_density: f32 = 0.1;
+
+/// Transforms the vertex.
+/// Positions are in world space.
+@vertex
+fn vs_main(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
+    return camera.view_proj * vec4(position, 1.0);
+}
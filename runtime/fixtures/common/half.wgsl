@@ -0,0 +1,21 @@
+enable f16;
+
+struct Particle {
+    position: vec3<f16>,
+    color: vec4h,
+}
+
+const SCALE: f16 = 0.5h;
+const ORIGIN = vec3h(0h, 1.5h, -2e-1h);
+
+@group(0) @binding(0) var<storage, read_write> particles: array<Particle>;
+@group(0) @binding(1) var<storage, read> transform: mat3x3<f32>;
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let particle = particles[id.x];
+    let position_f32 = transform * vec3<f32>(particle.position);
+    let offset: vec3h = vec3h(position_f32) * SCALE + ORIGIN;
+    particles[id.x].position = offset;
+    particles[id.x].color = particle.color * f16(0x1p-1h);
+}
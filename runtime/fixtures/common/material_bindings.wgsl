@@ -0,0 +1,3 @@
+@group(0) @binding(0) var albedo: texture_2d<f32>;
+@group(0) @binding(1) var albedo_sampler: sampler;
+@group( 1 ) @binding(0) var<uniform> material_params: vec4<f32>;
@@ -0,0 +1,8 @@
+// VIRTUAL HEADER
+// This tests including headers registered in memory, e.g. by derives.
+
+#include <test_header.wgsl>
+
+fn get_light() -> Light {
+    return Light(vec3(0.0), 1.0);
+}
@@ -0,0 +1,10 @@
+// EMBED
+// This tests generating const arrays from data files.
+
+#embed SSAO_KERNEL "data/ssao_kernel.csv" as vec3<f32>
+#embed PALETTE "data/palette.bin"
+#embed HALTON "data/halton.npy"
+
+fn sample_offset(i: u32) -> vec2<f32> {
+    return HALTON[i % 4u];
+}
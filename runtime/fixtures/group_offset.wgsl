@@ -0,0 +1,6 @@
+// GROUP OFFSET
+// This tests shifting the groups of an included file.
+
+@group(0) @binding(0) var<uniform> view_proj: mat4x4<f32>;
+
+#include "common/material_bindings.wgsl" group_offset 1
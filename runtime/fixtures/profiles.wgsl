@@ -0,0 +1,20 @@
+// PROFILES
+// This tests stripping declarations restricted to other profiles.
+
+#define WGPU_PP_PROFILE webgl2
+
+@group(0) @binding(0) var<storage, read_write> data: array<f32>;
+
+// @profiles(desktop, webgpu)
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x > 0u {
+        data[id.x] = 0.0;
+    }
+}
+
+// @profiles(webgl2)
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4(1.0);
+}
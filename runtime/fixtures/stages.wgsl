@@ -0,0 +1,22 @@
+// STAGES
+// This tests splitting a shader into stages, the markers are kept as comments.
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+#stage vertex
+
+@vertex
+fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
+    let uv = vec2(f32(i & 1u), f32(i >> 1u));
+    return VertexOutput(vec4(uv * 2.0 - 1.0, 0.0, 1.0), uv);
+}
+
+#stage fragment
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4(in.uv, 0.0, 1.0);
+}
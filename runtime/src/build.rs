@@ -0,0 +1,223 @@
+//! Preprocessing shaders from build scripts, as an alternative to the macros.
+//!
+//! ```no_run
+//! // build.rs
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! wgpu_pp_runtime::build::compile_dir("shaders", out_dir).unwrap();
+//! ```
+//!
+//! ```ignore
+//! // main.rs
+//! include!(concat!(env!("OUT_DIR"), "/wgpu_pp_shaders.rs"));
+//!
+//! for (path, source) in SHADERS {
+//!     println!("{}: {} bytes", path, source.len());
+//! }
+//! ```
+
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use naga::front::wgsl::Frontend;
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+use crate::preprocessor::preprocess_with_resolver;
+use crate::provider::Resolver;
+use crate::{depfile, wgsl_files, Preprocessor, PreprocessorError, PROFILE_DEFINE};
+
+/// The Rust file listing the compiled shaders, written to the output directory.
+pub const MANIFEST: &str = "wgpu_pp_shaders.rs";
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io(io::Error),
+    Preprocess(PathBuf, PreprocessorError),
+    Validate(PathBuf, String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io(e) => write!(f, "{}", e),
+            BuildError::Preprocess(path, e) => write!(f, "{}: {}", path.display(), e),
+            BuildError::Validate(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+/// Validates the source like the macros do.
+pub(crate) fn validate(source: &str) -> Result<(), String> {
+    let module = Frontend::new()
+        .parse(source)
+        .map_err(|e| format!("failed to parse WGSL: {}", e.emit_to_string(source)))?;
+
+    // Push constants are allowed here, since whether they're supported depends on the device.
+    let capabilities = Capabilities::default() | Capabilities::PUSH_CONSTANT;
+    Validator::new(ValidationFlags::all(), capabilities)
+        .validate(&module)
+        .map_err(|e| format!("failed to validate WGSL: {}", e.emit_to_string(source)))?;
+    Ok(())
+}
+
+/// Preprocesses and validates every shader in `dir`, see [`compile_dir_with`].
+pub fn compile_dir(dir: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Result<(), BuildError> {
+    compile_dir_with(&Preprocessor::new(), dir, out_dir)
+}
+
+/// Preprocesses and validates every shader in `dir` and its subdirectories,
+/// writing them to `out_dir` along with a manifest.
+///
+/// Files included by another shader in `dir` are headers, which are only
+/// compiled as part of the shaders including them, so they can depend on
+/// their includer, e.g. with `#require`. Each shader is written to
+/// the same path under `out_dir`, with a Makefile-style `.d` file next to it
+/// listing the files it was preprocessed from, and [`MANIFEST`] lists them as
+/// `SHADERS`, with their paths relative to `dir`. Like the macros, `WGPU_PP_PROFILE` is
+/// defined from the environment variable of the same name.
+///
+/// Prints `cargo:rerun-if-changed` for `dir` and every file read, so cargo only
+/// runs the build script again when a shader changes.
+pub fn compile_dir_with(
+    preprocessor: &Preprocessor,
+    dir: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+) -> Result<(), BuildError> {
+    let dir = dir.as_ref();
+    let out_dir = out_dir.as_ref();
+
+    let mut defines = preprocessor.defines.clone();
+    println!("cargo:rerun-if-env-changed={}", PROFILE_DEFINE);
+    match std::env::var(PROFILE_DEFINE) {
+        Ok(profile) if !profile.is_empty() => defines.push((PROFILE_DEFINE.to_string(), profile)),
+        _ => {}
+    }
+    let resolver = Resolver {
+        include_dirs: &preprocessor.include_dirs,
+        provider: None,
+        limits: preprocessor.limits,
+        run_commands: true,
+    };
+
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut shaders = vec![];
+    let mut included = HashSet::new();
+    for path in wgsl_files(dir)? {
+        let result =
+            preprocess_with_resolver(&path.to_string_lossy(), Path::new(""), &resolver, &defines);
+        if let Ok((_, info)) = &result {
+            // The first dependency is the shader itself.
+            for dependency in info.dependencies.iter().skip(1) {
+                included.insert(fs::canonicalize(dependency)?);
+            }
+            for dependency in info.dependencies.iter() {
+                println!("cargo:rerun-if-changed={}", dependency.display());
+            }
+        }
+        shaders.push((path, result));
+    }
+
+    let mut manifest = String::new();
+    manifest += "// Generated by wgpu_pp_runtime::build, don't edit.\n\n";
+    manifest +=
+        "/// `(path, source)` for each shader, with paths relative to the shader directory.\n";
+    manifest += "pub const SHADERS: &[(&str, &str)] = &[\n";
+    for (path, result) in shaders {
+        // Headers can fail on their own, e.g. with a `#require` that their
+        // includer satisfies, so their errors only count for shaders.
+        if included.contains(&fs::canonicalize(&path)?) {
+            continue;
+        }
+        let (source, info) = result.map_err(|e| BuildError::Preprocess(path.clone(), e))?;
+        validate(&source).map_err(|e| BuildError::Validate(path.clone(), e))?;
+
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let out_path = out_dir.join(relative);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&out_path, source)?;
+
+        let out_path = fs::canonicalize(&out_path)?;
+        let dependencies = info
+            .dependencies
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut depfile_path = out_path.clone().into_os_string();
+        depfile_path.push(".d");
+        fs::write(depfile_path, depfile(&out_path, &dependencies))?;
+
+        manifest += &format!(
+            "    ({:?}, include_str!({:?})),\n",
+            relative.to_string_lossy().replace('\\', "/"),
+            out_path.to_string_lossy()
+        );
+    }
+    manifest += "];\n";
+    fs::write(out_dir.join(MANIFEST), manifest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_compile_dir() {
+        let dir = std::env::temp_dir().join(format!("wgpu_pp_build_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (shaders, out_dir) = (dir.join("shaders"), dir.join("out"));
+        fs::create_dir_all(&shaders).unwrap();
+        fs::create_dir_all(&out_dir).unwrap();
+        // Headers that only preprocess as part of their includer.
+        fs::write(
+            shaders.join("lights.wgsl"),
+            "#require MAX_LIGHTS\nconst max_lights = MAX_LIGHTS;\n",
+        )
+        .unwrap();
+        fs::write(
+            shaders.join("blur.wgsl"),
+            "#require RADIUS\nconst radius = RADIUS;\n",
+        )
+        .unwrap();
+        fs::write(
+            shaders.join("main.wgsl"),
+            "#define MAX_LIGHTS 4u\n\
+             #include \"lights.wgsl\"\n\
+             #include \"blur.wgsl\" with (RADIUS = 2)\n\
+             @compute @workgroup_size(1) fn main() {}\n",
+        )
+        .unwrap();
+
+        compile_dir(&shaders, &out_dir).unwrap();
+        let manifest = fs::read_to_string(out_dir.join(MANIFEST)).unwrap();
+        assert!(manifest.contains("(\"main.wgsl\", "), "{}", manifest);
+        assert!(!manifest.contains("lights.wgsl"), "{}", manifest);
+        assert!(!out_dir.join("blur.wgsl").exists());
+        let main = fs::read_to_string(out_dir.join("main.wgsl")).unwrap();
+        assert!(main.contains("const max_lights = 4u;"), "{}", main);
+        assert!(out_dir.join("main.wgsl.d").exists());
+
+        // Shaders that nothing includes still fail.
+        fs::write(shaders.join("broken.wgsl"), "#require MISSING\n").unwrap();
+        match compile_dir(&shaders, &out_dir) {
+            Err(BuildError::Preprocess(path, _)) => assert!(path.ends_with("broken.wgsl")),
+            result => panic!("expected a preprocess error, got {:?}", result),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
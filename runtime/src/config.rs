@@ -0,0 +1,247 @@
+/// Reads defines from a config file, for `#include_defines`.
+///
+/// TOML and JSON files are supported, as long as they're key/value pairs:
+/// tables and objects prefix the names of the keys in them, e.g. `[player]`
+/// with `speed = 4.0` defines `player_speed`. Numbers and booleans are
+/// defined as they are, arrays as `array(...)`, and strings without their
+/// quotes, so they can hold any WGSL, e.g. `"vec3(1.0, 0.5, 0.0)"`.
+/// Arrays of tables, dates and nulls are errors.
+pub fn parse_defines(extension: &str, contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut defines = vec![];
+    match extension {
+        "toml" => {
+            let table = contents
+                .parse::<toml::Table>()
+                .map_err(|e| e.message().to_string() + &toml_location(contents, e.span()))?;
+            toml_defines("", table, &mut defines)?;
+        }
+        "json" => {
+            let value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+            let serde_json::Value::Object(object) = value else {
+                return Err("expected an object".to_string());
+            };
+            json_defines("", object, &mut defines)?;
+        }
+        _ => {
+            return Err(format!(
+                "unsupported file type: .{}, expected .toml or .json",
+                extension
+            ))
+        }
+    }
+    for (name, _) in defines.iter() {
+        if !is_identifier(name) {
+            return Err(format!("`{}` isn't a valid define name", name));
+        }
+    }
+    Ok(defines)
+}
+
+/// Returns whether `name` can be used as the name of a define or directive.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || unicode_ident::is_xid_start(c))
+        && chars.all(unicode_ident::is_xid_continue)
+}
+
+/// Returns ` at line N`, for the start of a TOML error's span.
+pub(crate) fn toml_location(contents: &str, span: Option<std::ops::Range<usize>>) -> String {
+    match span {
+        Some(span) => {
+            let line = contents[..span.start].matches('\n').count() + 1;
+            format!(" at line {}", line)
+        }
+        None => String::new(),
+    }
+}
+
+/// Formats a float so it stays a float in WGSL, e.g. `4.0` rather than `4`.
+fn float(name: &str, value: f64) -> Result<String, String> {
+    match value.is_finite() {
+        true => Ok(format!("{:?}", value)),
+        false => Err(format!("{}: unsupported value: {}", name, value)),
+    }
+}
+
+fn toml_value(name: &str, value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(f) => float(name, f),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| match item {
+                    toml::Value::Table(_) => {
+                        Err(format!("{}: arrays of tables aren't supported", name))
+                    }
+                    item => toml_value(name, item),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("array({})", items.join(", ")))
+        }
+        toml::Value::Datetime(date) => Err(format!("{}: unsupported value: {}", name, date)),
+        toml::Value::Table(_) => unreachable!("tables are flattened"),
+    }
+}
+
+/// Adds a define per key of a table, prefixed with the names of the tables it's in.
+fn toml_defines(
+    prefix: &str,
+    table: toml::Table,
+    defines: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = prefix.to_string() + &key;
+        match value {
+            toml::Value::Table(table) => toml_defines(&(name + "_"), table, defines)?,
+            value => {
+                let value = toml_value(&name, value)?;
+                defines.push((name, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn json_value(name: &str, value: serde_json::Value) -> Result<String, String> {
+    match value {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() => float(name, f),
+            _ => Ok(n.to_string()),
+        },
+        serde_json::Value::Bool(b) => Ok(b.to_string()),
+        serde_json::Value::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| match item {
+                    serde_json::Value::Object(_) => {
+                        Err(format!("{}: arrays of objects aren't supported", name))
+                    }
+                    item => json_value(name, item),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("array({})", items.join(", ")))
+        }
+        serde_json::Value::Null => Err(format!("{}: unsupported value: null", name)),
+        serde_json::Value::Object(_) => unreachable!("objects are flattened"),
+    }
+}
+
+/// Adds a define per key of an object, prefixed with the names of the objects it's in.
+fn json_defines(
+    prefix: &str,
+    object: serde_json::Map<String, serde_json::Value>,
+    defines: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (key, value) in object {
+        let name = prefix.to_string() + &key;
+        match value {
+            serde_json::Value::Object(object) => json_defines(&(name + "_"), object, defines)?,
+            value => {
+                let value = json_value(&name, value)?;
+                defines.push((name, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn define(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_toml() {
+        let toml = r#"
+GRAVITY = -9.81 # m/s²
+weights = [
+    0.25,
+    0.5, # the middle
+    0.25,
+]
+
+[player]
+speed = 4.0
+tint = "vec3(1.0, 0.5, 0.0)"
+name = "say \"hi\" # not a comment"
+limits = { min = 1, max = 10 }
+"#;
+        assert_eq!(
+            parse_defines("toml", toml).unwrap(),
+            vec![
+                define("GRAVITY", "-9.81"),
+                define("weights", "array(0.25, 0.5, 0.25)"),
+                define("player_speed", "4.0"),
+                define("player_tint", "vec3(1.0, 0.5, 0.0)"),
+                define("player_name", "say \"hi\" # not a comment"),
+                define("player_limits_min", "1"),
+                define("player_limits_max", "10"),
+            ]
+        );
+        assert_eq!(
+            parse_defines("toml", "s = \"\"\"\nmulti\nline\"\"\"").unwrap(),
+            vec![define("s", "multi\nline")]
+        );
+        assert_eq!(
+            parse_defines("toml", "s = \"\\u00e9\\U0001F600\\t\"").unwrap(),
+            vec![define("s", "é😀\t")]
+        );
+    }
+
+    #[test]
+    fn test_toml_unsupported() {
+        let error = |toml: &str| parse_defines("toml", toml).unwrap_err();
+        assert_eq!(
+            error("[[lights]]\nx = 1"),
+            "lights: arrays of tables aren't supported"
+        );
+        assert_eq!(
+            error("lights = [{ x = 1 }]"),
+            "lights: arrays of tables aren't supported"
+        );
+        assert_eq!(
+            error("day = 1979-05-27"),
+            "day: unsupported value: 1979-05-27"
+        );
+        assert_eq!(error("x = inf"), "x: unsupported value: inf");
+        assert!(error("a = 1\nvalues = [\n1,\n2").ends_with(" at line 4"));
+    }
+
+    #[test]
+    fn test_json() {
+        let json = r#"{
+            "GRAVITY": -9.81,
+            "player": { "speed": 4.0, "tint": "vec3(1.0, 0.5, 0.0)" },
+            "weights": [0.25, 0.5],
+            "escaped": "\"\u00e9\ud83d\ude00\/\n"
+        }"#;
+        assert_eq!(
+            parse_defines("json", json).unwrap(),
+            vec![
+                define("GRAVITY", "-9.81"),
+                define("player_speed", "4.0"),
+                define("player_tint", "vec3(1.0, 0.5, 0.0)"),
+                define("weights", "array(0.25, 0.5)"),
+                define("escaped", "\"é😀/\n"),
+            ]
+        );
+        assert_eq!(
+            parse_defines("json", r#"{"lights": [{"x": 1}]}"#).unwrap_err(),
+            "lights: arrays of objects aren't supported"
+        );
+        assert_eq!(
+            parse_defines("json", r#"{"s": null}"#).unwrap_err(),
+            "s: unsupported value: null"
+        );
+        assert!(parse_defines("json", r#"{"s": "\ud83d"}"#).is_err());
+    }
+}
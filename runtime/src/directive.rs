@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::config::{is_identifier, toml_location};
+
+/// The file in a crate's root that the macros and `wgsl-pp check` read
+/// custom directives from, see [`register_directives_file`].
+pub const DIRECTIVES_FILE: &str = "wgpu_pp_directives.toml";
+
+/// Set to `1` to let the macros and `wgsl-pp check` run the commands in
+/// [`DIRECTIVES_FILE`], see [`register_crate_directives`].
+pub const ALLOW_COMMANDS: &str = "WGPU_PP_ALLOW_COMMANDS";
+
+/// How long a command can run before it's killed and the directive fails.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a custom directive is expanded, see [`register_directive`].
+#[derive(Clone, Debug)]
+pub enum Directive {
+    /// Runs a program with these arguments followed by the directive's, and
+    /// splices in what it prints to stdout.
+    Command(String, Vec<String>),
+    /// Splices in a template, with `$1` to `$9` replaced by the directive's
+    /// arguments, `$*` by all of them separated by spaces, and `$$` by `$`.
+    Template(String),
+}
+
+lazy_static! {
+    static ref DIRECTIVES: Mutex<HashMap<String, Directive>> = Mutex::new(HashMap::new());
+}
+
+/// Registers a custom directive, so that shaders can use `#name args...`.
+///
+/// The directive's line is replaced by its expansion, which can use defines
+/// like any other line. Built-in directives like `#include` can't be replaced.
+///
+/// ```
+/// use wgpu_pp_runtime::{register_directive, Directive};
+///
+/// register_directive("repeat3", Directive::Template("$1, $1, $1".to_string()));
+/// register_directive(
+///     "generate_noise",
+///     Directive::Command("python3".to_string(), vec!["scripts/noise.py".to_string()]),
+/// );
+/// ```
+pub fn register_directive(name: &str, directive: Directive) {
+    DIRECTIVES
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), directive);
+}
+
+/// Parses custom directives from a TOML or JSON config file, with templates
+/// in a `template` table and commands in a `command` table:
+///
+/// ```toml
+/// [template]
+/// repeat3 = "const $1 = array($2, $2, $2);"
+///
+/// [command]
+/// generate_noise = "python3 scripts/noise.py"
+/// ```
+///
+/// Commands are split at whitespace into the program and its arguments.
+/// Other tables and keys, values that aren't strings, and names used for both
+/// a template and a command are errors.
+pub fn parse_directives(
+    extension: &str,
+    contents: &str,
+) -> Result<Vec<(String, Directive)>, String> {
+    // `(table, name, value)`, with `None` for values that aren't strings.
+    let mut entries = vec![];
+    match extension {
+        "toml" => {
+            let table = contents
+                .parse::<toml::Table>()
+                .map_err(|e| e.message().to_string() + &toml_location(contents, e.span()))?;
+            for (key, value) in table {
+                let toml::Value::Table(table) = value else {
+                    return Err(not_a_table(&key));
+                };
+                check_table(&key)?;
+                for (name, value) in table {
+                    let value = value.as_str().map(str::to_string);
+                    entries.push((key.clone(), name, value));
+                }
+            }
+        }
+        "json" => {
+            let value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+            let serde_json::Value::Object(object) = value else {
+                return Err("expected an object".to_string());
+            };
+            for (key, value) in object {
+                let serde_json::Value::Object(object) = value else {
+                    return Err(not_a_table(&key));
+                };
+                check_table(&key)?;
+                for (name, value) in object {
+                    let value = value.as_str().map(str::to_string);
+                    entries.push((key.clone(), name, value));
+                }
+            }
+        }
+        _ => {
+            return Err(format!(
+                "unsupported file type: .{}, expected .toml or .json",
+                extension
+            ))
+        }
+    }
+
+    let mut directives: Vec<(String, Directive)> = vec![];
+    for (table, name, value) in entries {
+        if !is_identifier(&name) {
+            return Err(format!("`{}` isn't a valid directive name", name));
+        }
+        let Some(value) = value else {
+            return Err(format!("`{}` isn't a string", name));
+        };
+        if directives.iter().any(|(existing, _)| *existing == name) {
+            return Err(format!("`{}` is both a template and a command", name));
+        }
+        let directive = match table.as_str() {
+            "template" => Directive::Template(value),
+            _ => {
+                let mut command = value.split_whitespace().map(str::to_string);
+                let Some(program) = command.next() else {
+                    return Err(format!("`{}` has an empty command", name));
+                };
+                Directive::Command(program, command.collect())
+            }
+        };
+        directives.push((name, directive));
+    }
+    Ok(directives)
+}
+
+/// Errors for a top-level key of a directives file that isn't a table.
+fn not_a_table(key: &str) -> String {
+    match key {
+        "template" | "command" => format!("`{}` isn't a table", key),
+        key => format!("`{}` isn't in the `template` or `command` table", key),
+    }
+}
+
+/// Errors for tables other than `template` and `command`.
+fn check_table(key: &str) -> Result<(), String> {
+    match key {
+        "template" | "command" => Ok(()),
+        key => Err(format!(
+            "unknown table `{}`, expected `template` or `command`",
+            key
+        )),
+    }
+}
+
+fn read_directives_file(path: &Path) -> Result<Vec<(String, Directive)>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    parse_directives(extension, &contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Registers the custom directives declared in a config file, see
+/// [`parse_directives`]. `wgsl-pp` reads the same file with `--directives`.
+pub fn register_directives_file(path: &Path) -> Result<(), String> {
+    for (name, directive) in read_directives_file(path)? {
+        register_directive(&name, directive);
+    }
+    Ok(())
+}
+
+/// Registers the custom directives in a crate's [`DIRECTIVES_FILE`], like
+/// [`register_directives_file`].
+///
+/// This is how the macros, which can't call [`register_directive`] since they
+/// run inside the compiler, get custom directives, and how `wgsl-pp check`
+/// gets the same ones. Compiling a crate, e.g. when an editor checks it,
+/// shouldn't run programs it names, so commands are an error unless
+/// [`ALLOW_COMMANDS`] is set to `1`, e.g. in the `[env]` table of
+/// `.cargo/config.toml`. Templates are always allowed.
+pub fn register_crate_directives(path: &Path) -> Result<(), String> {
+    let directives = read_directives_file(path)?;
+    if std::env::var(ALLOW_COMMANDS).as_deref() != Ok("1") {
+        reject_commands(&directives).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    for (name, directive) in directives {
+        register_directive(&name, directive);
+    }
+    Ok(())
+}
+
+/// Fails on the first command, for [`register_crate_directives`].
+pub(crate) fn reject_commands(directives: &[(String, Directive)]) -> Result<(), String> {
+    match directives
+        .iter()
+        .find(|(_, directive)| matches!(directive, Directive::Command(..)))
+    {
+        Some((name, _)) => Err(format!(
+            "`{}` runs a command, which needs {}=1",
+            name, ALLOW_COMMANDS
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the directive registered as `name`, if any.
+pub(crate) fn directive(name: &str) -> Option<Directive> {
+    DIRECTIVES.lock().unwrap().get(name).cloned()
+}
+
+/// Fills in a template's arguments.
+fn fill_template(template: &str, args: &[&str]) -> String {
+    let mut filled = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            filled.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => filled.push('$'),
+            Some('*') => filled += &args.join(" "),
+            Some(&digit @ '1'..='9') => {
+                let index = digit as usize - '1' as usize;
+                filled += args.get(index).copied().unwrap_or("");
+            }
+            _ => {
+                filled.push('$');
+                continue;
+            }
+        }
+        chars.next();
+    }
+    filled
+}
+
+impl Directive {
+    /// Expands the directive with the arguments it was given.
+    pub(crate) fn expand(&self, args: &[&str]) -> Result<String, String> {
+        match self {
+            Directive::Template(template) => Ok(fill_template(template, args)),
+            Directive::Command(program, program_args) => {
+                let mut command = Command::new(program);
+                command.args(program_args).args(args);
+                run(program, command, COMMAND_TIMEOUT)
+            }
+        }
+    }
+}
+
+/// Reads a pipe to the end on another thread, so a command filling one of
+/// its pipes doesn't block while the other is read.
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// Runs a command, returning its stdout, or killing it once `timeout` passes.
+pub(crate) fn run(
+    program: &str,
+    mut command: Command,
+    timeout: Duration,
+) -> Result<String, String> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "{} didn't finish within {}s",
+                    program,
+                    timeout.as_secs_f32()
+                ));
+            }
+            Err(e) => return Err(format!("failed to run {}: {}", program, e)),
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(match stderr.trim() {
+            "" => format!("{} failed with {}", program, status),
+            stderr => format!("{} failed with {}: {}", program, status, stderr),
+        });
+    }
+    let stdout =
+        String::from_utf8(stdout).map_err(|_| format!("{} printed invalid utf-8", program))?;
+    Ok(stdout.trim_end_matches('\n').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(extension: &str, contents: &str) -> String {
+        parse_directives(extension, contents).unwrap_err()
+    }
+
+    #[test]
+    fn test_valid_files() {
+        let toml = "[command]\nnoise = \"python3  scripts/noise.py --seed 1\"\n\n\
+                    [template]\nrepeat3 = \"$1, $1, $1\"\nempty = \"\"\n";
+        let json = r#"{
+            "command": { "noise": "python3  scripts/noise.py --seed 1" },
+            "template": { "repeat3": "$1, $1, $1", "empty": "" }
+        }"#;
+        for (extension, contents) in [("toml", toml), ("json", json)] {
+            let directives = parse_directives(extension, contents).unwrap();
+            assert!(matches!(
+                &directives[..],
+                [
+                    (noise, Directive::Command(program, args)),
+                    (repeat3, Directive::Template(template)),
+                    (empty, Directive::Template(nothing)),
+                ] if noise == "noise"
+                    && program == "python3"
+                    && args == &["scripts/noise.py", "--seed", "1"]
+                    && repeat3 == "repeat3"
+                    && template == "$1, $1, $1"
+                    && empty == "empty"
+                    && nothing.is_empty()
+            ));
+        }
+
+        assert!(parse_directives("toml", "").unwrap().is_empty());
+        assert!(parse_directives("json", r#"{"template": {}}"#)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        // Keys outside the tables aren't flattened into them.
+        assert_eq!(
+            error("toml", "template_repeat3 = \"$1\"\n"),
+            "`template_repeat3` isn't in the `template` or `command` table"
+        );
+        assert_eq!(
+            error("json", r#"{"command_noise": "python3"}"#),
+            "`command_noise` isn't in the `template` or `command` table"
+        );
+        assert_eq!(
+            error("toml", "[templates]\nrepeat3 = \"$1\"\n"),
+            "unknown table `templates`, expected `template` or `command`"
+        );
+        assert_eq!(
+            error("toml", "template = \"$1\"\n"),
+            "`template` isn't a table"
+        );
+        assert_eq!(
+            error("json", r#"{"command": ["python3"]}"#),
+            "`command` isn't a table"
+        );
+
+        assert_eq!(
+            error("toml", "[template]\nrepeat3 = 3\n"),
+            "`repeat3` isn't a string"
+        );
+        assert_eq!(
+            error("toml", "[template.nested]\nrepeat3 = \"$1\"\n"),
+            "`nested` isn't a string"
+        );
+        assert_eq!(
+            error("json", r#"{"template": {"repeat-3": "$1"}}"#),
+            "`repeat-3` isn't a valid directive name"
+        );
+        assert_eq!(
+            error(
+                "toml",
+                "[template]\nnoise = \"$1\"\n[command]\nnoise = \"python3\"\n"
+            ),
+            "`noise` is both a template and a command"
+        );
+        assert_eq!(
+            error("toml", "[command]\nnoise = \"\"\n"),
+            "`noise` has an empty command"
+        );
+
+        assert_eq!(error("json", "[]"), "expected an object");
+        assert!(error("toml", "[template]\nrepeat3 = ").ends_with(" at line 2"));
+        assert_eq!(
+            error("yaml", ""),
+            "unsupported file type: .yaml, expected .toml or .json"
+        );
+    }
+}
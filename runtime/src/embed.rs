@@ -0,0 +1,268 @@
+/// A scalar type data can be embedded as.
+#[derive(Clone, Copy, PartialEq)]
+enum Scalar {
+    F32,
+    I32,
+    U32,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        match name {
+            "f32" | "f" => Some(Scalar::F32),
+            "i32" | "i" => Some(Scalar::I32),
+            "u32" | "u" => Some(Scalar::U32),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Scalar::F32 => "f32",
+            Scalar::I32 => "i32",
+            Scalar::U32 => "u32",
+        }
+    }
+
+    /// Formats a value as a WGSL literal of this type.
+    fn literal(self, value: f64) -> Result<String, String> {
+        match self {
+            Scalar::F32 if value.is_finite() => Ok(format!("{:?}f", value as f32)),
+            Scalar::I32
+                if value.fract() == 0.0 && value >= i32::MIN as f64 && value <= i32::MAX as f64 =>
+            {
+                Ok(format!("{}i", value as i32))
+            }
+            Scalar::U32 if value.fract() == 0.0 && value >= 0.0 && value <= u32::MAX as f64 => {
+                Ok(format!("{}u", value as u32))
+            }
+            _ => Err(format!("{} is not a valid {}", value, self.name())),
+        }
+    }
+
+    /// Reinterprets a little-endian 32-bit word as this type.
+    fn read_word(self, bytes: [u8; 4]) -> f64 {
+        match self {
+            Scalar::F32 => f32::from_le_bytes(bytes) as f64,
+            Scalar::I32 => i32::from_le_bytes(bytes) as f64,
+            Scalar::U32 => u32::from_le_bytes(bytes) as f64,
+        }
+    }
+}
+
+/// The element type of an embedded array, e.g. `f32` or `vec4<f32>`.
+#[derive(Clone, Copy)]
+struct ElementType {
+    scalar: Scalar,
+    components: usize,
+}
+
+impl ElementType {
+    /// Parses `f32`, `vec3<f32>` or `vec3f`.
+    fn parse(name: &str) -> Option<ElementType> {
+        if let Some(scalar) = Scalar::parse(name).filter(|_| name.len() == 3) {
+            return Some(ElementType {
+                scalar,
+                components: 1,
+            });
+        }
+
+        let rest = name.strip_prefix("vec")?;
+        let components = match rest.get(..1)? {
+            "2" => 2,
+            "3" => 3,
+            "4" => 4,
+            _ => return None,
+        };
+        let scalar = match rest[1..].strip_prefix('<') {
+            Some(scalar) => Scalar::parse(scalar.strip_suffix('>')?)?,
+            None => Scalar::parse(&rest[1..]).filter(|_| rest.len() == 2)?,
+        };
+        Some(ElementType { scalar, components })
+    }
+
+    fn name(self) -> String {
+        match self.components {
+            1 => self.scalar.name().to_string(),
+            n => format!("vec{}<{}>", n, self.scalar.name()),
+        }
+    }
+}
+
+/// Parses comma or whitespace separated numbers. Lines starting with `#` are skipped.
+fn parse_csv(bytes: &[u8]) -> Result<Vec<f64>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "not valid utf-8".to_string())?;
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number: {}", value))
+        })
+        .collect()
+}
+
+/// Parses a `.npy` file, returning its values and default element type.
+///
+/// Only C-order arrays of little-endian numbers are supported. Arrays with a
+/// last dimension of 2 to 4 default to vectors.
+fn parse_npy(bytes: &[u8]) -> Result<(Vec<f64>, ElementType), String> {
+    let rest = bytes
+        .strip_prefix(b"\x93NUMPY")
+        .ok_or_else(|| "not a .npy file".to_string())?;
+    let (header_len, rest) = match rest {
+        [1, _, a, b, rest @ ..] => (u16::from_le_bytes([*a, *b]) as usize, rest),
+        [2 | 3, _, a, b, c, d, rest @ ..] => (u32::from_le_bytes([*a, *b, *c, *d]) as usize, rest),
+        _ => return Err("unsupported .npy version".to_string()),
+    };
+    if rest.len() < header_len {
+        return Err("truncated .npy header".to_string());
+    }
+    let header = String::from_utf8_lossy(&rest[..header_len]);
+    let data = &rest[header_len..];
+
+    // The header is a Python dict literal, e.g.
+    // {'descr': '<f4', 'fortran_order': False, 'shape': (64, 4), }
+    let field = |key: &str| {
+        let start = header.find(&format!("'{}':", key))? + key.len() + 3;
+        Some(header[start..].trim_start().to_string())
+    };
+    let descr = field("descr")
+        .and_then(|descr| descr.split('\'').nth(1).map(String::from))
+        .ok_or_else(|| "missing descr in .npy header".to_string())?;
+    if field("fortran_order").is_some_and(|order| order.starts_with("True")) {
+        return Err("fortran order .npy files are not supported".to_string());
+    }
+    let shape = field("shape")
+        .and_then(|shape| {
+            let end = shape.find(')')?;
+            shape[1..end]
+                .split(',')
+                .map(str::trim)
+                .filter(|dim| !dim.is_empty())
+                .map(|dim| dim.parse::<usize>().ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| "missing shape in .npy header".to_string())?;
+
+    let dtype = descr.trim_start_matches(['<', '|']);
+    let scalar = match dtype {
+        "f4" | "f8" => Scalar::F32,
+        "i1" | "i2" | "i4" | "i8" => Scalar::I32,
+        "u1" | "u2" | "u4" | "u8" | "b1" => Scalar::U32,
+        _ => return Err(format!("unsupported .npy dtype: {}", descr)),
+    };
+    let size = dtype[1..].parse::<usize>().unwrap();
+
+    let count = shape.iter().product::<usize>();
+    if data.len() < count * size {
+        return Err("truncated .npy data".to_string());
+    }
+    let values = data[..count * size]
+        .chunks_exact(size)
+        .map(|chunk| {
+            let mut bytes = [0u8; 8];
+            bytes[..size].copy_from_slice(chunk);
+            let negative = chunk[size - 1] & 0x80 != 0;
+            match (scalar, size) {
+                (Scalar::F32, 4) => f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+                (Scalar::F32, _) => f64::from_le_bytes(bytes),
+                (Scalar::I32, _) => {
+                    // Sign extend, then read as i64.
+                    if negative {
+                        bytes[size..].fill(0xff);
+                    }
+                    i64::from_le_bytes(bytes) as f64
+                }
+                (_, _) => u64::from_le_bytes(bytes) as f64,
+            }
+        })
+        .collect();
+
+    let components = match shape.as_slice() {
+        [_, .., last @ 2..=4] => *last,
+        _ => 1,
+    };
+    Ok((values, ElementType { scalar, components }))
+}
+
+/// Generates a WGSL `const` array from the contents of a data file.
+///
+/// The format follows the file's extension: `.csv` and `.txt` files hold numbers
+/// separated by commas or whitespace, `.npy` files are NumPy arrays, and
+/// anything else is read as little-endian 32-bit words. `ty` overrides the
+/// element type, which defaults to `f32`, `u32` for binary files, or the
+/// array's type for `.npy` files.
+pub fn embed(
+    name: &str,
+    extension: &str,
+    bytes: &[u8],
+    ty: Option<&str>,
+) -> Result<String, String> {
+    let ty = match ty {
+        Some(ty) => {
+            Some(ElementType::parse(ty).ok_or_else(|| format!("unsupported type: {}", ty))?)
+        }
+        None => None,
+    };
+
+    let (values, ty) = match extension {
+        "csv" | "txt" => (
+            parse_csv(bytes)?,
+            ty.unwrap_or(ElementType {
+                scalar: Scalar::F32,
+                components: 1,
+            }),
+        ),
+        "npy" => {
+            let (values, default) = parse_npy(bytes)?;
+            (values, ty.unwrap_or(default))
+        }
+        _ => {
+            if !bytes.len().is_multiple_of(4) {
+                return Err("binary data must be a multiple of 4 bytes".to_string());
+            }
+            let ty = ty.unwrap_or(ElementType {
+                scalar: Scalar::U32,
+                components: 1,
+            });
+            let values = bytes
+                .chunks_exact(4)
+                .map(|word| ty.scalar.read_word(word.try_into().unwrap()))
+                .collect();
+            (values, ty)
+        }
+    };
+
+    if values.is_empty() || !values.len().is_multiple_of(ty.components) {
+        return Err(format!(
+            "{} values can't be split into {} elements",
+            values.len(),
+            ty.name()
+        ));
+    }
+
+    let mut elements = vec![];
+    for chunk in values.chunks(ty.components) {
+        let literals = chunk
+            .iter()
+            .map(|value| ty.scalar.literal(*value))
+            .collect::<Result<Vec<_>, _>>()?;
+        elements.push(match ty.components {
+            1 => literals[0].clone(),
+            _ => format!("{}({})", ty.name(), literals.join(", ")),
+        });
+    }
+
+    Ok(format!(
+        "const {}: array<{}, {}> = array<{}, {}>(\n    {},\n);",
+        name,
+        ty.name(),
+        elements.len(),
+        ty.name(),
+        elements.len(),
+        elements.join(",\n    ")
+    ))
+}
@@ -0,0 +1,329 @@
+//! The WGSL preprocessor behind `wgpu-pp`, for loading shaders at runtime.
+//!
+//! The `include_wgsl!` family of macros preprocess shaders at compile time.
+//! This crate runs the same preprocessor at runtime, e.g. for user-moddable
+//! shaders, with the same `#include`, `#define` and `#embed` semantics.
+
+#[cfg(feature = "build")]
+pub mod build;
+mod config;
+mod directive;
+mod embed;
+mod lint;
+mod preprocessor;
+mod provider;
+#[cfg(feature = "dev-server")]
+mod server;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "hot-reload")]
+mod watcher;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use preprocessor::preprocess_with_resolver;
+use provider::{read_async, Resolver};
+
+pub use config::parse_defines;
+pub use directive::{
+    parse_directives, register_crate_directives, register_directive, register_directives_file,
+    Directive, ALLOW_COMMANDS, DIRECTIVES_FILE,
+};
+pub use preprocessor::{
+    declarations, enables_f16, f16_fallback, preprocess, preprocess_bundle,
+    preprocess_bundle_with_info, preprocess_with_defines, register_virtual_header, split_stages,
+    virtual_header, Docs, GroupOffset, PreprocessorError, SourceInfo, PROFILE_DEFINE,
+};
+pub use provider::{AsyncFileProvider, FileProvider};
+#[cfg(feature = "dev-server")]
+pub use server::DevServer;
+#[cfg(feature = "hot-reload")]
+pub use watcher::WgslWatcher;
+
+/// For `include_wgsl_reloadable!`, which logs failures to reload through it
+/// without the crate using it depending on `log` itself.
+#[doc(hidden)]
+pub use log;
+
+#[cfg(any(feature = "build", feature = "testing"))]
+/// Returns the `.wgsl` files in a directory and its subdirectories, sorted.
+pub(crate) fn wgsl_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(wgsl_files(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "wgsl") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Escapes a path for a Makefile rule.
+fn escape_make(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Formats a Makefile-style dependency file, for build systems like Ninja or
+/// Bazel to know which files `target` needs rebuilding after.
+///
+/// ```
+/// use std::path::PathBuf;
+///
+/// let dependencies = [PathBuf::from("water.wgsl"), PathBuf::from("common/light.wgsl")];
+/// assert_eq!(
+///     wgpu_pp_runtime::depfile("out/water.wgsl".as_ref(), &dependencies),
+///     "out/water.wgsl: water.wgsl common/light.wgsl\n",
+/// );
+/// ```
+pub fn depfile(target: &Path, dependencies: &[PathBuf]) -> String {
+    let mut depfile = format!("{}:", escape_make(target));
+    for dependency in dependencies {
+        depfile += " ";
+        depfile += &escape_make(dependency);
+    }
+    depfile += "\n";
+    depfile
+}
+
+/// The line endings of preprocessed source.
+///
+/// Shaders are read with either, and a byte order mark at the start of a file
+/// is removed, but the source is written with one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// Converts source with LF line endings to these line endings.
+    pub fn apply(self, source: String) -> String {
+        match self {
+            LineEnding::Lf => source,
+            LineEnding::CrLf => source.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Caps on the size of what the preprocessor reads and writes, so an
+/// accidental `#include` of a huge generated file, or a macro expanding
+/// exponentially, fails with an error instead of exhausting memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Limits {
+    /// The size of each file read, i.e. the shader, its includes and `#embed`
+    /// data, in bytes. 16 MiB by default.
+    pub max_file_size: usize,
+    /// The size of the expanded source, in bytes. 64 MiB by default.
+    pub max_output_size: usize,
+    /// How many files deep includes may be nested. 256 by default.
+    pub max_include_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_file_size: 16 << 20,
+            max_output_size: 64 << 20,
+            max_include_depth: 256,
+        }
+    }
+}
+
+/// Preprocesses shaders at runtime.
+///
+/// Includes are resolved relative to the including file first, then in each
+/// include directory in the order they were added. Defines behave as if the
+/// shader started with a `#define` for each of them. Unlike the macros, the
+/// `WGPU_PP_PROFILE` environment variable isn't read, so define
+/// [`PROFILE_DEFINE`] to build for a profile.
+///
+/// Files are read from disk, unless a [`FileProvider`] is set to read them
+/// from somewhere else, e.g. an asset archive. Without the `fs` feature, files
+/// can only be read through a `FileProvider`.
+///
+/// ```no_run
+/// use wgpu_pp_runtime::Preprocessor;
+///
+/// let source = Preprocessor::new()
+///     .include_dir("shaders/common")
+///     .define("MAX_LIGHTS", "16")
+///     .process_file("mods/water.wgsl")
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct Preprocessor {
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    provider: Option<Box<dyn FileProvider + Send + Sync>>,
+    line_ending: LineEnding,
+    limits: Limits,
+}
+
+impl fmt::Debug for Preprocessor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Preprocessor")
+            .field("include_dirs", &self.include_dirs)
+            .field("defines", &self.defines)
+            .field("provider", &self.provider.is_some())
+            .field("line_ending", &self.line_ending)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory to look up includes in.
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Defines `name` as `value`, as if by `#define name value`.
+    pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.push((name.into(), value.into()));
+        self
+    }
+
+    /// Writes the expanded source with these line endings, LF by default.
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Errors when a file read or the expanded source is larger than `limits`,
+    /// instead of the defaults.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Reads the shader and its includes through `provider` instead of from disk.
+    ///
+    /// ```no_run
+    /// use std::collections::HashMap;
+    /// use std::path::PathBuf;
+    ///
+    /// use wgpu_pp_runtime::Preprocessor;
+    ///
+    /// let files = HashMap::from([
+    ///     (PathBuf::from("main.wgsl"), "#include \"common/light.wgsl\"\n..."),
+    ///     (PathBuf::from("common/light.wgsl"), "struct Light { ... }"),
+    /// ]);
+    ///
+    /// let source = Preprocessor::new()
+    ///     .file_provider(files)
+    ///     .process_file("main.wgsl")
+    ///     .unwrap();
+    /// ```
+    pub fn file_provider(mut self, provider: impl FileProvider + Send + Sync + 'static) -> Self {
+        self.provider = Some(Box::new(provider));
+        self
+    }
+
+    /// Loads a shader and preprocesses it, returning the expanded source.
+    pub fn process_file(&self, path: impl AsRef<Path>) -> Result<String, PreprocessorError> {
+        self.process_file_with_info(path).map(|(source, _)| source)
+    }
+
+    /// Like `process_file`, but also returns what was collected about the
+    /// shader, e.g. the files it depends on for hot reloading.
+    ///
+    /// ```no_run
+    /// use wgpu_pp_runtime::Preprocessor;
+    ///
+    /// let (source, info) = Preprocessor::new()
+    ///     .process_file_with_info("shaders/water.wgsl")
+    ///     .unwrap();
+    /// for path in info.dependencies.iter() {
+    ///     println!("watching {}", path.display());
+    /// }
+    /// ```
+    pub fn process_file_with_info(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(String, SourceInfo), PreprocessorError> {
+        let path = path.as_ref().to_string_lossy();
+        let resolver = Resolver {
+            include_dirs: &self.include_dirs,
+            provider: self
+                .provider
+                .as_deref()
+                .map(|provider| provider as &dyn FileProvider),
+            limits: self.limits,
+            run_commands: true,
+        };
+        preprocess_with_resolver(&path, Path::new(""), &resolver, &self.defines)
+            .map(|(source, info)| (self.line_ending.apply(source), info))
+    }
+
+    /// Like `process_file`, but reads the shader and its includes through an
+    /// `AsyncFileProvider`, instead of from disk or the `FileProvider`.
+    ///
+    /// ```ignore
+    /// struct Http(reqwest::Client);
+    ///
+    /// impl AsyncFileProvider for Http {
+    ///     async fn read(&self, path: &Path) -> Option<String> {
+    ///         let url = format!("https://assets.example.com/{}", path.display());
+    ///         let response = self.0.get(url).send().await.ok()?.error_for_status().ok()?;
+    ///         response.text().await.ok()
+    ///     }
+    /// }
+    ///
+    /// let source = Preprocessor::new()
+    ///     .process_file_async("shaders/water.wgsl", &Http(client))
+    ///     .await?;
+    /// ```
+    pub async fn process_file_async(
+        &self,
+        path: impl AsRef<Path>,
+        provider: &impl AsyncFileProvider,
+    ) -> Result<String, PreprocessorError> {
+        self.process_file_with_info_async(path, provider)
+            .await
+            .map(|(source, _)| source)
+    }
+
+    /// Like `process_file_with_info`, but reads files like `process_file_async`.
+    pub async fn process_file_with_info_async(
+        &self,
+        path: impl AsRef<Path>,
+        provider: &impl AsyncFileProvider,
+    ) -> Result<(String, SourceInfo), PreprocessorError> {
+        let path = path.as_ref().to_string_lossy();
+        read_async(provider, |files, run_commands| {
+            let resolver = Resolver {
+                include_dirs: &self.include_dirs,
+                provider: Some(files),
+                limits: self.limits,
+                run_commands,
+            };
+            preprocess_with_resolver(&path, Path::new(""), &resolver, &self.defines)
+        })
+        .await
+        .map(|(source, info)| (self.line_ending.apply(source), info))
+    }
+}
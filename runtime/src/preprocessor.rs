@@ -0,0 +1,3019 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::config::parse_defines;
+use crate::directive::{directive, Directive};
+use crate::embed::embed;
+use crate::lint::wgsl_name;
+use crate::provider::Resolver;
+use crate::Limits;
+
+#[derive(Debug)]
+pub enum PreprocessorError {
+    FileNotFound(String),
+    FileNotValidUtf8(String),
+    UnknownDirective(String),
+    IncludeIncorrectArgs,
+    MacroNoParenthesis,
+    MacroIncorrectArgs(usize, usize),
+    EmbedIncorrectArgs,
+    EmbedInvalidData(String, String),
+    StageIncorrectArgs,
+    DirectiveFailed(String, String),
+    /// A file larger than `Limits::max_file_size`, with the limit.
+    FileTooLarge(String, usize),
+    /// Expanded source larger than `Limits::max_output_size`, with the limit.
+    OutputTooLarge(usize),
+    /// A file included from more files deep than `Limits::max_include_depth`,
+    /// with the limit.
+    IncludeTooDeep(String, usize),
+    /// A define that a `#require` needs, with its message, if any.
+    MissingDefine(String, Option<String>),
+    /// A function-like macro used without arguments, with its parameter
+    /// count and the line using it.
+    MacroWithoutArgs(String, usize, String),
+    /// An object-like define called with arguments, with its value and the
+    /// line using it. Only values that can't be called, like `1.0` or
+    /// `f32(3.14)`, are caught, since e.g. `vec4<f32>(...)` is a constructor.
+    DefineCalled(String, String, String),
+}
+
+impl fmt::Display for PreprocessorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessorError::FileNotFound(filename) => write!(f, "file not found: {}", filename),
+            PreprocessorError::FileNotValidUtf8(filename) => {
+                write!(f, "file not valid utf-8: {}", filename)
+            }
+            PreprocessorError::UnknownDirective(directive) => {
+                write!(f, "unknown directive: {}", directive)
+            }
+            PreprocessorError::IncludeIncorrectArgs => write!(f, "incorrect arguments to #include"),
+            PreprocessorError::MacroNoParenthesis => write!(f, "macro must have parenthesis"),
+            PreprocessorError::MacroIncorrectArgs(expected, got) => {
+                write!(f, "macro expected {} arguments, got {}", expected, got)
+            }
+            PreprocessorError::EmbedIncorrectArgs => write!(
+                f,
+                "incorrect arguments to #embed, expected `#embed NAME \"path\" [as TYPE]`"
+            ),
+            PreprocessorError::EmbedInvalidData(filename, e) => {
+                write!(f, "invalid data in {}: {}", filename, e)
+            }
+            PreprocessorError::StageIncorrectArgs => write!(
+                f,
+                "incorrect arguments to #stage, expected vertex, fragment or compute"
+            ),
+            PreprocessorError::DirectiveFailed(directive, e) => {
+                write!(f, "{}: {}", directive, e)
+            }
+            PreprocessorError::FileTooLarge(filename, limit) => {
+                write!(
+                    f,
+                    "{} is over the file size limit of {} bytes",
+                    filename, limit
+                )
+            }
+            PreprocessorError::OutputTooLarge(limit) => write!(
+                f,
+                "expanded source is over the size limit of {} bytes",
+                limit
+            ),
+            PreprocessorError::IncludeTooDeep(filename, limit) => write!(
+                f,
+                "{} is included over the limit of {} nested files",
+                filename, limit
+            ),
+            PreprocessorError::MissingDefine(_, Some(message)) => write!(f, "{}", message),
+            PreprocessorError::MissingDefine(name, None) => {
+                write!(f, "{} must be defined", name)
+            }
+            PreprocessorError::MacroWithoutArgs(name, params, line) => write!(
+                f,
+                "`{}` is a function-like macro taking {} argument{}, but is used without them in `{}`",
+                name,
+                params,
+                if *params == 1 { "" } else { "s" },
+                line
+            ),
+            PreprocessorError::DefineCalled(name, value, line) => write!(
+                f,
+                "`{}` is an object-like define of `{}`, which can't be called, but is used with arguments in `{}`",
+                name, value, line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PreprocessorError {}
+
+lazy_static! {
+    // Regex for define expressions. This only covers macros.
+    // - Group 1: identifier
+    // - Group 2: arguments separated by commas
+    // - Group 3: body
+    static ref REGEX_DEFINE_MACRO: Regex = Regex::new(r"((?:[_\p{XID_Start}][\p{XID_Continue}]+)|(?:[\p{XID_Start}]))\(((?:[_\p{XID_Start}][\p{XID_Continue}]*(?:,\s*)*)+)\)\s+(.*)").unwrap();
+
+    // Regex for block comments.
+    static ref REGEX_BLOCK_COMMENT: Regex = Regex::new(r"/\*.*?\*/").unwrap();
+
+    // Regex for profile attribute-comments, e.g. `// @profiles(desktop, webgpu)`.
+    // - Group 1: profiles separated by commas
+    static ref REGEX_PROFILES: Regex = Regex::new(r"^\s*//\s*@profiles\(([^)]*)\)\s*$").unwrap();
+
+    // Regex for push constant attribute-comments, e.g. `// @push_constant(desktop)`.
+    // - Group 1: profiles separated by commas
+    static ref REGEX_PUSH_CONSTANT: Regex = Regex::new(r"^\s*//\s*@push_constant\(([^)]*)\)\s*$").unwrap();
+
+    // Regex for the attributes binding a resource, which push constants don't have.
+    static ref REGEX_BINDING_ATTRIBUTES: Regex = Regex::new(r"@(?:group|binding)\(\s*\d+\s*\)\s*").unwrap();
+
+    // Regex for the uniform address space.
+    static ref REGEX_UNIFORM: Regex = Regex::new(r"var\s*<\s*uniform\s*>").unwrap();
+
+    // Regex for the function a `#template` declares, e.g. `fn sort<T>(`.
+    // - Group 1: identifier
+    // - Group 2: type parameters separated by commas
+    static ref REGEX_TEMPLATE: Regex = Regex::new(r"^\s*fn\s+([_\p{XID_Start}][\p{XID_Continue}]*)\s*<([^>]*)>").unwrap();
+
+    // Regex for `#instantiate` directives, e.g. `#instantiate sort<f32> as sort_f32`.
+    // - Group 1: template
+    // - Group 2: type arguments
+    // - Group 3: name of the instance
+    static ref REGEX_INSTANTIATE: Regex = Regex::new(r"^#instantiate\s+([_\p{XID_Start}][\p{XID_Continue}]*)\s*<(.*)>\s+as\s+([_\p{XID_Start}][\p{XID_Continue}]*)\s*$").unwrap();
+
+    // Regex for group attributes.
+    // - Group 1: group index
+    static ref REGEX_GROUP: Regex = Regex::new(r"@group\(\s*(\d+)\s*\)").unwrap();
+
+    // Regex for lines with only attributes, e.g. `@group(0) @binding(1)`.
+    static ref REGEX_ATTRIBUTES: Regex = Regex::new(r"^\s*(?:@\w+(?:\([^)]*\))?\s*)+$").unwrap();
+
+    // Regex for module-scope declarations, after any attributes.
+    // - Group 1: keyword
+    // - Group 2: identifier
+    static ref REGEX_DECLARATION: Regex = Regex::new(r"^\s*(?:@\w+(?:\([^)]*\))?\s*)*(struct|fn|override|const|alias|var(?:<[^>]*>)?)\s+([_\p{XID_Start}][\p{XID_Continue}]*)").unwrap();
+
+    // Regex for struct members, after any attributes.
+    // - Group 1: identifier
+    static ref REGEX_MEMBER: Regex = Regex::new(r"^\s*(?:@\w+(?:\([^)]*\))?\s*)*([_\p{XID_Start}][\p{XID_Continue}]*)\s*:").unwrap();
+
+    // Regex for `enable` directives.
+    // - Group 1: extensions separated by commas
+    static ref REGEX_ENABLE: Regex = Regex::new(r"^\s*enable\s+([^;]*);\s*$").unwrap();
+
+    // Regex for f16 scalar, vector and matrix types, e.g. `f16`, `vec3h` or `mat4x4h`.
+    // - Group 1: type without the `h` suffix, if it's a vector or matrix
+    static ref REGEX_F16_TYPE: Regex = Regex::new(r"\bf16\b|\b(vec[234]|mat[234]x[234])h\b").unwrap();
+
+    // Regex for decimal f16 literals, e.g. `1h`, `0.5h` or `1e-3h`.
+    // - Group 1: the character before the literal
+    // - Group 2: literal without the `h` suffix
+    static ref REGEX_F16_LITERAL: Regex = Regex::new(r"(^|[^\w.])((?:\d+\.\d*|\.\d+|\d+)(?:[eE][+-]?\d+)?)h\b").unwrap();
+
+    // Regex for hexadecimal f16 literals, e.g. `0x1p-2h`.
+    // - Group 1: literal without the `h` suffix
+    static ref REGEX_F16_HEX_LITERAL: Regex = Regex::new(r"\b(0[xX][0-9a-fA-F]*\.?[0-9a-fA-F]*[pP][+-]?\d+)h\b").unwrap();
+
+    // Headers generated by the macros, which can be included with `#include <name>`.
+    static ref VIRTUAL_HEADERS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// The headers shipped with the preprocessor, by the name they're included as.
+const STD_HEADERS: &[(&str, &str)] = &[
+    ("wgpu_pp/math.wgsl", include_str!("../wgsl/math.wgsl")),
+    ("wgpu_pp/color.wgsl", include_str!("../wgsl/color.wgsl")),
+    ("wgpu_pp/hash.wgsl", include_str!("../wgsl/hash.wgsl")),
+    ("wgpu_pp/noise.wgsl", include_str!("../wgsl/noise.wgsl")),
+];
+
+/// Returns a virtual header, registered or else shipped with the preprocessor.
+fn _virtual_header(name: &str) -> Option<String> {
+    if let Some(header) = virtual_header(name) {
+        return Some(header);
+    }
+    STD_HEADERS
+        .iter()
+        .find(|(header, _)| *header == name)
+        .map(|(_, contents)| contents.to_string())
+}
+
+/// Returns the contents of a virtual header registered with
+/// `register_virtual_header`, not counting the ones shipped with the preprocessor.
+pub fn virtual_header(name: &str) -> Option<String> {
+    VIRTUAL_HEADERS.lock().unwrap().get(name).cloned()
+}
+
+/// Registers a virtual header, so that shaders can `#include <name>` it.
+pub fn register_virtual_header(name: &str, contents: &str) {
+    VIRTUAL_HEADERS
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), contents.to_string());
+}
+
+fn _remove_comments(line: &mut String, in_block_comment: bool) -> bool {
+    // If we're already in a block comment, we need a */.
+    if in_block_comment {
+        if let Some(closing_idx) = line.find("*/") {
+            line.replace_range(0..closing_idx + 2, "")
+        } else {
+            // Return "identity," i.e. skip this line.
+            return in_block_comment;
+        }
+    }
+
+    // Replace all contained block comments with regex.
+    while let Some(block_comment) = REGEX_BLOCK_COMMENT.find(line) {
+        line.replace_range(block_comment.start()..block_comment.end(), "");
+    }
+
+    // Remove inline comments.
+    if let Some(inline_comment_idx) = line.find("//") {
+        line.replace_range(inline_comment_idx.., "");
+        return false;
+    }
+    // See if there's any hanging block comments.
+    if line.contains("/*") {
+        return true;
+    }
+    false
+}
+
+/// The define holding the profile being built for.
+///
+/// Declarations after a `// @profiles(...)` comment that doesn't list it are
+/// stripped. When it isn't defined, nothing is stripped.
+pub const PROFILE_DEFINE: &str = "WGPU_PP_PROFILE";
+
+/// Marks where a `#stage` section starts in the preprocessed source.
+///
+/// It's a comment, so sources with stages are still valid WGSL as a whole.
+const STAGE_MARKER: &str = "// #stage ";
+
+/// `///` doc comments keyed by the name they document, and `Struct.member` for struct members.
+pub type Docs = HashMap<String, String>;
+
+/// A `@group` shifted by `#include "file" group_offset N`.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GroupOffset {
+    pub filename: String,
+    pub group: u32,
+    pub shifted: u32,
+}
+
+/// What's collected about a shader while preprocessing it, besides its source.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceInfo {
+    pub docs: Docs,
+    pub group_offsets: Vec<GroupOffset>,
+    /// Every file read, i.e. the shader, its includes and `#embed` data, in the order they were read.
+    pub dependencies: Vec<PathBuf>,
+    /// The file and line, starting at 1, each line of the source came from.
+    ///
+    /// Lines from a virtual header come from `<name>`, and lines generated by
+    /// `#embed` from the directive's line.
+    pub source_map: Vec<(PathBuf, usize)>,
+    /// Every `#include`, as the including file and the file it includes.
+    ///
+    /// Files are only expanded the first time they're included, but every
+    /// `#include` of them is listed. They're listed once the included file has
+    /// been expanded, so a file's includes come after the ones nested in them.
+    pub includes: Vec<(PathBuf, PathBuf)>,
+    /// Every `#define`, as the file it's in and the name it defines.
+    pub defines: Vec<(PathBuf, String)>,
+    /// Every declaration at module scope, as the file it's in and the name it declares.
+    pub declarations: Vec<(PathBuf, String)>,
+    /// Defines shadowing a WGSL keyword, type or builtin, or a declaration,
+    /// which they're substituted into, as the file and line and a message.
+    pub warnings: Vec<(PathBuf, usize, String)>,
+    /// The defines in effect once the whole shader is preprocessed, including
+    /// the ones it was preprocessed with, sorted by name. Values have the
+    /// defines in them substituted, and macros are listed as `NAME(params)`
+    /// with their bodies. Defines that were `#undef`ined aren't listed.
+    pub define_values: Vec<(String, String)>,
+}
+
+enum DefineDirective {
+    Value(String),
+    /// The parameters, the body, and whether arguments are parenthesized, see `_parenthesize`.
+    Macro(Vec<String>, String, bool),
+    /// The type parameters and source of a `#template` function, keyed by
+    /// `#template name`. Instances are kept as values keyed by `#instance name`,
+    /// to catch duplicates. Neither key is a token, so they're never substituted.
+    Template(Vec<String>, String),
+}
+
+/// Splits the type arguments of `#instantiate` at the commas outside of
+/// nested types, e.g. `vec2<f32>, u32`.
+fn _split_type_args(text: &str) -> Vec<String> {
+    let mut args = vec![String::new()];
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '<' | '(' => depth += 1,
+            '>' | ')' => depth -= 1,
+            _ => {}
+        }
+        match c {
+            ',' if depth == 0 => args.push(String::new()),
+            c => args.last_mut().unwrap().push(c),
+        }
+    }
+    args.iter().map(|arg| arg.trim().to_string()).collect()
+}
+
+/// Returns whether `c` can be part of an identifier or number. Like WGSL,
+/// identifiers are Unicode XID, so they can contain e.g. combining marks.
+fn _is_token_char(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+/// Returns the length of the identifier or number at the start of `text`, or
+/// 0 if it doesn't start with one. Lengths are in bytes, and always end on a
+/// character boundary.
+fn _token_len(text: &str) -> usize {
+    text.find(|c: char| !_is_token_char(c))
+        .unwrap_or(text.len())
+}
+
+/// Returns whether `text` is an expression with an operator outside of
+/// parenthesis and brackets, which could bind to what's around it once
+/// substituted.
+///
+/// Statements, i.e. text with `;`, braces or an assignment, aren't, and
+/// neither are types like `vec4<f32>`, so `<` and `>` only count as
+/// comparisons with spaces around them.
+fn _needs_parens(text: &str) -> bool {
+    if text.contains([';', '{', '}']) {
+        return false;
+    }
+    let chars = text.trim().chars().collect::<Vec<_>>();
+    let mut depth = 0;
+    let mut operator = false;
+    for (i, &c) in chars.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1).copied();
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ if depth > 0 => {}
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '!' => operator = true,
+            '=' => match (prev, next) {
+                (Some('=' | '!' | '<' | '>'), _) | (_, Some('=')) => operator = true,
+                // An assignment.
+                _ => return false,
+            },
+            '<' | '>'
+                if next == Some('=')
+                    || next == Some(c)
+                    || prev == Some(c)
+                    || (prev == Some(' ') && next == Some(' ')) =>
+            {
+                operator = true
+            }
+            _ => {}
+        }
+    }
+    operator
+}
+
+/// Wraps `text` in parenthesis if it needs them to substitute like a single
+/// value, for `#pragma hygiene`. `#define DOUBLE(x) x * 2` then expands
+/// `DOUBLE(a + b)` to `((a + b) * 2)` instead of `a + b * 2`.
+fn _parenthesize(text: &str) -> String {
+    match _needs_parens(text) {
+        true => format!("({})", text.trim()),
+        false => text.to_string(),
+    }
+}
+
+/// Replaces the parameters of a macro's body with the arguments it was called with.
+fn _substitute_args(body: &str, params: &[String], args: &[String]) -> String {
+    let mut result = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        let len = _token_len(rest).max(c.len_utf8());
+        let token = &rest[..len];
+        match params.iter().position(|param| param == token) {
+            Some(i) => result += &args[i],
+            None => result += token,
+        }
+        rest = &rest[len..];
+    }
+    result
+}
+
+/// Returns the index of the `>` closing the template list that the `<` at
+/// `start` opens, if it does, following WGSL's template list discovery: the
+/// `<` follows an identifier, isn't part of `<<` or `<=`, and is matched by a
+/// `>` before the end of the enclosing parenthesis or an `&&` or `||`.
+fn _template_end(text: &str, start: usize) -> Option<usize> {
+    let before = text[..start].trim_end();
+    let (token_start, _) = before
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| _is_token_char(*c))
+        .last()?;
+    if before[token_start..].starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if matches!(text[start + 1..].chars().next(), Some('<' | '=')) {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut i = start + 1;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        match c {
+            '<' => {
+                if let Some(end) = _template_end(text, i) {
+                    i = end + 1;
+                    continue;
+                }
+            }
+            '>' if depth == 0 && !rest.starts_with(">=") => return Some(i),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => return None,
+            ')' | ']' | '}' => depth -= 1,
+            ';' => return None,
+            _ if rest.starts_with("&&") || rest.starts_with("||") => return None,
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+/// Splits the arguments of a macro call, without its parenthesis, at the
+/// commas outside of nested parenthesis, braces and template lists, so e.g.
+/// `array<f32, 4>` is a single argument.
+fn _split_macro_args(text: &str) -> Vec<String> {
+    let mut args = vec![String::new()];
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        if c == '<' {
+            if let Some(end) = _template_end(text, i) {
+                args.last_mut().unwrap().push_str(&text[i..=end]);
+                i = end + 1;
+                continue;
+            }
+        }
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            _ => {}
+        }
+        match c {
+            ',' if depth == 0 => args.push(String::new()),
+            c => args.last_mut().unwrap().push(c),
+        }
+        i += c.len_utf8();
+    }
+    args.iter().map(|arg| arg.trim().to_string()).collect()
+}
+
+/// Returns whether an object-like define's value can be called like a
+/// function, i.e. it ends with an identifier or a template list, like
+/// `max` or `vec4<f32>`, rather than e.g. a number or a call.
+fn _is_callable(value: &str) -> bool {
+    let value = value.trim();
+    if value.ends_with('>') {
+        return value
+            .char_indices()
+            .filter(|(_, c)| *c == '<')
+            .any(|(i, _)| _template_end(value, i) == Some(value.len() - 1));
+    }
+    let token = value
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| _is_token_char(*c))
+        .last()
+        .map(|(i, _)| &value[i..]);
+    token.is_some_and(|token| !token.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Reads the arguments of a macro call from `input`, if it continues with a
+/// parenthesis. Commas only separate arguments outside of nested parenthesis,
+/// braces and template lists, see `_split_macro_args`.
+fn _macro_args(input: &mut Vec<Expansion>) -> Result<Option<Vec<String>>, PreprocessorError> {
+    let mut call = String::new();
+    let mut depth = 0;
+    loop {
+        _drop_read(input);
+        let Some((text, pos, _)) = input.last_mut() else {
+            return match depth {
+                0 => Ok(None),
+                _ => Err(PreprocessorError::MacroNoParenthesis),
+            };
+        };
+        let c = text[*pos..].chars().next().unwrap();
+        if depth == 0 && c != '(' {
+            return Ok(None);
+        }
+        *pos += c.len_utf8();
+
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            _ => {}
+        }
+        match c {
+            '(' if depth == 1 => {}
+            ')' if depth == 0 => break,
+            c => call.push(c),
+        }
+    }
+    Ok(Some(_split_macro_args(&call)))
+}
+
+/// Text being substituted, and where it's at. Expansions of macros are pushed
+/// in front of the rest of the line, along with their macro, which isn't
+/// expanded again inside its own expansion.
+type Expansion<'a> = (Cow<'a, str>, usize, Option<&'a str>);
+
+/// Drops the expansions that were read.
+fn _drop_read(input: &mut Vec<Expansion>) {
+    while input
+        .last()
+        .is_some_and(|(text, pos, _)| *pos >= text.len())
+    {
+        input.pop();
+    }
+}
+
+/// Substitutes the defines and macros in a line, in a single pass.
+///
+/// Expansions are scanned again for more defines and macros, along with the
+/// rest of the line, so a define can expand to the name of a macro called
+/// after it. Numbers like `1e5` aren't identifiers, so they're left alone.
+/// Errors once the result is longer than `max_size`.
+fn _substitute_macros(
+    line: &str,
+    defines: &HashMap<String, DefineDirective>,
+    max_size: usize,
+) -> Result<String, PreprocessorError> {
+    let mut result = String::with_capacity(line.len());
+    _substitute_macros_into(line, defines, max_size, &[], &mut result)?;
+    Ok(result)
+}
+
+/// Like `_substitute_macros`, but appends to `result`, so lines can be
+/// written straight into the preprocessed source. The defines and macros in
+/// `expanding` are already being expanded, so they aren't expanded again.
+fn _substitute_macros_into<'a>(
+    line: &'a str,
+    defines: &'a HashMap<String, DefineDirective>,
+    max_size: usize,
+    expanding: &[&str],
+    result: &mut String,
+) -> Result<(), PreprocessorError> {
+    let mut input: Vec<Expansion> = vec![(Cow::Borrowed(line), 0, None)];
+    loop {
+        if result.len() > max_size {
+            return Err(PreprocessorError::OutputTooLarge(max_size));
+        }
+        _drop_read(&mut input);
+        let Some((text, pos, _)) = input.last_mut() else {
+            break;
+        };
+
+        let rest = &text[*pos..];
+        let c = rest.chars().next().unwrap();
+        let len = _token_len(rest);
+        if len == 0 || !(c == '_' || unicode_ident::is_xid_start(c)) {
+            // Copy everything up to the next identifier at once.
+            let len = match len {
+                0 => rest.find(_is_token_char).unwrap_or(rest.len()),
+                len => len,
+            };
+            *result += &rest[..len];
+            *pos += len;
+            continue;
+        }
+        let start = *pos;
+        *pos += len;
+
+        let token = &input.last().unwrap().0[start..start + len];
+        let define = defines.get_key_value(token).filter(|(name, _)| {
+            !expanding.contains(&name.as_str())
+                && !input
+                    .iter()
+                    .any(|(_, _, expanding)| *expanding == Some(name.as_str()))
+        });
+        let Some((name, define)) = define else {
+            *result += token;
+            continue;
+        };
+
+        match define {
+            DefineDirective::Value(value) => {
+                _drop_read(&mut input);
+                let called = input
+                    .last()
+                    .is_some_and(|(text, pos, _)| text[*pos..].starts_with('('));
+                if called && !value.trim().is_empty() && !_is_callable(value) {
+                    return Err(PreprocessorError::DefineCalled(
+                        name.clone(),
+                        value.trim().to_string(),
+                        line.trim().to_string(),
+                    ));
+                }
+                input.push((Cow::Borrowed(value.as_str()), 0, Some(name.as_str())))
+            }
+            DefineDirective::Macro(params, body, hygienic) => {
+                // Arguments are substituted in the context of the call, so
+                // they don't expand the defines around it again.
+                let context = input
+                    .iter()
+                    .filter_map(|(_, _, expanding)| *expanding)
+                    .chain(expanding.iter().copied())
+                    .collect::<Vec<_>>();
+                let Some(args) = _macro_args(&mut input)? else {
+                    return Err(PreprocessorError::MacroWithoutArgs(
+                        name.clone(),
+                        params.len(),
+                        line.trim().to_string(),
+                    ));
+                };
+                if args.len() != params.len() {
+                    return Err(PreprocessorError::MacroIncorrectArgs(
+                        params.len(),
+                        args.len(),
+                    ));
+                }
+
+                // Arguments are substituted before they replace the parameters.
+                // Swallow errors here, as they might be incomplete, unless they
+                // expand too much.
+                let args = args
+                    .into_iter()
+                    .map(|arg| {
+                        let mut substituted = String::with_capacity(arg.len());
+                        match _substitute_macros_into(
+                            &arg,
+                            defines,
+                            max_size,
+                            &context,
+                            &mut substituted,
+                        ) {
+                            Err(e @ PreprocessorError::OutputTooLarge(_)) => Err(e),
+                            Err(_) => Ok(arg),
+                            Ok(()) => Ok(substituted),
+                        }
+                    })
+                    .map(|arg| match hygienic {
+                        true => arg.map(|arg| _parenthesize(&arg)),
+                        false => arg,
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let body = _substitute_args(body, params, &args);
+                input.push((Cow::Owned(body), 0, Some(name.as_str())));
+            }
+            DefineDirective::Template(..) => unreachable!("templates aren't keyed by tokens"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the parameters of `#include "file" with (NAME = value, ...)`.
+/// Commas only separate parameters outside of nested parenthesis and brackets.
+fn _include_params(text: &str) -> Option<Vec<(String, String)>> {
+    let text = text.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&text[start..]);
+
+    items
+        .into_iter()
+        .map(|item| {
+            let (name, value) = item.split_once('=')?;
+            let (name, value) = (name.trim(), value.trim());
+            match !name.is_empty() && _token_len(name) == name.len() && !value.is_empty() {
+                true => Some((name.to_string(), value.to_string())),
+                false => None,
+            }
+        })
+        .collect()
+}
+
+/// Returns what a file is marked visited as. Files included with parameters
+/// are expanded once per set of parameters, rather than once.
+fn _visited_key(source_path: &Path, params: &[(String, String)]) -> PathBuf {
+    if params.is_empty() {
+        return source_path.to_path_buf();
+    }
+    let params = params
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect::<Vec<_>>();
+    PathBuf::from(format!(
+        "{} with ({})",
+        source_path.display(),
+        params.join(", ")
+    ))
+}
+
+/// A file being preprocessed, with where its lines are up to.
+///
+/// Files including others wait on a stack rather than the call stack, so a
+/// deep include chain fails with [`PreprocessorError::IncludeTooDeep`]
+/// instead of overflowing the stack, e.g. of a proc macro.
+struct FileState {
+    source_path: PathBuf,
+    source_path_parent: PathBuf,
+    text: Rc<str>,
+    /// The preprocessed source so far.
+    contents: String,
+    i: usize,
+    in_block_comment: bool,
+    pending_docs: Vec<String>,
+    current_struct: Option<String>,
+    /// Whether defines are parenthesized, after `#pragma hygiene` in this file.
+    hygiene: bool,
+    /// Whether the next declaration is a uniform to turn into a push constant.
+    push_constant: bool,
+    /// The brace depth of a declaration being stripped, and whether its body started.
+    strip: Option<(i32, bool)>,
+    /// The file and line the lines from an index on come from, after `#line`.
+    line_origin: Option<(PathBuf, usize, usize)>,
+    /// The `#include` waiting for the file it includes to be preprocessed.
+    include: Option<PendingInclude>,
+}
+
+impl FileState {
+    /// Returns the file and line a line of this file comes from.
+    fn origin(&self, line_number: usize) -> (PathBuf, usize) {
+        match &self.line_origin {
+            Some((path, from, number)) => (path.clone(), number + line_number - from),
+            None => (self.source_path.clone(), line_number),
+        }
+    }
+}
+
+/// An `#include`, with the line it's on.
+struct PendingInclude {
+    line: String,
+    line_number: usize,
+    /// The length of the source map before the line.
+    mapped: usize,
+    directive_idx: usize,
+    dest_path: String,
+    /// The contents of a virtual header, if it includes one.
+    header: Option<String>,
+    params: Vec<(String, String)>,
+    group_offset: u32,
+    /// The defines its parameters override, to restore after it.
+    overridden: Vec<(String, Option<DefineDirective>)>,
+}
+
+/// A file opened for preprocessing.
+enum Opened {
+    /// A file that's already preprocessed, with its path and source, and
+    /// whether it was copied as it is, see `_is_plain`.
+    Done(PathBuf, String, bool),
+    /// A file whose lines need preprocessing.
+    Lines(Box<FileState>),
+}
+
+/// Preprocesses a file, returning its path, the preprocessed source and
+/// whether it was copied as it is, see `_is_plain`.
+fn _preprocess(
+    filename: &str,
+    basepath: &Path,
+    resolver: &Resolver,
+    visited: &mut HashSet<PathBuf>,
+    defines: &mut HashMap<String, DefineDirective>,
+    info: &mut SourceInfo,
+    params: &[(String, String)],
+) -> Result<(PathBuf, String, bool), PreprocessorError> {
+    let mut stack: Vec<FileState> = vec![];
+    let mut opened = _open(
+        filename, None, basepath, resolver, visited, defines, info, params,
+    )?;
+    loop {
+        match opened {
+            Opened::Done(path, contents, plain) => match stack.last_mut() {
+                Some(file) => {
+                    _finish_include(file, path, contents, plain, resolver, defines, info)?
+                }
+                None => return Ok((path, contents, plain)),
+            },
+            Opened::Lines(file) => {
+                let limit = resolver.limits.max_include_depth;
+                if stack.len() >= limit {
+                    let filename = file.source_path.display().to_string();
+                    return Err(PreprocessorError::IncludeTooDeep(filename, limit));
+                }
+                stack.push(*file);
+            }
+        }
+
+        let file = stack.last_mut().unwrap();
+        _preprocess_lines(file, resolver, defines, info)?;
+        opened = match &file.include {
+            Some(include) => _open(
+                &include.dest_path,
+                include.header.as_deref(),
+                &file.source_path_parent,
+                resolver,
+                visited,
+                defines,
+                info,
+                &include.params,
+            )?,
+            None => {
+                let file = stack.pop().unwrap();
+                Opened::Done(file.source_path, file.contents, false)
+            }
+        };
+    }
+}
+
+/// Opens a file, or a virtual header if `header` is its contents, which is
+/// registered in memory instead of read from disk.
+#[allow(clippy::too_many_arguments)]
+fn _open(
+    filename: &str,
+    header: Option<&str>,
+    basepath: &Path,
+    resolver: &Resolver,
+    visited: &mut HashSet<PathBuf>,
+    defines: &HashMap<String, DefineDirective>,
+    info: &mut SourceInfo,
+    params: &[(String, String)],
+) -> Result<Opened, PreprocessorError> {
+    let (source_path, source_path_parent, contents) = match header {
+        Some(header) => (
+            PathBuf::from(format!("<{}>", filename)),
+            basepath.to_path_buf(),
+            header.to_string(),
+        ),
+        None => {
+            // See if the file exists, relative to the basepath or else one of the include directories.
+            // If it doesn't, return an error.
+            let (source_path, contents) = match resolver.read(filename, basepath)? {
+                Some(file) => file,
+                None => return Err(PreprocessorError::FileNotFound(filename.to_string())),
+            };
+            let source_path_parent = PathBuf::from(source_path.parent().unwrap());
+            (source_path, source_path_parent, contents)
+        }
+    };
+
+    if !visited.insert(_visited_key(&source_path, params)) {
+        return Ok(Opened::Done(source_path, "".to_string(), true));
+    }
+    if header.is_none() && !info.dependencies.contains(&source_path) {
+        info.dependencies.push(source_path.clone());
+    }
+
+    if _is_plain(&contents, defines) {
+        info.source_map.extend(
+            (1..=contents.lines().count()).map(|line_number| (source_path.clone(), line_number)),
+        );
+        let declared = declarations(&contents).into_iter();
+        info.declarations
+            .extend(declared.map(|name| (source_path.clone(), name)));
+        let mut contents = contents;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        _check_output_size(&contents, resolver)?;
+        return Ok(Opened::Done(source_path, contents, true));
+    }
+
+    Ok(Opened::Lines(Box::new(FileState {
+        source_path,
+        source_path_parent,
+        // Most lines are copied as they are, so the source is about as long as the file.
+        contents: String::with_capacity(contents.len() + 1),
+        text: contents.into(),
+        i: 0,
+        in_block_comment: false,
+        pending_docs: vec![],
+        current_struct: None,
+        hygiene: false,
+        push_constant: false,
+        strip: None,
+        line_origin: None,
+        include: None,
+    })))
+}
+
+/// Returns whether a file would be preprocessed as it is, because it has no
+/// directives, comments, continuations or defines, so it can be copied.
+fn _is_plain(contents: &str, defines: &HashMap<String, DefineDirective>) -> bool {
+    if contents.contains(['#', '/', '\\', '\r']) {
+        return false;
+    }
+    if defines.is_empty() {
+        return true;
+    }
+    let mut rest = contents;
+    while let Some(start) = rest.find(_is_token_char) {
+        rest = &rest[start..];
+        let len = _token_len(rest);
+        if defines.contains_key(&rest[..len]) {
+            return false;
+        }
+        rest = &rest[len..];
+    }
+    true
+}
+
+/// Preprocesses the lines of a file, until its end or an `#include`, which
+/// is left in `file.include` for the caller to preprocess the included file.
+fn _preprocess_lines(
+    file: &mut FileState,
+    resolver: &Resolver,
+    defines: &mut HashMap<String, DefineDirective>,
+    info: &mut SourceInfo,
+) -> Result<(), PreprocessorError> {
+    let text = Rc::clone(&file.text);
+    let lines = text.lines().collect::<Vec<_>>();
+    let source_path_parent = file.source_path_parent.clone();
+    loop {
+        if file.i >= lines.len() {
+            return Ok(());
+        }
+
+        let line_number = file.i + 1;
+        let mapped = info.source_map.len();
+        // Set by `#line`, for the lines after this one.
+        let mut next_origin = None;
+
+        // Copy `#verbatim` blocks as they are, without the directives around them.
+        if !file.in_block_comment && file.strip.is_none() && lines[file.i].trim() == "#verbatim" {
+            let Some(len) = lines[file.i + 1..]
+                .iter()
+                .position(|line| line.trim() == "#endverbatim")
+            else {
+                return Err(PreprocessorError::DirectiveFailed(
+                    "#verbatim".to_string(),
+                    "expected #endverbatim".to_string(),
+                ));
+            };
+            for (j, verbatim) in lines[file.i + 1..file.i + 1 + len].iter().enumerate() {
+                file.contents += verbatim;
+                file.contents.push('\n');
+                info.source_map.push(file.origin(line_number + 1 + j));
+            }
+            _check_output_size(&file.contents, resolver)?;
+            file.pending_docs.clear();
+            file.i += len + 2;
+            continue;
+        }
+        // Lines are only copied when they're changed, e.g. to remove comments.
+        let mut line = Cow::Borrowed(lines[file.i]);
+        // While the line's last character is a backslash, remove the backslash and append the next line.
+        while line.ends_with('\\') {
+            let line = line.to_mut();
+            line.pop();
+            file.i += 1;
+            if file.i >= lines.len() {
+                break;
+            }
+            *line += lines[file.i];
+        }
+
+        // Strip the next declaration if it's restricted to other profiles.
+        if let Some(caps) = REGEX_PROFILES.captures(&line) {
+            if let Some(DefineDirective::Value(profile)) = defines.get(PROFILE_DEFINE) {
+                if !caps[1].split(',').any(|p| p.trim() == profile) {
+                    file.strip = Some((0, false));
+                }
+            }
+        }
+
+        // Turn the next uniform into a push constant if the profile supports them.
+        if let Some(caps) = REGEX_PUSH_CONSTANT.captures(&line) {
+            if let Some(DefineDirective::Value(profile)) = defines.get(PROFILE_DEFINE) {
+                if caps[1].split(',').any(|p| p.trim() == profile) {
+                    file.push_constant = true;
+                }
+            }
+        }
+
+        // Keep doc comments for the next declaration.
+        if !file.in_block_comment {
+            if let Some(doc) = lines[file.i].trim_start().strip_prefix("///") {
+                file.pending_docs.push(doc.to_string());
+            }
+        }
+
+        // Remove opening/closing pairs of block comments via regex.
+        if file.in_block_comment || line.contains('/') {
+            file.in_block_comment = _remove_comments(line.to_mut(), file.in_block_comment);
+        }
+        if file.in_block_comment {
+            file.i += 1;
+            continue;
+        }
+
+        if let Some((mut depth, mut opened)) = file.strip {
+            for c in line.chars() {
+                match c {
+                    '{' => {
+                        depth += 1;
+                        opened = true;
+                    }
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+            }
+            file.strip = match opened && depth <= 0 {
+                true => None,
+                false => Some((depth, opened)),
+            };
+            file.pending_docs.clear();
+            file.i += 1;
+            continue;
+        }
+
+        // Remove the bindings of the uniform, which may be on lines before it.
+        if file.push_constant && !line.trim().is_empty() {
+            if REGEX_UNIFORM.is_match(&line) {
+                let rewritten = REGEX_BINDING_ATTRIBUTES.replace_all(&line, "");
+                line = Cow::Owned(
+                    REGEX_UNIFORM
+                        .replace(&rewritten, "var<push_constant>")
+                        .to_string(),
+                );
+                file.push_constant = false;
+            } else if REGEX_DECLARATION.is_match(&line) {
+                return Err(PreprocessorError::DirectiveFailed(
+                    "@push_constant".to_string(),
+                    "expected a var<uniform> declaration".to_string(),
+                ));
+            } else {
+                line = Cow::Owned(REGEX_BINDING_ATTRIBUTES.replace_all(&line, "").to_string());
+            }
+        }
+
+        // Declarations at module scope, which defines shouldn't shadow.
+        if !line.starts_with(char::is_whitespace) {
+            if let Some(caps) = REGEX_DECLARATION.captures(&line) {
+                let name = caps[2].to_string();
+                if defines.contains_key(&name) {
+                    let message = format!(
+                        "`{}` is declared while a `#define` of it is in effect, so its name is substituted",
+                        name
+                    );
+                    let (path, line_number) = file.origin(line_number);
+                    info.warnings.push((path, line_number, message));
+                }
+                info.declarations.push((file.source_path.clone(), name));
+            }
+        }
+
+        // Included source that's added after the line is substituted.
+        let mut verbatim = None;
+
+        // Parse precompiler directives.
+        if let Some(directive_idx) = line.find('#') {
+            // If the closer index is a directive, process it.
+            let mut directive_content = "".to_string();
+            let directive_line = &line[directive_idx..];
+            let directive_args = directive_line
+                .split(' ')
+                .filter(|arg| !arg.trim().is_empty())
+                .collect::<Vec<&str>>();
+
+            if directive_args[0] == "#include" {
+                // #include "path" [group_offset N] [with (NAME = value, ...)]
+                let (include_line, params) = match directive_line.split_once(" with ") {
+                    Some((include_line, params)) => (
+                        include_line,
+                        _include_params(params).ok_or(PreprocessorError::IncludeIncorrectArgs)?,
+                    ),
+                    None => (directive_line, vec![]),
+                };
+                let directive_args = include_line
+                    .split(' ')
+                    .filter(|arg| !arg.trim().is_empty())
+                    .collect::<Vec<&str>>();
+                let group_offset = match directive_args.len() {
+                    2 => 0,
+                    4 if directive_args[2] == "group_offset" => directive_args[3]
+                        .parse::<u32>()
+                        .map_err(|_| PreprocessorError::IncludeIncorrectArgs)?,
+                    _ => return Err(PreprocessorError::IncludeIncorrectArgs),
+                };
+                let dest_path = directive_args[1];
+                if !((dest_path.starts_with('"') && dest_path.ends_with('"'))
+                    || (dest_path.starts_with('<') && dest_path.ends_with('>')))
+                {
+                    return Err(PreprocessorError::IncludeIncorrectArgs);
+                }
+
+                let is_system = dest_path.starts_with('<');
+                let dest_path = &dest_path[1..dest_path.len() - 1];
+
+                // System includes may refer to virtual headers, e.g. from derives
+                // or `<wgpu_pp/math.wgsl>`.
+                let header = match is_system {
+                    true => _virtual_header(dest_path),
+                    false => None,
+                };
+
+                // Parameters only apply to this include, so the defines they
+                // override are restored after it.
+                let overridden = params
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = match file.hygiene {
+                            true => _parenthesize(value),
+                            false => value.clone(),
+                        };
+                        let previous = defines.insert(name.clone(), DefineDirective::Value(value));
+                        (name.clone(), previous)
+                    })
+                    .collect::<Vec<_>>();
+                // The line is finished once the included file is preprocessed.
+                let dest_path = dest_path.to_string();
+                file.include = Some(PendingInclude {
+                    line: line.into_owned(),
+                    line_number,
+                    mapped,
+                    directive_idx,
+                    dest_path,
+                    header,
+                    params,
+                    group_offset,
+                    overridden,
+                });
+                return Ok(());
+            } else if directive_args[0] == "#define" {
+                if directive_args.len() < 3 {
+                    return Err(PreprocessorError::IncludeIncorrectArgs);
+                }
+
+                // Check if it's a macro.
+                if let Some(caps) = REGEX_DEFINE_MACRO.captures(directive_line) {
+                    let macro_name = caps.get(1).unwrap().as_str();
+                    let macro_args = caps.get(2).unwrap().as_str();
+                    let macro_body = caps.get(3).unwrap().as_str();
+
+                    let macro_args = macro_args
+                        .split(',')
+                        .map(|arg| arg.trim().to_string())
+                        .collect::<Vec<String>>();
+                    let macro_body = match file.hygiene {
+                        true => _parenthesize(macro_body),
+                        false => macro_body.to_string(),
+                    };
+
+                    info.defines
+                        .push((file.source_path.clone(), macro_name.to_string()));
+                    _lint_define(macro_name, file.origin(line_number), info);
+                    defines.insert(
+                        macro_name.to_string(),
+                        DefineDirective::Macro(macro_args, macro_body, file.hygiene),
+                    );
+                } else {
+                    let var_name = directive_args[1];
+                    let var_value = directive_args[2..].join(" ");
+                    let var_value = match file.hygiene {
+                        true => _parenthesize(&var_value),
+                        false => var_value,
+                    };
+
+                    info.defines
+                        .push((file.source_path.clone(), var_name.to_string()));
+                    _lint_define(var_name, file.origin(line_number), info);
+                    defines.insert(var_name.to_string(), DefineDirective::Value(var_value));
+                }
+            } else if directive_args[0] == "#template" {
+                // #template fn name<T, ...>(...) { ... }, up to the brace closing its body.
+                let failed = |e: &str| {
+                    PreprocessorError::DirectiveFailed("#template".to_string(), e.to_string())
+                };
+                let header = directive_line["#template".len()..].trim();
+                let Some(caps) = REGEX_TEMPLATE.captures(header) else {
+                    return Err(failed("expected `#template fn name<T, ...>(...)`"));
+                };
+                let name = caps[1].to_string();
+                let params = caps[2]
+                    .split(',')
+                    .map(|param| param.trim().to_string())
+                    .collect::<Vec<_>>();
+                if params
+                    .iter()
+                    .any(|param| _token_len(param) != param.len() || param.is_empty())
+                {
+                    return Err(failed("expected type parameters like `<T, U>`"));
+                }
+                let key = format!("#template {}", name);
+                if defines.contains_key(&key) {
+                    return Err(failed(&format!("`{}` is already defined", name)));
+                }
+
+                // The source without the type parameters, which instances replace.
+                let params_range = caps.get(2).unwrap().range();
+                let mut template = format!(
+                    "{}{}",
+                    header[..params_range.start - 1].trim_end(),
+                    &header[params_range.end + 1..]
+                );
+                let mut depth = 0;
+                let mut opened = false;
+                let mut body_line = header.to_string();
+                let mut in_comment = false;
+                loop {
+                    for c in body_line.chars() {
+                        match c {
+                            '{' => {
+                                depth += 1;
+                                opened = true;
+                            }
+                            '}' => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                    if opened && depth <= 0 {
+                        break;
+                    }
+                    file.i += 1;
+                    if file.i >= lines.len() {
+                        return Err(failed(&format!("`{}` has no body", name)));
+                    }
+                    body_line = lines[file.i].to_string();
+                    in_comment = _remove_comments(&mut body_line, in_comment);
+                    if in_comment {
+                        body_line.clear();
+                        continue;
+                    }
+                    template.push('\n');
+                    template += &body_line;
+                }
+                defines.insert(key, DefineDirective::Template(params, template));
+            } else if directive_args[0] == "#instantiate" {
+                // #instantiate name<TYPE, ...> as INSTANCE
+                let failed = |e: &str| {
+                    PreprocessorError::DirectiveFailed("#instantiate".to_string(), e.to_string())
+                };
+                let Some(caps) = REGEX_INSTANTIATE.captures(directive_line.trim_end()) else {
+                    return Err(failed(
+                        "expected `#instantiate name<TYPE, ...> as INSTANCE`",
+                    ));
+                };
+                let (name, instance) = (&caps[1], &caps[3]);
+                let args = _split_type_args(&caps[2]);
+                let Some(DefineDirective::Template(params, template)) =
+                    defines.get(&format!("#template {}", name))
+                else {
+                    return Err(failed(&format!("unknown template `{}`", name)));
+                };
+                if args.len() != params.len() || args.iter().any(|arg| arg.is_empty()) {
+                    let msg = format!(
+                        "`{}` expects {} type arguments, got {}",
+                        name,
+                        params.len(),
+                        args.len()
+                    );
+                    return Err(failed(&msg));
+                }
+
+                // Instantiating the same types under the same name again, e.g.
+                // from two headers, is fine, and only generates the function once.
+                let signature = format!("{}<{}>", name, args.join(", "));
+                let key = format!("#instance {}", instance);
+                match defines.get(&key) {
+                    Some(DefineDirective::Value(existing)) if *existing == signature => {}
+                    Some(DefineDirective::Value(existing)) => {
+                        let msg = format!("`{}` is already an instance of {}", instance, existing);
+                        return Err(failed(&msg));
+                    }
+                    _ => {
+                        let mut names = params.clone();
+                        names.push(name.to_string());
+                        let mut values = args.clone();
+                        values.push(instance.to_string());
+                        directive_content += &_substitute_args(template, &names, &values);
+                        defines.insert(key, DefineDirective::Value(signature));
+                    }
+                }
+            } else if directive_args[0] == "#include_raw" {
+                // #include_raw "path", copied like a `#verbatim` block.
+                let dest_path = match directive_args[1..] {
+                    [path] if path.len() > 1 && path.starts_with('"') && path.ends_with('"') => {
+                        &path[1..path.len() - 1]
+                    }
+                    _ => return Err(PreprocessorError::IncludeIncorrectArgs),
+                };
+                let (raw_path, mut raw) = match resolver.read(dest_path, &source_path_parent)? {
+                    Some(file) => file,
+                    None => return Err(PreprocessorError::FileNotFound(dest_path.to_string())),
+                };
+                if !info.dependencies.contains(&raw_path) {
+                    info.dependencies.push(raw_path.clone());
+                }
+                info.source_map.extend(
+                    (1..=raw.lines().count()).map(|line_number| (raw_path.clone(), line_number)),
+                );
+                if !raw.is_empty() && !raw.ends_with('\n') {
+                    raw.push('\n');
+                }
+                verbatim = Some(raw);
+            } else if directive_args[0] == "#include_defines" {
+                // #include_defines "path"
+                let dest_path = match directive_args[1..] {
+                    [path] if path.len() > 1 && path.starts_with('"') && path.ends_with('"') => {
+                        &path[1..path.len() - 1]
+                    }
+                    _ => {
+                        return Err(PreprocessorError::DirectiveFailed(
+                            "#include_defines".to_string(),
+                            "expected `#include_defines \"path\"`".to_string(),
+                        ))
+                    }
+                };
+
+                let (config_path, config) = match resolver.read(dest_path, &source_path_parent)? {
+                    Some(file) => file,
+                    None => return Err(PreprocessorError::FileNotFound(dest_path.to_string())),
+                };
+                if !info.dependencies.contains(&config_path) {
+                    info.dependencies.push(config_path.clone());
+                }
+                let extension = config_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or("");
+
+                let config_defines = parse_defines(extension, &config).map_err(|e| {
+                    PreprocessorError::DirectiveFailed(
+                        "#include_defines".to_string(),
+                        format!("{}: {}", dest_path, e),
+                    )
+                })?;
+                // Values are checked and parenthesized like those of `#define`.
+                for (name, value) in config_defines {
+                    if value.contains(['\n', '\r']) {
+                        return Err(PreprocessorError::DirectiveFailed(
+                            "#include_defines".to_string(),
+                            format!("{}: {}: values can't span lines", dest_path, name),
+                        ));
+                    }
+                    let value = match file.hygiene {
+                        true => _parenthesize(&value),
+                        false => value,
+                    };
+                    info.defines.push((config_path.clone(), name.clone()));
+                    _lint_define(&name, file.origin(line_number), info);
+                    defines.insert(name, DefineDirective::Value(value));
+                }
+            } else if directive_args[0] == "#embed" {
+                // #embed NAME "path" [as TYPE]
+                let ty = match directive_args.len() {
+                    3 => None,
+                    5 if directive_args[3] == "as" => Some(directive_args[4]),
+                    _ => return Err(PreprocessorError::EmbedIncorrectArgs),
+                };
+                let name = directive_args[1];
+                let dest_path = directive_args[2];
+                if !(dest_path.len() > 1 && dest_path.starts_with('"') && dest_path.ends_with('"'))
+                {
+                    return Err(PreprocessorError::EmbedIncorrectArgs);
+                }
+                let dest_path = &dest_path[1..dest_path.len() - 1];
+
+                let (data_path, bytes) =
+                    match resolver.read_bytes(dest_path, &source_path_parent)? {
+                        Some(data) => data,
+                        None => return Err(PreprocessorError::FileNotFound(dest_path.to_string())),
+                    };
+                if !info.dependencies.contains(&data_path) {
+                    info.dependencies.push(data_path.clone());
+                }
+                let extension = data_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or("");
+
+                directive_content += &embed(name, extension, &bytes, ty)
+                    .map_err(|e| PreprocessorError::EmbedInvalidData(dest_path.to_string(), e))?;
+            } else if directive_args[0] == "#stage" {
+                if directive_args.len() != 2
+                    || !matches!(directive_args[1], "vertex" | "fragment" | "compute")
+                {
+                    return Err(PreprocessorError::StageIncorrectArgs);
+                }
+
+                directive_content += STAGE_MARKER;
+                directive_content += directive_args[1];
+            } else if directive_args[0] == "#line" {
+                // #line N ["file"]
+                let usage = || {
+                    PreprocessorError::DirectiveFailed(
+                        "#line".to_string(),
+                        "expected `#line N [\"file\"]`".to_string(),
+                    )
+                };
+                let number = match directive_args.get(1).map(|number| number.parse()) {
+                    Some(Ok(number)) if number > 0 => number,
+                    _ => return Err(usage()),
+                };
+                let path = match directive_args[2..] {
+                    [] => file.origin(line_number).0,
+                    [path] if path.len() > 1 && path.starts_with('"') && path.ends_with('"') => {
+                        source_path_parent.join(&path[1..path.len() - 1])
+                    }
+                    _ => return Err(usage()),
+                };
+                next_origin = Some((path, line_number + 1, number));
+            } else if directive_args[0] == "#require" {
+                // #require NAME ["message"]
+                let usage = || {
+                    PreprocessorError::DirectiveFailed(
+                        "#require".to_string(),
+                        "expected `#require NAME [\"message\"]`".to_string(),
+                    )
+                };
+                let Some(name) = directive_args.get(1) else {
+                    return Err(usage());
+                };
+                let message = directive_line["#require".len()..].trim_start()[name.len()..].trim();
+                let message = match message {
+                    "" => None,
+                    _ if message.len() > 1
+                        && message.starts_with('"')
+                        && message.ends_with('"') =>
+                    {
+                        Some(message[1..message.len() - 1].to_string())
+                    }
+                    _ => return Err(usage()),
+                };
+                if !defines.contains_key(*name) {
+                    return Err(PreprocessorError::MissingDefine(name.to_string(), message));
+                }
+            } else if directive_args[0] == "#undef" {
+                if directive_args.len() != 2 {
+                    return Err(PreprocessorError::IncludeIncorrectArgs);
+                }
+
+                let var_name = directive_args[1];
+                defines.remove(var_name);
+            } else if directive_args[0] == "#pragma" {
+                match directive_args[1..] {
+                    ["hygiene"] => file.hygiene = true,
+                    _ => {
+                        return Err(PreprocessorError::UnknownDirective(
+                            directive_args.join(" "),
+                        ))
+                    }
+                }
+            } else if let Some(custom) = directive(&directive_args[0][1..]) {
+                if resolver.run_commands || !matches!(custom, Directive::Command(..)) {
+                    directive_content += &custom.expand(&directive_args[1..]).map_err(|e| {
+                        PreprocessorError::DirectiveFailed(directive_args[0].to_string(), e)
+                    })?;
+                }
+            } else {
+                return Err(PreprocessorError::UnknownDirective(
+                    directive_args[0].to_string(),
+                ));
+            }
+
+            // Return the line up to the closer index.
+            line.to_mut()
+                .replace_range(directive_idx.., &directive_content);
+        };
+
+        _finish_line(
+            file,
+            &line,
+            line_number,
+            mapped,
+            verbatim,
+            resolver,
+            defines,
+            info,
+        )?;
+        if next_origin.is_some() {
+            file.line_origin = next_origin;
+        }
+    }
+}
+
+/// Adds the source of an included file to the line including it, and finishes the line.
+fn _finish_include(
+    file: &mut FileState,
+    included_path: PathBuf,
+    contents_to_add: String,
+    plain: bool,
+    resolver: &Resolver,
+    defines: &mut HashMap<String, DefineDirective>,
+    info: &mut SourceInfo,
+) -> Result<(), PreprocessorError> {
+    let include = file.include.take().unwrap();
+    for (name, previous) in include.overridden.into_iter().rev() {
+        match previous {
+            Some(previous) => defines.insert(name, previous),
+            None => defines.remove(&name),
+        };
+    }
+    info.includes
+        .push((file.source_path.clone(), included_path));
+    let contents_to_add = match include.group_offset {
+        0 => contents_to_add,
+        _ => _shift_groups(
+            &contents_to_add,
+            &include.dest_path,
+            include.group_offset,
+            info,
+        ),
+    };
+
+    // A plain file has nothing to substitute, so it isn't scanned again.
+    let mut line = include.line;
+    let verbatim = match plain && line[..include.directive_idx].trim().is_empty() {
+        true => {
+            line.truncate(include.directive_idx);
+            Some(contents_to_add)
+        }
+        false => {
+            line.replace_range(include.directive_idx.., &contents_to_add);
+            None
+        }
+    };
+    _finish_line(
+        file,
+        &line,
+        include.line_number,
+        include.mapped,
+        verbatim,
+        resolver,
+        defines,
+        info,
+    )
+}
+
+/// Substitutes a line into the contents of a file, followed by `verbatim`,
+/// and moves on to the next line.
+#[allow(clippy::too_many_arguments)]
+fn _finish_line(
+    file: &mut FileState,
+    line: &str,
+    line_number: usize,
+    mapped: usize,
+    verbatim: Option<String>,
+    resolver: &Resolver,
+    defines: &HashMap<String, DefineDirective>,
+    info: &mut SourceInfo,
+) -> Result<(), PreprocessorError> {
+    // Add the line to the contents.
+    let start = file.contents.len();
+    let max_size = resolver.limits.max_output_size;
+    _substitute_macros_into(line, defines, max_size, &[], &mut file.contents)?;
+    if let Some(verbatim) = verbatim {
+        file.contents += &verbatim;
+    }
+    _check_output_size(&file.contents, resolver)?;
+    let line = &file.contents[start..];
+
+    _collect_docs(
+        line,
+        &mut file.pending_docs,
+        &mut file.current_struct,
+        &mut info.docs,
+    );
+
+    // Lines from includes were mapped while preprocessing them, the rest come from this line.
+    let added = line.matches('\n').count() + 1;
+    let included = info.source_map.len() - mapped;
+    let origin = file.origin(line_number);
+    info.source_map
+        .extend(std::iter::repeat_n(origin, added.saturating_sub(included)));
+
+    file.contents.push('\n');
+    file.i += 1;
+    Ok(())
+}
+
+/// Warns if a define shadows a WGSL name or an earlier declaration, since
+/// it's substituted wherever the name is used, usually breaking the source.
+fn _lint_define(name: &str, (path, line_number): (PathBuf, usize), info: &mut SourceInfo) {
+    let shadowed = match wgsl_name(name) {
+        Some(kind) => format!("the WGSL {} `{}`", kind, name),
+        None => match info
+            .declarations
+            .iter()
+            .find(|(_, declared)| declared == name)
+        {
+            Some((declared_in, _)) => format!("`{}` declared in {}", name, declared_in.display()),
+            None => return,
+        },
+    };
+    let message = format!(
+        "`#define {}` shadows {}, which is substituted wherever it's used",
+        name, shadowed
+    );
+    info.warnings.push((path, line_number, message));
+}
+
+/// Errors if `contents` is longer than the expanded source may be. Included
+/// files are checked on their own, before they're added to the source.
+fn _check_output_size(contents: &str, resolver: &Resolver) -> Result<(), PreprocessorError> {
+    match contents.len() > resolver.limits.max_output_size {
+        true => Err(PreprocessorError::OutputTooLarge(
+            resolver.limits.max_output_size,
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Adds `offset` to every `@group(N)` in `contents`, recording the new groups.
+fn _shift_groups(contents: &str, filename: &str, offset: u32, info: &mut SourceInfo) -> String {
+    REGEX_GROUP
+        .replace_all(contents, |caps: &regex::Captures| {
+            let group = caps[1].parse::<u32>().unwrap();
+            let shifted = GroupOffset {
+                filename: filename.to_string(),
+                group,
+                shifted: group + offset,
+            };
+            let result = format!("@group({})", shifted.shifted);
+            if !info.group_offsets.contains(&shifted) {
+                info.group_offsets.push(shifted);
+            }
+            result
+        })
+        .to_string()
+}
+
+/// Attaches pending doc comments to the declaration on `line`, if there is one.
+fn _collect_docs(
+    line: &str,
+    pending_docs: &mut Vec<String>,
+    current_struct: &mut Option<String>,
+    docs: &mut Docs,
+) {
+    if line.trim().is_empty() || REGEX_ATTRIBUTES.is_match(line) {
+        return;
+    }
+
+    let name = if let Some(caps) = REGEX_DECLARATION.captures(line) {
+        let name = caps[2].to_string();
+        if &caps[1] == "struct" {
+            *current_struct = Some(name.clone());
+        }
+        Some(name)
+    } else {
+        match (current_struct.as_ref(), REGEX_MEMBER.captures(line)) {
+            (Some(parent), Some(caps)) => Some(format!("{}.{}", parent, &caps[1])),
+            _ => None,
+        }
+    };
+
+    if let (Some(name), false) = (name, pending_docs.is_empty()) {
+        docs.insert(name, pending_docs.join("\n"));
+    }
+    pending_docs.clear();
+
+    if line.contains('}') {
+        *current_struct = None;
+    }
+}
+
+/// Splits preprocessed source into a source per `#stage`.
+///
+/// Everything before the first `#stage` is shared by all stages, and sections
+/// for the same stage are joined. Returns nothing if there are no stages.
+pub fn split_stages(source: &str) -> Vec<(String, String)> {
+    let mut common = String::new();
+    let mut stages: Vec<(String, String)> = vec![];
+    let mut current = None;
+    for line in source.lines() {
+        if let Some(stage) = line.trim().strip_prefix(STAGE_MARKER) {
+            current = match stages.iter().position(|(name, _)| name == stage) {
+                Some(i) => Some(i),
+                None => {
+                    stages.push((stage.to_string(), String::new()));
+                    Some(stages.len() - 1)
+                }
+            };
+            continue;
+        }
+
+        let section = match current {
+            Some(i) => &mut stages[i].1,
+            None => &mut common,
+        };
+        section.push_str(line);
+        section.push('\n');
+    }
+
+    stages
+        .into_iter()
+        .map(|(name, section)| (name, format!("{}{}", common, section)))
+        .collect()
+}
+
+/// Preprocesses several files for one module, returning the source of each.
+///
+/// Files are included at most once across all of them, so shared headers only
+/// end up in the source of the first file including them. Defines carry over
+/// from one file to the next for the same reason.
+pub fn preprocess_bundle(
+    filenames: &[String],
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<Vec<String>, PreprocessorError> {
+    let sources = preprocess_bundle_with_info(filenames, basepath, defines)?;
+    Ok(sources.into_iter().map(|(source, _)| source).collect())
+}
+
+/// Like `preprocess_bundle`, also returning what was collected about each
+/// file. Declarations carry over along with the defines, so a define in one
+/// file shadowing a declaration of an earlier one is caught.
+pub fn preprocess_bundle_with_info(
+    filenames: &[String],
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<Vec<(String, SourceInfo)>, PreprocessorError> {
+    let mut visited = HashSet::new();
+    let mut defines = defines
+        .iter()
+        .map(|(name, value)| (name.clone(), DefineDirective::Value(value.clone())))
+        .collect();
+    let resolver = Resolver {
+        include_dirs: &[],
+        provider: None,
+        limits: Limits::default(),
+        run_commands: true,
+    };
+    let mut declarations = vec![];
+    let mut sources = vec![];
+    for filename in filenames {
+        let mut info = SourceInfo {
+            declarations,
+            ..SourceInfo::default()
+        };
+        let (_, source, _) = _preprocess(
+            filename,
+            basepath,
+            &resolver,
+            &mut visited,
+            &mut defines,
+            &mut info,
+            &[],
+        )?;
+        info.define_values = _define_values(&defines, resolver.limits.max_output_size);
+        declarations = info.declarations.clone();
+        sources.push((source, info));
+    }
+    Ok(sources)
+}
+
+/// Returns the names declared at module scope, i.e. on lines that aren't indented.
+pub fn declarations(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter(|line| !line.starts_with(char::is_whitespace))
+        .filter_map(|line| REGEX_DECLARATION.captures(line))
+        .map(|caps| caps[2].to_string())
+        .collect()
+}
+
+/// Returns whether preprocessed source has `enable f16`.
+pub fn enables_f16(source: &str) -> bool {
+    source.lines().any(|line| {
+        REGEX_ENABLE
+            .captures(line)
+            .is_some_and(|caps| caps[1].split(',').any(|ext| ext.trim() == "f16"))
+    })
+}
+
+/// Rewrites preprocessed source using f16 to use f32, for devices without `SHADER_F16`.
+///
+/// `f16` and the `h` vector and matrix types become their f32 equivalents,
+/// literals lose their `h` suffix, and `f16` is removed from `enable` directives.
+pub fn f16_fallback(source: &str) -> String {
+    let mut fallback = String::new();
+    for line in source.lines() {
+        if let Some(caps) = REGEX_ENABLE.captures(line) {
+            let extensions = caps[1]
+                .split(',')
+                .map(str::trim)
+                .filter(|ext| !ext.is_empty() && *ext != "f16")
+                .collect::<Vec<_>>();
+            if !extensions.is_empty() {
+                fallback.push_str(&format!("enable {};\n", extensions.join(", ")));
+            }
+            continue;
+        }
+
+        let line = REGEX_F16_TYPE.replace_all(line, |caps: &regex::Captures| match caps.get(1) {
+            Some(ty) => format!("{}f", ty.as_str()),
+            None => "f32".to_string(),
+        });
+        let line = REGEX_F16_LITERAL.replace_all(&line, "${1}${2}f");
+        let line = REGEX_F16_HEX_LITERAL.replace_all(&line, "${1}f");
+        fallback.push_str(&line);
+        fallback.push('\n');
+    }
+    fallback
+}
+
+/// Loads a WGSL and preprocesses it.
+pub fn preprocess(filename: &str, basepath: &Path) -> Result<String, PreprocessorError> {
+    preprocess_with_defines(filename, basepath, &[]).map(|(source, _)| source)
+}
+
+/// Loads a WGSL and preprocesses it as if it started with a `#define` for each of `defines`.
+///
+/// Also returns the `///` doc comments of the shader, the groups shifted by `#include`,
+/// and the files that were read.
+pub fn preprocess_with_defines(
+    filename: &str,
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<(String, SourceInfo), PreprocessorError> {
+    let resolver = Resolver {
+        include_dirs: &[],
+        provider: None,
+        limits: Limits::default(),
+        run_commands: true,
+    };
+    preprocess_with_resolver(filename, basepath, &resolver, defines)
+}
+
+/// Lists the defines for `SourceInfo::define_values`.
+fn _define_values(
+    defines: &HashMap<String, DefineDirective>,
+    max_size: usize,
+) -> Vec<(String, String)> {
+    let mut values = defines
+        .iter()
+        .filter_map(|(name, define)| match define {
+            // Instances of templates are keyed by `#instance name`.
+            DefineDirective::Value(_) if name.starts_with('#') => None,
+            DefineDirective::Value(value) => {
+                let value =
+                    _substitute_macros(value, defines, max_size).unwrap_or_else(|_| value.clone());
+                Some((name.clone(), value))
+            }
+            DefineDirective::Macro(params, body, _) => {
+                Some((format!("{}({})", name, params.join(", ")), body.clone()))
+            }
+            DefineDirective::Template(..) => None,
+        })
+        .collect::<Vec<_>>();
+    values.sort();
+    values
+}
+
+/// Like `preprocess_with_defines`, reading files through `resolver`.
+pub(crate) fn preprocess_with_resolver(
+    filename: &str,
+    basepath: &Path,
+    resolver: &Resolver,
+    defines: &[(String, String)],
+) -> Result<(String, SourceInfo), PreprocessorError> {
+    let mut info = SourceInfo::default();
+    let mut defines = defines
+        .iter()
+        .map(|(name, value)| (name.clone(), DefineDirective::Value(value.clone())))
+        .collect();
+    let (_, source, _) = _preprocess(
+        filename,
+        basepath,
+        resolver,
+        &mut HashSet::new(), // visited
+        &mut defines,
+        &mut info,
+        &[],
+    )?;
+    info.define_values = _define_values(&defines, resolver.limits.max_output_size);
+    Ok((source, info))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "fs")]
+    use std::fs::read_dir;
+
+    use super::*;
+
+    /// Preprocesses `entry`, reading includes from `files` instead of disk.
+    fn preprocess_files(
+        files: &[(&str, &str)],
+        entry: &str,
+    ) -> Result<(String, SourceInfo), PreprocessorError> {
+        preprocess_files_with_defines(files, entry, &[])
+    }
+
+    /// Like `preprocess_files`, with `defines` defined before `entry`.
+    fn preprocess_files_with_defines(
+        files: &[(&str, &str)],
+        entry: &str,
+        defines: &[(String, String)],
+    ) -> Result<(String, SourceInfo), PreprocessorError> {
+        let files = files
+            .iter()
+            .map(|(path, text)| (PathBuf::from(path), *text))
+            .collect::<HashMap<_, _>>();
+        let resolver = Resolver {
+            include_dirs: &[],
+            provider: Some(&files),
+            limits: Limits::default(),
+            run_commands: true,
+        };
+        preprocess_with_resolver(entry, Path::new(""), &resolver, defines)
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_snapshot() {
+        let snapshot_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+
+        for entry in read_dir(&snapshot_dir).unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path();
+            let filename = path.file_name().unwrap().to_str().unwrap().to_string();
+            if !filename.ends_with(".wgsl") {
+                continue;
+            }
+
+            println!("starting {}", filename);
+
+            let result = preprocess(&filename, &snapshot_dir);
+            if result.is_err() {
+                println!("{:?}", result);
+            }
+            assert!(result.is_ok(), "Failed to preprocess file: {}", filename);
+
+            insta::assert_snapshot!(filename, result.unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_virtual_header() {
+        let snapshot_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/common");
+
+        register_virtual_header(
+            "test_header.wgsl",
+            "struct Light {\n    position: vec3<f32>,\n    intensity: f32,\n}\n",
+        );
+
+        let result = preprocess("virtual_header.wgsl", &snapshot_dir);
+        assert!(
+            result.is_ok(),
+            "Failed to preprocess file: virtual_header.wgsl"
+        );
+
+        insta::assert_snapshot!("virtual_header.wgsl", result.unwrap());
+    }
+
+    #[test]
+    fn test_std_headers() {
+        let main = STD_HEADERS
+            .iter()
+            .map(|(name, _)| format!("#include <{}>\n", name))
+            .collect::<String>();
+        let (source, info) = preprocess_files(&[("main.wgsl", &main)], "main.wgsl").unwrap();
+        assert!(source.contains("fn quat_rotate(q: vec4f, v: vec3f) -> vec3f {"));
+        assert!(source.contains("fn srgb_to_linear(c: vec3f) -> vec3f {"));
+        assert_eq!(
+            info.docs["luminance"],
+            " The relative luminance of linear Rec. 709 (and sRGB) color."
+        );
+
+        // The headers are checked against the version of Naga the macros use.
+        #[cfg(feature = "build")]
+        crate::build::validate(&source).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_file_cache() {
+        let dir = std::env::temp_dir().join(format!("wgpu_pp_cache_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("light.wgsl"), "const SCALE = 1.0;\n").unwrap();
+        std::fs::write(dir.join("main.wgsl"), "#include \"light.wgsl\"\n").unwrap();
+
+        let source = preprocess("main.wgsl", &dir).unwrap();
+        assert_eq!(source.trim(), "const SCALE = 1.0;");
+        // Changes are seen, since the cache is keyed by modification time and size.
+        std::fs::write(dir.join("light.wgsl"), "const SCALE = 10.0;\n").unwrap();
+        let source = preprocess("main.wgsl", &dir).unwrap();
+        assert_eq!(source.trim(), "const SCALE = 10.0;");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_substitute_macros() {
+        let defines = HashMap::from([
+            ("E".to_string(), DefineDirective::Value("2.718".to_string())),
+            (
+                "LOOP".to_string(),
+                DefineDirective::Value("LOOP + 1".to_string()),
+            ),
+            (
+                "SQUARE".to_string(),
+                DefineDirective::Value("MUL".to_string()),
+            ),
+            (
+                "MUL".to_string(),
+                DefineDirective::Macro(
+                    vec!["a".to_string(), "b".to_string()],
+                    "(a * b)".to_string(),
+                    false,
+                ),
+            ),
+            (
+                "FOO".to_string(),
+                DefineDirective::Macro(vec!["x".to_string()], "x".to_string(), false),
+            ),
+            (
+                "BAR".to_string(),
+                DefineDirective::Value("FOO(BAR)".to_string()),
+            ),
+        ]);
+        let substitute = |line: &str| _substitute_macros(line, &defines, usize::MAX).unwrap();
+
+        // Numbers aren't identifiers.
+        assert_eq!(
+            substitute("let x = 1E + 1e5 + E;"),
+            "let x = 1E + 1e5 + 2.718;"
+        );
+        // Defines aren't expanded inside themselves.
+        assert_eq!(substitute("LOOP"), "LOOP + 1");
+        // Expansions are scanned again along with the rest of the line.
+        assert_eq!(substitute("SQUARE(E, MUL(2, 3))"), "(2.718 * (2 * 3))");
+        // Identifiers are Unicode, including combining marks.
+        assert_eq!(
+            substitute("const café = E; // naïve ☃"),
+            "const café = 2.718; // naïve ☃"
+        );
+        assert_eq!(substitute("E\u{301} + MUL(π, é)"), "E\u{301} + (π * é)");
+        // Commas in template lists don't separate arguments, unlike in comparisons.
+        assert_eq!(
+            substitute("MUL(array<vec2<f32>, 4>, vec2<f32>(1.0, E))"),
+            "(array<vec2<f32>, 4> * vec2<f32>(1.0, 2.718))"
+        );
+        assert_eq!(substitute("MUL(1 < 2, 3 > 2)"), "(1 < 2 * 3 > 2)");
+        assert_eq!(substitute("MUL(a < b, c)"), "(a < b * c)");
+        assert_eq!(substitute("MUL(a << b, c >> d)"), "(a << b * c >> d)");
+        assert_eq!(
+            substitute("MUL(a < b && E > d, c)"),
+            "(a < b && 2.718 > d * c)"
+        );
+        assert!(matches!(
+            _substitute_macros("MUL(1, 2, 3)", &defines, usize::MAX),
+            Err(PreprocessorError::MacroIncorrectArgs(2, 3))
+        ));
+        // Function-like macros need arguments, and object-like defines can
+        // only be called when their value can, like a type.
+        assert!(matches!(
+            _substitute_macros("let f = MUL;", &defines, usize::MAX),
+            Err(PreprocessorError::MacroWithoutArgs(name, 2, line))
+                if name == "MUL" && line == "let f = MUL;"
+        ));
+        assert!(matches!(
+            _substitute_macros("let x = E(2.0);", &defines, usize::MAX),
+            Err(PreprocessorError::DefineCalled(name, value, _)) if name == "E" && value == "2.718"
+        ));
+        assert_eq!(substitute("let v = SQUARE(2, 3);"), "let v = (2 * 3);");
+        // Defines passed to macros aren't expanded inside themselves either.
+        assert_eq!(substitute("const a = BAR;"), "const a = BAR;");
+        // Like in WGSL, comparisons separated by commas need parenthesis.
+        assert!(matches!(
+            _substitute_macros("MUL(a < b, c > d)", &defines, usize::MAX),
+            Err(PreprocessorError::MacroIncorrectArgs(2, 1))
+        ));
+        assert!(matches!(
+            _substitute_macros("MUL(1, 2", &defines, usize::MAX),
+            Err(PreprocessorError::MacroNoParenthesis)
+        ));
+    }
+
+    #[test]
+    fn test_hygiene() {
+        let header = "#define DOUBLE(x) x * 2\n#define HALF 1.0 / 2.0\n";
+        let main = "const a = DOUBLE(1 + 2) / HALF;\n";
+        let plain = header.to_string() + main;
+        let hygienic = "#pragma hygiene\n".to_string() + header + main;
+        let files = [
+            ("plain.wgsl", plain.as_str()),
+            ("hygienic.wgsl", &hygienic),
+            (
+                "types.wgsl",
+                "#pragma hygiene\n#define T vec4<f32>\n#define B @group(0) @binding(1)\n\
+                 #define CMP a < b\n#define SET(x) x = 1.0;\nB var<uniform> v: T;\nconst c = !CMP;\nSET(v.x)\n",
+            ),
+            ("bad.wgsl", "#pragma once\n"),
+        ];
+        let preprocess =
+            |filename: &str| preprocess_files(&files, filename).map(|(source, _)| source);
+
+        assert_eq!(
+            preprocess("plain.wgsl").unwrap().trim(),
+            "const a = 1 + 2 * 2 / 1.0 / 2.0;"
+        );
+        assert_eq!(
+            preprocess("hygienic.wgsl").unwrap().trim(),
+            "const a = ((1 + 2) * 2) / (1.0 / 2.0);"
+        );
+        // Types, attributes and statements aren't expressions, so they're left alone.
+        assert_eq!(
+            preprocess("types.wgsl").unwrap().trim(),
+            "@group(0) @binding(1) var<uniform> v: vec4<f32>;\nconst c = !(a < b);\nv.x = 1.0;"
+        );
+        assert!(matches!(
+            preprocess("bad.wgsl"),
+            Err(PreprocessorError::UnknownDirective(directive)) if directive == "#pragma once"
+        ));
+    }
+
+    #[test]
+    fn test_templates() {
+        let header = "#template fn swap<T>(a: ptr<function, T>, b: ptr<function, T>) {\n    let t = *a; // Temporary.\n    *a = *b;\n    *b = t;\n}\n\
+                      #template fn pick<T, U>(x: T, y: U) -> T { return x; }\n";
+        let files = [
+            ("swap.wgsl", header),
+            (
+                "main.wgsl",
+                "#include \"swap.wgsl\"\n#instantiate swap<f32> as swap_f32\n\
+                 #instantiate pick<vec2<f32>, u32> as pick_v2\n#instantiate swap<f32> as swap_f32\n",
+            ),
+            (
+                "conflict.wgsl",
+                "#include \"swap.wgsl\"\n#instantiate swap<f32> as swap_1\n#instantiate swap<i32> as swap_1\n",
+            ),
+            ("unknown.wgsl", "#instantiate sort<f32> as sort_f32\n"),
+            (
+                "arity.wgsl",
+                "#include \"swap.wgsl\"\n#instantiate pick<f32> as pick_f32\n",
+            ),
+        ];
+        let preprocess =
+            |filename: &str| preprocess_files(&files, filename).map(|(source, _)| source);
+
+        let source = preprocess("main.wgsl").unwrap();
+        assert_eq!(
+            source
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .collect::<Vec<_>>(),
+            [
+                "fn swap_f32(a: ptr<function, f32>, b: ptr<function, f32>) {",
+                "    let t = *a; ",
+                "    *a = *b;",
+                "    *b = t;",
+                "}",
+                "fn pick_v2(x: vec2<f32>, y: u32) -> vec2<f32> { return x; }",
+            ]
+        );
+        let failed = |filename: &str| match preprocess(filename) {
+            Err(PreprocessorError::DirectiveFailed(directive, e)) => (directive, e),
+            result => panic!("expected an error, got {:?}", result),
+        };
+        assert_eq!(
+            failed("conflict.wgsl").1,
+            "`swap_1` is already an instance of swap<f32>"
+        );
+        assert_eq!(failed("unknown.wgsl").1, "unknown template `sort`");
+        assert_eq!(
+            failed("arity.wgsl"),
+            (
+                "#instantiate".to_string(),
+                "`pick` expects 2 type arguments, got 1".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_push_constants() {
+        let files = [
+            (
+                "main.wgsl",
+                "// @push_constant(desktop, native)\n@group(0) @binding(0)\nvar<uniform> params: Params;\n\
+                 @group(0) @binding(1) var<uniform> camera: Camera;\n",
+            ),
+            ("bad.wgsl", "// @push_constant(native)\nfn f() {}\n"),
+        ];
+        let preprocess = |filename: &str, profile: &str| {
+            let defines = [(PROFILE_DEFINE.to_string(), profile.to_string())];
+            preprocess_files_with_defines(&files, filename, &defines).map(|(source, _)| source)
+        };
+
+        // Only the uniform after the comment becomes a push constant.
+        assert_eq!(
+            preprocess("main.wgsl", "native").unwrap(),
+            "\n\nvar<push_constant> params: Params;\n@group(0) @binding(1) var<uniform> camera: Camera;\n"
+        );
+        // Other profiles keep the uniform.
+        assert_eq!(
+            preprocess("main.wgsl", "webgpu").unwrap(),
+            "\n@group(0) @binding(0)\nvar<uniform> params: Params;\n@group(0) @binding(1) var<uniform> camera: Camera;\n"
+        );
+        assert!(matches!(
+            preprocess("bad.wgsl", "native"),
+            Err(PreprocessorError::DirectiveFailed(directive, _)) if directive == "@push_constant"
+        ));
+    }
+
+    #[test]
+    fn test_include_defines() {
+        let files = [
+            (
+                "tuning.toml",
+                "# Tuned by design.\nGRAVITY = -9.81\nMAX_LIGHTS = 1_024\n\n[player]\nspeed = 4.0 # Units per second.\n\
+                 tint = \"vec3(1.0, 0.5, 0.0)\"\nweights = [0.25, 0.75]\n",
+            ),
+            (
+                "tuning.json",
+                "{\"GRAVITY\": -9.81, \"player\": {\"speed\": 4, \"hidden\": false}}",
+            ),
+            (
+                "main.wgsl",
+                "#include_defines \"tuning.toml\"\nconst g = GRAVITY;\nconst n = MAX_LIGHTS;\n\
+                 const s = player_speed;\nconst t = player_tint;\nconst w = player_weights;\n",
+            ),
+            (
+                "json.wgsl",
+                "#include_defines \"tuning.json\"\nconst g = GRAVITY * player_speed;\nconst h = player_hidden;\n",
+            ),
+            ("bad.toml", "NAN = nan\n"),
+            ("bad.wgsl", "#include_defines \"bad.toml\"\n"),
+            ("lines.toml", "BODY = \"\"\"\nreturn 1;\n\"\"\"\n"),
+            ("lines.wgsl", "#include_defines \"lines.toml\"\n"),
+            ("hygiene.toml", "HALF = \"1.0 / 2.0\"\nselect = 1\n"),
+            (
+                "hygiene.wgsl",
+                "#pragma hygiene\n#include_defines \"hygiene.toml\"\nconst a = 2.0 / HALF;\n",
+            ),
+        ];
+        let preprocess = |filename: &str| preprocess_files(&files, filename);
+
+        let (source, info) = preprocess("main.wgsl").unwrap();
+        assert_eq!(
+            source.trim(),
+            "const g = -9.81;\nconst n = 1024;\nconst s = 4.0;\nconst t = vec3(1.0, 0.5, 0.0);\nconst w = array(0.25, 0.75);"
+        );
+        assert_eq!(
+            info.dependencies,
+            [PathBuf::from("main.wgsl"), PathBuf::from("tuning.toml")]
+        );
+        assert_eq!(
+            preprocess("json.wgsl").unwrap().0.trim(),
+            "const g = -9.81 * 4;\nconst h = false;"
+        );
+        assert!(matches!(
+            preprocess("bad.wgsl"),
+            Err(PreprocessorError::DirectiveFailed(_, e)) if e == "bad.toml: NAN: unsupported value: NaN"
+        ));
+        assert!(matches!(
+            preprocess("lines.wgsl"),
+            Err(PreprocessorError::DirectiveFailed(_, e)) if e == "lines.toml: BODY: values can't span lines"
+        ));
+
+        // Like `#define`, values are parenthesized after `#pragma hygiene`, and
+        // shadowing a WGSL name is a warning.
+        let (source, info) = preprocess("hygiene.wgsl").unwrap();
+        assert_eq!(source.trim(), "const a = 2.0 / (1.0 / 2.0);");
+        assert_eq!(
+            info.warnings,
+            [(
+                PathBuf::from("hygiene.wgsl"),
+                2,
+                "`#define select` shadows the WGSL builtin function `select`, which is substituted wherever it's used"
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_require() {
+        let header = "#require LIGHTS \"light.wgsl needs LIGHTS; include config.wgsl first\"\n#require SHADOWS\n\
+                      const l = LIGHTS;\n";
+        let files = [
+            ("light.wgsl", header),
+            (
+                "configured.wgsl",
+                "#define LIGHTS 4\n#define SHADOWS 1\n#include \"light.wgsl\"\n",
+            ),
+            ("lights.wgsl", "#define LIGHTS 4\n#include \"light.wgsl\"\n"),
+        ];
+        let preprocess =
+            |filename: &str| preprocess_files(&files, filename).map(|(source, _)| source);
+
+        assert_eq!(
+            preprocess("configured.wgsl").unwrap().trim(),
+            "const l = 4;"
+        );
+        assert_eq!(
+            preprocess("light.wgsl").unwrap_err().to_string(),
+            "light.wgsl needs LIGHTS; include config.wgsl first"
+        );
+        assert_eq!(
+            preprocess("lights.wgsl").unwrap_err().to_string(),
+            "SHADOWS must be defined"
+        );
+    }
+
+    #[test]
+    fn test_verbatim() {
+        let files = [
+            (
+                "main.wgsl",
+                "#define SIZE 4\n#verbatim\n// Generated, SIZE is not a define here.\nconst SIZE_2 = 8; // #not a directive\n\
+                 #endverbatim\nconst a = SIZE;\n#include_raw \"generated.wgsl\"\n",
+            ),
+            ("generated.wgsl", "/* #SIZE */ const b = SIZE;"),
+            ("unterminated.wgsl", "#verbatim\nconst a = 1;\n"),
+        ];
+        let preprocess = |filename: &str| preprocess_files(&files, filename);
+
+        let (source, info) = preprocess("main.wgsl").unwrap();
+        assert_eq!(
+            source,
+            "\n// Generated, SIZE is not a define here.\nconst SIZE_2 = 8; // #not a directive\n\
+             const a = 4;\n/* #SIZE */ const b = SIZE;\n\n"
+        );
+        let lines = info
+            .source_map
+            .iter()
+            .map(|(path, line)| (path.to_str().unwrap(), *line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                ("main.wgsl", 1),
+                ("main.wgsl", 3),
+                ("main.wgsl", 4),
+                ("main.wgsl", 6),
+                ("generated.wgsl", 1),
+                ("main.wgsl", 7),
+            ]
+        );
+        assert!(matches!(
+            preprocess("unterminated.wgsl"),
+            Err(PreprocessorError::DirectiveFailed(directive, _)) if directive == "#verbatim"
+        ));
+    }
+
+    #[test]
+    fn test_define_values() {
+        let files = [(
+            "main.wgsl",
+            "#define SCALE 2.0\n#define HALF SCALE / 2.0\n#define DOUBLE(x) x * SCALE\n#define TMP 1\n#undef TMP\n\
+             #template fn id<T>(x: T) -> T { return x; }\n#instantiate id<f32> as id_f32\n",
+        )];
+        let defines = [("QUALITY".to_string(), "HALF".to_string())];
+
+        let (_, info) = preprocess_files_with_defines(&files, "main.wgsl", &defines).unwrap();
+        let values = info
+            .define_values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                ("DOUBLE(x)", "x * SCALE"),
+                ("HALF", "2.0 / 2.0"),
+                ("QUALITY", "2.0 / 2.0"),
+                ("SCALE", "2.0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_params() {
+        let files = [
+            (
+                "blur.wgsl",
+                "#include \"common.wgsl\"\nfn NAME(uv: vec2f) -> f32 { return f(uv, DIRECTION * f32(RADIUS)); }\n",
+            ),
+            ("common.wgsl", "const c = 1;\n"),
+            (
+                "main.wgsl",
+                "#define RADIUS 2\n\
+                 #include \"blur.wgsl\" with (NAME = blur_h, RADIUS = 4, DIRECTION = vec2(1.0, 0.0))\n\
+                 #include \"blur.wgsl\" with (NAME = blur_v, RADIUS = 4, DIRECTION = vec2(0.0, 1.0))\n\
+                 #include \"blur.wgsl\" with (NAME = blur_v, RADIUS = 4, DIRECTION = vec2(0.0, 1.0))\n\
+                 const r = RADIUS;\nconst d = DIRECTION;\n",
+            ),
+            ("bad.wgsl", "#include \"blur.wgsl\" with (RADIUS)\n"),
+        ];
+        let preprocess = |filename: &str| preprocess_files(&files, filename);
+
+        let (source, info) = preprocess("main.wgsl").unwrap();
+        let lines = source
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>();
+        // Parameters don't outlive the include, and the same parameters only include once.
+        assert_eq!(
+            lines,
+            [
+                "const c = 1;",
+                "fn blur_h(uv: vec2f) -> f32 { return f(uv, vec2(1.0, 0.0) * f32(4)); }",
+                "fn blur_v(uv: vec2f) -> f32 { return f(uv, vec2(0.0, 1.0) * f32(4)); }",
+                "const r = 2;",
+                "const d = DIRECTION;",
+            ]
+        );
+        assert_eq!(
+            info.dependencies,
+            [
+                PathBuf::from("main.wgsl"),
+                PathBuf::from("blur.wgsl"),
+                PathBuf::from("common.wgsl")
+            ]
+        );
+        assert!(matches!(
+            preprocess("bad.wgsl"),
+            Err(PreprocessorError::IncludeIncorrectArgs)
+        ));
+    }
+
+    #[test]
+    fn test_custom_directive() {
+        use crate::directive::{register_directive, Directive};
+
+        register_directive(
+            "repeat3",
+            Directive::Template("const $1 = array($2, $2, $2);".to_string()),
+        );
+        register_directive(
+            "echo",
+            Directive::Command("echo".to_string(), vec!["const".to_string()]),
+        );
+        register_directive("fail", Directive::Command("false".to_string(), vec![]));
+
+        let files = [(
+            "main.wgsl",
+            "#define SCALE 2.0\n#repeat3 WEIGHTS SCALE\n#echo ANSWER = 42;\n",
+        )];
+        let (source, _) = preprocess_files(&files, "main.wgsl").unwrap();
+        assert_eq!(
+            source.trim(),
+            "const WEIGHTS = array(2.0, 2.0, 2.0);\nconst ANSWER = 42;"
+        );
+
+        let result = preprocess_files(&[("main.wgsl", "#fail\n")], "main.wgsl");
+        assert!(matches!(
+            result,
+            Err(PreprocessorError::DirectiveFailed(..))
+        ));
+    }
+
+    #[test]
+    fn test_parse_directives() {
+        use crate::directive::{parse_directives, reject_commands, Directive};
+
+        let directives = parse_directives(
+            "toml",
+            "[template]\nrepeat3 = \"const $1 = array($2, $2, $2);\"\n\n\
+             [command]\ngenerate_noise = \"python3 scripts/noise.py\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            &directives[..],
+            [(repeat3, Directive::Template(template)), (noise, Directive::Command(program, args))]
+                if repeat3 == "repeat3"
+                    && template == "const $1 = array($2, $2, $2);"
+                    && noise == "generate_noise"
+                    && program == "python3"
+                    && args == &["scripts/noise.py"]
+        ));
+
+        let e = parse_directives("toml", "repeat3 = \"$1\"\n").unwrap_err();
+        assert_eq!(e, "`repeat3` isn't in the `template` or `command` table");
+        let e = parse_directives("json", r#"{"command": {"noise": " "}}"#).unwrap_err();
+        assert_eq!(e, "`noise` has an empty command");
+
+        assert!(reject_commands(&directives[..1]).is_ok());
+        assert_eq!(
+            reject_commands(&directives).unwrap_err(),
+            "`generate_noise` runs a command, which needs WGPU_PP_ALLOW_COMMANDS=1"
+        );
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        use std::{process::Command, time::Duration};
+
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let e = crate::directive::run("sleep", command, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(e, "sleep didn't finish within 0.1s");
+    }
+
+    #[test]
+    fn test_plain_files() {
+        let main = "#include \"plain.wgsl\"\n#define SCALE 2.0\n#include \"scaled.wgsl\"\n";
+        let plain = "fn f() {}\n\nfn g() {}";
+        let scaled = "const scale = SCALE;\n";
+
+        // Files are only copied when nothing in them would be substituted.
+        let defines = HashMap::new();
+        assert!(_is_plain(plain, &defines));
+        assert!(!_is_plain(main, &defines));
+        let defines = HashMap::from([(
+            "SCALE".to_string(),
+            DefineDirective::Value("2.0".to_string()),
+        )]);
+        assert!(!_is_plain(scaled, &defines));
+        assert!(!_is_plain("let a = 1; // comment", &defines));
+
+        let files = [
+            ("main.wgsl", main),
+            ("plain.wgsl", plain),
+            ("scaled.wgsl", scaled),
+        ];
+        let (source, info) = preprocess_files(&files, "main.wgsl").unwrap();
+        assert_eq!(source, "fn f() {}\n\nfn g() {}\n\n\nconst scale = 2.0;\n\n");
+        let lines = info
+            .source_map
+            .iter()
+            .map(|(path, line)| (path.to_str().unwrap(), *line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                ("plain.wgsl", 1),
+                ("plain.wgsl", 2),
+                ("plain.wgsl", 3),
+                ("main.wgsl", 1),
+                ("main.wgsl", 2),
+                ("scaled.wgsl", 1),
+                ("main.wgsl", 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        use crate::{LineEnding, Preprocessor};
+
+        let files = [
+            (
+                "main.wgsl",
+                "\u{feff}#include \"light.wgsl\"\r\nconst scale = \\\r\n    SCALE;\r\n",
+            ),
+            ("light.wgsl", "\u{feff}#define SCALE 2.0\r\n"),
+        ];
+
+        // Byte order marks are removed, and continuations work with CRLF.
+        let (source, _) = preprocess_files(&files, "main.wgsl").unwrap();
+        assert_eq!(source, "\n\nconst scale =     2.0;\n");
+
+        let source = Preprocessor::new()
+            .file_provider(HashMap::from(
+                files.map(|(path, text)| (PathBuf::from(path), text)),
+            ))
+            .line_ending(LineEnding::CrLf)
+            .process_file("main.wgsl")
+            .unwrap();
+        assert_eq!(source, "\r\n\r\nconst scale =     2.0;\r\n");
+    }
+
+    #[test]
+    fn test_limits() {
+        use crate::Preprocessor;
+
+        let limits = Limits {
+            max_file_size: 1000,
+            max_output_size: 1000,
+            ..Limits::default()
+        };
+        let large = "const x = 1;\n".repeat(100);
+        // Each define doubles the one before it, for 2^30 copies of `x`.
+        let mut exploding = "#define A0 x\n".to_string();
+        for i in 1..=30 {
+            exploding += &format!("#define A{} A{} A{}\n", i, i - 1, i - 1);
+        }
+        exploding += "A30\n";
+        let files = HashMap::from([
+            (PathBuf::from("large.wgsl"), large.clone()),
+            (
+                PathBuf::from("main.wgsl"),
+                "#include \"large.wgsl\"\n".to_string(),
+            ),
+            (PathBuf::from("exploding.wgsl"), exploding),
+            (
+                PathBuf::from("repeated.wgsl"),
+                "#define X const x = 1;\n".to_string() + &"X\n".repeat(100),
+            ),
+        ]);
+        let preprocessor = Preprocessor::new().file_provider(files).limits(limits);
+
+        assert!(matches!(
+            preprocessor.process_file("main.wgsl"),
+            Err(PreprocessorError::FileTooLarge(filename, 1000)) if filename == "large.wgsl"
+        ));
+        assert!(matches!(
+            preprocessor.process_file("exploding.wgsl"),
+            Err(PreprocessorError::OutputTooLarge(1000))
+        ));
+        assert!(matches!(
+            preprocessor.process_file("repeated.wgsl"),
+            Err(PreprocessorError::OutputTooLarge(1000))
+        ));
+
+        let preprocessor = Preprocessor::new().file_provider(HashMap::from([(
+            PathBuf::from("large.wgsl"),
+            large.clone(),
+        )]));
+        assert_eq!(preprocessor.process_file("large.wgsl").unwrap(), large);
+    }
+
+    #[test]
+    fn test_include_depth() {
+        use crate::Preprocessor;
+
+        // A chain of includes deeper than the default limit.
+        let depth = 1000;
+        let mut files = (0..depth)
+            .map(|i| {
+                let contents = format!("#include \"{}.wgsl\"\nconst c{} = 1;\n", i + 1, i);
+                (PathBuf::from(format!("{}.wgsl", i)), contents)
+            })
+            .collect::<HashMap<_, _>>();
+        files.insert(
+            PathBuf::from(format!("{}.wgsl", depth)),
+            "const last = 1;\n".to_string(),
+        );
+
+        let preprocessor = Preprocessor::new().file_provider(files.clone());
+        assert!(matches!(
+            preprocessor.process_file("0.wgsl"),
+            Err(PreprocessorError::IncludeTooDeep(filename, 256)) if filename == "256.wgsl"
+        ));
+
+        let limits = Limits {
+            max_include_depth: depth,
+            ..Limits::default()
+        };
+        let preprocessor = Preprocessor::new().file_provider(files).limits(limits);
+        let source = preprocessor.process_file("0.wgsl").unwrap();
+        let expected = format!("const last = 1;\n\nconst c{} = 1;\n", depth - 1);
+        assert!(source.starts_with(&expected));
+        assert!(source.ends_with("const c0 = 1;\n"));
+    }
+
+    #[test]
+    fn test_define_warnings() {
+        let main = [
+            "#include \"lights.wgsl\"",
+            "#define select(a, b, c) a",
+            "#define light 2.0",
+            "#define SCALE 2.0",
+            "const SCALE = 1.0;",
+            "fn main() { let x = SCALE; }",
+        ]
+        .join("\n");
+        let files = [
+            ("lights.wgsl", "fn light() -> f32 { return 1.0; }\n"),
+            ("main.wgsl", &main),
+        ];
+        let (_, info) = preprocess_files(&files, "main.wgsl").unwrap();
+
+        let main = PathBuf::from("main.wgsl");
+        assert_eq!(
+            info.warnings,
+            vec![
+                (
+                    main.clone(),
+                    2,
+                    "`#define select` shadows the WGSL builtin function `select`, which is substituted wherever it's used".to_string()
+                ),
+                (
+                    main.clone(),
+                    3,
+                    "`#define light` shadows `light` declared in lights.wgsl, which is substituted wherever it's used".to_string()
+                ),
+                (
+                    main.clone(),
+                    5,
+                    "`SCALE` is declared while a `#define` of it is in effect, so its name is substituted".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            info.declarations,
+            vec![
+                (PathBuf::from("lights.wgsl"), "light".to_string()),
+                (main.clone(), "SCALE".to_string()),
+                (main, "main".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_docs() {
+        let snapshot_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/common");
+
+        let result = preprocess_with_defines("docs.wgsl", &snapshot_dir, &[]);
+        assert!(result.is_ok(), "Failed to preprocess file: docs.wgsl");
+
+        let (_, info) = result.unwrap();
+        let mut docs = info.docs.into_iter().collect::<Vec<_>>();
+        docs.sort();
+        insta::assert_snapshot!("docs.wgsl", format!("{:#?}", docs));
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_f16_fallback() {
+        let snapshot_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/common");
+
+        let result = preprocess("half.wgsl", &snapshot_dir);
+        assert!(result.is_ok(), "Failed to preprocess file: half.wgsl");
+
+        let source = result.unwrap();
+        assert!(enables_f16(&source));
+        insta::assert_snapshot!("half.wgsl", f16_fallback(&source));
+    }
+
+    #[test]
+    fn test_file_provider() {
+        let files = [
+            (
+                "main.wgsl",
+                "#include \"lights/point.wgsl\"\n#embed WEIGHTS \"data/weights.csv\"\n\nfn main() {}\n",
+            ),
+            (
+                "lights/point.wgsl",
+                "#include \"../common/light.wgsl\"\n\nstruct PointLight {\n    light: Light,\n    radius: f32,\n}\n",
+            ),
+            (
+                "common/light.wgsl",
+                "struct Light {\n    color: vec3<f32>,\n}\n",
+            ),
+            ("data/weights.csv", "0.25, 0.5, 0.25\n"),
+        ];
+
+        let result = preprocess_files(&files, "main.wgsl");
+        assert!(result.is_ok(), "Failed to preprocess file: main.wgsl");
+
+        let (source, info) = result.unwrap();
+        assert_eq!(
+            info.dependencies,
+            [
+                "main.wgsl",
+                "lights/point.wgsl",
+                "common/light.wgsl",
+                "data/weights.csv"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            info.includes,
+            [
+                ("lights/point.wgsl", "common/light.wgsl"),
+                ("main.wgsl", "lights/point.wgsl")
+            ]
+            .map(|(from, to)| (PathBuf::from(from), PathBuf::from(to)))
+        );
+        insta::assert_snapshot!("file_provider.wgsl", source);
+    }
+
+    #[test]
+    fn test_line_directive() {
+        let files = [
+            (
+                "gen/main.wgsl",
+                "const a = 1;\n#line 10 \"lights.tmpl\"\nconst b = 2;\nconst c = 3;\n#line 40\nconst d = 4;\n",
+            ),
+            ("bad.wgsl", "#line ten\n"),
+        ];
+
+        let (source, info) = preprocess_files(&files, "gen/main.wgsl").unwrap();
+        assert!(!source.contains("#line"));
+        let mapped = source
+            .lines()
+            .zip(info.source_map.iter())
+            .filter(|(line, _)| !line.is_empty())
+            .map(|(line, (path, number))| format!("{} {}:{}", line, path.display(), number))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mapped,
+            [
+                "const a = 1; gen/main.wgsl:1",
+                "const b = 2; gen/lights.tmpl:10",
+                "const c = 3; gen/lights.tmpl:11",
+                "const d = 4; gen/lights.tmpl:40",
+            ]
+        );
+        assert!(matches!(
+            preprocess_files(&files, "bad.wgsl"),
+            Err(PreprocessorError::DirectiveFailed(directive, _)) if directive == "#line"
+        ));
+    }
+
+    #[test]
+    fn test_source_map() {
+        let files = [
+            (
+                "main.wgsl",
+                "#define SCALE 2.0\n#include \"common/light.wgsl\"\n/* block\n   comment */\n#embed WEIGHTS \"weights.csv\"\n\nfn main() {\n    let x = \\\n        SCALE;\n}\n",
+            ),
+            (
+                "common/light.wgsl",
+                "struct Light {\n    color: vec3<f32>,\n}\n",
+            ),
+            ("weights.csv", "0.25, 0.5\n0.25\n"),
+        ];
+
+        let result = preprocess_files(&files, "main.wgsl");
+        assert!(result.is_ok(), "Failed to preprocess file: main.wgsl");
+
+        let (source, info) = result.unwrap();
+        assert_eq!(source.lines().count(), info.source_map.len());
+        let mapped = source
+            .lines()
+            .zip(info.source_map.iter())
+            .map(|(line, (path, line_number))| {
+                format!("{}:{}: {}", path.display(), line_number, line)
+            })
+            .collect::<Vec<_>>();
+        insta::assert_snapshot!("source_map.wgsl", mapped.join("\n"));
+    }
+
+    #[test]
+    fn test_async_file_provider() {
+        use std::cell::RefCell;
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        use crate::provider::{read_async, AsyncFileProvider};
+
+        struct Files {
+            files: HashMap<PathBuf, &'static str>,
+            reads: RefCell<Vec<PathBuf>>,
+        }
+
+        impl AsyncFileProvider for Files {
+            async fn read(&self, path: &Path) -> Option<String> {
+                self.reads.borrow_mut().push(path.to_path_buf());
+                self.files.get(path).map(|text| text.to_string())
+            }
+        }
+
+        let provider = Files {
+            files: HashMap::from([
+                (
+                    PathBuf::from("main.wgsl"),
+                    "#include \"light.wgsl\"\n#include \"light.wgsl\"\n#embed WEIGHTS \"weights.csv\"\n\nfn main() {}\n",
+                ),
+                (
+                    PathBuf::from("common/light.wgsl"),
+                    "struct Light {\n    color: vec3<f32>,\n}\n",
+                ),
+                (PathBuf::from("weights.csv"), "0.25, 0.5, 0.25\n"),
+            ]),
+            reads: RefCell::new(vec![]),
+        };
+        let include_dirs = [PathBuf::from("common")];
+
+        // The provider never waits, so the future is ready after one poll.
+        let mut future = std::pin::pin!(read_async(&provider, |files, run_commands| {
+            let resolver = Resolver {
+                include_dirs: &include_dirs,
+                provider: Some(files),
+                limits: Limits::default(),
+                run_commands,
+            };
+            preprocess_with_resolver("main.wgsl", Path::new(""), &resolver, &[])
+        }));
+        let Poll::Ready(result) = future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        else {
+            panic!("reading files took more than one poll");
+        };
+        assert!(result.is_ok(), "Failed to preprocess file: main.wgsl");
+
+        // Each file is only read once, including `light.wgsl` which isn't found.
+        assert_eq!(
+            *provider.reads.borrow(),
+            [
+                "main.wgsl",
+                "light.wgsl",
+                "weights.csv",
+                "common/light.wgsl"
+            ]
+            .map(PathBuf::from)
+        );
+        let (source, _) = result.unwrap();
+        insta::assert_snapshot!("async_file_provider.wgsl", source);
+    }
+
+    #[test]
+    fn test_async_passes() {
+        use std::cell::{Cell, RefCell};
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        use crate::directive::register_directive;
+        use crate::provider::{read_async, AsyncFileProvider};
+
+        struct Files {
+            files: HashMap<PathBuf, &'static str>,
+            reads: RefCell<Vec<PathBuf>>,
+        }
+
+        impl AsyncFileProvider for Files {
+            async fn read(&self, path: &Path) -> Option<String> {
+                self.reads.borrow_mut().push(path.to_path_buf());
+                self.files.get(path).map(|text| text.to_string())
+            }
+        }
+
+        // The command appends a line to `runs` every time it runs.
+        let runs = std::env::temp_dir().join(format!("wgpu-pp-runs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&runs);
+        register_directive(
+            "count_runs",
+            Directive::Command(
+                "sh".to_string(),
+                vec![
+                    "-c".to_string(),
+                    format!("echo >> '{}'; echo 'const ran = 1;'", runs.display()),
+                ],
+            ),
+        );
+
+        let provider = Files {
+            files: HashMap::from([
+                (
+                    PathBuf::from("main.wgsl"),
+                    "#include \"a.wgsl\"\n#include \"b.wgsl\"\n#include \"c.wgsl\"\n#count_runs\n",
+                ),
+                (PathBuf::from("a.wgsl"), "const a = 1;\n"),
+                (PathBuf::from("b.wgsl"), "const b = 2;\n"),
+                (PathBuf::from("c.wgsl"), "const c = 3;\n"),
+            ]),
+            reads: RefCell::new(vec![]),
+        };
+        let passes = Cell::new(0);
+
+        let mut future = std::pin::pin!(read_async(&provider, |files, run_commands| {
+            passes.set(passes.get() + 1);
+            let resolver = Resolver {
+                include_dirs: &[],
+                provider: Some(files),
+                limits: Limits::default(),
+                run_commands,
+            };
+            preprocess_with_resolver("main.wgsl", Path::new(""), &resolver, &[])
+        }));
+        let Poll::Ready(result) = future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        else {
+            panic!("reading files took more than one poll");
+        };
+        let (source, _) = result.unwrap();
+        assert_eq!(
+            source.trim(),
+            "const a = 1;\n\nconst b = 2;\n\nconst c = 3;\n\nconst ran = 1;"
+        );
+
+        // The includes are fetched together, each once, and the command only
+        // runs in the pass after the last fetch.
+        assert_eq!(
+            *provider.reads.borrow(),
+            ["main.wgsl", "a.wgsl", "b.wgsl", "c.wgsl"].map(PathBuf::from)
+        );
+        assert_eq!(passes.get(), 4);
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "\n");
+        std::fs::remove_file(&runs).unwrap();
+    }
+}
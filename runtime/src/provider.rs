@@ -0,0 +1,411 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+#[cfg(feature = "fs")]
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "fs")]
+use lazy_static::lazy_static;
+
+use crate::preprocessor::PreprocessorError;
+use crate::Limits;
+
+/// Reads the files a shader includes, e.g. from an asset archive instead of the filesystem.
+///
+/// Paths are the including file's directory joined with the include, with `.`
+/// and `dir/..` components removed.
+pub trait FileProvider {
+    /// Returns the contents of a file, or `None` if it doesn't exist.
+    fn read(&self, path: &Path) -> Option<Cow<'_, str>>;
+
+    /// Returns the contents of a file read by `#embed`.
+    ///
+    /// Defaults to the bytes of `read`, which covers text formats like `.csv`.
+    fn read_bytes(&self, path: &Path) -> Option<Cow<'_, [u8]>> {
+        match self.read(path)? {
+            Cow::Borrowed(text) => Some(Cow::Borrowed(text.as_bytes())),
+            Cow::Owned(text) => Some(Cow::Owned(text.into_bytes())),
+        }
+    }
+}
+
+/// Files keyed by path, e.g. shaders embedded with `include_str!`.
+impl<S: AsRef<str>> FileProvider for HashMap<PathBuf, S> {
+    fn read(&self, path: &Path) -> Option<Cow<'_, str>> {
+        self.get(path).map(|text| Cow::Borrowed(text.as_ref()))
+    }
+}
+
+/// Reads the files a shader includes asynchronously, e.g. over the network or
+/// from an async asset store.
+///
+/// Paths are passed like they are to `FileProvider`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncFileProvider {
+    /// Returns the contents of a file, or `None` if it doesn't exist.
+    async fn read(&self, path: &Path) -> Option<String>;
+
+    /// Returns the contents of a file read by `#embed`.
+    ///
+    /// Defaults to the bytes of `read`, which covers text formats like `.csv`.
+    async fn read_bytes(&self, path: &Path) -> Option<Vec<u8>> {
+        self.read(path).await.map(String::into_bytes)
+    }
+}
+
+/// Files fetched from an `AsyncFileProvider` so far, and the ones read that weren't.
+///
+/// Files that weren't fetched read as empty, so the rest of the shader is
+/// still read and every include it reaches is fetched at once. Empty files
+/// can change what's read after them, e.g. whether an include is looked up in
+/// the include directories, so a pass that missed any is run again.
+#[derive(Default)]
+struct Prefetched {
+    text: HashMap<PathBuf, Option<String>>,
+    bytes: HashMap<PathBuf, Option<Vec<u8>>>,
+    /// The paths read but not fetched, and whether they were read as bytes.
+    misses: RefCell<Vec<(PathBuf, bool)>>,
+}
+
+impl Prefetched {
+    fn record_miss(&self, path: &Path, is_bytes: bool) {
+        let mut misses = self.misses.borrow_mut();
+        if !misses.iter().any(|(miss, _)| miss == path) {
+            misses.push((path.to_path_buf(), is_bytes));
+        }
+    }
+}
+
+impl FileProvider for Prefetched {
+    fn read(&self, path: &Path) -> Option<Cow<'_, str>> {
+        match self.text.get(path) {
+            Some(text) => text.as_deref().map(Cow::Borrowed),
+            None => {
+                self.record_miss(path, false);
+                Some(Cow::Borrowed(""))
+            }
+        }
+    }
+
+    fn read_bytes(&self, path: &Path) -> Option<Cow<'_, [u8]>> {
+        match self.bytes.get(path) {
+            Some(bytes) => bytes.as_deref().map(Cow::Borrowed),
+            None => {
+                self.record_miss(path, true);
+                Some(Cow::Borrowed(&[]))
+            }
+        }
+    }
+}
+
+/// Runs the preprocessor with files from an `AsyncFileProvider`.
+///
+/// The preprocessor itself is synchronous, so `run` is called again after
+/// fetching every file it read that wasn't fetched yet, until it doesn't read
+/// any new ones, which takes about as many passes as includes are nested.
+/// Every file is fetched at most once.
+///
+/// `run` is told whether to run commands. Passes that fetch files are thrown
+/// away, so commands only run in one more pass once every file is fetched.
+pub(crate) async fn read_async<T>(
+    provider: &impl AsyncFileProvider,
+    run: impl Fn(&dyn FileProvider, bool) -> Result<T, PreprocessorError>,
+) -> Result<T, PreprocessorError> {
+    let mut files = Prefetched::default();
+    let mut run_commands = false;
+    loop {
+        let result = run(&files, run_commands);
+        let misses = files.misses.take();
+        if misses.is_empty() {
+            if run_commands {
+                return result;
+            }
+            run_commands = true;
+            continue;
+        }
+
+        run_commands = false;
+        for (path, is_bytes) in misses {
+            if is_bytes {
+                let bytes = provider.read_bytes(&path).await;
+                files.bytes.insert(path, bytes);
+            } else {
+                let text = provider.read(&path).await;
+                files.text.insert(path, text);
+            }
+        }
+    }
+}
+
+/// Removes a UTF-8 byte order mark and converts CRLF line endings to LF, so
+/// shaders saved on Windows preprocess like any other.
+fn normalize_text(contents: String) -> String {
+    let contents = match contents.strip_prefix('\u{feff}') {
+        Some(contents) => contents.to_string(),
+        None => contents,
+    };
+    match contents.contains('\r') {
+        true => contents.replace("\r\n", "\n"),
+        false => contents,
+    }
+}
+
+/// Removes `.` components, and `..` components following a directory.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The most bytes of files kept in `FILE_CACHE`, beyond which the least
+/// recently used are dropped, so large `#embed` files don't pile up.
+#[cfg(feature = "fs")]
+const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Files modified more recently than this aren't cached, since a rewrite
+/// within the filesystem's timestamp granularity that keeps the size the
+/// same wouldn't change the key. Two seconds covers FAT, the coarsest.
+#[cfg(feature = "fs")]
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+/// A file read from disk, with the modification time and size it had when it
+/// was read, and when it was last used.
+#[cfg(feature = "fs")]
+struct CachedFile {
+    key: (SystemTime, u64),
+    bytes: Vec<u8>,
+    used: u64,
+}
+
+/// Files read from disk, keyed by path.
+#[cfg(feature = "fs")]
+#[derive(Default)]
+struct FileCache {
+    files: HashMap<PathBuf, CachedFile>,
+    /// The total size of `files`.
+    size: usize,
+    /// Counts reads, to order the files by when they were last used.
+    clock: u64,
+}
+
+#[cfg(feature = "fs")]
+impl FileCache {
+    fn get(&mut self, path: &Path, key: (SystemTime, u64)) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let file = self.files.get_mut(path).filter(|file| file.key == key)?;
+        file.used = self.clock;
+        Some(file.bytes.clone())
+    }
+
+    /// Caches a file, dropping the least recently used ones once there are
+    /// more than `max_size` bytes. Files larger than that aren't cached.
+    fn insert(&mut self, path: &Path, key: (SystemTime, u64), bytes: Vec<u8>, max_size: usize) {
+        if let Some(old) = self.files.remove(path) {
+            self.size -= old.bytes.len();
+        }
+        if bytes.len() > max_size {
+            return;
+        }
+        self.clock += 1;
+        self.size += bytes.len();
+        let file = CachedFile {
+            key,
+            bytes,
+            used: self.clock,
+        };
+        self.files.insert(path.to_path_buf(), file);
+
+        while self.size > max_size {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .min_by_key(|(_, file)| file.used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            let file = self.files.remove(&oldest).unwrap();
+            self.size -= file.bytes.len();
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+lazy_static! {
+    static ref FILE_CACHE: Mutex<FileCache> = Mutex::new(FileCache::default());
+}
+
+/// Errors if a file of `size` bytes is larger than `max_size`.
+fn check_size(path: &Path, size: usize, max_size: usize) -> Result<(), PreprocessorError> {
+    match size > max_size {
+        true => Err(PreprocessorError::FileTooLarge(
+            path.to_string_lossy().to_string(),
+            max_size,
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Reads a file from disk, through a cache shared by the whole process.
+///
+/// Headers included by many shaders, e.g. by every `include_wgsl!` in a crate,
+/// are only read once per compiler session. Files are read again when their
+/// modification time or size changes, and aren't cached until a while after
+/// they're modified, so hot reloading still sees edits. Files larger than
+/// `max_size` aren't read at all.
+#[cfg(feature = "fs")]
+fn read_file(path: &Path, max_size: usize) -> Result<Option<Vec<u8>>, PreprocessorError> {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Ok(None);
+    };
+    check_size(
+        path,
+        metadata.len().try_into().unwrap_or(usize::MAX),
+        max_size,
+    )?;
+    let Ok(modified) = metadata.modified() else {
+        return Ok(std::fs::read(path).ok());
+    };
+    let key = (modified, metadata.len());
+    if let Some(bytes) = FILE_CACHE.lock().unwrap().get(path, key) {
+        return Ok(Some(bytes));
+    }
+
+    let Ok(bytes) = std::fs::read(path) else {
+        return Ok(None);
+    };
+    let settled = SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age >= MTIME_GRANULARITY);
+    if settled {
+        FILE_CACHE
+            .lock()
+            .unwrap()
+            .insert(path, key, bytes.clone(), MAX_CACHED_BYTES);
+    }
+    Ok(Some(bytes))
+}
+
+/// Without the `fs` feature, e.g. on the web, files can only be read through a `FileProvider`.
+#[cfg(not(feature = "fs"))]
+fn read_file(_path: &Path, _max_size: usize) -> Result<Option<Vec<u8>>, PreprocessorError> {
+    Ok(None)
+}
+
+/// Finds and reads files, through a `FileProvider` if there is one, or else from disk.
+pub(crate) struct Resolver<'a> {
+    /// Directories to look up includes in when they're not next to the including file.
+    pub include_dirs: &'a [PathBuf],
+    pub provider: Option<&'a dyn FileProvider>,
+    pub limits: Limits,
+    /// Whether custom directives that run commands do, rather than expanding
+    /// to nothing while `read_async` is still fetching files.
+    pub run_commands: bool,
+}
+
+impl Resolver<'_> {
+    /// Reads `filename` relative to `basepath`, or else one of the include
+    /// directories, returning its path and contents, see `normalize_text`.
+    pub fn read(
+        &self,
+        filename: &str,
+        basepath: &Path,
+    ) -> Result<Option<(PathBuf, String)>, PreprocessorError> {
+        let max_size = self.limits.max_file_size;
+        for dir in std::iter::once(basepath).chain(self.include_dirs.iter().map(PathBuf::as_path)) {
+            let path = dir.join(filename);
+            let contents = match self.provider {
+                Some(provider) => {
+                    let path = normalize(&path);
+                    match provider.read(&path) {
+                        Some(contents) => {
+                            check_size(&path, contents.len(), max_size)?;
+                            Some((path, normalize_text(contents.into_owned())))
+                        }
+                        None => None,
+                    }
+                }
+                None => match read_file(&path, max_size)? {
+                    Some(bytes) => match String::from_utf8(bytes) {
+                        Ok(contents) => Some((path, normalize_text(contents))),
+                        Err(_) => {
+                            return Err(PreprocessorError::FileNotValidUtf8(filename.to_string()))
+                        }
+                    },
+                    None => None,
+                },
+            };
+            if contents.is_some() {
+                return Ok(contents);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the data file `filename` relative to `basepath`.
+    pub fn read_bytes(
+        &self,
+        filename: &str,
+        basepath: &Path,
+    ) -> Result<Option<(PathBuf, Vec<u8>)>, PreprocessorError> {
+        let path = basepath.join(filename);
+        let max_size = self.limits.max_file_size;
+        match self.provider {
+            Some(provider) => {
+                let path = normalize(&path);
+                let Some(bytes) = provider.read_bytes(&path) else {
+                    return Ok(None);
+                };
+                check_size(&path, bytes.len(), max_size)?;
+                Ok(Some((path, bytes.into_owned())))
+            }
+            None => Ok(read_file(&path, max_size)?.map(|bytes| (path, bytes))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_cache() {
+        let mut cache = FileCache::default();
+        let key = (SystemTime::UNIX_EPOCH, 4);
+        cache.insert(Path::new("a"), key, vec![0; 4], 8);
+        cache.insert(Path::new("b"), key, vec![1; 4], 8);
+        assert_eq!(cache.get(Path::new("a"), key), Some(vec![0; 4]));
+        assert_eq!(cache.get(Path::new("a"), (SystemTime::now(), 4)), None);
+
+        // `b` is the least recently used, so it's dropped to make room.
+        cache.insert(Path::new("c"), key, vec![2; 4], 8);
+        assert_eq!(cache.get(Path::new("b"), key), None);
+        assert_eq!(cache.get(Path::new("c"), key), Some(vec![2; 4]));
+        assert_eq!(cache.size, 8);
+
+        // Replacing a file, here with one too large to cache, drops the old one.
+        cache.insert(Path::new("a"), key, vec![0; 16], 8);
+        assert_eq!(cache.get(Path::new("a"), key), None);
+        assert_eq!(cache.size, 4);
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::watcher::{Changes, Message};
+use crate::{Preprocessor, PreprocessorError};
+
+/// How long a client can take to send its request, or to take an event,
+/// before its connection is dropped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most requests handled at once, each on its own thread, and the most
+/// `/events` clients. Connections beyond them get a 503 response.
+const MAX_CONNECTIONS: usize = 64;
+
+/// A shader that was served, and the files it was preprocessed from.
+struct Served {
+    dependencies: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Keyed by the path the shader was requested with.
+    served: HashMap<String, Served>,
+    /// Connections listening to `/events`.
+    clients: Vec<TcpStream>,
+}
+
+/// Serves preprocessed shaders over HTTP, for reloading them in a running process or web build.
+///
+/// `GET /path/to/shader.wgsl` preprocesses the `.wgsl` file at that path under
+/// the root directory, and responds with its source, or the error with a 422
+/// status. `GET /events` is a stream of [server-sent events], with the path of
+/// a shader that was served before as the data whenever one of its files
+/// changes, so clients know to fetch it again. Pages served from loopback
+/// addresses, like `http://localhost:8080`, can use the responses, and
+/// [`allow_origin`](DevServer::allow_origin) allows others.
+///
+/// [server-sent events]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events
+///
+/// ```no_run
+/// use wgpu_pp_runtime::{DevServer, Preprocessor};
+///
+/// let server = DevServer::bind(Preprocessor::new(), "shaders", "127.0.0.1:8484").unwrap();
+/// server.run().unwrap();
+/// ```
+///
+/// ```js
+/// const events = new EventSource("http://127.0.0.1:8484/events");
+/// events.onmessage = async (event) => {
+///     const source = await fetch(`http://127.0.0.1:8484/${event.data}`).then((r) => r.text());
+///     rebuildPipelines(event.data, source);
+/// };
+/// ```
+pub struct DevServer {
+    listener: TcpListener,
+    root: PathBuf,
+    preprocessor: Arc<Preprocessor>,
+    state: Arc<Mutex<State>>,
+    allowed_origin: Option<Arc<str>>,
+}
+
+impl DevServer {
+    /// Listens on `addr`, serving the shaders under `root`.
+    pub fn bind(
+        preprocessor: Preprocessor,
+        root: impl AsRef<Path>,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        Ok(DevServer {
+            listener: TcpListener::bind(addr)?,
+            root: root.as_ref().to_path_buf(),
+            preprocessor: Arc::new(preprocessor),
+            state: Arc::default(),
+            allowed_origin: None,
+        })
+    }
+
+    /// Allows pages from `origin`, e.g. `"https://editor.example.com"`, to use
+    /// the responses, or pages from anywhere with `"*"`. Any site open in a
+    /// browser on the machine could then read the shaders.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.allowed_origin = Some(origin.into());
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until accepting a connection fails.
+    pub fn run(self) -> io::Result<()> {
+        let mut changes = Changes::new();
+        let refresh = changes.sender();
+        let state = self.state.clone();
+        let watching = thread::spawn(move || {
+            while let Some(changed) = changes.wait() {
+                let state = notify_changes(&state, &changed);
+                changes.watch(
+                    state
+                        .served
+                        .values()
+                        .flat_map(|served| served.dependencies.iter().map(PathBuf::as_path)),
+                );
+            }
+        });
+
+        let result = self.serve(&refresh);
+        let _ = refresh.send(Message::Stop);
+        let _ = watching.join();
+        result
+    }
+
+    fn serve(&self, refresh: &Sender<Message>) -> io::Result<()> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in self.listener.incoming() {
+            let mut stream = stream?;
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                // The response fits in the socket's buffer, so don't wait on the client.
+                let _ = stream.set_nonblocking(true);
+                let _ = respond(
+                    &mut stream,
+                    "503 Service Unavailable",
+                    "",
+                    "too many connections",
+                );
+                continue;
+            }
+
+            let connections = connections.clone();
+            let root = self.root.clone();
+            let preprocessor = self.preprocessor.clone();
+            let state = self.state.clone();
+            let refresh = refresh.clone();
+            let allowed_origin = self.allowed_origin.clone();
+            thread::spawn(move || {
+                // A client hanging up isn't an error for the server.
+                let _ = handle(
+                    stream,
+                    &root,
+                    &preprocessor,
+                    &state,
+                    &refresh,
+                    allowed_origin.as_deref(),
+                );
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Sends the path of every served shader with a file in `changed` to the
+/// `/events` clients, returning the state to watch its files.
+fn notify_changes<'a>(state: &'a Mutex<State>, changed: &[PathBuf]) -> MutexGuard<'a, State> {
+    let mut state = state.lock().unwrap();
+    let changed = state
+        .served
+        .iter()
+        .filter(|(_, served)| {
+            served
+                .dependencies
+                .iter()
+                .any(|path| changed.contains(path))
+        })
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+
+    for path in changed {
+        let event = format!("data: {}\n\n", path);
+        state
+            .clients
+            .retain_mut(|client| client.write_all(event.as_bytes()).is_ok());
+    }
+    state
+}
+
+/// Returns the shader's path under the root, if it's a `.wgsl` file that
+/// doesn't leave the root.
+fn shader_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let is_shader = path
+        .extension()
+        .is_some_and(|extension| extension == "wgsl");
+    (is_shader
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_))))
+    .then(|| root.join(path))
+}
+
+/// Decodes the `%XX` escapes of a request target, returning `None` if they're
+/// malformed or decode to invalid UTF-8 or control characters.
+fn percent_decode(target: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = target.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes)
+        .ok()
+        .filter(|decoded| !decoded.chars().any(char::is_control))
+}
+
+/// Whether an `Origin` is a page served from this machine.
+fn is_loopback_origin(origin: &str) -> bool {
+    let Some(host) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Returns the CORS headers for a request from `origin`: the allowed origin
+/// if there is one, or else `origin` itself if it's a loopback origin.
+fn cors_headers(allowed_origin: Option<&str>, origin: Option<&str>) -> String {
+    let origin = match allowed_origin {
+        Some(allowed) => Some(allowed),
+        None => origin.filter(|origin| is_loopback_origin(origin)),
+    };
+    match origin {
+        Some(origin) => format!(
+            "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n",
+            origin
+        ),
+        None => String::new(),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, cors: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        cors,
+        body
+    )
+}
+
+fn handle(
+    mut stream: TcpStream,
+    root: &Path,
+    preprocessor: &Preprocessor,
+    state: &Mutex<State>,
+    refresh: &Sender<Message>,
+    allowed_origin: Option<&str>,
+) -> io::Result<()> {
+    // Clients that stall would otherwise hold their thread, or the state
+    // while an event is written to them, forever.
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    // Only the request line and the `Origin` header matter.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut origin = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Origin") {
+                origin = Some(value.trim().to_string());
+            }
+        }
+        line.clear();
+    }
+    let cors = cors_headers(allowed_origin, origin.as_deref());
+
+    let (method, target) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, _] => (method, target),
+        _ => return respond(&mut stream, "400 Bad Request", &cors, "malformed request"),
+    };
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            &cors,
+            "only GET is supported",
+        );
+    }
+    let target = target.split('?').next().unwrap().trim_start_matches('/');
+    let Some(target) = percent_decode(target) else {
+        return respond(&mut stream, "400 Bad Request", &cors, "malformed path");
+    };
+    let target = target.as_str();
+
+    if target == "events" {
+        if state.lock().unwrap().clients.len() >= MAX_CONNECTIONS {
+            return respond(
+                &mut stream,
+                "503 Service Unavailable",
+                &cors,
+                "too many connections",
+            );
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}\r\n",
+            cors
+        )?;
+        state.lock().unwrap().clients.push(stream);
+        return Ok(());
+    }
+
+    let Some(path) = shader_path(root, target) else {
+        return respond(&mut stream, "404 Not Found", &cors, "not found");
+    };
+    match preprocessor.process_file_with_info(&path) {
+        Ok((source, info)) => {
+            let served = Served {
+                dependencies: info.dependencies,
+            };
+            state
+                .lock()
+                .unwrap()
+                .served
+                .insert(target.to_string(), served);
+            let _ = refresh.send(Message::Refresh);
+            respond(&mut stream, "200 OK", &cors, &source)
+        }
+        Err(e @ PreprocessorError::FileNotFound(_)) if !path.is_file() => {
+            respond(&mut stream, "404 Not Found", &cors, &e.to_string())
+        }
+        Err(e) => {
+            // Keep watching the shader, so the client hears when it's fixed.
+            let served = Served {
+                dependencies: vec![path],
+            };
+            state
+                .lock()
+                .unwrap()
+                .served
+                .entry(target.to_string())
+                .or_insert(served);
+            let _ = refresh.send(Message::Refresh);
+            respond(
+                &mut stream,
+                "422 Unprocessable Entity",
+                &cors,
+                &e.to_string(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(
+            percent_decode("deep%20water/sh%C3%A9.wgsl").as_deref(),
+            Some("deep water/shé.wgsl")
+        );
+        // Decoded before the path is checked, so escapes can't leave the root.
+        assert_eq!(
+            percent_decode("%2e%2e/secret").as_deref(),
+            Some("../secret")
+        );
+        assert!(shader_path(Path::new("shaders"), "../secret").is_none());
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("bad%zz"), None);
+        assert_eq!(percent_decode("a%0Adata:%20x"), None);
+    }
+
+    #[test]
+    fn test_cors_headers() {
+        let allowed = "Access-Control-Allow-Origin: http://localhost:8080\r\nVary: Origin\r\n";
+        assert_eq!(cors_headers(None, Some("http://localhost:8080")), allowed);
+        assert!(!cors_headers(None, Some("http://127.0.0.1:3000")).is_empty());
+        assert!(!cors_headers(None, Some("http://[::1]:3000")).is_empty());
+        assert_eq!(cors_headers(None, Some("https://example.com")), "");
+        assert_eq!(cors_headers(None, Some("http://localhost.example.com")), "");
+        assert_eq!(cors_headers(None, None), "");
+        assert_eq!(
+            cors_headers(Some("*"), Some("https://example.com")),
+            "Access-Control-Allow-Origin: *\r\nVary: Origin\r\n"
+        );
+    }
+
+    /// Sends a request for `target` and returns the response.
+    fn get(addr: SocketAddr, target: &str) -> String {
+        use std::io::Read;
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            target
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("water.wgsl"), "const a = 1;\n").unwrap();
+        std::fs::write(dir.join("broken.wgsl"), "#include \"missing.wgsl\"\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a shader").unwrap();
+
+        let server = DevServer::bind(Preprocessor::new(), &dir, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let response = get(addr, "water.wgsl");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nconst a = 1;\n"));
+        assert!(get(addr, "missing.wgsl").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(addr, "notes.txt").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(addr, "broken.wgsl").starts_with("HTTP/1.1 422 Unprocessable Entity\r\n"));
+
+        let mut events = TcpStream::connect(addr).unwrap();
+        write!(events, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut events = BufReader::new(events);
+        let mut line = String::new();
+        events.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+        while line != "\r\n" {
+            line.clear();
+            events.read_line(&mut line).unwrap();
+        }
+
+        // The shader's files are watched in the background, so a write can
+        // come before they are. Write again until one is noticed.
+        events
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let event = (0..50)
+            .find_map(|i| {
+                std::fs::write(dir.join("water.wgsl"), format!("const a = {};\n", i + 2)).unwrap();
+                line.clear();
+                events.read_line(&mut line).ok().map(|_| line.clone())
+            })
+            .expect("no event within 5s");
+        assert_eq!(event, "data: water.wgsl\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Snapshot tests for a crate's own shaders, so unintended changes to their
+//! expansions show up in review.
+//!
+//! ```no_run
+//! // tests/shaders.rs
+//! #[test]
+//! fn shaders() {
+//!     wgpu_pp_runtime::testing::snapshot_dir("shaders");
+//! }
+//! ```
+//!
+//! The expansion of each shader is kept in a `snapshots` directory next to
+//! the shaders. When one changes, the test fails and writes the new expansion
+//! next to the old one as `.snap.new`. Run the test again with
+//! `WGPU_PP_UPDATE_SNAPSHOTS=1` to accept the changes.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{wgsl_files, Preprocessor};
+
+/// Set to `1` to write the snapshots instead of checking them.
+pub const UPDATE_SNAPSHOTS: &str = "WGPU_PP_UPDATE_SNAPSHOTS";
+
+/// Checks the shaders in `dir` against their snapshots, see [`snapshot_dir_with`].
+#[track_caller]
+pub fn snapshot_dir(dir: impl AsRef<Path>) {
+    snapshot_dir_with(&Preprocessor::new(), dir)
+}
+
+/// Preprocesses every shader in `dir` and its subdirectories, and panics if
+/// any of them don't match their snapshot in `dir/snapshots`.
+///
+/// Like `build::compile_dir`, files included by another shader
+/// in `dir` are headers, which are only checked as part of the shaders
+/// including them. Relative paths are relative to the crate being tested,
+/// since that's where cargo runs tests from.
+#[track_caller]
+pub fn snapshot_dir_with(preprocessor: &Preprocessor, dir: impl AsRef<Path>) {
+    let update = std::env::var(UPDATE_SNAPSHOTS).is_ok_and(|value| value == "1");
+    if let Err(e) = check(preprocessor, dir.as_ref(), update) {
+        panic!("{}", e);
+    }
+}
+
+/// Returns the first line where two sources differ, for the failure message.
+fn first_difference(old: &str, new: &str) -> String {
+    let mut old_lines = old.lines();
+    let mut new_lines = new.lines();
+    for line in 1.. {
+        match (old_lines.next(), new_lines.next()) {
+            (Some(old), Some(new)) if old == new => continue,
+            (old, new) => {
+                return format!(
+                    "line {}:\n    - {}\n    + {}",
+                    line,
+                    old.unwrap_or("<end of file>"),
+                    new.unwrap_or("<end of file>")
+                );
+            }
+        }
+    }
+    unreachable!()
+}
+
+fn check(preprocessor: &Preprocessor, dir: &Path, update: bool) -> Result<(), String> {
+    let snapshots_dir = dir.join("snapshots");
+    let files = wgsl_files(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    let mut shaders = vec![];
+    let mut included = HashSet::new();
+    for path in files {
+        if path.starts_with(&snapshots_dir) {
+            continue;
+        }
+        let result = preprocessor.process_file_with_info(&path);
+        if let Ok((_, info)) = &result {
+            // The first dependency is the shader itself.
+            for dependency in info.dependencies.iter().skip(1) {
+                included.insert(fs::canonicalize(dependency).unwrap_or(dependency.clone()));
+            }
+        }
+        shaders.push((path, result));
+    }
+
+    let mut failures = vec![];
+    for (path, result) in shaders {
+        // Headers can fail on their own, e.g. with a `#require` that their
+        // includer satisfies, so their errors only count for shaders.
+        if included.contains(&fs::canonicalize(&path).unwrap_or(path.clone())) {
+            continue;
+        }
+        let (source, _) = result.map_err(|e| format!("{}: {}", path.display(), e))?;
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let mut snapshot_path = snapshots_dir.join(relative).into_os_string();
+        snapshot_path.push(".snap");
+        let snapshot_path = PathBuf::from(snapshot_path);
+        let mut new_path = snapshot_path.clone().into_os_string();
+        new_path.push(".new");
+
+        let write = |path: &Path| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, &source)
+        };
+        let failure = match fs::read_to_string(&snapshot_path) {
+            Ok(snapshot) if snapshot == source => None,
+            Ok(snapshot) => Some(first_difference(&snapshot, &source)),
+            Err(_) => Some("no snapshot".to_string()),
+        };
+        match failure {
+            _ if update => {
+                write(&snapshot_path).map_err(|e| format!("{}: {}", snapshot_path.display(), e))?;
+                let _ = fs::remove_file(&new_path);
+            }
+            Some(failure) => {
+                write(new_path.as_ref())
+                    .map_err(|e| format!("{}: {}", new_path.to_string_lossy(), e))?;
+                failures.push(format!("{}: {}", relative.display(), failure));
+            }
+            None => {
+                let _ = fs::remove_file(&new_path);
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} shader snapshot(s) didn't match, the new expansions were written next to them as \
+         .snap.new. Run again with {}=1 to accept them.\n\n{}",
+        failures.len(),
+        UPDATE_SNAPSHOTS,
+        failures.join("\n\n")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::check;
+    use crate::Preprocessor;
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("wgpu_pp_testing_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("common")).unwrap();
+        fs::write(dir.join("common/light.wgsl"), "#define SCALE 2.0\n").unwrap();
+        // A header that only preprocesses as part of its includer.
+        fs::write(
+            dir.join("common/fog.wgsl"),
+            "#require FOG\nconst fog = FOG;\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.wgsl"),
+            "#include \"common/light.wgsl\"\n\
+             #include \"common/fog.wgsl\" with (FOG = 0.5)\n\
+             const scale = SCALE;\n",
+        )
+        .unwrap();
+        let preprocessor = Preprocessor::new().include_dir(&dir);
+
+        // Missing snapshots fail, and are written when updating.
+        assert!(check(&preprocessor, &dir, false).is_err());
+        assert!(dir.join("snapshots/main.wgsl.snap.new").exists());
+        check(&preprocessor, &dir, true).unwrap();
+        check(&preprocessor, &dir, false).unwrap();
+        assert!(!dir.join("snapshots/main.wgsl.snap.new").exists());
+        // Headers are only checked as part of the shaders including them.
+        assert!(!dir.join("snapshots/common/light.wgsl.snap").exists());
+
+        fs::write(dir.join("common/light.wgsl"), "#define SCALE 3.0\n").unwrap();
+        let e = check(&preprocessor, &dir, false).unwrap_err();
+        assert!(
+            e.contains("main.wgsl: line 6:\n    - const scale = 2.0;\n    + const scale = 3.0;"),
+            "{}",
+            e
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
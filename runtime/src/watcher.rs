@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{Preprocessor, PreprocessorError};
+
+/// How often the dependencies are checked for changes when file system
+/// events aren't available, and so how long a change can take to be noticed.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for more events after one, since saving a file from an
+/// editor is often several, e.g. writing a temporary file and renaming it.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Returns the modification time of a file, `None` if it doesn't exist.
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Returns the path file system events name a file by, its canonical
+/// directory joined with its name, which works for files that don't exist.
+fn event_path(path: &Path) -> PathBuf {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (dir.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// What wakes up `Changes::wait`.
+pub(crate) enum Message {
+    /// A file system event about these paths.
+    Event(Vec<PathBuf>),
+    /// The files to watch changed, so `wait` returns to have them updated.
+    Refresh,
+    /// Stops waiting for good.
+    Stop,
+}
+
+/// Watches files for changes with file system events from `notify`, falling
+/// back to polling their modification times every `POLL_INTERVAL` when those
+/// aren't available, e.g. when the directory of a file doesn't exist.
+///
+/// Directories are watched rather than files, since editors often save by
+/// replacing a file, which would end a watch on the file itself.
+pub(crate) struct Changes {
+    sender: Sender<Message>,
+    messages: Receiver<Message>,
+    /// `None` once polling instead.
+    watcher: Option<RecommendedWatcher>,
+    dirs: HashSet<PathBuf>,
+    /// The watched files, keyed by `event_path`, with the paths they were
+    /// watched as and their modification times, for polling.
+    files: HashMap<PathBuf, (Vec<PathBuf>, Option<SystemTime>)>,
+}
+
+impl Changes {
+    pub fn new() -> Self {
+        let (sender, messages) = mpsc::channel();
+        let events = sender.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            // Reading a file is an event too, including when it's preprocessed.
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    let _ = events.send(Message::Event(event.paths));
+                }
+                _ => {}
+            }
+        });
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                log::warn!(
+                    "file system events aren't available, polling instead: {}",
+                    e
+                );
+                None
+            }
+        };
+        Changes {
+            sender,
+            messages,
+            watcher,
+            dirs: HashSet::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Returns a sender for `Message::Refresh` and `Message::Stop`.
+    pub fn sender(&self) -> Sender<Message> {
+        self.sender.clone()
+    }
+
+    /// Watches `paths`, instead of the files watched before.
+    pub fn watch<'a>(&mut self, paths: impl IntoIterator<Item = &'a Path>) {
+        let mut files = HashMap::<PathBuf, (Vec<PathBuf>, Option<SystemTime>)>::new();
+        for path in paths {
+            let (paths, _) = files
+                .entry(event_path(path))
+                .or_insert_with(|| (vec![], file_modified(path)));
+            paths.push(path.to_path_buf());
+        }
+
+        if let Some(watcher) = self.watcher.as_mut() {
+            let dirs = files
+                .keys()
+                .filter_map(|path| path.parent())
+                .map(Path::to_path_buf)
+                .collect::<HashSet<_>>();
+            for dir in self.dirs.difference(&dirs) {
+                let _ = watcher.unwatch(dir);
+            }
+            let watched = dirs
+                .difference(&self.dirs)
+                .try_for_each(|dir| watcher.watch(dir, RecursiveMode::NonRecursive));
+            self.dirs = dirs;
+            if let Err(e) = watched {
+                log::warn!(
+                    "failed to watch for file system events, polling instead: {}",
+                    e
+                );
+                self.watcher = None;
+                self.dirs.clear();
+            }
+        }
+        self.files = files;
+    }
+
+    /// Waits until watched files change, returning the paths they were
+    /// watched as, or `None` once stopped. Returns no paths on
+    /// `Message::Refresh`.
+    pub fn wait(&mut self) -> Option<Vec<PathBuf>> {
+        let mut changed = vec![];
+        loop {
+            let message = match (&self.watcher, changed.is_empty()) {
+                (Some(_), true) => self
+                    .messages
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+                (Some(_), false) => self.messages.recv_timeout(DEBOUNCE),
+                (None, _) => self.messages.recv_timeout(POLL_INTERVAL),
+            };
+            match message {
+                Ok(Message::Event(paths)) => {
+                    for path in paths {
+                        if let Some((paths, _)) = self.files.get(&event_path(&path)) {
+                            changed.extend(paths.iter().cloned());
+                        }
+                    }
+                }
+                Ok(Message::Refresh) => break,
+                Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => return None,
+                // No more events came within `DEBOUNCE`.
+                Err(RecvTimeoutError::Timeout) if self.watcher.is_some() => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    for (paths, last_modified) in self.files.values_mut() {
+                        let modified = file_modified(&paths[0]);
+                        if modified != *last_modified {
+                            *last_modified = modified;
+                            changed.extend(paths.iter().cloned());
+                        }
+                    }
+                    if !changed.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+        changed.sort();
+        changed.dedup();
+        Some(changed)
+    }
+}
+
+/// Watches a shader and the files it depends on, preprocessing it again when they change.
+///
+/// The directories of the files are watched for file system events on a
+/// background thread, through `notify`. Where those aren't available, the
+/// files' modification times are polled every 250 ms instead. The
+/// dependencies are updated on every change, so includes added while running
+/// are watched too. If the shader fails to preprocess, the error is delivered
+/// instead, and it's tried again on the next change.
+///
+/// Dropping the watcher wakes the thread and waits for it to stop, which is
+/// immediate unless the shader is being preprocessed or the callback is running.
+///
+/// ```no_run
+/// use wgpu_pp_runtime::{Preprocessor, WgslWatcher};
+///
+/// let (watcher, changes) = WgslWatcher::channel(Preprocessor::new(), "shaders/water.wgsl");
+///
+/// // Once per frame.
+/// while let Ok(result) = changes.try_recv() {
+///     match result {
+///         Ok(source) => println!("reloaded: {} bytes", source.len()),
+///         Err(e) => eprintln!("failed to reload: {}", e),
+///     }
+/// }
+/// ```
+pub struct WgslWatcher {
+    stop: Sender<Message>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WgslWatcher {
+    /// Watches the shader at `path`, calling `callback` with the new source on every change.
+    pub fn new(
+        preprocessor: Preprocessor,
+        path: impl AsRef<Path>,
+        mut callback: impl FnMut(Result<String, PreprocessorError>) + Send + 'static,
+    ) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut changes = Changes::new();
+        let stop = changes.sender();
+
+        let thread = thread::spawn(move || {
+            let mut dependencies = match preprocessor.process_file_with_info(&path) {
+                Ok((_, info)) => info.dependencies,
+                Err(_) => vec![path.clone()],
+            };
+            changes.watch(dependencies.iter().map(PathBuf::as_path));
+
+            while let Some(changed) = changes.wait() {
+                if changed.is_empty() {
+                    continue;
+                }
+
+                let result = match preprocessor.process_file_with_info(&path) {
+                    Ok((source, info)) => {
+                        dependencies = info.dependencies;
+                        Ok(source)
+                    }
+                    Err(e) => Err(e),
+                };
+                changes.watch(dependencies.iter().map(PathBuf::as_path));
+                callback(result);
+            }
+        });
+
+        WgslWatcher {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Watches the shader at `path`, sending the new source over a channel on every change.
+    pub fn channel(
+        preprocessor: Preprocessor,
+        path: impl AsRef<Path>,
+    ) -> (Self, Receiver<Result<String, PreprocessorError>>) {
+        let (sender, receiver) = mpsc::channel();
+        let watcher = WgslWatcher::new(preprocessor, path, move |result| {
+            // The receiver may be gone, the watcher stops once it's dropped too.
+            let _ = sender.send(result);
+        });
+        (watcher, receiver)
+    }
+}
+
+impl Drop for WgslWatcher {
+    fn drop(&mut self) {
+        let _ = self.stop.send(Message::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_watcher() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shader.wgsl");
+        std::fs::write(&path, "const a = 1;\n").unwrap();
+
+        // The watcher starts watching in the background, so a write can come
+        // before it does. Write again until one is noticed.
+        let (watcher, changes) = WgslWatcher::channel(Preprocessor::new(), &path);
+        let source = (0..50)
+            .find_map(|i| {
+                std::fs::write(&path, format!("const a = {};\n", i + 2)).unwrap();
+                changes.recv_timeout(Duration::from_millis(100)).ok()
+            })
+            .expect("no change was noticed within 5s");
+        assert!(source.unwrap().starts_with("const a = "));
+
+        // Dropping wakes the thread instead of waiting for the next event.
+        let start = Instant::now();
+        drop(watcher);
+        assert!(start.elapsed() < POLL_INTERVAL);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-changes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.wgsl"), dir.join("b.wgsl"));
+        std::fs::write(&a, "").unwrap();
+
+        let mut changes = Changes::new();
+        changes.watch([a.as_path()]);
+        // Fail rather than hang if the change isn't noticed.
+        let sender = changes.sender();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(5));
+            let _ = sender.send(Message::Stop);
+        });
+
+        // Only watched files count.
+        std::fs::write(&b, "const b = 1;\n").unwrap();
+        std::fs::write(&a, "const a = 1;\n").unwrap();
+        assert_eq!(changes.wait(), Some(vec![a]));
+
+        changes.sender().send(Message::Refresh).unwrap();
+        assert_eq!(changes.wait(), Some(vec![]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
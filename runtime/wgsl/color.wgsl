@@ -0,0 +1,53 @@
+// Color space conversions and helpers.
+// Include with `#include <wgpu_pp/color.wgsl>`.
+
+/// Converts sRGB encoded color to linear, like an `*Srgb` texture format does when sampled.
+fn srgb_to_linear(c: vec3f) -> vec3f {
+    return select(pow((c + 0.055) / 1.055, vec3f(2.4)), c / 12.92, c <= vec3f(0.04045));
+}
+
+/// Converts linear color to sRGB encoded, like an `*Srgb` texture format does when written.
+fn linear_to_srgb(c: vec3f) -> vec3f {
+    return select(1.055 * pow(c, vec3f(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3f(0.0031308));
+}
+
+/// `srgb_to_linear` for colors with alpha, which is linear either way.
+fn srgba_to_linear(c: vec4f) -> vec4f {
+    return vec4f(srgb_to_linear(c.rgb), c.a);
+}
+
+/// `linear_to_srgb` for colors with alpha, which is linear either way.
+fn linear_to_srgba(c: vec4f) -> vec4f {
+    return vec4f(linear_to_srgb(c.rgb), c.a);
+}
+
+/// The relative luminance of linear Rec. 709 (and sRGB) color.
+fn luminance(c: vec3f) -> f32 {
+    return dot(c, vec3f(0.2126, 0.7152, 0.0722));
+}
+
+/// Converts RGB to hue, saturation and value, each from 0 to 1.
+fn rgb_to_hsv(c: vec3f) -> vec3f {
+    let k = vec4f(0.0, -1.0 / 3.0, 2.0 / 3.0, -1.0);
+    let p = select(vec4f(c.gb, k.xy), vec4f(c.bg, k.wz), c.g < c.b);
+    let q = select(vec4f(c.r, p.yzx), vec4f(p.xyw, c.r), c.r < p.x);
+    let d = q.x - min(q.w, q.y);
+    let e = 1.0e-10;
+    return vec3f(abs(q.z + (q.w - q.y) / (6.0 * d + e)), d / (q.x + e), q.x);
+}
+
+/// Converts hue, saturation and value, each from 0 to 1, to RGB.
+fn hsv_to_rgb(c: vec3f) -> vec3f {
+    let p = abs(fract(c.xxx + vec3f(1.0, 2.0 / 3.0, 1.0 / 3.0)) * 6.0 - 3.0);
+    return c.z * mix(vec3f(1.0), clamp(p - 1.0, vec3f(0.0), vec3f(1.0)), c.y);
+}
+
+/// Reinhard tone mapping, from linear HDR color to the 0 to 1 range.
+fn tonemap_reinhard(c: vec3f) -> vec3f {
+    return c / (1.0 + c);
+}
+
+/// An approximation of the ACES filmic tone mapping curve, by Krzysztof Narkowicz.
+fn tonemap_aces(c: vec3f) -> vec3f {
+    return saturate((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14));
+}
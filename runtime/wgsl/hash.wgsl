@@ -0,0 +1,61 @@
+// Integer hashes for random numbers on the GPU.
+// Include with `#include <wgpu_pp/hash.wgsl>`.
+
+/// The PCG hash, from "Hash Functions for GPU Rendering" by Jarzynski and Olano.
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+/// Hashes two values at once, e.g. pixel coordinates.
+fn pcg2d(v_in: vec2u) -> vec2u {
+    var v = v_in * 1664525u + 1013904223u;
+    v.x += v.y * 1664525u;
+    v.y += v.x * 1664525u;
+    v = v ^ (v >> vec2u(16u));
+    v.x += v.y * 1664525u;
+    v.y += v.x * 1664525u;
+    v = v ^ (v >> vec2u(16u));
+    return v;
+}
+
+/// Hashes three values at once, e.g. pixel coordinates and the frame.
+fn pcg3d(v_in: vec3u) -> vec3u {
+    var v = v_in * 1664525u + 1013904223u;
+    v.x += v.y * v.z;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    v = v ^ (v >> vec3u(16u));
+    v.x += v.y * v.z;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    return v;
+}
+
+/// Maps a hash to a float from 0 to 1, excluding 1.
+fn hash_to_unit(h: u32) -> f32 {
+    return f32(h >> 8u) * (1.0 / 16777216.0);
+}
+
+/// A random float from 0 to 1 for a float.
+fn hash11(p: f32) -> f32 {
+    return hash_to_unit(pcg(bitcast<u32>(p)));
+}
+
+/// A random float from 0 to 1 for a 2D point.
+fn hash21(p: vec2f) -> f32 {
+    return hash_to_unit(pcg2d(bitcast<vec2u>(p)).x);
+}
+
+/// A random 2D point, each from 0 to 1, for a 2D point.
+fn hash22(p: vec2f) -> vec2f {
+    let h = pcg2d(bitcast<vec2u>(p));
+    return vec2f(hash_to_unit(h.x), hash_to_unit(h.y));
+}
+
+/// A random 3D point, each from 0 to 1, for a 3D point.
+fn hash33(p: vec3f) -> vec3f {
+    let h = pcg3d(bitcast<vec3u>(p));
+    return vec3f(hash_to_unit(h.x), hash_to_unit(h.y), hash_to_unit(h.z));
+}
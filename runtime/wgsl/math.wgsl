@@ -0,0 +1,114 @@
+// Constants and helpers for vectors, quaternions and matrices.
+// Include with `#include <wgpu_pp/math.wgsl>`.
+
+const PI: f32 = 3.14159265358979323846;
+const TAU: f32 = 6.28318530717958647693;
+const HALF_PI: f32 = 1.57079632679489661923;
+const INV_PI: f32 = 0.31830988618379067154;
+const SQRT_2: f32 = 1.41421356237309504880;
+const E: f32 = 2.71828182845904523536;
+
+/// Where `x` is between `a` and `b`, the inverse of `mix`.
+fn inverse_mix(a: f32, b: f32, x: f32) -> f32 {
+    return (x - a) / (b - a);
+}
+
+/// Maps `x` from the range `a` to `b` to the range `c` to `d`.
+fn remap(x: f32, a: f32, b: f32, c: f32, d: f32) -> f32 {
+    return mix(c, d, inverse_mix(a, b, x));
+}
+
+/// The identity quaternion, which doesn't rotate. Quaternions are `vec4f(x, y, z, w)`.
+fn quat_identity() -> vec4f {
+    return vec4f(0.0, 0.0, 0.0, 1.0);
+}
+
+/// A rotation of `angle` radians around the normalized `axis`.
+fn quat_from_axis_angle(axis: vec3f, angle: f32) -> vec4f {
+    return vec4f(axis * sin(angle * 0.5), cos(angle * 0.5));
+}
+
+/// The rotation `b` followed by `a`.
+fn quat_mul(a: vec4f, b: vec4f) -> vec4f {
+    return vec4f(
+        a.w * b.xyz + b.w * a.xyz + cross(a.xyz, b.xyz),
+        a.w * b.w - dot(a.xyz, b.xyz),
+    );
+}
+
+/// The inverse of a normalized quaternion.
+fn quat_conjugate(q: vec4f) -> vec4f {
+    return vec4f(-q.xyz, q.w);
+}
+
+/// Rotates `v` by the normalized quaternion `q`.
+fn quat_rotate(q: vec4f, v: vec3f) -> vec3f {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
+
+/// The rotation matrix of a normalized quaternion.
+fn quat_to_mat3(q: vec4f) -> mat3x3f {
+    let x2 = q.x + q.x;
+    let y2 = q.y + q.y;
+    let z2 = q.z + q.z;
+    let xx = q.x * x2;
+    let yy = q.y * y2;
+    let zz = q.z * z2;
+    let xy = q.x * y2;
+    let xz = q.x * z2;
+    let yz = q.y * z2;
+    let wx = q.w * x2;
+    let wy = q.w * y2;
+    let wz = q.w * z2;
+    return mat3x3f(
+        vec3f(1.0 - (yy + zz), xy + wz, xz - wy),
+        vec3f(xy - wz, 1.0 - (xx + zz), yz + wx),
+        vec3f(xz + wy, yz - wx, 1.0 - (xx + yy)),
+    );
+}
+
+/// A rotation of `angle` radians around the X axis.
+fn rotation_x(angle: f32) -> mat3x3f {
+    let s = sin(angle);
+    let c = cos(angle);
+    return mat3x3f(vec3f(1.0, 0.0, 0.0), vec3f(0.0, c, s), vec3f(0.0, -s, c));
+}
+
+/// A rotation of `angle` radians around the Y axis.
+fn rotation_y(angle: f32) -> mat3x3f {
+    let s = sin(angle);
+    let c = cos(angle);
+    return mat3x3f(vec3f(c, 0.0, -s), vec3f(0.0, 1.0, 0.0), vec3f(s, 0.0, c));
+}
+
+/// A rotation of `angle` radians around the Z axis.
+fn rotation_z(angle: f32) -> mat3x3f {
+    let s = sin(angle);
+    let c = cos(angle);
+    return mat3x3f(vec3f(c, s, 0.0), vec3f(-s, c, 0.0), vec3f(0.0, 0.0, 1.0));
+}
+
+/// A translation by `t`.
+fn translation(t: vec3f) -> mat4x4f {
+    return mat4x4f(
+        vec4f(1.0, 0.0, 0.0, 0.0),
+        vec4f(0.0, 1.0, 0.0, 0.0),
+        vec4f(0.0, 0.0, 1.0, 0.0),
+        vec4f(t, 1.0),
+    );
+}
+
+/// The upper left 3x3 of a 4x4 matrix, e.g. its rotation and scale.
+fn mat4_to_mat3(m: mat4x4f) -> mat3x3f {
+    return mat3x3f(m[0].xyz, m[1].xyz, m[2].xyz);
+}
+
+/// The inverse of a 3x3 matrix, e.g. for transforming normals with
+/// `transpose(inverse_mat3(m))`.
+fn inverse_mat3(m: mat3x3f) -> mat3x3f {
+    let c0 = cross(m[1], m[2]);
+    let c1 = cross(m[2], m[0]);
+    let c2 = cross(m[0], m[1]);
+    return transpose(mat3x3f(c0, c1, c2)) * (1.0 / dot(m[0], c0));
+}
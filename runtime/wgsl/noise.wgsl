@@ -0,0 +1,98 @@
+// Value, Perlin and simplex noise, and blue noise helpers.
+// Include with `#include <wgpu_pp/noise.wgsl>`.
+
+#include <wgpu_pp/hash.wgsl>
+
+/// A random unit vector for a lattice point.
+fn noise_gradient(i: vec2i) -> vec2f {
+    let angle = hash_to_unit(pcg2d(bitcast<vec2u>(i)).x) * 6.28318530717958647693;
+    return vec2f(cos(angle), sin(angle));
+}
+
+/// Value noise, from 0 to 1, with a random value at each integer point.
+fn value_noise(p: vec2f) -> f32 {
+    let i = vec2i(floor(p));
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let a = hash_to_unit(pcg2d(bitcast<vec2u>(i)).x);
+    let b = hash_to_unit(pcg2d(bitcast<vec2u>(i + vec2i(1, 0))).x);
+    let c = hash_to_unit(pcg2d(bitcast<vec2u>(i + vec2i(0, 1))).x);
+    let d = hash_to_unit(pcg2d(bitcast<vec2u>(i + vec2i(1, 1))).x);
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+/// Perlin (gradient) noise, from about -1 to 1, and 0 at each integer point.
+fn perlin_noise(p: vec2f) -> f32 {
+    let i = vec2i(floor(p));
+    let f = fract(p);
+    let u = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+    let a = dot(noise_gradient(i), f);
+    let b = dot(noise_gradient(i + vec2i(1, 0)), f - vec2f(1.0, 0.0));
+    let c = dot(noise_gradient(i + vec2i(0, 1)), f - vec2f(0.0, 1.0));
+    let d = dot(noise_gradient(i + vec2i(1, 1)), f - vec2f(1.0, 1.0));
+    return 1.41421356 * mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+/// The contribution of a simplex corner at lattice point `i`, `x` away.
+fn simplex_corner(i: vec2i, x: vec2f) -> f32 {
+    let t = max(0.5 - dot(x, x), 0.0);
+    return t * t * t * t * dot(noise_gradient(i), x);
+}
+
+/// Simplex noise, from about -1 to 1, with fewer directional artifacts than
+/// Perlin noise.
+fn simplex_noise(p: vec2f) -> f32 {
+    // (sqrt(3) - 1) / 2 and (3 - sqrt(3)) / 6, for skewing to and from the simplex grid.
+    let F2 = 0.36602540378;
+    let G2 = 0.21132486540;
+    let s = floor(p + (p.x + p.y) * F2);
+    let x0 = p - s + (s.x + s.y) * G2;
+    let o = select(vec2f(0.0, 1.0), vec2f(1.0, 0.0), x0.x > x0.y);
+    let x1 = x0 - o + G2;
+    let x2 = x0 - 1.0 + 2.0 * G2;
+    let i = vec2i(s);
+    let n = simplex_corner(i, x0) + simplex_corner(i + vec2i(o), x1)
+        + simplex_corner(i + vec2i(1, 1), x2);
+    return 99.2 * n;
+}
+
+/// Fractal noise, summing `octaves` of simplex noise, each at twice the
+/// frequency and half the amplitude of the last. From about -1 to 1.
+fn fbm(p: vec2f, octaves: u32) -> f32 {
+    var sum = 0.0;
+    var amplitude = 0.5;
+    var frequency = 1.0;
+    for (var octave = 0u; octave < octaves; octave++) {
+        sum += amplitude * simplex_noise(p * frequency);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum;
+}
+
+/// Interleaved gradient noise, from "Next Generation Post Processing in Call of
+/// Duty: Advanced Warfare" by Jimenez. A cheap, blue-noise-like value from 0 to 1
+/// per pixel, e.g. for dithering or rotating sample patterns.
+fn interleaved_gradient_noise(pixel: vec2f) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2f(0.06711056, 0.00583715))));
+}
+
+/// The `n`th point of the R2 low-discrepancy sequence, each from 0 to 1. Successive
+/// points are evenly spread, e.g. for jittering samples between frames.
+fn r2_sequence(n: u32) -> vec2f {
+    return fract(vec2f(0.5) + f32(n) * vec2f(0.75487766624669276, 0.56984029099805327));
+}
+
+/// The texel of a tiling blue noise texture of `size` to read for `pixel`,
+/// offset by the R2 sequence each frame so the pattern doesn't stay in place.
+fn blue_noise_texel(pixel: vec2u, frame: u32, size: u32) -> vec2u {
+    let offset = vec2u(r2_sequence(frame) * f32(size));
+    return (pixel + offset) % vec2u(size);
+}
+
+/// Remaps uniform noise from 0 to 1 to a triangular distribution from -1 to 1,
+/// which dithers with less visible noise.
+fn triangular_noise(v: f32) -> f32 {
+    let n = v * 2.0 - 1.0;
+    return sign(n) * (1.0 - sqrt(1.0 - abs(n)));
+}
@@ -0,0 +1,136 @@
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use crate::{compile_error, parse_filename};
+
+/// An option passed to a macro after the paths, e.g. `encase` or `map(A = B)`.
+pub struct MacroOption {
+    pub name: String,
+    /// Tokens after `=`, or inside the parenthesis.
+    pub value: Vec<TokenTree>,
+}
+
+/// Arguments to a macro: string literal paths followed by options.
+pub struct MacroArgs {
+    pub filenames: Vec<String>,
+    pub options: Vec<MacroOption>,
+}
+
+impl MacroArgs {
+    pub fn parse(input: TokenStream) -> Result<MacroArgs, TokenStream> {
+        let mut args = MacroArgs {
+            filenames: vec![],
+            options: vec![],
+        };
+
+        // Split the input on top-level commas.
+        let mut items: Vec<Vec<TokenTree>> = vec![vec![]];
+        for token in input {
+            match token {
+                TokenTree::Punct(ref p) if p.as_char() == ',' => items.push(vec![]),
+                token => items.last_mut().unwrap().push(token),
+            }
+        }
+        // Allow a trailing comma.
+        if items.len() > 1 && items.last().unwrap().is_empty() {
+            items.pop();
+        }
+
+        for mut item in items {
+            // Fragments passed through `macro_rules!`, e.g. `$path:literal`,
+            // come wrapped in an invisible group.
+            if let [TokenTree::Group(group)] = item.as_slice() {
+                if group.delimiter() == Delimiter::None {
+                    item = group.stream().into_iter().collect();
+                }
+            }
+            match item.as_slice() {
+                [] => return Err(compile_error("expected a path or an option")),
+                [TokenTree::Literal(_)] if args.options.is_empty() => {
+                    args.filenames.push(parse_filename(&item[0])?)
+                }
+                [TokenTree::Ident(name)] => args.options.push(MacroOption {
+                    name: name.to_string(),
+                    value: vec![],
+                }),
+                [TokenTree::Ident(name), TokenTree::Punct(p), value @ ..] if p.as_char() == '=' => {
+                    args.options.push(MacroOption {
+                        name: name.to_string(),
+                        value: value.to_vec(),
+                    })
+                }
+                [TokenTree::Ident(name), TokenTree::Group(group)]
+                    if group.delimiter() == Delimiter::Parenthesis =>
+                {
+                    args.options.push(MacroOption {
+                        name: name.to_string(),
+                        value: group.stream().into_iter().collect(),
+                    })
+                }
+                _ => {
+                    let msg = format!(
+                        "expected a path or an option, got `{}`",
+                        item.into_iter().collect::<TokenStream>()
+                    );
+                    return Err(compile_error(&msg));
+                }
+            }
+        }
+
+        if args.filenames.is_empty() {
+            return Err(compile_error("expected at least one path"));
+        }
+        Ok(args)
+    }
+
+    /// Errors on options not in `allowed`.
+    pub fn check_options(&self, allowed: &[&str]) -> Result<(), TokenStream> {
+        for option in self.options.iter() {
+            if !allowed.contains(&option.name.as_str()) {
+                let msg = format!("unknown option: {}", option.name);
+                return Err(compile_error(&msg));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn option(&self, name: &str) -> Option<&MacroOption> {
+        self.options.iter().find(|option| option.name == name)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.option(name).is_some()
+    }
+}
+
+impl MacroOption {
+    /// Parses the value as comma separated `key = value` pairs.
+    pub fn pairs(&self) -> Result<Vec<(String, String)>, TokenStream> {
+        let mut pairs = vec![];
+        let mut tokens = self.value.iter().peekable();
+        while tokens.peek().is_some() {
+            let key = match tokens.next() {
+                Some(TokenTree::Ident(key)) => key.to_string(),
+                _ => {
+                    let msg = format!("expected `key = value` pairs in {}", self.name);
+                    return Err(compile_error(&msg));
+                }
+            };
+            match tokens.next() {
+                Some(TokenTree::Punct(p)) if p.as_char() == '=' => {}
+                _ => {
+                    let msg = format!("expected `=` after {} in {}", key, self.name);
+                    return Err(compile_error(&msg));
+                }
+            }
+
+            let mut value = TokenStream::new();
+            while let Some(token) = tokens.next_if(|t| !matches!(t, TokenTree::Punct(p) if p.as_char() == ','))
+            {
+                value.extend([token.clone()]);
+            }
+            tokens.next();
+            pairs.push((key, value.to_string()));
+        }
+        Ok(pairs)
+    }
+}
@@ -0,0 +1,421 @@
+//! Bounds checks for development builds, so out-of-bounds indexing of storage
+//! and workgroup arrays reads and writes the last element deterministically
+//! instead of silently corrupting memory or reading whatever is there.
+//!
+//! The preprocessed source is rewritten rather than Naga's IR, since the
+//! macros embed the source. Direct accesses of module-scope arrays, e.g.
+//! `particles[i]` or `buffer.data[i]`, are clamped, while accesses through
+//! pointers or `let` aliases aren't. Locals and parameters that shadow an
+//! array are left alone, and so are comments.
+//!
+//! Runtime-sized arrays are clamped to `max(arrayLength(&a), 1u) - 1u`, so
+//! an empty one is indexed at 0 rather than at a wrapped-around index. That's
+//! still out of bounds, and left to the driver's robustness checks.
+
+use naga::{AddressSpace, ArraySize, Module, TypeInner};
+
+/// A storage or workgroup array whose accesses are clamped.
+struct Array {
+    /// The path of the array, e.g. `buffer.data`.
+    path: String,
+    /// An expression for its last index.
+    last: String,
+    /// Whether it has a constant size, which validation already checks
+    /// literal indices against.
+    fixed: bool,
+}
+
+impl Array {
+    fn new(path: String, size: ArraySize) -> Self {
+        let (last, fixed) = match size {
+            ArraySize::Constant(size) => (format!("{}u", size.get() - 1), true),
+            ArraySize::Dynamic => (format!("max(arrayLength(&{}), 1u) - 1u", path), false),
+        };
+        Array { path, last, fixed }
+    }
+}
+
+/// Returns the storage and workgroup arrays in `module`, including the ones
+/// in structs, e.g. `buffer.data`.
+fn arrays(module: &Module) -> Vec<Array> {
+    let mut arrays = vec![];
+    for (_, global) in module.global_variables.iter() {
+        if !matches!(
+            global.space,
+            AddressSpace::Storage { .. } | AddressSpace::WorkGroup
+        ) {
+            continue;
+        }
+        let Some(name) = global.name.as_ref() else {
+            continue;
+        };
+        match module.types[global.ty].inner {
+            TypeInner::Array { size, .. } => arrays.push(Array::new(name.clone(), size)),
+            TypeInner::Struct { ref members, .. } => {
+                for member in members.iter() {
+                    let (Some(member_name), TypeInner::Array { size, .. }) =
+                        (member.name.as_ref(), &module.types[member.ty].inner)
+                    else {
+                        continue;
+                    };
+                    arrays.push(Array::new(format!("{}.{}", name, member_name), *size));
+                }
+            }
+            _ => {}
+        }
+    }
+    arrays
+}
+
+fn is_ident_char(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+fn ident_len(text: &str) -> usize {
+    text.find(|c| !is_ident_char(c)).unwrap_or(text.len())
+}
+
+/// Returns the length of the comment `text` starts with, if it does. Block
+/// comments nest, and an unterminated one goes on to the end.
+fn comment_len(text: &str) -> Option<usize> {
+    if text.starts_with("//") {
+        return Some(text.find('\n').unwrap_or(text.len()));
+    }
+    if !text.starts_with("/*") {
+        return None;
+    }
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if rest.starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        } else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    Some(text.len())
+}
+
+/// Returns the index of the `]` closing the `[` `text` starts with.
+fn closing_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut skip_to = 0;
+    for (i, c) in text.char_indices() {
+        if i < skip_to {
+            continue;
+        }
+        if let Some(len) = comment_len(&text[i..]) {
+            skip_to = i + len;
+            continue;
+        }
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns whether an index is a literal.
+fn is_literal(index: &str) -> bool {
+    let index = index.trim();
+    index.starts_with(|c: char| c.is_ascii_digit()) && index.chars().all(is_ident_char)
+}
+
+/// The names declared in function bodies, which shadow module-scope arrays
+/// of the same name.
+#[derive(Default)]
+struct Scopes {
+    /// The current depth of braces.
+    depth: usize,
+    /// The names in scope, along with the depth of the block declaring them.
+    names: Vec<(String, usize)>,
+    /// Names declared by the current statement, which come into scope after
+    /// it, since its initializer still refers to what they shadow.
+    pending: Vec<String>,
+    /// The parameters of the function whose body comes next.
+    params: Vec<String>,
+}
+
+impl Scopes {
+    fn is_shadowed(&self, name: &str) -> bool {
+        self.names.iter().any(|(declared, _)| declared == name)
+    }
+
+    fn open_block(&mut self) {
+        self.depth += 1;
+        let depth = self.depth;
+        self.names
+            .extend(self.params.drain(..).map(|param| (param, depth)));
+    }
+
+    fn close_block(&mut self) {
+        let depth = self.depth;
+        self.names.retain(|(_, declared)| *declared < depth);
+        self.depth = depth.saturating_sub(1);
+    }
+
+    fn end_statement(&mut self) {
+        let depth = self.depth;
+        self.names
+            .extend(self.pending.drain(..).map(|name| (name, depth)));
+    }
+
+    /// Records the name declared by `let`, `var` or `const` in a function
+    /// body, given the text after the keyword, e.g. `<function> data: ...`.
+    fn declare(&mut self, after: &str) {
+        if self.depth == 0 {
+            return;
+        }
+        let after = after.trim_start();
+        let after = match after.strip_prefix('<') {
+            Some(after) => after
+                .find('>')
+                .map_or(after, |i| after[i + 1..].trim_start()),
+            None => after,
+        };
+        let len = ident_len(after);
+        if len > 0 {
+            self.pending.push(after[..len].to_string());
+        }
+    }
+
+    /// Records the parameters of a function, given the text after `fn`, e.g.
+    /// `main(@builtin(global_invocation_id) id: vec3<u32>) { ... }`.
+    fn declare_params(&mut self, after: &str) {
+        let Some(open) = after.find('(') else {
+            return;
+        };
+        let mut depth = 0;
+        let mut close = after.len();
+        for (i, c) in after[open..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = open + i;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Each parameter is `[attributes] name: type`, and commas within
+        // types, e.g. `array<f32, 4>`, leave pieces without a `:`.
+        self.params = after[open + 1..close]
+            .split(',')
+            .filter_map(|param| param.split_once(':'))
+            .filter_map(|(name, _)| name.trim().rsplit(|c| !is_ident_char(c)).next())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+}
+
+/// Clamps the indices of accesses of `arrays` in `text`, including accesses
+/// nested in the indices, skipping names that `scopes` has shadowed.
+fn clamp_accesses(text: &str, arrays: &[Array], scopes: &mut Scopes) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    'outer: while let Some(c) = rest.chars().next() {
+        if let Some(len) = comment_len(rest) {
+            result += &rest[..len];
+            rest = &rest[len..];
+            continue;
+        }
+        if !is_ident_char(c) {
+            match c {
+                '{' => scopes.open_block(),
+                '}' => scopes.close_block(),
+                ';' => scopes.end_statement(),
+                _ => {}
+            }
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let len = ident_len(rest);
+        match &rest[..len] {
+            "let" | "var" | "const" => scopes.declare(&rest[len..]),
+            "fn" => scopes.declare_params(&rest[len..]),
+            _ => {}
+        }
+        // Members of other variables aren't module-scope arrays, and neither
+        // are locals and parameters with the same name.
+        if !result.ends_with('.') && !scopes.is_shadowed(&rest[..len]) {
+            // Try `a`, then `a.b`, for arrays in structs.
+            let mut end = len;
+            loop {
+                let path = &rest[..end];
+                let after = rest[end..].trim_start();
+                let array = arrays.iter().find(|array| array.path == path);
+                if let (Some(array), true) = (array, after.starts_with('[')) {
+                    let open = rest.len() - after.len();
+                    if let Some(close) = closing_bracket(after) {
+                        let index = &after[1..close];
+                        let clamped = clamp_accesses(index, arrays, scopes);
+                        result += path;
+                        match array.fixed && is_literal(index) {
+                            true => result += &format!("[{}]", clamped),
+                            false => {
+                                let clamped = clamped.trim();
+                                result += &format!("[min(u32({}), {})]", clamped, array.last)
+                            }
+                        }
+                        rest = &rest[open + close + 1..];
+                        continue 'outer;
+                    }
+                }
+
+                let member = &rest[end..];
+                match member.strip_prefix('.') {
+                    Some(member) if member.starts_with(is_ident_char) => {
+                        end += 1 + ident_len(member);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        result += &rest[..len];
+        rest = &rest[len..];
+    }
+    result
+}
+
+/// Returns `source` with the indices of storage and workgroup array accesses
+/// clamped to the arrays' bounds.
+pub fn inject(source: &str, module: &Module) -> String {
+    let arrays = arrays(module);
+    if arrays.is_empty() {
+        return source.to_string();
+    }
+    clamp_accesses(source, &arrays, &mut Scopes::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    /// Injects the bounds checks into `source`, checking that the result is
+    /// still valid.
+    fn checked(source: &str) -> String {
+        let (module, _) = validate(source).unwrap();
+        let checked = inject(source, &module);
+        validate(&checked).unwrap();
+        checked
+    }
+
+    const BUFFERS: &str = "
+struct Particle { position: vec2<f32> }
+struct Grid { size: u32, cells: array<u32, 16> }
+@group(0) @binding(0) var<storage, read_write> particles: array<Particle>;
+@group(0) @binding(1) var<storage, read_write> grid: Grid;
+var<workgroup> tile: array<f32, 4>;
+";
+
+    #[test]
+    fn test_dynamic_array() {
+        let checked = checked(&format!(
+            "{}fn f(i: u32) -> vec2<f32> {{ return particles[i].position; }}",
+            BUFFERS
+        ));
+        assert!(checked
+            .contains("particles[min(u32(i), max(arrayLength(&particles), 1u) - 1u)].position"));
+    }
+
+    #[test]
+    fn test_struct_member() {
+        let checked = checked(&format!(
+            "{}fn f(i: u32) -> u32 {{ return grid.cells[i]; }}",
+            BUFFERS
+        ));
+        assert!(checked.contains("grid.cells[min(u32(i), 15u)]"));
+    }
+
+    #[test]
+    fn test_nested_index() {
+        let checked = checked(&format!(
+            "{}fn f(i: u32) -> f32 {{ return tile[grid.cells[i]]; }}",
+            BUFFERS
+        ));
+        assert!(checked.contains("tile[min(u32(grid.cells[min(u32(i), 15u)]), 3u)]"));
+    }
+
+    #[test]
+    fn test_literal_index() {
+        let checked = checked(&format!(
+            "{}fn f() -> f32 {{ return tile[2] + particles[5].position.x; }}",
+            BUFFERS
+        ));
+        assert!(checked.contains("tile[2]"));
+        assert!(checked.contains("particles[min(u32(5), max(arrayLength(&particles), 1u) - 1u)]"));
+    }
+
+    #[test]
+    fn test_shadowed() {
+        let source = format!(
+            "{}
+fn local(i: u32) -> f32 {{
+    var tile: array<f32, 4>;
+    return tile[i];
+}}
+fn param(tile: vec4<f32>, i: u32) -> f32 {{
+    return tile[i];
+}}
+fn block(i: u32) -> f32 {{
+    {{
+        let tile = vec4<f32>(1.0, 2.0, 3.0, 4.0);
+        let x = tile[i];
+    }}
+    return tile[i];
+}}",
+            BUFFERS
+        );
+        let checked = checked(&source);
+        assert_eq!(checked.matches("tile[i]").count(), 3);
+        assert_eq!(checked.matches("tile[min(u32(i), 3u)]").count(), 1);
+    }
+
+    #[test]
+    fn test_shadowing_initializer() {
+        let checked = checked(&format!(
+            "{}fn f(i: u32) -> f32 {{ let tile = tile[i]; return tile; }}",
+            BUFFERS
+        ));
+        assert!(checked.contains("let tile = tile[min(u32(i), 3u)];"));
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = format!(
+            "{}
+fn f(i: u32) -> f32 {{
+    // tile[i] {{
+    /* tile[i] /* nested */ {{ */
+    return tile[i /* ] */];
+}}",
+            BUFFERS
+        );
+        let checked = checked(&source);
+        assert!(checked.contains("// tile[i] {\n"));
+        assert!(checked.contains("/* tile[i] /* nested */ { */"));
+        assert!(checked.contains("return tile[min(u32(i /* ] */), 3u)];"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Size budgets for expanded shaders, to keep uber-shader bloat visible.
+//!
+//! A budget is set for every shader with `WGPU_PP_SIZE_BUDGET`, or for a
+//! single macro call with `budget = N`. Shaders expanding to more bytes than
+//! their budget get a warning listing the files that contributed most, or an
+//! error with `WGPU_PP_STRICT_BUDGET=1`, e.g. in CI.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use proc_macro::{Span, TokenStream, TokenTree};
+
+use crate::args::MacroArgs;
+use crate::compile_error;
+
+/// The budget of every shader, in bytes.
+const SIZE_BUDGET: &str = "WGPU_PP_SIZE_BUDGET";
+
+/// Set to `1` to make going over budget an error instead of a warning.
+const STRICT_BUDGET: &str = "WGPU_PP_STRICT_BUDGET";
+
+/// How many of the largest files are listed when a shader is over budget.
+const LARGEST_FILES: usize = 5;
+
+#[derive(Clone, Copy)]
+pub struct Budget {
+    bytes: usize,
+    strict: bool,
+}
+
+fn strict() -> bool {
+    std::env::var(STRICT_BUDGET).is_ok_and(|value| value == "1")
+}
+
+impl Budget {
+    /// Returns the budget set for every shader, if any.
+    pub fn global() -> Option<Budget> {
+        let bytes = std::env::var(SIZE_BUDGET).ok()?.trim().parse().ok()?;
+        Some(Budget {
+            bytes,
+            strict: strict(),
+        })
+    }
+
+    /// Returns the budget of a macro call, `budget = N` or else the global one.
+    pub fn for_args(args: &MacroArgs) -> Result<Option<Budget>, TokenStream> {
+        let Some(option) = args.option("budget") else {
+            return Ok(Budget::global());
+        };
+        let bytes = match option.value.as_slice() {
+            [TokenTree::Literal(literal)] => literal.to_string().replace('_', "").parse().ok(),
+            _ => None,
+        };
+        match bytes {
+            Some(bytes) => Ok(Some(Budget {
+                bytes,
+                strict: strict(),
+            })),
+            None => Err(compile_error("expected `budget = <bytes>`")),
+        }
+    }
+
+    /// Warns, or errors when strict, if `source` is over budget. `sizes` is
+    /// how many bytes of it came from each file.
+    pub fn check(
+        budget: Option<Budget>,
+        name: &str,
+        source: &str,
+        sizes: Vec<(PathBuf, usize)>,
+    ) -> Result<(), TokenStream> {
+        let Some(budget) = budget.filter(|budget| source.len() > budget.bytes) else {
+            return Ok(());
+        };
+
+        let msg = budget.message(name, source, sizes);
+        match budget.strict {
+            true => Err(compile_error(&msg)),
+            false => {
+                Span::call_site().warning(msg).emit();
+                Ok(())
+            }
+        }
+    }
+
+    /// Describes `source` going over the budget, listing the largest files.
+    fn message(&self, name: &str, source: &str, mut sizes: Vec<(PathBuf, usize)>) -> String {
+        sizes.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then(a_path.cmp(b_path)));
+        let mut msg = format!(
+            "{} expands to {} bytes, over its budget of {} bytes. The largest files are:",
+            name,
+            source.len(),
+            self.bytes
+        );
+        for (path, bytes) in sizes.iter().take(LARGEST_FILES) {
+            msg += &format!(
+                "\n  {} bytes ({}%) {}",
+                bytes,
+                bytes * 100 / source.len().max(1),
+                path.display()
+            );
+        }
+        msg
+    }
+}
+
+/// Returns how many bytes of preprocessed source came from each file, from its
+/// source map.
+pub fn file_sizes(source: &str, source_map: &[(PathBuf, usize)]) -> Vec<(PathBuf, usize)> {
+    let mut sizes: HashMap<&Path, usize> = HashMap::new();
+    for (line, (path, _)) in source.lines().zip(source_map) {
+        *sizes.entry(path).or_default() += line.len() + 1;
+    }
+    sizes
+        .into_iter()
+        .map(|(path, bytes)| (path.to_path_buf(), bytes))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_sizes() {
+        let source = "fn a() {}\nfn b() {}\n// a\n";
+        let source_map = [
+            (PathBuf::from("main.wgsl"), 1),
+            (PathBuf::from("common.wgsl"), 1),
+            (PathBuf::from("main.wgsl"), 3),
+        ];
+        let mut sizes = file_sizes(source, &source_map);
+        sizes.sort();
+        assert_eq!(
+            sizes,
+            [
+                (PathBuf::from("common.wgsl"), 10),
+                (PathBuf::from("main.wgsl"), 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_within_budget() {
+        let budget = Budget {
+            bytes: 4,
+            strict: true,
+        };
+        assert!(Budget::check(Some(budget), "main.wgsl", "abcd", vec![]).is_ok());
+        assert!(Budget::check(None, "main.wgsl", "abcdef", vec![]).is_ok());
+    }
+
+    #[test]
+    fn test_message() {
+        let budget = Budget {
+            bytes: 10,
+            strict: false,
+        };
+        let sizes = (0..7)
+            .map(|i| (PathBuf::from(format!("{}.wgsl", i)), i * 2))
+            .chain([(PathBuf::from("a.wgsl"), 12)])
+            .collect();
+        let source = "x".repeat(100);
+        assert_eq!(
+            budget.message("main.wgsl", &source, sizes),
+            "main.wgsl expands to 100 bytes, over its budget of 10 bytes. The largest files are:
+  12 bytes (12%) 6.wgsl
+  12 bytes (12%) a.wgsl
+  10 bytes (10%) 5.wgsl
+  8 bytes (8%) 4.wgsl
+  6 bytes (6%) 3.wgsl"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Remembers which sources were validated, across builds.
+//!
+//! Every time a module calling the macros is recompiled, its shaders would be
+//! parsed and validated again. Instead, a file named after the hash of each
+//! source that passed validation is kept in the target directory, and
+//! validation is skipped for sources that have one. The expanded source
+//! covers every input file and option, so nothing else needs to be tracked.
+//!
+//! Only macros that embed the source use the cache. Macros generating code
+//! from the shader's reflection, like `include_wgsl_module!`, need the
+//! `ModuleInfo` that only validation produces, so they always validate, and
+//! `include_wgsl!` with `expect` or `bounds_checks` still parses the source
+//! for its entry points and arrays.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use naga::valid::{Capabilities, ValidationFlags};
+
+use crate::hash::xxh3_128;
+
+/// Overrides the directory of the cache. Setting it to an empty string disables the cache.
+const CACHE_DIR: &str = "WGPU_PP_CACHE_DIR";
+
+/// The version of Naga validating the sources, which has to match `Cargo.lock`.
+const NAGA_VERSION: &str = "22.1.0";
+
+/// The most entries kept. Beyond that, the least recently used are removed
+/// until a quarter of them are gone.
+const MAX_ENTRIES: usize = 1024;
+
+/// Returns the target directory, `CARGO_TARGET_DIR`, or else the `target`
+/// directory closest to the crate being compiled, which covers workspaces.
+pub fn target_dir() -> Option<PathBuf> {
+    match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => {
+            let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR")?);
+            manifest_dir
+                .ancestors()
+                .map(|dir| dir.join("target"))
+                .find(|dir| dir.is_dir())
+        }
+    }
+}
+
+/// Returns the directory of the cache, `wgpu-pp` in the target directory.
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(CACHE_DIR) {
+        return (!dir.is_empty()).then(|| PathBuf::from(dir));
+    }
+    Some(target_dir()?.join("wgpu-pp"))
+}
+
+/// Returns what an entry for the source contains: the versions and options
+/// validation depends on, followed by the source.
+fn key(source: &str, capabilities: Capabilities, flags: ValidationFlags) -> String {
+    format!(
+        "wgpu-pp {} naga {} capabilities {:x} flags {:x}\n{}",
+        env!("CARGO_PKG_VERSION"),
+        NAGA_VERSION,
+        capabilities.bits(),
+        flags.bits(),
+        source
+    )
+}
+
+/// Returns the path of the file marking a key as validated.
+///
+/// Files are named after the hash of the key, and contain the key itself, so
+/// sources whose hashes collide aren't mistaken for each other.
+fn entry(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{:032x}", xxh3_128(key.as_bytes())))
+}
+
+/// Returns whether an earlier build validated the source with the same options.
+pub fn is_validated(source: &str, capabilities: Capabilities, flags: ValidationFlags) -> bool {
+    cache_dir().is_some_and(|dir| has_entry(&dir, &key(source, capabilities, flags)))
+}
+
+/// Records that the source passed validation with the given options. Failing
+/// to is only a missed optimization, so errors are ignored.
+pub fn record_validated(source: &str, capabilities: Capabilities, flags: ValidationFlags) {
+    if let Some(dir) = cache_dir() {
+        add_entry(&dir, &key(source, capabilities, flags));
+    }
+}
+
+fn has_entry(dir: &Path, key: &str) -> bool {
+    let path = entry(dir, key);
+    if std::fs::read(&path).ok().as_deref() != Some(key.as_bytes()) {
+        return false;
+    }
+
+    // Entries are pruned by modification time, so mark this one as used.
+    if let Ok(file) = File::options().append(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    true
+}
+
+fn add_entry(dir: &Path, key: &str) {
+    let _ = std::fs::create_dir_all(dir);
+    let _ = std::fs::write(entry(dir, key), key);
+    prune(dir);
+}
+
+/// Removes the least recently used entries once there are more than `MAX_ENTRIES`.
+fn prune(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    if entries.len() <= MAX_ENTRIES {
+        return;
+    }
+
+    entries.sort();
+    for (_, path) in entries.iter().take(entries.len() - MAX_ENTRIES * 3 / 4) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_naga_version() {
+        let lock = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.lock");
+        let lock = std::fs::read_to_string(lock).unwrap();
+        let version = lock
+            .split("[[package]]")
+            .find(|package| package.contains("name = \"naga\"\n"))
+            .and_then(|package| package.split("version = \"").nth(1))
+            .and_then(|version| version.split('"').next());
+        assert_eq!(version, Some(NAGA_VERSION));
+    }
+
+    #[test]
+    fn test_entries() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-cache-{}", std::process::id()));
+        let flags = ValidationFlags::all();
+        let a = key("fn main() {}", Capabilities::default(), flags);
+        let b = key("fn main() {}", Capabilities::all(), flags);
+
+        assert!(!has_entry(&dir, &a));
+        add_entry(&dir, &a);
+        assert!(has_entry(&dir, &a));
+        assert!(!has_entry(&dir, &b));
+
+        // An entry for another key under the same name, as if their hashes collided.
+        std::fs::write(entry(&dir, &b), &a).unwrap();
+        assert!(!has_entry(&dir, &b));
+
+        for i in 0..MAX_ENTRIES {
+            std::fs::write(dir.join(i.to_string()), "").unwrap();
+        }
+        add_entry(&dir, &b);
+        assert_eq!(
+            std::fs::read_dir(&dir).unwrap().count(),
+            MAX_ENTRIES * 3 / 4
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
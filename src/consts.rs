@@ -0,0 +1,165 @@
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use crate::derive::{parse_wgsl_attr, skip_visibility, take_attrs, wgsl_type};
+use crate::{compile_error, compile_error_at};
+
+/// Strips Rust-only syntax from a literal, e.g. `1_000u32` becomes `1000`.
+pub fn wgsl_literal(literal: &str) -> String {
+    const SUFFIXES: &[&str] = &[
+        "u8", "u16", "u32", "u64", "usize", "i8", "i16", "i32", "i64", "isize", "f32", "f64",
+    ];
+
+    let mut literal = literal.replace('_', "");
+    let is_hex = literal.starts_with("0x");
+    for suffix in SUFFIXES.iter() {
+        // Hex literals can end in digits that look like a float suffix.
+        if is_hex && suffix.starts_with('f') {
+            continue;
+        }
+        if let Some(stripped) = literal.strip_suffix(suffix) {
+            literal = stripped.to_string();
+            break;
+        }
+    }
+    literal
+}
+
+/// Returns the type of the elements of an array or the columns of a matrix.
+fn element_type(ty: &str) -> String {
+    if let Some((elem, _)) = ty.strip_prefix("array<").and_then(|ty| ty.rsplit_once(',')) {
+        return elem.trim().to_string();
+    }
+    match ty.strip_prefix("mat").and_then(|ty| ty.split_once('x')) {
+        Some((_, rows)) => format!("vec{}", rows),
+        None => String::new(),
+    }
+}
+
+/// Returns whether a type from `wgsl_type` is built into WGSL, rather than
+/// assumed to be a struct of the same name.
+fn is_builtin(ty: &str) -> bool {
+    if let Some((elem, _)) = ty.strip_prefix("array<").and_then(|ty| ty.rsplit_once(',')) {
+        return is_builtin(elem.trim());
+    }
+    matches!(ty, "f32" | "i32" | "u32" | "bool") || ty.starts_with("vec") || ty.starts_with("mat")
+}
+
+/// Converts a Rust constant expression to WGSL.
+///
+/// Arrays become constructors of the WGSL type, e.g. `[1.0, 0.0]` with a
+/// type of `vec2<f32>` becomes `vec2<f32>(1.0, 0.0)`.
+fn wgsl_expr(tokens: &[TokenTree], ty: &str) -> String {
+    let mut expr = String::new();
+    for token in tokens.iter() {
+        match token {
+            TokenTree::Literal(literal) => expr += &wgsl_literal(&literal.to_string()),
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Bracket => {
+                let elem_ty = element_type(ty);
+                let elems = group.stream().into_iter().collect::<Vec<_>>();
+                let elems = elems
+                    .split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ','))
+                    .filter(|elem| !elem.is_empty())
+                    .map(|elem| wgsl_expr(elem, &elem_ty))
+                    .collect::<Vec<_>>();
+                expr += &format!("{}({})", ty, elems.join(", "));
+            }
+            TokenTree::Group(group) => {
+                let inner = group.stream().into_iter().collect::<Vec<_>>();
+                expr += &format!("({})", wgsl_expr(&inner, ty));
+            }
+            TokenTree::Punct(p) => expr.push(p.as_char()),
+            TokenTree::Ident(ident) => {
+                if !expr.is_empty() && !expr.ends_with(|c: char| c.is_ascii_punctuation()) {
+                    expr.push(' ');
+                }
+                expr += &ident.to_string();
+            }
+        }
+    }
+    expr
+}
+
+/// The Rust items to emit, and the WGSL header registered for them.
+pub struct Consts {
+    pub header: String,
+    pub wgsl: String,
+    pub rust: TokenStream,
+}
+
+/// Parses the input to `wgsl_consts!`.
+///
+/// An optional `#![wgsl(header = "...")]` comes first, followed by `const` items.
+pub fn parse(input: TokenStream) -> Result<Consts, TokenStream> {
+    let tokens = input.into_iter().collect::<Vec<_>>();
+    let mut header = "consts.wgsl".to_string();
+    let mut rest = tokens.as_slice();
+
+    if let [TokenTree::Punct(hash), TokenTree::Punct(bang), TokenTree::Group(group), tail @ ..] =
+        rest
+    {
+        if hash.as_char() == '#' && bang.as_char() == '!' {
+            let inner = group.stream().into_iter().collect::<Vec<_>>();
+            if let [TokenTree::Ident(name), TokenTree::Group(args)] = inner.as_slice() {
+                if name.to_string() == "wgsl" {
+                    for (key, value) in parse_wgsl_attr(args.stream())? {
+                        match key.as_str() {
+                            "header" => header = value,
+                            _ => return Err(compile_error(&format!("unknown option: {}", key))),
+                        }
+                    }
+                }
+            }
+            rest = tail;
+        }
+    }
+
+    let mut wgsl = String::new();
+    for item in rest.split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ';')) {
+        if item.is_empty() {
+            continue;
+        }
+
+        let (_, after_attrs) = take_attrs(item)?;
+        let decl = match skip_visibility(after_attrs) {
+            [TokenTree::Ident(kw), TokenTree::Ident(name), TokenTree::Punct(colon), decl @ ..]
+                if kw.to_string() == "const" && colon.as_char() == ':' =>
+            {
+                Some((name.to_string(), name.span(), decl))
+            }
+            _ => None,
+        };
+        let Some((name, span, decl)) = decl else {
+            return Err(compile_error(
+                "expected `const NAME: Type = value;` items in wgsl_consts!",
+            ));
+        };
+
+        let eq = decl
+            .iter()
+            .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == '='))
+            .ok_or_else(|| compile_error(&format!("expected a value for {}", name)))?;
+        let rust_ty = decl[..eq].iter().cloned().collect::<TokenStream>().to_string();
+        // Errors point at the constant, since it's otherwise valid Rust.
+        let ty = wgsl_type(&rust_ty)
+            .map_err(|msg| compile_error_at(&format!("{}: {}", name, msg), span))?;
+        if !is_builtin(&ty) {
+            let msg = format!(
+                "{}: {} has no WGSL equivalent, wgsl_consts! supports f32, i32, u32, bool, and vectors, matrices and arrays of them",
+                name, rust_ty
+            );
+            return Err(compile_error_at(&msg, span));
+        }
+
+        wgsl += &format!("const {}: {} = {};\n", name, ty, wgsl_expr(&decl[eq + 1..], &ty));
+    }
+
+    Ok(Consts {
+        header,
+        wgsl,
+        rust: tokens
+            .iter()
+            .skip(tokens.len() - rest.len())
+            .cloned()
+            .collect(),
+    })
+}
@@ -0,0 +1,408 @@
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+use crate::compile_error;
+
+/// Key/value pairs from `#[wgsl(...)]` attributes.
+type Attrs = Vec<(String, String)>;
+
+/// A field of a struct, or a variant of an enum.
+pub struct Field {
+    pub name: String,
+    /// The type for struct fields, or the discriminant for enum variants.
+    pub value: Option<String>,
+    pub attrs: Attrs,
+}
+
+/// The parts of a derive input used by the derives, i.e. no generics.
+pub struct DeriveInput {
+    pub name: String,
+    pub is_enum: bool,
+    pub attrs: Attrs,
+    pub fields: Vec<Field>,
+}
+
+fn find_attr<'a>(attrs: &'a Attrs, key: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+impl DeriveInput {
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        find_attr(&self.attrs, key)
+    }
+}
+
+impl Field {
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        find_attr(&self.attrs, key)
+    }
+}
+
+/// Parses the contents of `#[wgsl(key = "value", ...)]` into pairs.
+pub fn parse_wgsl_attr(stream: TokenStream) -> Result<Attrs, TokenStream> {
+    let mut pairs = vec![];
+    let tokens = stream.into_iter().collect::<Vec<_>>();
+    for item in tokens.split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ',')) {
+        match item {
+            [] => {}
+            [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(value)]
+                if eq.as_char() == '=' =>
+            {
+                let value = match litrs::StringLit::try_from(value) {
+                    Ok(value) => value.value().to_string(),
+                    Err(_) => value.to_string(),
+                };
+                pairs.push((key.to_string(), value));
+            }
+            _ => return Err(compile_error("expected `#[wgsl(key = \"value\", ...)]`")),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Collects `#[wgsl(...)]` attributes from the front of `tokens`, returning the rest.
+pub fn take_attrs(tokens: &[TokenTree]) -> Result<(Attrs, &[TokenTree]), TokenStream> {
+    let mut attrs = vec![];
+    let mut rest = tokens;
+    while let [TokenTree::Punct(p), TokenTree::Group(group), tail @ ..] = rest {
+        if p.as_char() != '#' || group.delimiter() != Delimiter::Bracket {
+            break;
+        }
+
+        let inner = group.stream().into_iter().collect::<Vec<_>>();
+        if let [TokenTree::Ident(name), TokenTree::Group(args)] = inner.as_slice() {
+            if name.to_string() == "wgsl" {
+                attrs.extend(parse_wgsl_attr(args.stream())?);
+            }
+        }
+        rest = tail;
+    }
+    Ok((attrs, rest))
+}
+
+/// Skips `pub`, `pub(crate)` and friends.
+pub fn skip_visibility(tokens: &[TokenTree]) -> &[TokenTree] {
+    match tokens {
+        [TokenTree::Ident(vis), TokenTree::Group(g), rest @ ..]
+            if vis.to_string() == "pub" && g.delimiter() == Delimiter::Parenthesis =>
+        {
+            rest
+        }
+        [TokenTree::Ident(vis), rest @ ..] if vis.to_string() == "pub" => rest,
+        _ => tokens,
+    }
+}
+
+/// Splits on commas outside of `<...>`, since types like `HashMap<K, V>` contain them.
+///
+/// Brackets, parentheses and braces are already groups, so only angle brackets
+/// are counted, and only in a field's type, after its `:`. A discriminant like
+/// `1 << 2` has shifts and comparisons instead, and the `>` of a `->` in a
+/// function pointer type doesn't close anything.
+fn split_fields(tokens: Vec<TokenTree>) -> Vec<Vec<TokenTree>> {
+    let mut fields = vec![vec![]];
+    let mut depth = 0;
+    let mut in_type = false;
+    let mut after_dash = false;
+    for token in tokens {
+        let arrow = std::mem::take(&mut after_dash);
+        if let TokenTree::Punct(ref p) = token {
+            match p.as_char() {
+                ':' => in_type = true,
+                '-' => after_dash = p.spacing() == Spacing::Joint,
+                '<' if in_type => depth += 1,
+                '>' if in_type && !arrow => depth -= 1,
+                ',' if depth == 0 => {
+                    fields.push(vec![]);
+                    in_type = false;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        fields.last_mut().unwrap().push(token);
+    }
+    fields.retain(|field| !field.is_empty());
+    fields
+}
+
+pub fn parse(input: TokenStream) -> Result<DeriveInput, TokenStream> {
+    let tokens = input.into_iter().collect::<Vec<_>>();
+    let (attrs, rest) = take_attrs(&tokens)?;
+    let rest = skip_visibility(rest);
+
+    let (is_enum, name, body) = match rest {
+        [TokenTree::Ident(kind), TokenTree::Ident(name), TokenTree::Group(body)]
+            if body.delimiter() == Delimiter::Brace =>
+        {
+            (kind.to_string() == "enum", name.to_string(), body.stream())
+        }
+        _ => {
+            return Err(compile_error(
+                "expected a struct with named fields or an enum without generics",
+            ))
+        }
+    };
+
+    let mut fields = vec![];
+    for field in split_fields(body.into_iter().collect()) {
+        let (field_attrs, rest) = take_attrs(&field)?;
+        let rest = skip_visibility(rest);
+        let (name, value) = match rest {
+            [TokenTree::Ident(name)] => (name.to_string(), None),
+            [TokenTree::Ident(name), TokenTree::Punct(p), value @ ..]
+                if p.as_char() == ':' || p.as_char() == '=' =>
+            {
+                let value = value.iter().cloned().collect::<TokenStream>().to_string();
+                (name.to_string(), Some(value))
+            }
+            _ => {
+                let msg = format!("unsupported field in {}", name);
+                return Err(compile_error(&msg));
+            }
+        };
+        fields.push(Field {
+            name: name.trim_start_matches("r#").to_string(),
+            value,
+            attrs: field_attrs,
+        });
+    }
+
+    Ok(DeriveInput {
+        name,
+        is_enum,
+        attrs,
+        fields,
+    })
+}
+
+/// Maps a Rust field type to WGSL.
+///
+/// Arrays of 2 to 4 scalars are vectors, and arrays of those are matrices,
+/// mirroring `include_wgsl_structs!`. `glam` vectors and matrices are also
+/// understood, and any other type is assumed to be a struct of the same name.
+pub fn wgsl_type(rust_ty: &str) -> Result<String, String> {
+    let ty = rust_ty.replace(' ', "");
+
+    fn scalar(ty: &str) -> Option<&'static str> {
+        match ty {
+            "f32" => Some("f32"),
+            "i32" => Some("i32"),
+            "u32" => Some("u32"),
+            "bool" => Some("bool"),
+            _ => None,
+        }
+    }
+
+    if let Some(scalar) = scalar(&ty) {
+        return Ok(scalar.to_string());
+    }
+
+    if let Some(inner) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+        let (elem, len) = inner
+            .rsplit_once(';')
+            .ok_or_else(|| format!("unsupported slice type: {}", rust_ty))?;
+        let len = len
+            .parse::<u32>()
+            .map_err(|_| format!("array length must be a literal: {}", rust_ty))?;
+
+        if let (Some(scalar), 2..=4) = (scalar(elem), len) {
+            return Ok(format!("vec{}<{}>", len, scalar));
+        }
+        let elem = wgsl_type(elem)?;
+        if let (Some(rows), 2..=4) = (elem.strip_prefix("vec"), len) {
+            if !rows.ends_with("<f32>") {
+                return Err(format!("matrices must be of f32: {}", rust_ty));
+            }
+            return Ok(format!("mat{}x{}", len, rows));
+        }
+        return Ok(format!("array<{}, {}>", elem, len));
+    }
+
+    // Only look at the last path segment, e.g. `glam::Vec3`.
+    let name = ty.rsplit("::").next().unwrap_or(&ty);
+    let glam = [
+        ("Vec", "f32"),
+        ("IVec", "i32"),
+        ("UVec", "u32"),
+        ("BVec", "bool"),
+    ];
+    for (prefix, scalar) in glam.iter() {
+        if let Some(n @ ("2" | "3" | "4")) = name.strip_prefix(prefix) {
+            return Ok(format!("vec{}<{}>", n, scalar));
+        }
+    }
+    if let Some(n @ ("2" | "3" | "4")) = name.strip_prefix("Mat") {
+        return Ok(format!("mat{}x{}<f32>", n, n));
+    }
+
+    if name.contains('<') || name.is_empty() {
+        return Err(format!("unsupported type: {}", rust_ty));
+    }
+    Ok(name.to_string())
+}
+
+/// Rounds `size` up to a multiple of `align`, for layouts given as Rust
+/// constant expressions.
+fn round_up(size: &str, align: &str) -> String {
+    match (size.parse::<u32>(), align.parse::<u32>()) {
+        (Ok(size), Ok(align)) => size.next_multiple_of(align).to_string(),
+        _ => format!("({}).next_multiple_of({})", size, align),
+    }
+}
+
+/// Returns the alignment and size of a host-shareable WGSL type, as Rust
+/// constant expressions of type `usize`.
+///
+/// Structs don't have a layout until they're declared, so they refer to the
+/// `WGSL_ALIGN` and `WGSL_SIZE` that `#[derive(WgslStruct)]` generates, on
+/// `rust_ty` if given, or else on the Rust type of the same name.
+pub fn wgsl_layout(wgsl_ty: &str, rust_ty: Option<&str>) -> Result<(String, String), String> {
+    let ty = wgsl_ty.replace(' ', "");
+
+    fn scalar_width(scalar: &str) -> Option<u32> {
+        match scalar {
+            "f32" | "i32" | "u32" => Some(4),
+            "f16" => Some(2),
+            _ => None,
+        }
+    }
+
+    // `vec3<f32>` and `vec3f`.
+    fn vector(ty: &str) -> Option<(u32, u32)> {
+        let rest = ty.strip_prefix("vec")?;
+        let n = rest
+            .get(..1)?
+            .parse::<u32>()
+            .ok()
+            .filter(|n| (2..=4).contains(n))?;
+        let width = match &rest[1..] {
+            "f" | "i" | "u" => 4,
+            "h" => 2,
+            scalar => scalar_width(scalar.strip_prefix('<')?.strip_suffix('>')?)?,
+        };
+        Some((if n == 3 { 4 } else { n } * width, n * width))
+    }
+
+    if ty == "bool" || ty.contains("<bool>") {
+        return Err(format!(
+            "{} isn't host-shareable, so it can't be used in buffers; use u32 instead",
+            wgsl_ty
+        ));
+    }
+    if let Some(width) = scalar_width(&ty) {
+        return Ok((width.to_string(), width.to_string()));
+    }
+    if ty.starts_with("atomic<") {
+        return Ok(("4".to_string(), "4".to_string()));
+    }
+    if let Some((align, size)) = vector(&ty) {
+        return Ok((align.to_string(), size.to_string()));
+    }
+
+    // `mat4x3<f32>` and `mat4x3f` are 4 columns of `vec3<f32>`.
+    if let Some(rest) = ty.strip_prefix("mat") {
+        let dims = rest.get(..3).and_then(|dims| dims.split_once('x'));
+        let scalar = rest.get(3..).unwrap_or_default();
+        let column = dims.and_then(|(_, rows)| vector(&format!("vec{}{}", rows, scalar)));
+        return match (dims.and_then(|(cols, _)| cols.parse::<u32>().ok()), column) {
+            (Some(cols), Some((align, size))) => Ok((
+                align.to_string(),
+                (cols * size.next_multiple_of(align)).to_string(),
+            )),
+            _ => Err(format!("unsupported matrix type: {}", wgsl_ty)),
+        };
+    }
+
+    if let Some(inner) = ty
+        .strip_prefix("array<")
+        .and_then(|ty| ty.strip_suffix('>'))
+    {
+        let (elem, len) = inner
+            .rsplit_once(',')
+            .ok_or_else(|| format!("runtime-sized arrays have no fixed size: {}", wgsl_ty))?;
+        let rust_elem = rust_ty.map(|ty| ty.replace(' ', "")).and_then(|ty| {
+            let (elem, _) = ty.strip_prefix('[')?.strip_suffix(']')?.rsplit_once(';')?;
+            Some(elem.to_string())
+        });
+        let (align, size) = wgsl_layout(elem, rust_elem.as_deref())?;
+        let stride = round_up(&size, &align);
+        let size = match (len.parse::<u32>(), stride.parse::<u32>()) {
+            (Ok(len), Ok(stride)) => (len * stride).to_string(),
+            _ => format!("{} * {}", len, stride),
+        };
+        return Ok((align, size));
+    }
+
+    if ty.contains('<') || ty.is_empty() {
+        return Err(format!("unsupported type: {}", wgsl_ty));
+    }
+    let path = rust_ty
+        .filter(|ty| !ty.trim_start().starts_with('['))
+        .unwrap_or(wgsl_ty);
+    Ok((
+        format!("<{}>::WGSL_ALIGN", path),
+        format!("<{}>::WGSL_SIZE", path),
+    ))
+}
+
+/// Converts `MaterialKind` to `MATERIAL_KIND`.
+pub fn screaming_snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut result = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        let prev_lower = i > 0 && (chars[i - 1].is_lowercase() || chars[i - 1].is_ascii_digit());
+        let next_lower = chars.get(i + 1).map(|c| c.is_lowercase()).unwrap_or(false);
+        let prev_upper = i > 0 && chars[i - 1].is_uppercase();
+        if c.is_uppercase() && (prev_lower || (prev_upper && next_lower)) {
+            result.push('_');
+        }
+        result.extend(c.to_uppercase());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wgsl_type() {
+        assert_eq!(wgsl_type("[f32; 3]").unwrap(), "vec3<f32>");
+        assert_eq!(wgsl_type("[[f32; 4]; 4]").unwrap(), "mat4x4<f32>");
+        assert_eq!(wgsl_type("glam::Vec3").unwrap(), "vec3<f32>");
+        assert_eq!(wgsl_type("[Light; 8]").unwrap(), "array<Light, 8>");
+        assert_eq!(
+            wgsl_type("[[i32; 3]; 3]").unwrap_err(),
+            "matrices must be of f32: [[i32; 3]; 3]"
+        );
+    }
+
+    #[test]
+    fn test_wgsl_layout() {
+        let layout = |ty: &str| wgsl_layout(ty, None).unwrap();
+        assert_eq!(layout("f32"), ("4".to_string(), "4".to_string()));
+        // Unlike `[f32; 3]`, which is aligned to 4.
+        assert_eq!(layout("vec3<f32>"), ("16".to_string(), "12".to_string()));
+        assert_eq!(layout("vec2f"), ("8".to_string(), "8".to_string()));
+        // Each column is padded to 16 bytes, unlike `[[f32; 3]; 3]`.
+        assert_eq!(layout("mat3x3<f32>"), ("16".to_string(), "48".to_string()));
+        assert_eq!(
+            layout("array<vec3<f32>, 4>"),
+            ("16".to_string(), "64".to_string())
+        );
+
+        assert_eq!(
+            wgsl_layout("array<Light, 2>", Some("[lights::Light; 2]")).unwrap(),
+            (
+                "<lights::Light>::WGSL_ALIGN".to_string(),
+                "2 * (<lights::Light>::WGSL_SIZE).next_multiple_of(<lights::Light>::WGSL_ALIGN)"
+                    .to_string()
+            )
+        );
+        assert!(wgsl_layout("bool", None).is_err());
+        assert!(wgsl_layout("vec3<bool>", None).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Entry point assertions, so a typo or a deleted entry point fails to compile
+//! instead of failing pipeline creation at runtime.
+//!
+//! `expect = [vertex("vs_main"), fragment("fs_main")]` lists the entry points a
+//! shader must have, along with their stages.
+
+use naga::{Module, ShaderStage};
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use crate::args::MacroArgs;
+use crate::{compile_error, parse_filename};
+
+const USAGE: &str = "expected `expect = [vertex(\"name\"), fragment(\"name\"), ...]`";
+
+/// An entry point a shader must have.
+pub struct Expected {
+    stage: ShaderStage,
+    name: String,
+}
+
+pub fn stage_name(stage: ShaderStage) -> &'static str {
+    match stage {
+        ShaderStage::Vertex => "vertex",
+        ShaderStage::Fragment => "fragment",
+        ShaderStage::Compute => "compute",
+    }
+}
+
+/// Parses `expect = [...]`, returning no entry points if the macro call doesn't have it.
+pub fn parse(args: &MacroArgs) -> Result<Vec<Expected>, TokenStream> {
+    let Some(option) = args.option("expect") else {
+        return Ok(vec![]);
+    };
+    let list = match option.value.as_slice() {
+        [TokenTree::Group(list)] if list.delimiter() == Delimiter::Bracket => list,
+        _ => return Err(compile_error(USAGE)),
+    };
+
+    let tokens = list.stream().into_iter().collect::<Vec<_>>();
+    let mut expected = vec![];
+    for item in tokens.split(|token| matches!(token, TokenTree::Punct(p) if p.as_char() == ',')) {
+        let (stage, name) = match item {
+            // Allow a trailing comma.
+            [] => continue,
+            [TokenTree::Ident(stage), TokenTree::Group(name)]
+                if name.delimiter() == Delimiter::Parenthesis =>
+            {
+                (
+                    stage.to_string(),
+                    name.stream().into_iter().collect::<Vec<_>>(),
+                )
+            }
+            _ => return Err(compile_error(USAGE)),
+        };
+        let stage = match stage.as_str() {
+            "vertex" => ShaderStage::Vertex,
+            "fragment" => ShaderStage::Fragment,
+            "compute" => ShaderStage::Compute,
+            _ => {
+                let msg = format!(
+                    "unknown stage `{}`, expected vertex, fragment or compute",
+                    stage
+                );
+                return Err(compile_error(&msg));
+            }
+        };
+        let name = match name.as_slice() {
+            [token] => parse_filename(token)?,
+            _ => return Err(compile_error(USAGE)),
+        };
+        expected.push(Expected { stage, name });
+    }
+    Ok(expected)
+}
+
+/// Errors if `module` is missing any of the `expected` entry points, or has
+/// them with other stages, listing the entry points it has.
+pub fn check(expected: &[Expected], filename: &str, module: &Module) -> Result<(), TokenStream> {
+    check_entry_points(expected, filename, module).map_err(|msg| compile_error(&msg))
+}
+
+/// Checks the entry points like `check`, returning the message.
+fn check_entry_points(
+    expected: &[Expected],
+    filename: &str,
+    module: &Module,
+) -> Result<(), String> {
+    let mut problems = vec![];
+    for expected in expected.iter() {
+        let stage = stage_name(expected.stage);
+        match module
+            .entry_points
+            .iter()
+            .find(|entry_point| entry_point.name == expected.name)
+        {
+            None => problems.push(format!("no {} entry point `{}`", stage, expected.name)),
+            Some(entry_point) if entry_point.stage != expected.stage => problems.push(format!(
+                "`{}` is a {} entry point, not {}",
+                expected.name,
+                stage_name(entry_point.stage),
+                stage
+            )),
+            Some(_) => {}
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let entry_points = module
+        .entry_points
+        .iter()
+        .map(|entry_point| format!("{} ({})", entry_point.name, stage_name(entry_point.stage)))
+        .collect::<Vec<_>>();
+    Err(format!(
+        "{}: {}. Its entry points are: {}",
+        filename,
+        problems.join("; "),
+        match entry_points.is_empty() {
+            true => "none".to_string(),
+            false => entry_points.join(", "),
+        }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    const SHADER: &str = "
+@vertex
+fn vs_main() -> @builtin(position) vec4<f32> { return vec4<f32>(0.0); }
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> { return vec4<f32>(1.0); }
+";
+
+    fn expected(stage: ShaderStage, name: &str) -> Expected {
+        Expected {
+            stage,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_entry_points() {
+        let (module, _) = validate(SHADER).unwrap();
+        let check = |expected: &[Expected]| check_entry_points(expected, "shader.wgsl", &module);
+
+        assert_eq!(check(&[]), Ok(()));
+        assert_eq!(
+            check(&[
+                expected(ShaderStage::Vertex, "vs_main"),
+                expected(ShaderStage::Fragment, "fs_main"),
+            ]),
+            Ok(())
+        );
+
+        assert_eq!(
+            check(&[
+                expected(ShaderStage::Compute, "cs_main"),
+                expected(ShaderStage::Vertex, "fs_main"),
+            ]),
+            Err(
+                "shader.wgsl: no compute entry point `cs_main`; `fs_main` is a fragment entry \
+                point, not vertex. Its entry points are: vs_main (vertex), fs_main (fragment)"
+                    .to_string()
+            )
+        );
+
+        let (empty, _) = validate("fn f() {}").unwrap();
+        assert_eq!(
+            check_entry_points(
+                &[expected(ShaderStage::Vertex, "vs_main")],
+                "empty.wgsl",
+                &empty
+            ),
+            Err(
+                "empty.wgsl: no vertex entry point `vs_main`. Its entry points are: none"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,5 @@
+/// Hashes bytes with 128-bit XXH3, which is stable across platforms and
+/// releases, and wide enough to key caches by.
+pub fn xxh3_128(bytes: &[u8]) -> u128 {
+    xxhash_rust::xxh3::xxh3_128(bytes)
+}
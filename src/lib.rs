@@ -1,107 +1,1826 @@
 #![feature(proc_macro_span)]
+#![feature(proc_macro_diagnostic)]
 
 extern crate proc_macro;
 
-mod preprocessor;
+mod args;
+mod bounds;
+mod budget;
+mod cache;
+mod consts;
+mod derive;
+mod expect;
+mod hash;
+mod manifest;
+mod parallel;
+mod reflection;
+mod split;
+mod stats;
+mod variants;
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use naga::front::wgsl::Frontend;
-use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::valid::{Capabilities, ModuleInfo, ValidationFlags, Validator};
+use naga::Module;
 
+use args::MacroArgs;
+use budget::{file_sizes, Budget};
 use litrs::Literal;
-use preprocessor::{preprocess, PreprocessorError};
-use proc_macro::{Span, TokenStream, TokenTree};
+use proc_macro::{Group, Span, TokenStream, TokenTree};
+use wgpu_pp_runtime::{
+    declarations, depfile, enables_f16, f16_fallback, parse_defines, preprocess_bundle_with_info,
+    preprocess_with_defines, register_crate_directives, register_virtual_header, split_stages,
+    virtual_header, Docs, GroupOffset, PreprocessorError, SourceInfo, DIRECTIVES_FILE,
+    PROFILE_DEFINE,
+};
+
+/// Braces allow this in both expression and item position.
+fn compile_error(msg: &str) -> TokenStream {
+    format!("compile_error! {{ {:?} }}", msg).parse().unwrap()
+}
+
+/// Like `compile_error`, but reported at `span` instead of the macro call.
+fn compile_error_at(msg: &str, span: Span) -> TokenStream {
+    fn respan(stream: TokenStream, span: Span) -> TokenStream {
+        stream
+            .into_iter()
+            .map(|token| match token {
+                TokenTree::Group(group) => {
+                    let mut group = Group::new(group.delimiter(), respan(group.stream(), span));
+                    group.set_span(span);
+                    TokenTree::Group(group)
+                }
+                mut token => {
+                    token.set_span(span);
+                    token
+                }
+            })
+            .collect()
+    }
+    respan(compile_error(msg), span)
+}
 
-fn validate_wgsl(wgsl_source: &str) -> Result<(), TokenStream> {
+/// Returns the capabilities and flags shaders are validated with.
+fn validation_options() -> (Capabilities, ValidationFlags) {
+    // Push constants are allowed here, since whether they're supported depends on the device.
+    (
+        Capabilities::default() | Capabilities::PUSH_CONSTANT,
+        ValidationFlags::all(),
+    )
+}
+
+/// Parses and validates the source, returning the error message on failure.
+fn validate(wgsl_source: &str) -> Result<(Module, ModuleInfo), String> {
     let mut frontend = Frontend::new();
-    let module = frontend.parse(wgsl_source).map_err(|e| {
-        let msg = format!("failed to parse WGSL: {}", e.emit_to_string(wgsl_source));
-        format!("compile_error!(\"{}\")", msg)
-            .parse::<TokenStream>()
-            .unwrap()
-    })?;
+    let module = frontend
+        .parse(wgsl_source)
+        .map_err(|e| format!("failed to parse WGSL: {}", e.emit_to_string(wgsl_source)))?;
 
-    let mut validator = Validator::new(ValidationFlags::all(), Capabilities::default());
-    validator.validate(&module).map_err(|e| {
-        let msg = format!("failed to validate WGSL: {}", e.emit_to_string(wgsl_source));
-        format!("compile_error!(\"{}\")", msg)
-            .parse::<TokenStream>()
-            .unwrap()
-    })?;
+    let (capabilities, flags) = validation_options();
+    let mut validator = Validator::new(flags, capabilities);
+    let info = validator
+        .validate(&module)
+        .map_err(|e| format!("failed to validate WGSL: {}", e.emit_to_string(wgsl_source)))?;
 
-    Ok(())
+    Ok((module, info))
 }
 
-#[proc_macro]
-pub fn include_wgsl(input: TokenStream) -> TokenStream {
-    let input = input.into_iter().collect::<Vec<_>>();
-    if input.len() != 1 {
-        let msg = format!("expected exactly one input token, got {}", input.len());
-        return format!("compile_error!(\"{}\")", msg).parse().unwrap();
+fn validate_wgsl(wgsl_source: &str) -> Result<(Module, ModuleInfo), TokenStream> {
+    validate(wgsl_source).map_err(|msg| compile_error(&msg))
+}
+
+/// Validates the source like `validate`, for macros that don't need the
+/// module, skipping sources that an earlier build validated.
+fn check(wgsl_source: &str) -> Result<(), String> {
+    let (capabilities, flags) = validation_options();
+    if cache::is_validated(wgsl_source, capabilities, flags) {
+        return Ok(());
     }
+    validate(wgsl_source)?;
+    cache::record_validated(wgsl_source, capabilities, flags);
+    Ok(())
+}
+
+fn check_wgsl(wgsl_source: &str) -> Result<(), TokenStream> {
+    check(wgsl_source).map_err(|msg| compile_error(&msg))
+}
+
+/// Returns the source with bounds checks, for the `bounds_checks` option, see
+/// `bounds::inject`.
+fn bounds_checked(wgsl_source: &str, module: &Module) -> Result<String, TokenStream> {
+    let checked = bounds::inject(wgsl_source, module);
+    check(&checked).map_err(|msg| compile_error(&format!("with bounds checks, {}", msg)))?;
+    Ok(checked)
+}
 
+/// Returns the directory of the file the macro was invoked from, which `path`
+/// is relative to.
+///
+/// rust-analyzer doesn't say which file that is, so the directory is looked up
+/// instead, as the first one in the crate where `path` exists. That way IDE
+/// expansions have the real source, unless the crate has several files at `path`.
+fn call_site_dir(path: &str) -> Option<PathBuf> {
     let call_site = Span::call_site();
     let source_path = call_site.source_file().path();
-    let basepath = match source_path.parent() {
+    if let Some(dir) = source_path.parent() {
+        return Some(dir.to_path_buf());
+    }
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR")?);
+    find_dir(&manifest_dir, path)
+}
+
+/// Returns the crate's directory, or the current directory if cargo didn't say.
+fn manifest_dir() -> PathBuf {
+    std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// Returns the directory the paths of a macro call are relative to: its
+/// `base = "dir"`, relative to the crate's directory, or else the directory of
+/// the file it was invoked from, see `call_site_dir`.
+///
+/// `base` is for calls from `macro_rules!` wrappers or generated code, whose
+/// call site isn't the file the paths are written relative to.
+fn base_dir(args: &MacroArgs) -> Result<Option<PathBuf>, TokenStream> {
+    let Some(option) = args.option("base") else {
+        return Ok(call_site_dir(&args.filenames[0]));
+    };
+    match option.value.as_slice() {
+        [token] => Ok(Some(manifest_dir().join(parse_filename(token)?))),
+        _ => Err(compile_error("expected `base = \"dir\"`")),
+    }
+}
+
+/// Returns the file of the `defines = "file.toml"` option, relative to the
+/// shader's directory.
+fn config_path(args: &MacroArgs, basepath: &Path) -> Result<Option<PathBuf>, TokenStream> {
+    let Some(option) = args.option("defines") else {
+        return Ok(None);
+    };
+    match option.value.as_slice() {
+        [token] => Ok(Some(basepath.join(parse_filename(token)?))),
+        _ => Err(compile_error("expected `defines = \"file.toml\"`")),
+    }
+}
+
+/// Reads the defines of the `defines` option, like `#include_defines`.
+fn config_defines(path: Option<&Path>) -> Result<Vec<(String, String)>, TokenStream> {
+    let Some(path) = path else {
+        return Ok(vec![]);
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        compile_error(&format!("failed to read {}: {}", path.to_string_lossy(), e))
+    })?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let defines = parse_defines(extension, &contents)
+        .map_err(|e| compile_error(&format!("{}: {}", path.to_string_lossy(), e)))?;
+    if let Some((name, _)) = defines.iter().find(|(_, value)| value.contains(['\n', '\r'])) {
+        return Err(compile_error(&format!(
+            "{}: {}: values can't span lines",
+            path.to_string_lossy(),
+            name
+        )));
+    }
+    Ok(defines)
+}
+
+/// Returns the shallowest directory under `root` where `path` exists, skipping
+/// `target` and hidden directories.
+fn find_dir(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut dirs = VecDeque::from([root.to_path_buf()]);
+    while let Some(dir) = dirs.pop_front() {
+        if dir.join(path).exists() {
+            return Some(dir);
+        }
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut subdirs = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                path.is_dir() && name != "target" && !name.starts_with('.')
+            })
+            .collect::<Vec<_>>();
+        subdirs.sort();
+        dirs.extend(subdirs);
+    }
+    None
+}
+
+/// Returns a path with `/` separators, for paths ending up in the expanded code.
+fn forward_slashes(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+/// Returns the path of a file read while preprocessing, relative to the crate
+/// being compiled if it's inside it, with `/` separators.
+///
+/// Paths files are read from depend on the directory cargo ran in, and are
+/// absolute for dependencies, so they can't end up in the expanded code as is
+/// without making builds irreproducible.
+fn portable_path(path: &Path) -> String {
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let relative = manifest_dir
+        .and_then(|dir| dir.canonicalize().ok())
+        .zip(path.canonicalize().ok())
+        .and_then(|(dir, path)| path.strip_prefix(dir).ok().map(Path::to_path_buf));
+    forward_slashes(&relative.as_deref().unwrap_or(path).to_string_lossy())
+}
+
+fn parse_filename(token: &TokenTree) -> Result<String, TokenStream> {
+    match Literal::try_from(token) {
+        Ok(Literal::String(str)) => Ok(str.value().to_string()),
+        // Error if the token is not a string literal
+        Err(e) => Err(e.to_compile_error()),
+        _ => Err(compile_error("expected a string literal")),
+    }
+}
+
+fn preprocessor_message(e: PreprocessorError, basepath: &Path) -> String {
+    match e {
+        PreprocessorError::FileNotFound(filename) => format!(
+            "file not found: {}",
+            basepath.join(filename).to_string_lossy()
+        ),
+        e => e.to_string(),
+    }
+}
+
+/// A preprocessed and validated shader.
+struct Shader {
+    filename: String,
+    source: String,
+    docs: Docs,
+    group_offsets: Vec<GroupOffset>,
+    dependencies: Vec<PathBuf>,
+    define_values: Vec<(String, String)>,
+    source_map: Vec<(PathBuf, usize)>,
+    module: Module,
+    info: ModuleInfo,
+}
+
+impl Shader {
+    /// Checks the shader against the global size budget, for macros embedding its source.
+    fn check_budget(&self) -> Result<(), TokenStream> {
+        let sizes = file_sizes(&self.source, &self.source_map);
+        Budget::check(Budget::global(), &self.filename, &self.source, sizes)
+    }
+}
+
+/// Defines `WGPU_PP_PROFILE` from the environment variable of the same name, if it's set.
+///
+/// Cargo doesn't track the variable, so changing it needs a rebuild.
+fn profile_defines() -> Vec<(String, String)> {
+    match std::env::var(PROFILE_DEFINE) {
+        Ok(profile) if !profile.is_empty() => vec![(PROFILE_DEFINE.to_string(), profile)],
+        _ => vec![],
+    }
+}
+
+/// The directory to write a Makefile-style `.d` file per shader to, if any.
+const DEPFILE_DIR: &str = "WGPU_PP_DEPFILE_DIR";
+
+/// Writes a `.d` file listing the files a shader was preprocessed from, if
+/// `WGPU_PP_DEPFILE_DIR` is set.
+///
+/// The file is named after the shader's path, e.g. `src_shaders_water.wgsl.d`,
+/// and paths in it are absolute. Cargo doesn't track the variable, so changing
+/// it needs a rebuild.
+fn write_depfile(filename: &str, basepath: &Path, dependencies: &[PathBuf]) -> Result<(), String> {
+    let dir = match std::env::var_os(DEPFILE_DIR) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => return Ok(()),
+    };
+
+    let absolute = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let shader = absolute(&basepath.join(filename));
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|dir| shader.strip_prefix(absolute(&dir)).ok().map(PathBuf::from))
+        .unwrap_or_else(|| shader.clone());
+    let name = relative
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("_");
+
+    let dependencies = dependencies
+        .iter()
+        .map(|path| absolute(path))
+        .collect::<Vec<_>>();
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| {
+            std::fs::write(
+                dir.join(format!("{}.d", name)),
+                depfile(&shader, &dependencies),
+            )
+        })
+        .map_err(|e| format!("failed to write depfile to {}: {}", dir.display(), e))
+}
+
+/// Registers the custom directives in the crate's `wgpu_pp_directives.toml`,
+/// if it has one, since the macros can't see `register_directive` calls.
+/// Returns its path, so it's tracked like the files the shader includes.
+///
+/// Its commands only run with `WGPU_PP_ALLOW_COMMANDS=1`, see
+/// `register_crate_directives`.
+fn crate_directives() -> Result<Option<PathBuf>, String> {
+    let path = manifest_dir().join(DIRECTIVES_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    register_crate_directives(&path)?;
+    Ok(Some(path))
+}
+
+/// Preprocesses a shader as if it started with a `#define` for each of
+/// `defines` and the profile, writing its depfile and manifest entry.
+///
+/// Errors are messages rather than `compile_error!`s, so this also runs off
+/// the macro's thread, where the proc macro API can't be used.
+fn preprocess_source(
+    filename: &str,
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<(String, SourceInfo), String> {
+    let mut defines = defines.to_vec();
+    defines.extend(profile_defines());
+    let directives = crate_directives()?;
+    let (source, mut source_info) = preprocess_with_defines(filename, basepath, &defines)
+        .map_err(|e| preprocessor_message(e, basepath))?;
+    source_info.dependencies.extend(directives);
+    if proc_macro::is_available() {
+        warn_defines(&source_info);
+    }
+    write_depfile(filename, basepath, &source_info.dependencies)?;
+    manifest::record(
+        manifest::manifest_path().as_deref(),
+        &basepath.join(filename),
+        &source,
+        &source_info.dependencies,
+    )?;
+    Ok((source, source_info))
+}
+
+/// Warns about the defines shadowing WGSL names or declarations, see
+/// `SourceInfo::warnings`. Headers are usually included by several shaders,
+/// so each warning is only emitted once per compiler session.
+fn warn_defines(source_info: &SourceInfo) {
+    static WARNED: Mutex<Vec<(PathBuf, usize, String)>> = Mutex::new(Vec::new());
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    for warning in &source_info.warnings {
+        if warned.contains(warning) {
+            continue;
+        }
+        let (path, line, message) = warning;
+        let msg = format!("{}:{}: {}", path.display(), line, message);
+        Span::call_site().warning(msg).emit();
+        warned.push(warning.clone());
+    }
+}
+
+/// Preprocesses the shaders of a bundle for `include_wgsl_bundle!`, writing
+/// the depfile of each, like `preprocess_source`, and its entry in `manifest`.
+fn preprocess_bundle_sources(
+    filenames: &[String],
+    basepath: &Path,
+    manifest: Option<&Path>,
+) -> Result<Vec<String>, String> {
+    let directives = crate_directives()?;
+    let results = preprocess_bundle_with_info(filenames, basepath, &profile_defines())
+        .map_err(|e| preprocessor_message(e, basepath))?;
+    let mut sources = vec![];
+    for (filename, (source, mut source_info)) in filenames.iter().zip(results) {
+        source_info.dependencies.extend(directives.clone());
+        if proc_macro::is_available() {
+            warn_defines(&source_info);
+        }
+        write_depfile(filename, basepath, &source_info.dependencies)?;
+        manifest::record(
+            manifest,
+            &basepath.join(filename),
+            &source,
+            &source_info.dependencies,
+        )?;
+        sources.push(source);
+    }
+    Ok(sources)
+}
+
+fn preprocess_shader(
+    filename: &str,
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<(String, SourceInfo), TokenStream> {
+    preprocess_source(filename, basepath, defines).map_err(|msg| compile_error(&msg))
+}
+
+/// Preprocesses and checks a shader, for macros that only need its source.
+///
+/// Like `preprocess_source`, errors are messages.
+fn load_source(
+    filename: &str,
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<(String, SourceInfo), String> {
+    let (source, source_info) = preprocess_source(filename, basepath, defines)?;
+    check(&source)?;
+    Ok((source, source_info))
+}
+
+fn load_shader(filename: &str, basepath: &Path) -> Result<Shader, TokenStream> {
+    load_shader_with_defines(filename, basepath, &[])
+}
+
+/// Loads a shader as if it started with a `#define` for each of `defines`.
+///
+/// Generating code from the shader needs the `ModuleInfo` of validating it,
+/// so unlike `load_source`, it's validated even if an earlier build did.
+fn load_shader_with_defines(
+    filename: &str,
+    basepath: &Path,
+    defines: &[(String, String)],
+) -> Result<Shader, TokenStream> {
+    let (source, source_info) = preprocess_shader(filename, basepath, defines)?;
+    let (module, info) = validate_wgsl(&source)?;
+
+    Ok(Shader {
+        filename: forward_slashes(filename),
+        source,
+        docs: source_info.docs,
+        group_offsets: source_info.group_offsets,
+        dependencies: source_info.dependencies,
+        define_values: source_info.define_values,
+        source_map: source_info.source_map,
+        module,
+        info,
+    })
+}
+
+/// Loads the shader for macros taking exactly one path.
+///
+/// Returns `None` when the call site can't be resolved, i.e. in rust-analyzer
+/// when the file isn't found in the crate either.
+fn load_single_shader(input: TokenStream) -> Result<Option<Shader>, TokenStream> {
+    let args = MacroArgs::parse(input)?;
+    args.check_options(&["base", "expect"])?;
+    load_args_shader(&args)
+}
+
+/// Like `load_single_shader`, for macros with options of their own, which
+/// check the options themselves.
+fn load_args_shader(args: &MacroArgs) -> Result<Option<Shader>, TokenStream> {
+    let [ref filename] = args.filenames[..] else {
+        return Err(compile_error("expected exactly one path"));
+    };
+    let expected = expect::parse(args)?;
+    let basepath = match base_dir(args)? {
         Some(p) => p,
-        _ => {
-            // This happens in the Rust Analyzer, just let it go...
-            return "\"\"".parse().unwrap();
+        _ => return Ok(None),
+    };
+
+    let config = config_path(args, &basepath)?;
+    let defines = config_defines(config.as_deref())?;
+    let mut shader = load_shader_with_defines(filename, &basepath, &defines)?;
+    shader.dependencies.extend(config);
+    expect::check(&expected, filename, &shader.module)?;
+    Ok(Some(shader))
+}
+
+#[proc_macro]
+pub fn include_wgsl(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["budget", "base", "expect", "bounds_checks", "defines"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+    let budget = match Budget::for_args(&args) {
+        Ok(budget) => budget,
+        Err(e) => return e,
+    };
+    let expected = match expect::parse(&args) {
+        Ok(expected) => expected,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return "\"\"".parse().unwrap(),
+        Err(e) => return e,
+    };
+    let defines = match config_path(&args, &basepath)
+        .and_then(|config| config_defines(config.as_deref()))
+    {
+        Ok(defines) => defines,
+        Err(e) => return e,
+    };
+
+    let source = match load_source(filename, &basepath, &defines) {
+        Ok((source, source_info)) => {
+            let sizes = file_sizes(&source, &source_info.source_map);
+            if let Err(e) = Budget::check(budget, filename, &source, sizes) {
+                return e;
+            }
+            source
         }
+        Err(msg) => return compile_error(&msg),
     };
 
-    let filename = match Literal::try_from(&input[0]) {
-        Ok(Literal::String(str)) => str.value().to_string(),
-        // Error if the token is not a string literal
-        Err(e) => return e.to_compile_error(),
-        _ => {
-            return "compile_error!(\"expected a string literal\")"
-                .to_string()
-                .parse()
-                .unwrap();
-        }
-    };
-
-    let shader = match preprocess(&filename, basepath) {
-        Ok(value) => value,
-        Err(e) => match e {
-            PreprocessorError::FileNotFound(filename) => {
-                let msg = format!(
-                    "file not found: {}",
-                    basepath.join(filename).to_string_lossy()
-                );
-                return format!("compile_error!(\"{}\")", msg).parse().unwrap();
+    let bounds_checks = args.flag("bounds_checks");
+    if expected.is_empty() && !bounds_checks {
+        return TokenTree::Literal(proc_macro::Literal::string(&source)).into();
+    }
+
+    // Checking the source may not have parsed it, if an earlier build
+    // validated it, so it's parsed again for its entry points and arrays.
+    let module = match naga::front::wgsl::parse_str(&source) {
+        Ok(module) => module,
+        Err(e) => return compile_error(&e.emit_to_string(&source)),
+    };
+    if let Err(e) = expect::check(&expected, filename, &module) {
+        return e;
+    }
+    if !bounds_checks {
+        return TokenTree::Literal(proc_macro::Literal::string(&source)).into();
+    }
+
+    let checked = match bounds_checked(&source, &module) {
+        Ok(checked) => checked,
+        Err(e) => return e,
+    };
+    format!(
+        r#"{{
+            #[cfg(debug_assertions)]
+            let source = {checked:?};
+            #[cfg(not(debug_assertions))]
+            let source = {source:?};
+            source
+        }}"#,
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Generates the merged pipeline layout for the shaders of one pipeline.
+///
+/// Takes one or more shaders (e.g. a vertex and a fragment shader, or a single
+/// compute shader) and expands to a value with `bind_group_layouts` and
+/// `push_constant_ranges`, along with a `create` method for building the
+/// `wgpu::PipelineLayout`. Stages sharing a binding must agree on its type.
+///
+/// The descriptors are labelled with the shader paths, and bind group layouts
+/// with their group, e.g. `shader.wgsl @group(1)`.
+///
+/// Options:
+/// - `label = "name"`: use `name` instead of the shader paths.
+/// - `label = false`: leave the labels empty.
+///
+/// ```ignore
+/// let layout = include_wgsl_pipeline_layout!("shader.vert.wgsl", "shader.frag.wgsl");
+/// let (bind_group_layouts, pipeline_layout) = layout.create(&device);
+/// ```
+#[proc_macro]
+pub fn include_wgsl_pipeline_layout(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["label", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+
+    let label = match args.option("label").map(|option| option.value.as_slice()) {
+        None => Some(forward_slashes(&args.filenames.join(", "))),
+        Some([TokenTree::Ident(value)]) if value.to_string() == "false" => None,
+        Some([token]) => match parse_filename(token) {
+            Ok(label) => Some(label),
+            Err(e) => return e,
+        },
+        Some(_) => return compile_error("expected `label = \"name\"` or `label = false`"),
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+
+    let mut shaders = vec![];
+    for filename in args.filenames.iter() {
+        match load_shader(filename, &basepath) {
+            Ok(shader) => shaders.push(shader),
+            Err(e) => return e,
+        }
+    }
+
+    match reflection::pipeline_layout(&shaders, label.as_deref()) {
+        Ok(layout) => layout.parse().unwrap(),
+        Err(msg) => compile_error(&msg),
+    }
+}
+
+/// Generates `#[repr(C)]` Rust structs for the host-shareable structs of a shader.
+///
+/// Padding between fields follows WGSL's layout rules and is made explicit, so
+/// the structs derive `bytemuck::Pod` and `bytemuck::Zeroable` (the calling
+/// crate needs `bytemuck` with the `derive` feature). Matrices with three rows
+/// and arrays of `vec3` are padded to four components.
+///
+/// Options:
+/// - `encase`: derive `encase::ShaderType` instead, using `glam` types for
+///   vectors and matrices and leaving the padding to encase.
+/// - `map(Name = path::to::Type)`: use an existing Rust type for a WGSL struct,
+///   asserting that its size matches instead of generating it.
+///
+/// ```ignore
+/// include_wgsl_structs!("shader.wgsl");
+///
+/// let uniforms = Uniforms::new(view_proj, [0.0, 1.0, 0.0]);
+/// queue.write_buffer(&buffer, 0, bytemuck::bytes_of(&uniforms));
+/// ```
+#[proc_macro]
+pub fn include_wgsl_structs(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["encase", "map", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    if args.filenames.len() != 1 {
+        return compile_error("expected exactly one path");
+    }
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+
+    let options = reflection::StructOptions {
+        encase: args.flag("encase"),
+        map: match args.option("map").map(|map| map.pairs()).transpose() {
+            Ok(map) => map.unwrap_or_default().into_iter().collect(),
+            Err(e) => return e,
+        },
+    };
+
+    let shader = match load_shader(&args.filenames[0], &basepath) {
+        Ok(shader) => shader,
+        Err(e) => return e,
+    };
+
+    match reflection::structs(&shader, &options) {
+        Ok(structs) => structs.parse().unwrap(),
+        Err(msg) => compile_error(&msg),
+    }
+}
+
+/// Checks at compile time that a Rust struct matches the layout of a WGSL struct.
+///
+/// The shader path is relative to the current file, or to `base = "dir"` in
+/// the crate, like `include_wgsl!`. Each WGSL member needs a Rust field of
+/// the same name and offset, and the sizes need to match. Extra Rust fields
+/// must start with `_`, for padding.
+///
+/// ```ignore
+/// #[repr(C)]
+/// #[derive(WgslCompatible)]
+/// #[wgsl(shader = "shader.wgsl", struct = "Uniforms")]
+/// struct Uniforms {
+///     view_proj: [[f32; 4]; 4],
+///     time: f32,
+///     _padding: [f32; 3],
+/// }
+/// ```
+#[proc_macro_derive(WgslCompatible, attributes(wgsl))]
+pub fn derive_wgsl_compatible(input: TokenStream) -> TokenStream {
+    let input = match derive::parse(input) {
+        Ok(input) => input,
+        Err(e) => return e,
+    };
+    if input.is_enum {
+        return compile_error("WgslCompatible can only be derived for structs");
+    }
+
+    let filename = match input.attr("shader") {
+        Some(filename) => filename,
+        None => return compile_error("expected #[wgsl(shader = \"...\")]"),
+    };
+    let wgsl_name = input.attr("struct").unwrap_or(&input.name);
+
+    let basepath = match input.attr("base") {
+        Some(base) => manifest_dir().join(base),
+        None => match call_site_dir(filename) {
+            Some(p) => p,
+            _ => return TokenStream::new(),
+        },
+    };
+
+    let shader = match load_shader(filename, &basepath) {
+        Ok(shader) => shader,
+        Err(e) => return e,
+    };
+
+    let fields = input
+        .fields
+        .iter()
+        .map(|field| field.name.clone())
+        .collect::<Vec<_>>();
+    match reflection::check_struct(&shader, wgsl_name, &input.name, &fields) {
+        Ok(assertions) => assertions.parse().unwrap(),
+        Err(msg) => compile_error(&msg),
+    }
+}
+
+/// Generates the WGSL declaration of a Rust struct, so Rust is the source of truth.
+///
+/// The declaration is available as `Name::WGSL`, and as a virtual header that
+/// shaders can `#include <Name.wgsl>`. The header name can be changed with
+/// `#[wgsl(header = "...")]`, and field types with `#[wgsl(type = "...")]`.
+/// Fields starting with `_` are treated as padding and skipped.
+///
+/// Virtual headers only exist once the derive has expanded, so the struct
+/// needs to come before any `include_wgsl!` using it in the same crate.
+///
+/// The offset of each field and the size of the struct are checked against
+/// WGSL's layout at compile time, and are also available as `Name::WGSL_ALIGN`
+/// and `Name::WGSL_SIZE`. Rust aligns `[f32; 3]` to 4 bytes where WGSL aligns
+/// `vec3<f32>` to 16, so the struct needs `#[repr(C)]` and padding fields to
+/// match. Fields of other struct types need the derive too, for their layout.
+/// `bool` can't be used in buffers, so it's an error.
+///
+/// ```ignore
+/// #[repr(C)]
+/// #[derive(WgslStruct)]
+/// struct Light {
+///     position: [f32; 3],
+///     intensity: f32,
+/// }
+/// ```
+#[proc_macro_derive(WgslStruct, attributes(wgsl))]
+pub fn derive_wgsl_struct(input: TokenStream) -> TokenStream {
+    let input = match derive::parse(input) {
+        Ok(input) => input,
+        Err(e) => return e,
+    };
+    if input.is_enum {
+        return compile_error("WgslStruct can only be derived for structs");
+    }
+
+    let mut members = vec![];
+    let mut aligns = vec![];
+    // Statements computing the end of each member in WGSL, and checking the
+    // offset of the matching Rust field on the way.
+    let mut ends = vec![];
+    let mut checks = vec![];
+    for field in input.fields.iter() {
+        if field.name.starts_with('_') {
+            continue;
+        }
+
+        let ty = match (field.attr("type"), &field.value) {
+            (Some(ty), _) => ty.to_string(),
+            (None, Some(ty)) => match derive::wgsl_type(ty) {
+                Ok(ty) => ty,
+                Err(msg) => return compile_error(&format!("{}.{}: {}", input.name, field.name, msg)),
+            },
+            (None, None) => return compile_error("WgslStruct requires named fields"),
+        };
+        let (align, size) = match derive::wgsl_layout(&ty, field.value.as_deref()) {
+            Ok(layout) => layout,
+            Err(msg) => return compile_error(&format!("{}.{}: {}", input.name, field.name, msg)),
+        };
+        members.push(format!("    {}: {},", field.name, ty));
+
+        ends.push(format!(
+            "let end = end.next_multiple_of({}) + {};",
+            align, size
+        ));
+        checks.push(format!(
+            "let offset = end.next_multiple_of({align}); assert!(::core::mem::offset_of!({name}, {ident}) == offset, \"offset of {name}.{field} doesn't match WGSL, add padding fields before it\"); let end = offset + {size};",
+            align = align,
+            size = size,
+            name = input.name,
+            ident = reflection::rust_ident(&field.name),
+            field = field.name,
+        ));
+        aligns.push(format!("if {a} > align {{ align = {a}; }}", a = align));
+    }
+
+    let wgsl = format!("struct {} {{\n{}\n}}\n", input.name, members.join("\n"));
+    let header = input
+        .attr("header")
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.wgsl", input.name));
+    register_virtual_header(&header, &wgsl);
+
+    // Rust lays out `[f32; 3]` with an alignment of 4 where WGSL aligns
+    // `vec3<f32>` to 16, so the Rust struct has to be padded to match. Every
+    // offset is checked at compile time, like `WgslCompatible` does.
+    format!(
+        r#"
+        impl {name} {{
+            pub const WGSL: &'static str = {wgsl:?};
+            /// The alignment of the struct in WGSL, in bytes.
+            pub const WGSL_ALIGN: usize = {{
+                let mut align = 1;
+                {aligns}
+                align
+            }};
+            /// The size of the struct in WGSL, in bytes.
+            pub const WGSL_SIZE: usize = {{
+                let end = 0usize;
+                {ends}
+                end.next_multiple_of(Self::WGSL_ALIGN)
+            }};
+        }}
+
+        const _: () = {{
+            let end = 0usize;
+            {checks}
+            assert!(::core::mem::size_of::<{name}>() == end.next_multiple_of({name}::WGSL_ALIGN), "size of {name} doesn't match WGSL, add padding fields at the end");
+        }};
+        "#,
+        name = input.name,
+        wgsl = wgsl,
+        aligns = aligns.join("\n"),
+        ends = ends.join("\n"),
+        checks = checks.join("\n"),
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Defines constants shared between Rust and WGSL.
+///
+/// The constants are emitted as-is for Rust, and as a virtual header that
+/// shaders can `#include <consts.wgsl>`. The header name can be changed with
+/// `#![wgsl(header = "...")]` at the top. Like derived structs, the header only
+/// exists once the macro has expanded, so it needs to come first in the crate.
+/// Types without a WGSL equivalent, like `usize`, are an error.
+///
+/// ```ignore
+/// wgsl_consts! {
+///     #![wgsl(header = "lighting.wgsl")]
+///     pub const MAX_LIGHTS: u32 = 16;
+///     pub const AMBIENT: [f32; 3] = [0.1, 0.1, 0.1];
+/// }
+/// ```
+#[proc_macro]
+pub fn wgsl_consts(input: TokenStream) -> TokenStream {
+    let consts = match consts::parse(input) {
+        Ok(consts) => consts,
+        Err(e) => return e,
+    };
+
+    register_virtual_header(&consts.header, &consts.wgsl);
+    consts.rust
+}
+
+/// Shares the discriminants of a Rust enum with WGSL.
+///
+/// Each variant becomes a `const MATERIAL_KIND_METAL: u32 = 1;` declaration in
+/// a virtual header that shaders can `#include <MaterialKind.wgsl>`, also
+/// available as `MaterialKind::WGSL`. The header name and the prefix can be
+/// changed with `#[wgsl(header = "...", prefix = "...")]`.
+///
+/// ```ignore
+/// #[derive(WgslEnum)]
+/// #[repr(u32)]
+/// enum MaterialKind {
+///     Diffuse,
+///     Metal = 4,
+///     Glass,
+/// }
+/// ```
+#[proc_macro_derive(WgslEnum, attributes(wgsl))]
+pub fn derive_wgsl_enum(input: TokenStream) -> TokenStream {
+    let input = match derive::parse(input) {
+        Ok(input) => input,
+        Err(e) => return e,
+    };
+    if !input.is_enum {
+        return compile_error("WgslEnum can only be derived for enums");
+    }
+
+    let prefix = input
+        .attr("prefix")
+        .map(String::from)
+        .unwrap_or_else(|| derive::screaming_snake_case(&input.name));
+
+    let mut wgsl = String::new();
+    let mut discriminant: u32 = 0;
+    for variant in input.fields.iter() {
+        if let Some(ref value) = variant.value {
+            let value = consts::wgsl_literal(&value.replace(' ', ""));
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse::<u32>(),
+            };
+            discriminant = match parsed {
+                Ok(discriminant) => discriminant,
+                Err(_) => {
+                    let msg = format!(
+                        "{}::{}: discriminants must be unsigned integer literals",
+                        input.name, variant.name
+                    );
+                    return compile_error(&msg);
+                }
+            };
+        }
+
+        wgsl += &format!(
+            "const {}_{}: u32 = {}u;\n",
+            prefix,
+            derive::screaming_snake_case(&variant.name),
+            discriminant
+        );
+        discriminant = discriminant.wrapping_add(1);
+    }
+
+    let header = input
+        .attr("header")
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.wgsl", input.name));
+    register_virtual_header(&header, &wgsl);
+
+    format!(
+        "impl {} {{ pub const WGSL: &'static str = {:?}; }}",
+        input.name, wgsl
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Generates constants for the entry points of a shader.
+///
+/// Each entry point gets an `ENTRY_*` name constant, and a variant in an
+/// `EntryPoint` enum with `name()`, `stage()` and `workgroup_size()` methods.
+/// Compute entry points also get a `WORKGROUP_SIZE_*` constant and a
+/// `dispatch_size_*` function, and if there's only one, `WORKGROUP_SIZE` and
+/// `dispatch_size` as well.
+///
+/// ```ignore
+/// include_wgsl_entry_points!("shader.wgsl");
+///
+/// let vertex = wgpu::VertexState {
+///     module: &module,
+///     entry_point: ENTRY_VS_MAIN,
+///     ...
+/// };
+/// ```
+#[proc_macro]
+pub fn include_wgsl_entry_points(input: TokenStream) -> TokenStream {
+    match load_single_shader(input) {
+        Ok(Some(shader)) => reflection::entry_points(&shader).parse().unwrap(),
+        Ok(None) => TokenStream::new(),
+        Err(e) => e,
+    }
+}
+
+/// Generates constants for the push constant ranges used by a shader.
+///
+/// `PUSH_CONSTANT_RANGES` holds one `wgpu::PushConstantRange` per distinct
+/// size, shared by the stages using it, and `PUSH_CONSTANT_SIZE` the total
+/// size the device needs to support.
+///
+/// ```ignore
+/// include_wgsl_push_constants!("shader.wgsl");
+///
+/// let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+///     push_constant_ranges: PUSH_CONSTANT_RANGES,
+///     ...
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_push_constants(input: TokenStream) -> TokenStream {
+    match load_single_shader(input) {
+        Ok(Some(shader)) => reflection::push_constant_consts(&shader).parse().unwrap(),
+        Ok(None) => TokenStream::new(),
+        Err(e) => e,
+    }
+}
+
+/// Generates a table and a typed builder for the `override` declarations of a shader.
+///
+/// `OVERRIDES` lists each override's name, `@id`, type and default. The
+/// `Overrides` builder takes overrides without a default in `new`, and has a
+/// setter for each override, so that unknown keys fail to compile instead of
+/// being ignored at pipeline creation.
+///
+/// ```ignore
+/// include_wgsl_overrides!("shader.wgsl");
+///
+/// let constants = Overrides::new(1.5).use_fog(true).build();
+/// let compilation_options = wgpu::PipelineCompilationOptions {
+///     constants: &constants,
+///     ..Default::default()
+/// };
+/// ```
+#[proc_macro]
+pub fn include_wgsl_overrides(input: TokenStream) -> TokenStream {
+    match load_single_shader(input) {
+        Ok(Some(shader)) => match reflection::overrides(&shader) {
+            Ok(overrides) => overrides.parse().unwrap(),
+            Err(msg) => compile_error(&msg),
+        },
+        Ok(None) => TokenStream::new(),
+        Err(e) => e,
+    }
+}
+
+/// Expands to a stable `u128` hash of the preprocessed shader source, its XXH3.
+///
+/// The hash only changes when the expanded source does, so it can key pipeline
+/// and on-disk shader caches without hashing at startup. It's the hash of the
+/// source `include_wgsl!` embeds and the same value as `HASH` in
+/// `include_wgsl_module!`, as long as they're given the same `defines` and
+/// `bounds_checks` options, which change the source. With `bounds_checks`,
+/// debug builds get the hash of the checked source.
+///
+/// ```
+/// # use wgpu_pp::{include_wgsl, include_wgsl_hash};
+/// const SHADER_HASH: u128 = include_wgsl_hash!("material.wgsl", base = "fixtures");
+///
+/// let source = include_wgsl!("material.wgsl", base = "fixtures");
+/// assert_eq!(SHADER_HASH, xxhash_rust::xxh3::xxh3_128(source.as_bytes()));
+///
+/// let checked = include_wgsl!("material.wgsl", base = "fixtures", bounds_checks);
+/// assert_eq!(
+///     include_wgsl_hash!("material.wgsl", base = "fixtures", bounds_checks),
+///     xxhash_rust::xxh3::xxh3_128(checked.as_bytes())
+/// );
+/// ```
+#[proc_macro]
+pub fn include_wgsl_hash(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["base", "bounds_checks", "defines"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return "0u128".parse().unwrap(),
+        Err(e) => return e,
+    };
+    let defines = match config_path(&args, &basepath)
+        .and_then(|config| config_defines(config.as_deref()))
+    {
+        Ok(defines) => defines,
+        Err(e) => return e,
+    };
+    let source = match load_source(filename, &basepath, &defines) {
+        Ok((source, _)) => source,
+        Err(msg) => return compile_error(&msg),
+    };
+    let hash = hash::xxh3_128(source.as_bytes());
+    if !args.flag("bounds_checks") {
+        return format!("{}u128", hash).parse().unwrap();
+    }
+
+    // The same checked source as `include_wgsl!` embeds in debug builds.
+    let module = match naga::front::wgsl::parse_str(&source) {
+        Ok(module) => module,
+        Err(e) => return compile_error(&e.emit_to_string(&source)),
+    };
+    let checked = match bounds_checked(&source, &module) {
+        Ok(checked) => checked,
+        Err(e) => return e,
+    };
+    format!(
+        "if cfg!(debug_assertions) {{ {}u128 }} else {{ {}u128 }}",
+        hash::xxh3_128(checked.as_bytes()),
+        hash
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Generates the preprocessed source of a shader for every combination of defines.
+///
+/// Defines are either `NAME`, which is defined as `false` and `true`, or
+/// `NAME = [values]`. Every combination is preprocessed and validated, and
+/// listed in `VARIANTS` along with its defines. For large sets of defines,
+/// `variants([...], ...)` lists the combinations to generate instead, where
+/// boolean defines that aren't named are `false` and the others take their
+/// first value. `base` is the only other option, and the options of the
+/// other macros, like `budget`, are rejected rather than defined.
+///
+/// ```ignore
+/// include_wgsl_variants!("uber.wgsl", USE_FOG, QUALITY = [1, 2, 3]);
+/// include_wgsl_variants!("uber.wgsl", USE_FOG, QUALITY = [1, 2, 3], variants([QUALITY = 1], [USE_FOG, QUALITY = 3]));
+///
+/// for variant in VARIANTS {
+///     let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///         label: None,
+///         source: wgpu::ShaderSource::Wgsl(variant.source.into()),
+///     });
+/// }
+/// ```
+#[proc_macro]
+pub fn include_wgsl_variants(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&variants::allowed_options(&args.options))?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+    let variants = match variants::parse(&args.options) {
+        Ok(variants) => variants,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+
+    // Variants are independent, so they're preprocessed and validated in parallel.
+    let results = parallel::map(&variants.combinations, |combination| {
+        load_source(filename, &basepath, &variants.defines(combination))
+    });
+    let mut sources = vec![];
+    for (combination, result) in variants.combinations.iter().zip(results) {
+        match result {
+            Ok((source, source_info)) => {
+                // Variants are preprocessed off the macro's thread, which can't warn.
+                if proc_macro::is_available() {
+                    warn_defines(&source_info);
+                }
+                let name = format!("{} ({})", filename, variants.describe(combination));
+                let sizes = file_sizes(&source, &source_info.source_map);
+                if let Err(e) = Budget::check(Budget::global(), &name, &source, sizes) {
+                    return e;
+                }
+                sources.push(source);
             }
-            PreprocessorError::FileNotValidUtf8(filename) => {
-                let msg = format!("file not valid utf-8: {}", filename);
-                return format!("compile_error!(\"{}\")", msg).parse().unwrap();
+            Err(msg) => {
+                let mut error =
+                    compile_error(&format!("in variant {}", variants.describe(combination)));
+                error.extend(compile_error(&msg));
+                return error;
             }
-            PreprocessorError::UnknownDirective(directive) => {
-                let msg = format!("unknown directive: {}", directive);
-                return format!("compile_error!(\"{}\")", msg).parse().unwrap();
+        }
+    }
+
+    variants::variants(&variants, &sources).parse().unwrap()
+}
+
+/// Splits a shader with `#stage` sections into a validated source per stage.
+///
+/// Everything before the first `#stage vertex`, `#stage fragment` or
+/// `#stage compute` is shared by all stages. Expands to a value with a
+/// `&'static str` field for each stage in the file.
+///
+/// ```ignore
+/// let stages = include_wgsl_stages!("sprite.wgsl");
+///
+/// let vertex = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///     label: Some("sprite.wgsl vertex"),
+///     source: wgpu::ShaderSource::Wgsl(stages.vertex.into()),
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_stages(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["budget", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+    let budget = match Budget::for_args(&args) {
+        Ok(budget) => budget,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+
+    let source = match preprocess_shader(filename, &basepath, &[]) {
+        Ok((source, source_info)) => {
+            let sizes = file_sizes(&source, &source_info.source_map);
+            if let Err(e) = Budget::check(budget, filename, &source, sizes) {
+                return e;
             }
-            PreprocessorError::IncludeIncorrectArgs => {
-                return "compile_error!(\"incorrect arguments to #include\")"
-                    .to_string()
-                    .parse()
-                    .unwrap();
+            source
+        }
+        Err(e) => return e,
+    };
+    let stages = split_stages(&source);
+    if stages.is_empty() {
+        let msg = format!("{} has no #stage sections", filename);
+        return compile_error(&msg);
+    }
+
+    let mut fields = vec![];
+    let mut values = vec![];
+    for (stage, source) in stages.iter() {
+        if let Err(e) = check_wgsl(source) {
+            let mut error = compile_error(&format!("in #stage {}", stage));
+            error.extend(e);
+            return error;
+        }
+        fields.push(format!("pub {}: &'static str,", stage));
+        values.push(format!("{}: {:?},", stage, source));
+    }
+
+    format!(
+        r#"{{
+            struct Stages {{
+                {}
+            }}
+
+            Stages {{
+                {}
+            }}
+        }}"#,
+        fields.join("\n"),
+        values.join("\n"),
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Splits a shader into a WGSL source per entry point.
+///
+/// Each source contains only the entry point and the functions and globals it
+/// uses, so drivers don't pay for unrelated entry points at pipeline creation.
+/// Expands to a value with a `&'static str` field per entry point. The
+/// sources are generated by Naga, so they're not the original text.
+///
+/// ```ignore
+/// let sources = include_wgsl_split!("shader.wgsl");
+///
+/// let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///     label: Some("shader.wgsl:cs_main"),
+///     source: wgpu::ShaderSource::Wgsl(sources.cs_main.into()),
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_split(input: TokenStream) -> TokenStream {
+    let shader = match load_single_shader(input) {
+        Ok(Some(shader)) => shader,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+    if let Err(e) = shader.check_budget() {
+        return e;
+    }
+    let sources = match split::split(&shader.module) {
+        Ok(sources) => sources,
+        Err(msg) => return compile_error(&msg),
+    };
+
+    let fields = sources
+        .iter()
+        .map(|(name, _)| format!("pub {}: &'static str,", reflection::rust_ident(name)))
+        .collect::<Vec<_>>();
+    let values = sources
+        .iter()
+        .map(|(name, source)| format!("{}: {:?},", reflection::rust_ident(name), source))
+        .collect::<Vec<_>>();
+
+    format!(
+        r#"{{
+            struct EntryPointSources {{
+                {}
+            }}
+
+            EntryPointSources {{
+                {}
+            }}
+        }}"#,
+        fields.join("\n"),
+        values.join("\n"),
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Includes a shader that's re-read from disk in debug builds.
+///
+/// Expands to a `Cow<'static, str>`. In debug builds, every evaluation
+/// preprocesses the shader again with `wgpu_pp_runtime`, so edits show up
+/// without recompiling, falling back to the source embedded at compile time if
+/// that fails, with a warning logged through `log`. Release builds only embed
+/// the source. The runtime source isn't validated, and the crate using the
+/// macro needs to depend on `wgpu-pp-runtime`.
+///
+/// The shader is found relative to the `CARGO_MANIFEST_DIR` the binary runs
+/// with, as under `cargo run`, or else relative to the current directory.
+/// Virtual headers the shader includes, e.g. from `#[derive(WgslStruct)]`, are
+/// available to it at runtime too.
+///
+/// ```ignore
+/// // Called again whenever the shader should be reloaded.
+/// fn shader_module(device: &wgpu::Device) -> wgpu::ShaderModule {
+///     device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///         label: Some("shader.wgsl"),
+///         source: wgpu::ShaderSource::Wgsl(include_wgsl_reloadable!("shader.wgsl")),
+///     })
+/// }
+/// ```
+#[proc_macro]
+pub fn include_wgsl_reloadable(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["budget", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+    let budget = match Budget::for_args(&args) {
+        Ok(budget) => budget,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return "::std::borrow::Cow::Borrowed(\"\")".parse().unwrap(),
+        Err(e) => return e,
+    };
+    let (source, source_info) = match load_source(filename, &basepath, &[]) {
+        Ok((source, source_info)) => {
+            let sizes = file_sizes(&source, &source_info.source_map);
+            if let Err(e) = Budget::check(budget, filename, &source, sizes) {
+                return e;
             }
-            PreprocessorError::MacroNoParenthesis => {
-                return "compile_error!(\"macro must have parenthesis\")"
-                    .to_string()
-                    .parse()
-                    .unwrap();
+            (source, source_info)
+        }
+        Err(msg) => return compile_error(&msg),
+    };
+
+    reloadable(&source, &basepath.join(filename), &source_info)
+        .parse()
+        .unwrap()
+}
+
+/// Returns the virtual headers registered by derives, as their name and
+/// contents, that a shader includes.
+fn included_virtual_headers(source_info: &SourceInfo) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = vec![];
+    for (_, included) in &source_info.includes {
+        let included = included.to_string_lossy();
+        let Some(name) = included.strip_prefix('<').and_then(|name| name.strip_suffix('>')) else {
+            continue;
+        };
+        if headers.iter().any(|(header, _)| header == name) {
+            continue;
+        }
+        if let Some(contents) = virtual_header(name) {
+            headers.push((name.to_string(), contents));
+        }
+    }
+    headers
+}
+
+/// Expands `include_wgsl_reloadable!` for a shader at `path`, preprocessed to `source`.
+///
+/// The path is embedded relative to the crate's directory, like the other paths
+/// in expanded code, and resolved against `CARGO_MANIFEST_DIR` at runtime,
+/// which `cargo run` and `cargo test` set. The virtual headers the shader
+/// includes are registered before reloading it, since the derives registering
+/// them only ran in the compiler.
+fn reloadable(source: &str, path: &Path, source_info: &SourceInfo) -> String {
+    let defines = profile_defines()
+        .iter()
+        .map(|(name, value)| format!(".define({:?}, {:?})", name, value))
+        .collect::<Vec<_>>();
+    let headers = included_virtual_headers(source_info)
+        .iter()
+        .map(|(name, contents)| {
+            format!("::wgpu_pp_runtime::register_virtual_header({name:?}, {contents:?});")
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        r#"{{
+            const EMBEDDED: &str = {source:?};
+
+            #[cfg(debug_assertions)]
+            let source = {{
+                {headers}
+                let path = ::std::env::var_os("CARGO_MANIFEST_DIR")
+                    .map(::std::path::PathBuf::from)
+                    .unwrap_or_default()
+                    .join({path:?});
+                match ::wgpu_pp_runtime::Preprocessor::new(){defines}.process_file(&path) {{
+                    Ok(source) => ::std::borrow::Cow::Owned(source),
+                    Err(e) => {{
+                        ::wgpu_pp_runtime::log::warn!("failed to reload {{}}, using the embedded source: {{}}", path.display(), e);
+                        ::std::borrow::Cow::Borrowed(EMBEDDED)
+                    }}
+                }}
+            }};
+            #[cfg(not(debug_assertions))]
+            let source = ::std::borrow::Cow::Borrowed(EMBEDDED);
+
+            source
+        }}"#,
+        headers = headers.join("\n"),
+        defines = defines.join(""),
+        path = portable_path(path),
+    )
+}
+
+/// Includes a shader using `enable f16`, along with a fallback using f32.
+///
+/// The fallback has `f16` types and literals rewritten to f32, and the enable
+/// removed, for devices without `wgpu::Features::SHADER_F16`. Naga can't parse
+/// f16 yet, so only the fallback is validated. Expands to a value with `f16`
+/// and `f32` fields holding the sources.
+///
+/// ```ignore
+/// let sources = include_wgsl_f16!("particles.wgsl");
+/// let source = match device.features().contains(wgpu::Features::SHADER_F16) {
+///     true => sources.f16,
+///     false => sources.f32,
+/// };
+///
+/// let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///     label: Some("particles.wgsl"),
+///     source: wgpu::ShaderSource::Wgsl(source.into()),
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_f16(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["budget", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref filename] = args.filenames[..] else {
+        return compile_error("expected exactly one path");
+    };
+    let budget = match Budget::for_args(&args) {
+        Ok(budget) => budget,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p,
+        Ok(None) => return TokenStream::new(),
+        Err(e) => return e,
+    };
+
+    let source = match preprocess_shader(filename, &basepath, &[]) {
+        Ok((source, source_info)) => {
+            let sizes = file_sizes(&source, &source_info.source_map);
+            if let Err(e) = Budget::check(budget, filename, &source, sizes) {
+                return e;
             }
-            PreprocessorError::MacroIncorrectArgs(expected, got) => {
-                let msg = format!("macro expected {} arguments, got {}", expected, got);
-                return format!("compile_error!(\"{}\")", msg).parse().unwrap();
+            source
+        }
+        Err(e) => return e,
+    };
+    if !enables_f16(&source) {
+        let msg = format!("{} doesn't have `enable f16;`", filename);
+        return compile_error(&msg);
+    }
+
+    let fallback = f16_fallback(&source);
+    if let Err(e) = check_wgsl(&fallback) {
+        let mut error = compile_error("in the f32 fallback");
+        error.extend(e);
+        return error;
+    }
+
+    format!(
+        r#"{{
+            struct F16Sources {{
+                pub f16: &'static str,
+                pub f32: &'static str,
+            }}
+
+            F16Sources {{
+                f16: {:?},
+                f32: {:?},
+            }}
+        }}"#,
+        source, fallback,
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Combines the shaders in a directory into one module.
+///
+/// Every `.wgsl` file directly in the directory is preprocessed, in order of
+/// their names, and concatenated. Headers shared between them are included once,
+/// and declaring the same name in two files fails to compile. The combined
+/// module is validated, and entry points are selected at pipeline creation.
+///
+/// ```ignore
+/// let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///     label: Some("shaders"),
+///     source: wgpu::ShaderSource::Wgsl(include_wgsl_bundle!("shaders").into()),
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_bundle(input: TokenStream) -> TokenStream {
+    let args = match MacroArgs::parse(input).and_then(|args| {
+        args.check_options(&["budget", "base"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let [ref dirname] = args.filenames[..] else {
+        return compile_error("expected exactly one directory");
+    };
+    let budget = match Budget::for_args(&args) {
+        Ok(budget) => budget,
+        Err(e) => return e,
+    };
+
+    let basepath = match base_dir(&args) {
+        Ok(Some(p)) => p.join(dirname),
+        Ok(None) => return "\"\"".parse().unwrap(),
+        Err(e) => return e,
+    };
+
+    let entries = match std::fs::read_dir(&basepath) {
+        Ok(entries) => entries,
+        Err(_) => {
+            let msg = format!("directory not found: {}", basepath.to_string_lossy());
+            return compile_error(&msg);
+        }
+    };
+    let mut filenames = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|filename| filename.ends_with(".wgsl"))
+        .collect::<Vec<_>>();
+    filenames.sort();
+    if filenames.is_empty() {
+        let msg = format!("no .wgsl files in {}", basepath.to_string_lossy());
+        return compile_error(&msg);
+    }
+
+    let manifest = manifest::manifest_path();
+    let sources = match preprocess_bundle_sources(&filenames, &basepath, manifest.as_deref()) {
+        Ok(sources) => sources,
+        Err(msg) => return compile_error(&msg),
+    };
+
+    // Report collisions with the files involved, rather than as a WGSL error.
+    let mut declared: HashMap<String, &str> = HashMap::new();
+    for (filename, source) in filenames.iter().zip(sources.iter()) {
+        for name in declarations(source) {
+            if let Some(other) = declared.insert(name.clone(), filename) {
+                let msg = format!("`{}` is declared in both {} and {}", name, other, filename);
+                return compile_error(&msg);
             }
-        },
+        }
+    }
+
+    let source = sources.concat();
+    let sizes = filenames
+        .iter()
+        .zip(sources.iter())
+        .map(|(filename, source)| (PathBuf::from(filename), source.len()))
+        .collect();
+    if let Err(e) = Budget::check(budget, dirname, &source, sizes) {
+        return e;
+    }
+    if let Err(e) = check_wgsl(&source) {
+        return e;
+    }
+    TokenTree::Literal(proc_macro::Literal::string(&source)).into()
+}
+
+/// Generates a module with everything about a shader in one place.
+///
+/// The module contains the preprocessed `SOURCE`, its `PATH` and `HASH`, the
+/// `DEPENDENCIES` it was preprocessed from and the `DEFINES` in effect, along with the constants from
+/// `include_wgsl_entry_points!`. Each binding gets `GROUP_*` and `BINDING_*`
+/// index constants, and a `BINDING_TYPE_*` constant with its
+/// `wgpu::BindingType`, e.g. the texture's dimension and sample type. Each
+/// group gets a `BindGroup*` builder with a method per binding, taking the
+/// buffer, texture view or sampler it binds. The `@location`s of vertex
+/// attributes, varyings and color targets get `LOC_*` constants.
+///
+/// With the `stats` option, a `stats` module holds the function and global
+/// variable counts, and the approximate instruction count of each entry point.
+/// With `bounds_checks`, debug builds get a `SOURCE` with storage and
+/// workgroup array indices clamped to the arrays' bounds.
+///
+/// ```ignore
+/// include_wgsl_module!(pub mod lighting, "lighting.wgsl");
+///
+/// let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+///     label: Some(lighting::PATH),
+///     source: wgpu::ShaderSource::Wgsl(lighting::SOURCE.into()),
+/// });
+/// ```
+#[proc_macro]
+pub fn include_wgsl_module(input: TokenStream) -> TokenStream {
+    // Split off `pub mod name`, everything after the first comma is the path.
+    let mut tokens = input.into_iter().collect::<Vec<_>>();
+    let comma = tokens
+        .iter()
+        .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ','));
+    let Some(comma) = comma else {
+        return compile_error("expected `mod name, \"path\"`");
     };
+    let rest = tokens.split_off(comma + 1);
+    tokens.pop();
 
-    match validate_wgsl(&shader) {
-        Ok(_) => {}
+    let mod_decl = tokens.iter().cloned().collect::<TokenStream>().to_string();
+    if !matches!(tokens.as_slice(), [.., TokenTree::Ident(kw), TokenTree::Ident(_)] if kw.to_string() == "mod")
+    {
+        return compile_error("expected `mod name, \"path\"`");
+    }
+
+    let args = match MacroArgs::parse(rest.into_iter().collect()).and_then(|args| {
+        args.check_options(&["base", "expect", "stats", "bounds_checks", "defines"])?;
+        Ok(args)
+    }) {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+    let shader = match load_args_shader(&args) {
+        Ok(Some(shader)) => shader,
+        Ok(None) => return format!("{} {{}}", mod_decl).parse().unwrap(),
         Err(e) => return e,
+    };
+    if let Err(e) = shader.check_budget() {
+        return e;
     }
 
-    TokenTree::Literal(proc_macro::Literal::string(&shader)).into()
+    // With bounds checks, debug builds get the checked source.
+    let source_consts = |source: &str, cfg: &str| {
+        format!(
+            "{cfg} pub const SOURCE: &str = {source:?};\n{cfg} pub const HASH: u128 = {hash};",
+            hash = hash::xxh3_128(source.as_bytes())
+        )
+    };
+    let source = match args.flag("bounds_checks") {
+        true => match bounds_checked(&shader.source, &shader.module) {
+            Ok(checked) => {
+                source_consts(&checked, "#[cfg(debug_assertions)]")
+                    + &source_consts(&shader.source, "#[cfg(not(debug_assertions))]")
+            }
+            Err(e) => return e,
+        },
+        false => source_consts(&shader.source, ""),
+    };
+    let bindings = match reflection::binding_consts(&shader) {
+        Ok(bindings) => bindings,
+        Err(msg) => return compile_error(&msg),
+    };
+    let bind_groups = match reflection::bind_groups(&shader) {
+        Ok(bind_groups) => bind_groups,
+        Err(msg) => return compile_error(&msg),
+    };
+
+    format!(
+        r#"
+        {mod_decl} {{
+            {source}
+            pub const PATH: &str = {path:?};
+            /// The files read while preprocessing, i.e. the shader, its includes and `#embed` data,
+            /// relative to the crate's directory.
+            pub const DEPENDENCIES: &[&str] = &[{dependencies}];
+            /// The defines in effect at the end of the shader and their values,
+            /// e.g. for keying caches on the configuration it was built with.
+            pub const DEFINES: &[(&str, &str)] = &[{defines}];
+
+            {entry_points}
+
+            {bindings}
+            {bind_groups}
+            {locations}
+            {group_offsets}
+            {stats}
+        }}
+        "#,
+        mod_decl = mod_decl,
+        source = source,
+        path = shader.filename,
+        dependencies = shader
+            .dependencies
+            .iter()
+            .map(|path| format!("{:?},", portable_path(path)))
+            .collect::<Vec<_>>()
+            .join(" "),
+        defines = shader
+            .define_values
+            .iter()
+            .map(|define| format!("{:?},", define))
+            .collect::<Vec<_>>()
+            .join(" "),
+        entry_points = reflection::entry_points(&shader),
+        bindings = bindings,
+        bind_groups = bind_groups,
+        locations = reflection::locations(&shader),
+        group_offsets = reflection::group_offsets(&shader),
+        stats = match args.flag("stats") {
+            true => stats::stats_module(&shader),
+            false => String::new(),
+        },
+    )
+    .parse()
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_manifest() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-bundle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("common.wgsl"), "const scale = 2.0;\n").unwrap();
+        std::fs::write(
+            dir.join("a.wgsl"),
+            "#include \"common.wgsl\"\n@compute @workgroup_size(1)\nfn a() {}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("b.wgsl"), "@compute @workgroup_size(1)\nfn b() {}\n").unwrap();
+        let manifest = dir.join("manifest.jsonl");
+
+        let filenames = vec!["a.wgsl".to_string(), "b.wgsl".to_string()];
+        let sources = preprocess_bundle_sources(&filenames, &dir, Some(&manifest)).unwrap();
+        assert_eq!(sources.len(), 2);
+
+        let manifest = std::fs::read_to_string(&manifest).unwrap();
+        let lines = manifest.lines().collect::<Vec<_>>();
+        let absolute = dir.canonicalize().unwrap().display().to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dir = absolute;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(&format!("\"source\":\"{}/a.wgsl\"", dir)));
+        assert!(lines[0].contains(&format!("{}/common.wgsl", dir)));
+        assert!(lines[0].contains("{\"name\":\"a\",\"stage\":\"compute\"}"));
+        assert!(lines[1].contains(&format!("\"source\":\"{}/b.wgsl\"", dir)));
+    }
+
+    #[test]
+    fn test_reloadable_virtual_headers() {
+        register_virtual_header("reloadable/light.wgsl", "struct Light { color: vec3f }\n");
+        let main = PathBuf::from("main.wgsl");
+        let source_info = SourceInfo {
+            includes: vec![
+                (main.clone(), PathBuf::from("<reloadable/light.wgsl>")),
+                (main.clone(), PathBuf::from("<reloadable/light.wgsl>")),
+                (main.clone(), PathBuf::from("<wgpu_pp/math.wgsl>")),
+                (main.clone(), PathBuf::from("common.wgsl")),
+            ],
+            ..Default::default()
+        };
+
+        let expansion = reloadable("", &main, &source_info);
+        assert!(expansion.contains(
+            "register_virtual_header(\"reloadable/light.wgsl\", \"struct Light { color: vec3f }\\n\");"
+        ));
+        assert_eq!(expansion.matches("register_virtual_header").count(), 1);
+        assert!(!expansion.contains("wgpu_pp/math.wgsl"));
+    }
+
+    #[test]
+    fn test_reloadable_relative_path() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let expansion = reloadable("", &manifest_dir.join("src/lib.rs"), &SourceInfo::default());
+        assert!(expansion.contains(".join(\"src/lib.rs\")"));
+        assert!(!expansion.contains(&*manifest_dir.to_string_lossy()));
+    }
 }
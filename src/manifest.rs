@@ -0,0 +1,162 @@
+//! A manifest of every shader processed in a build, for asset pipelines and
+//! tooling that need to know what was embedded.
+//!
+//! Macros run in separate, possibly parallel compilations, so each appends a
+//! JSON line for its shaders instead of rewriting a shared file. Modules that
+//! didn't change aren't expanded again, so the manifest keeps entries from
+//! earlier builds, and a shader is listed again whenever its output changes.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use naga::front::wgsl::Frontend;
+
+use crate::cache::target_dir;
+use crate::expect::stage_name;
+use crate::hash::xxh3_128;
+
+/// Enables the manifest, as `1` for `wgpu-pp-manifest.jsonl` in the target
+/// directory, or as the path of the file to write.
+const MANIFEST: &str = "WGPU_PP_MANIFEST";
+
+/// Returns the path of the manifest, if it's enabled.
+pub fn manifest_path() -> Option<PathBuf> {
+    match std::env::var_os(MANIFEST)? {
+        value if value.is_empty() || value == "0" => None,
+        value if value == "1" => Some(target_dir()?.join("wgpu-pp-manifest.jsonl")),
+        value => Some(PathBuf::from(value)),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_path(path: &Path) -> String {
+    json_string(&path.to_string_lossy())
+}
+
+/// Appends an entry for a preprocessed shader to the manifest at `path`, if
+/// it's enabled, see `manifest_path`.
+///
+/// Entry points are those of the expanded source, which is left to the macro
+/// to validate, so sources Naga can't parse as a whole, like `#stage`
+/// sections, have none.
+pub fn record(
+    path: Option<&Path>,
+    shader: &Path,
+    source: &str,
+    dependencies: &[PathBuf],
+) -> Result<(), String> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+
+    let absolute = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let entry_points = Frontend::new()
+        .parse(source)
+        .map(|module| {
+            module
+                .entry_points
+                .iter()
+                .map(|entry_point| {
+                    format!(
+                        "{{\"name\":{},\"stage\":\"{}\"}}",
+                        json_string(&entry_point.name),
+                        stage_name(entry_point.stage)
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let dependencies = dependencies
+        .iter()
+        .map(|dependency| json_path(&absolute(dependency)))
+        .collect::<Vec<_>>();
+    let line = format!(
+        "{{\"source\":{},\"hash\":\"{:032x}\",\"size\":{},\"entry_points\":[{}],\"dependencies\":[{}]}}\n",
+        json_path(&absolute(shader)),
+        xxh3_128(source.as_bytes()),
+        source.len(),
+        entry_points.join(","),
+        dependencies.join(","),
+    );
+
+    let existing = std::fs::read_to_string(path).unwrap_or_default();
+    if existing.lines().any(|existing| existing == line.trim_end()) {
+        return Ok(());
+    }
+    // A single write of the whole line, so entries from parallel builds don't interleave.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| format!("failed to write manifest {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("main.wgsl"), "\"main.wgsl\"");
+        assert_eq!(
+            json_string("a \"b\"\\c\nd\te"),
+            "\"a \\\"b\\\"\\\\c\\nd\\u0009e\""
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = std::env::temp_dir().join(format!("wgpu-pp-manifest-{}", std::process::id()));
+        let path = dir.join("manifest.jsonl");
+        let shader = dir.join("shader.wgsl");
+        let source = "@compute @workgroup_size(1)\nfn cs_main() {}\n";
+
+        assert!(record(None, &shader, source, &[]).is_ok());
+        assert!(!dir.exists());
+
+        record(Some(&path), &shader, source, &[]).unwrap();
+        let expected = format!(
+            "{{\"source\":{},\"hash\":\"{:032x}\",\"size\":{},\"entry_points\":[{{\"name\":\"cs_main\",\"stage\":\"compute\"}}],\"dependencies\":[]}}\n",
+            json_path(&shader),
+            xxh3_128(source.as_bytes()),
+            source.len()
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+
+        // The same output isn't listed twice, while a changed one is appended.
+        record(Some(&path), &shader, source, &[]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        record(
+            Some(&path),
+            &shader,
+            "#stage vertex\n",
+            std::slice::from_ref(&shader),
+        )
+        .unwrap();
+        let manifest = std::fs::read_to_string(&path).unwrap();
+        let lines = manifest.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("\"entry_points\":[]"));
+        assert!(lines[1].ends_with(&format!("\"dependencies\":[{}]}}", json_path(&shader))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
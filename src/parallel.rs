@@ -0,0 +1,81 @@
+//! Running independent work on every core, e.g. for the variants of a shader.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Calls `f` on every item, on a thread per core, returning the results in order.
+///
+/// The proc macro API only works on the thread the macro was called on, so
+/// `f` can't create `TokenStream`s.
+pub fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Threads take the next item when they're done with one, since some
+    // items, like variants enabling more code, take longer than others.
+    let next = AtomicUsize::new(0);
+    let mut results = (0..items.len()).map(|_| None).collect::<Vec<_>>();
+    thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            let done = worker
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            for (i, result) in done {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        assert_eq!(map(&[] as &[u32], |i| i * 2), Vec::<u32>::new());
+        assert_eq!(map(&[1], |i| i * 2), [2]);
+
+        // Results stay in order, even when later items finish first.
+        let items = (0..64).collect::<Vec<u64>>();
+        let results = map(&items, |i| {
+            thread::sleep(std::time::Duration::from_millis((64 - i) % 4));
+            i * 2
+        });
+        assert_eq!(results, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+
+        // Every item is mapped exactly once.
+        let calls = AtomicUsize::new(0);
+        map(&items, |_| calls.fetch_add(1, Ordering::Relaxed));
+        assert_eq!(calls.load(Ordering::Relaxed), items.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "item 3")]
+    fn test_map_panic() {
+        map(&[0, 1, 2, 3, 4, 5, 6, 7], |i| {
+            if *i == 3 {
+                panic!("item 3");
+            }
+        });
+    }
+}
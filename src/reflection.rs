@@ -0,0 +1,1662 @@
+use std::collections::{BTreeMap, HashMap};
+
+use naga::{
+    AddressSpace, ArraySize, Handle, ImageClass, ImageDimension, Module, ScalarKind, ShaderStage,
+    StorageAccess, TypeInner,
+};
+use naga::valid::TypeFlags;
+use wgpu_pp_runtime::Docs;
+
+use crate::{forward_slashes, Shader};
+
+/// Shader stages, stored as `wgpu::ShaderStages` bits.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stages(u32);
+
+impl Stages {
+    pub fn from_stage(stage: ShaderStage) -> Self {
+        match stage {
+            ShaderStage::Vertex => Stages(1),
+            ShaderStage::Fragment => Stages(2),
+            ShaderStage::Compute => Stages(4),
+        }
+    }
+
+    pub fn union(self, other: Stages) -> Self {
+        Stages(self.0 | other.0)
+    }
+
+    pub fn to_rust(self) -> String {
+        let names = [(1, "VERTEX"), (2, "FRAGMENT"), (4, "COMPUTE")]
+            .iter()
+            .filter(|(bit, _)| self.0 & bit != 0)
+            .map(|(_, name)| format!("::wgpu::ShaderStages::{}", name))
+            .collect::<Vec<_>>();
+
+        match names.split_first() {
+            None => "::wgpu::ShaderStages::NONE".to_string(),
+            Some((first, rest)) => rest
+                .iter()
+                .fold(first.clone(), |acc, name| format!("{}.union({})", acc, name)),
+        }
+    }
+}
+
+/// A resource binding used by at least one entry point.
+pub struct Binding {
+    pub name: String,
+    pub group: u32,
+    pub binding: u32,
+    pub visibility: Stages,
+    /// `wgpu::BindingType` as Rust source.
+    pub ty: String,
+    /// `Option<NonZeroU32>` as Rust source.
+    pub count: String,
+    /// The type of the resource bound, for bind group builders, as Rust source.
+    pub param: String,
+    /// Turns `resource`, of type `param`, into a `wgpu::BindingResource`, as Rust source.
+    pub resource: String,
+}
+
+fn view_dimension(dim: ImageDimension, arrayed: bool) -> &'static str {
+    match (dim, arrayed) {
+        (ImageDimension::D1, _) => "D1",
+        (ImageDimension::D2, false) => "D2",
+        (ImageDimension::D2, true) => "D2Array",
+        (ImageDimension::D3, _) => "D3",
+        (ImageDimension::Cube, false) => "Cube",
+        (ImageDimension::Cube, true) => "CubeArray",
+    }
+}
+
+fn binding_type(
+    module: &Module,
+    space: AddressSpace,
+    ty: Handle<naga::Type>,
+) -> Result<String, String> {
+    let gctx = module.to_ctx();
+    let inner = match module.types[ty].inner {
+        TypeInner::BindingArray { base, .. } => &module.types[base].inner,
+        ref inner => inner,
+    };
+
+    let binding_type = match space {
+        AddressSpace::Uniform | AddressSpace::Storage { .. } => {
+            let buffer_ty = match space {
+                AddressSpace::Storage { access } => format!(
+                    "::wgpu::BufferBindingType::Storage {{ read_only: {} }}",
+                    !access.contains(StorageAccess::STORE)
+                ),
+                _ => "::wgpu::BufferBindingType::Uniform".to_string(),
+            };
+            format!(
+                "::wgpu::BindingType::Buffer {{ ty: {}, has_dynamic_offset: false, min_binding_size: ::core::num::NonZeroU64::new({}) }}",
+                buffer_ty,
+                inner.size(gctx)
+            )
+        }
+        _ => match *inner {
+            TypeInner::Image {
+                dim,
+                arrayed,
+                class,
+            } => {
+                let view_dimension = view_dimension(dim, arrayed);
+                match class {
+                    ImageClass::Sampled { kind, multi } => {
+                        let sample_type = match kind {
+                            ScalarKind::Sint => "::wgpu::TextureSampleType::Sint".to_string(),
+                            ScalarKind::Uint => "::wgpu::TextureSampleType::Uint".to_string(),
+                            // Multisampled float textures can't be filtered.
+                            _ => format!(
+                                "::wgpu::TextureSampleType::Float {{ filterable: {} }}",
+                                !multi
+                            ),
+                        };
+                        format!(
+                            "::wgpu::BindingType::Texture {{ sample_type: {}, view_dimension: ::wgpu::TextureViewDimension::{}, multisampled: {} }}",
+                            sample_type, view_dimension, multi
+                        )
+                    }
+                    ImageClass::Depth { multi } => format!(
+                        "::wgpu::BindingType::Texture {{ sample_type: ::wgpu::TextureSampleType::Depth, view_dimension: ::wgpu::TextureViewDimension::{}, multisampled: {} }}",
+                        view_dimension, multi
+                    ),
+                    ImageClass::Storage { format, access } => {
+                        let access = if access.contains(StorageAccess::LOAD | StorageAccess::STORE)
+                        {
+                            "ReadWrite"
+                        } else if access.contains(StorageAccess::STORE) {
+                            "WriteOnly"
+                        } else {
+                            "ReadOnly"
+                        };
+                        format!(
+                            "::wgpu::BindingType::StorageTexture {{ access: ::wgpu::StorageTextureAccess::{}, format: ::wgpu::TextureFormat::{:?}, view_dimension: ::wgpu::TextureViewDimension::{} }}",
+                            access, format, view_dimension
+                        )
+                    }
+                }
+            }
+            TypeInner::Sampler { comparison: true } => {
+                "::wgpu::BindingType::Sampler(::wgpu::SamplerBindingType::Comparison)".to_string()
+            }
+            TypeInner::Sampler { comparison: false } => {
+                "::wgpu::BindingType::Sampler(::wgpu::SamplerBindingType::Filtering)".to_string()
+            }
+            TypeInner::AccelerationStructure => {
+                "::wgpu::BindingType::AccelerationStructure".to_string()
+            }
+            ref inner => return Err(format!("unsupported binding type {:?}", inner)),
+        },
+    };
+    Ok(binding_type)
+}
+
+fn binding_count(module: &Module, ty: Handle<naga::Type>) -> String {
+    match module.types[ty].inner {
+        TypeInner::BindingArray {
+            size: ArraySize::Constant(size),
+            ..
+        } => format!("::core::num::NonZeroU32::new({})", size),
+        _ => "None".to_string(),
+    }
+}
+
+/// Returns the type bind group builders take for a binding, and the
+/// expression turning `resource` into a `wgpu::BindingResource`.
+///
+/// Buffers are bound whole. Acceleration structures don't have a
+/// `BindingResource` of their own, so they take one directly.
+fn binding_resource(
+    module: &Module,
+    space: AddressSpace,
+    ty: Handle<naga::Type>,
+) -> (&'static str, &'static str) {
+    let (inner, array) = match module.types[ty].inner {
+        TypeInner::BindingArray { base, .. } => (&module.types[base].inner, true),
+        ref inner => (inner, false),
+    };
+    match (space, inner, array) {
+        (AddressSpace::Uniform | AddressSpace::Storage { .. }, _, false) => {
+            ("&'a ::wgpu::Buffer", "resource.as_entire_binding()")
+        }
+        (AddressSpace::Uniform | AddressSpace::Storage { .. }, _, true) => (
+            "&'a [::wgpu::BufferBinding<'a>]",
+            "::wgpu::BindingResource::BufferArray(resource)",
+        ),
+        (_, TypeInner::Image { .. }, false) => (
+            "&'a ::wgpu::TextureView",
+            "::wgpu::BindingResource::TextureView(resource)",
+        ),
+        (_, TypeInner::Image { .. }, true) => (
+            "&'a [&'a ::wgpu::TextureView]",
+            "::wgpu::BindingResource::TextureViewArray(resource)",
+        ),
+        (_, TypeInner::Sampler { .. }, false) => (
+            "&'a ::wgpu::Sampler",
+            "::wgpu::BindingResource::Sampler(resource)",
+        ),
+        (_, TypeInner::Sampler { .. }, true) => (
+            "&'a [&'a ::wgpu::Sampler]",
+            "::wgpu::BindingResource::SamplerArray(resource)",
+        ),
+        _ => ("::wgpu::BindingResource<'a>", "resource"),
+    }
+}
+
+/// Returns the bindings of a shader, along with the stages that use them.
+pub fn bindings(shader: &Shader) -> Result<Vec<Binding>, String> {
+    let module = &shader.module;
+    let mut bindings = vec![];
+
+    for (handle, var) in module.global_variables.iter() {
+        let Some(ref resource) = var.binding else {
+            continue;
+        };
+
+        let mut visibility = Stages::default();
+        for (i, entry_point) in module.entry_points.iter().enumerate() {
+            if !shader.info.get_entry_point(i)[handle].is_empty() {
+                visibility = visibility.union(Stages::from_stage(entry_point.stage));
+            }
+        }
+
+        let name = var.name.clone().unwrap_or_default();
+        let ty = binding_type(module, var.space, var.ty).map_err(|e| format!("{} for {}", e, name))?;
+        let (param, binding_resource) = binding_resource(module, var.space, var.ty);
+        bindings.push(Binding {
+            name,
+            group: resource.group,
+            binding: resource.binding,
+            visibility,
+            ty,
+            count: binding_count(module, var.ty),
+            param: param.to_string(),
+            resource: binding_resource.to_string(),
+        });
+    }
+
+    Ok(bindings)
+}
+
+/// Returns the push constant size used by each stage of a shader.
+pub fn push_constants(shader: &Shader) -> Vec<(Stages, u32)> {
+    let module = &shader.module;
+    let mut ranges = vec![];
+
+    for (handle, var) in module.global_variables.iter() {
+        if var.space != AddressSpace::PushConstant {
+            continue;
+        }
+
+        let size = module.types[var.ty].inner.size(module.to_ctx());
+        for (i, entry_point) in module.entry_points.iter().enumerate() {
+            if !shader.info.get_entry_point(i)[handle].is_empty() {
+                ranges.push((Stages::from_stage(entry_point.stage), size));
+            }
+        }
+    }
+
+    ranges
+}
+
+/// Merges push constant sizes into `wgpu::PushConstantRange`s, as Rust source.
+///
+/// Stages using the same size share a range.
+pub fn push_constant_ranges(sizes: &[(Stages, u32)]) -> Vec<String> {
+    let mut ranges: Vec<(Stages, u32)> = vec![];
+    for (stages, size) in sizes.iter() {
+        match ranges.iter_mut().find(|(_, s)| s == size) {
+            Some(range) => range.0 = range.0.union(*stages),
+            None => ranges.push((*stages, *size)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(stages, size)| {
+            format!(
+                "::wgpu::PushConstantRange {{ stages: {}, range: 0..{} }}",
+                stages.to_rust(),
+                size
+            )
+        })
+        .collect()
+}
+
+/// Generates `PUSH_CONSTANT_RANGES` and `PUSH_CONSTANT_SIZE` constants for a shader.
+///
+/// The size is the end of the largest range, i.e. what has to fit in
+/// `wgpu::Limits::max_push_constant_size`.
+pub fn push_constant_consts(shader: &Shader) -> String {
+    let sizes = push_constants(shader);
+    format!(
+        r#"
+        pub const PUSH_CONSTANT_RANGES: &[::wgpu::PushConstantRange] = &[{}];
+        pub const PUSH_CONSTANT_SIZE: u32 = {};
+        "#,
+        push_constant_ranges(&sizes).join(", "),
+        sizes.iter().map(|(_, size)| *size).max().unwrap_or(0)
+    )
+}
+
+/// Generates the merged pipeline layout across the shaders of one pipeline.
+///
+/// `label` names the pipeline layout, and with `@group(N)` appended, the bind group layouts.
+pub fn pipeline_layout(shaders: &[Shader], label: Option<&str>) -> Result<String, String> {
+    // Merge bindings by (group, binding), making sure the stages agree on their types.
+    let mut merged: BTreeMap<(u32, u32), (String, Binding)> = BTreeMap::new();
+    for shader in shaders.iter() {
+        for binding in bindings(shader)? {
+            let key = (binding.group, binding.binding);
+            match merged.get_mut(&key) {
+                Some((filename, existing)) => {
+                    if existing.ty != binding.ty || existing.count != binding.count {
+                        return Err(format!(
+                            "@group({}) @binding({}) disagrees between shaders: `{}` in {} and `{}` in {}",
+                            key.0, key.1, existing.name, filename, binding.name, shader.filename
+                        ));
+                    }
+                    existing.visibility = existing.visibility.union(binding.visibility);
+                }
+                None => {
+                    merged.insert(key, (shader.filename.clone(), binding));
+                }
+            }
+        }
+    }
+
+    let sizes = shaders
+        .iter()
+        .flat_map(push_constants)
+        .collect::<Vec<_>>();
+    for (i, (stages, size)) in sizes.iter().enumerate() {
+        if sizes[..i].iter().any(|(s, other)| s == stages && other != size) {
+            return Err("push constant sizes disagree between shaders".to_string());
+        }
+    }
+
+    // Bind group layouts must be contiguous, so unused groups are left empty.
+    let group_count = merged.keys().last().map(|(group, _)| group + 1).unwrap_or(0);
+    let mut groups = vec![];
+    for group in 0..group_count {
+        let entries = merged
+            .range((group, 0)..=(group, u32::MAX))
+            .map(|(_, (_, binding))| {
+                format!(
+                    "::wgpu::BindGroupLayoutEntry {{ binding: {}, visibility: {}, ty: {}, count: {} }}",
+                    binding.binding,
+                    binding.visibility.to_rust(),
+                    binding.ty,
+                    binding.count
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let group_label = label.map(|label| format!("{} @group({})", label, group));
+        groups.push(format!(
+            "::wgpu::BindGroupLayoutDescriptor {{ label: {:?}, entries: &[{}] }}",
+            group_label,
+            entries.join(", ")
+        ));
+    }
+
+    let layout_label = format!("{:?}", label);
+    Ok(format!(
+        r#"{{
+            struct PipelineLayout {{
+                pub bind_group_layouts: &'static [::wgpu::BindGroupLayoutDescriptor<'static>],
+                pub push_constant_ranges: &'static [::wgpu::PushConstantRange],
+            }}
+
+            impl PipelineLayout {{
+                #[allow(dead_code)]
+                fn create(&self, device: &::wgpu::Device) -> (::std::vec::Vec<::wgpu::BindGroupLayout>, ::wgpu::PipelineLayout) {{
+                    let bind_group_layouts = self
+                        .bind_group_layouts
+                        .iter()
+                        .map(|desc| device.create_bind_group_layout(desc))
+                        .collect::<::std::vec::Vec<_>>();
+                    let pipeline_layout = device.create_pipeline_layout(&::wgpu::PipelineLayoutDescriptor {{
+                        label: {},
+                        bind_group_layouts: &bind_group_layouts.iter().collect::<::std::vec::Vec<_>>(),
+                        push_constant_ranges: self.push_constant_ranges,
+                    }});
+                    (bind_group_layouts, pipeline_layout)
+                }}
+            }}
+
+            const BIND_GROUP_LAYOUTS: &[::wgpu::BindGroupLayoutDescriptor<'static>] = &[{}];
+            const PUSH_CONSTANT_RANGES: &[::wgpu::PushConstantRange] = &[{}];
+
+            PipelineLayout {{
+                bind_group_layouts: BIND_GROUP_LAYOUTS,
+                push_constant_ranges: PUSH_CONSTANT_RANGES,
+            }}
+        }}"#,
+        layout_label,
+        groups.join(", "),
+        push_constant_ranges(&sizes).join(", ")
+    ))
+}
+
+/// Options for generating Rust structs.
+#[derive(Default)]
+pub struct StructOptions {
+    /// Derive `encase::ShaderType` instead of `bytemuck::Pod`.
+    pub encase: bool,
+    /// WGSL struct names mapped to existing Rust types.
+    pub map: HashMap<String, String>,
+}
+
+fn scalar_type(scalar: naga::Scalar) -> Result<&'static str, String> {
+    match (scalar.kind, scalar.width) {
+        (ScalarKind::Float, 4) => Ok("f32"),
+        (ScalarKind::Float, 8) => Ok("f64"),
+        (ScalarKind::Sint, 4) => Ok("i32"),
+        (ScalarKind::Uint, 4) => Ok("u32"),
+        (ScalarKind::Sint, 8) => Ok("i64"),
+        (ScalarKind::Uint, 8) => Ok("u64"),
+        _ => Err(format!("unsupported scalar type: {:?}", scalar)),
+    }
+}
+
+/// Returns the `glam` type for a vector or matrix, as used by encase.
+fn glam_type(inner: &TypeInner) -> Result<String, String> {
+    let prefix = |scalar: naga::Scalar| match scalar_type(scalar)? {
+        "f32" => Ok(""),
+        "f64" => Ok("D"),
+        "i32" => Ok("I"),
+        "u32" => Ok("U"),
+        _ => Err(format!("unsupported glam scalar type: {:?}", scalar)),
+    };
+
+    match *inner {
+        TypeInner::Vector { size, scalar } => {
+            Ok(format!("::glam::{}Vec{}", prefix(scalar)?, size as u32))
+        }
+        TypeInner::Matrix {
+            columns,
+            rows,
+            scalar,
+        } if columns == rows && (scalar.width == 4 || scalar.width == 8) => {
+            Ok(format!("::glam::{}Mat{}", prefix(scalar)?, columns as u32))
+        }
+        _ => Err(format!("unsupported encase type: {:?}", inner)),
+    }
+}
+
+/// Returns the Rust type mirroring a WGSL type, along with its size in bytes.
+fn rust_type(
+    module: &Module,
+    ty: Handle<naga::Type>,
+    options: &StructOptions,
+) -> Result<(String, u32), String> {
+    let ty = &module.types[ty];
+    match ty.inner {
+        TypeInner::Scalar(s) | TypeInner::Atomic(s) => {
+            Ok((scalar_type(s)?.to_string(), s.width as u32))
+        }
+        TypeInner::Vector { .. } | TypeInner::Matrix { .. } if options.encase => {
+            Ok((glam_type(&ty.inner)?, ty.inner.size(module.to_ctx())))
+        }
+        TypeInner::Vector { size, scalar } => Ok((
+            format!("[{}; {}]", scalar_type(scalar)?, size as u32),
+            size as u32 * scalar.width as u32,
+        )),
+        TypeInner::Matrix {
+            columns,
+            rows,
+            scalar,
+        } => {
+            // Columns are aligned like vectors, so vec3 columns are padded to vec4.
+            let rows = match rows {
+                naga::VectorSize::Tri => 4,
+                rows => rows as u32,
+            };
+            Ok((
+                format!("[[{}; {}]; {}]", scalar_type(scalar)?, rows, columns as u32),
+                columns as u32 * rows * scalar.width as u32,
+            ))
+        }
+        TypeInner::Array {
+            base,
+            size: ArraySize::Constant(size),
+            stride,
+        } => {
+            let (mut base_ty, mut base_size) = rust_type(module, base, options)?;
+            // encase pads array elements itself.
+            if options.encase {
+                return Ok((format!("[{}; {}]", base_ty, size), stride * size.get()));
+            }
+
+            // Array elements of vec3 are padded to vec4.
+            if let TypeInner::Vector {
+                size: naga::VectorSize::Tri,
+                scalar,
+            } = module.types[base].inner
+            {
+                base_ty = format!("[{}; 4]", scalar_type(scalar)?);
+                base_size = 4 * scalar.width as u32;
+            }
+
+            if base_size != stride {
+                return Err(format!(
+                    "unsupported array stride {} for element type {}",
+                    stride, base_ty
+                ));
+            }
+            Ok((format!("[{}; {}]", base_ty, size), stride * size.get()))
+        }
+        TypeInner::Array {
+            base,
+            size: ArraySize::Dynamic,
+            stride,
+        } if options.encase => {
+            let (base_ty, _) = rust_type(module, base, options)?;
+            Ok((format!("::std::vec::Vec<{}>", base_ty), stride))
+        }
+        TypeInner::Struct { span, .. } => match ty.name {
+            Some(ref name) => Ok((options.map.get(name).unwrap_or(name).clone(), span)),
+            None => Err("unsupported anonymous struct".to_string()),
+        },
+        _ => Err(format!(
+            "unsupported type: {}",
+            ty.name.clone().unwrap_or_else(|| format!("{:?}", ty.inner))
+        )),
+    }
+}
+
+pub fn rust_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else",
+        "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+        "move", "mut", "pub", "ref", "return", "static", "struct", "trait", "true", "type",
+        "unsafe", "use", "where", "while", "yield",
+    ];
+
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn is_runtime_sized(module: &Module, ty: Handle<naga::Type>) -> bool {
+    match module.types[ty].inner {
+        TypeInner::Array {
+            size: ArraySize::Dynamic,
+            ..
+        } => true,
+        TypeInner::Struct { ref members, .. } => members
+            .last()
+            .map(|member| is_runtime_sized(module, member.ty))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Returns `#[doc]` attributes for the WGSL doc comments of `key`, if any.
+fn doc_attrs(docs: &Docs, key: &str) -> String {
+    docs.get(key)
+        .map(|doc| {
+            doc.lines()
+                .map(|line| format!("#[doc = {:?}]", line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+/// Generates a `#[repr(C)]` struct with explicit padding, deriving `bytemuck::Pod`.
+fn pod_struct(
+    module: &Module,
+    docs: &Docs,
+    name: &str,
+    members: &[naga::StructMember],
+    span: u32,
+    options: &StructOptions,
+) -> Result<String, String> {
+    let mut fields = vec![];
+    let mut args = vec![];
+    let mut inits = vec![];
+    let mut offset = 0;
+    for member in members.iter() {
+        let member_name = member.name.clone().unwrap_or_default();
+        let (member_ty, member_size) = rust_type(module, member.ty, options)
+            .map_err(|e| format!("{}.{}: {}", name, member_name, e))?;
+
+        if member.offset > offset {
+            fields.push(format!("pub _pad{}: [u8; {}],", fields.len(), member.offset - offset));
+            inits.push(format!("_pad{}: [0; {}],", fields.len() - 1, member.offset - offset));
+        }
+
+        let ident = rust_ident(&member_name);
+        let doc = doc_attrs(docs, &format!("{}.{}", name, member_name));
+        fields.push(format!("{} pub {}: {},", doc, ident, member_ty));
+        args.push(format!("{}: {}", ident, member_ty));
+        inits.push(format!("{},", ident));
+        offset = member.offset + member_size;
+    }
+
+    if span > offset {
+        fields.push(format!("pub _pad{}: [u8; {}],", fields.len(), span - offset));
+        inits.push(format!("_pad{}: [0; {}],", fields.len() - 1, span - offset));
+    }
+
+    Ok(format!(
+        r#"
+        {doc}
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq, ::bytemuck::Pod, ::bytemuck::Zeroable)]
+        pub struct {name} {{
+            {fields}
+        }}
+
+        impl {name} {{
+            #[allow(clippy::too_many_arguments)]
+            pub const fn new({args}) -> Self {{
+                Self {{ {inits} }}
+            }}
+        }}
+
+        const _: () = assert!(::core::mem::size_of::<{name}>() == {span});
+        "#,
+        doc = doc_attrs(docs, name),
+        name = name,
+        fields = fields.join("\n"),
+        args = args.join(", "),
+        inits = inits.join(" "),
+        span = span,
+    ))
+}
+
+/// Generates a struct deriving `encase::ShaderType`, which lays out the fields itself.
+fn encase_struct(
+    module: &Module,
+    docs: &Docs,
+    name: &str,
+    members: &[naga::StructMember],
+    span: u32,
+    options: &StructOptions,
+) -> Result<String, String> {
+    let runtime_sized = members
+        .last()
+        .map(|member| is_runtime_sized(module, member.ty))
+        .unwrap_or(false);
+
+    let mut fields = vec![];
+    for (i, member) in members.iter().enumerate() {
+        let member_name = member.name.clone().unwrap_or_default();
+        let (member_ty, _) = rust_type(module, member.ty, options)
+            .map_err(|e| format!("{}.{}: {}", name, member_name, e))?;
+
+        // Only the last field may be runtime sized.
+        let attr = if runtime_sized && i == members.len() - 1 {
+            "#[size(runtime)]"
+        } else {
+            ""
+        };
+        let doc = doc_attrs(docs, &format!("{}.{}", name, member_name));
+        fields.push(format!(
+            "{} {} pub {}: {},",
+            doc,
+            attr,
+            rust_ident(&member_name),
+            member_ty
+        ));
+    }
+
+    let (derives, assertion) = if runtime_sized {
+        ("Clone, Debug, PartialEq", String::new())
+    } else {
+        (
+            "Clone, Copy, Debug, PartialEq",
+            format!(
+                "const _: () = assert!(<{} as ::encase::ShaderSize>::SHADER_SIZE.get() == {});",
+                name, span
+            ),
+        )
+    };
+
+    Ok(format!(
+        r#"
+        {doc}
+        #[derive({derives}, ::encase::ShaderType)]
+        pub struct {name} {{
+            {fields}
+        }}
+
+        {assertion}
+        "#,
+        doc = doc_attrs(docs, name),
+        derives = derives,
+        name = name,
+        fields = fields.join("\n"),
+        assertion = assertion,
+    ))
+}
+
+/// Generates Rust structs mirroring the host-shareable structs of a shader.
+///
+/// By default the structs are `#[repr(C)]` with explicit `_padN` fields so they
+/// can derive `bytemuck::Pod`, and each gets a `new` constructor that zeroes the
+/// padding. With `encase`, they derive `encase::ShaderType` instead. Mapped
+/// structs aren't generated, only checked against the WGSL size.
+pub fn structs(shader: &Shader, options: &StructOptions) -> Result<String, String> {
+    let module = &shader.module;
+    let mut items = vec![];
+
+    for (handle, ty) in module.types.iter() {
+        let TypeInner::Struct { ref members, span } = ty.inner else {
+            continue;
+        };
+        let Some(ref name) = ty.name else {
+            continue;
+        };
+
+        // Skip structs that never touch buffers.
+        if !shader.info[handle].contains(TypeFlags::HOST_SHAREABLE) {
+            continue;
+        }
+
+        if let Some(mapped) = options.map.get(name) {
+            if !is_runtime_sized(module, handle) {
+                items.push(if options.encase {
+                    format!(
+                        "const _: () = assert!(<{} as ::encase::ShaderSize>::SHADER_SIZE.get() == {});",
+                        mapped, span
+                    )
+                } else {
+                    format!(
+                        "const _: () = assert!(::core::mem::size_of::<{}>() == {});",
+                        mapped, span
+                    )
+                });
+            }
+            continue;
+        }
+
+        if options.encase {
+            items.push(encase_struct(module, &shader.docs, name, members, span, options)?);
+        } else if !is_runtime_sized(module, handle) {
+            // Runtime sized structs can't be represented with plain `repr(C)`.
+            items.push(pod_struct(module, &shader.docs, name, members, span, options)?);
+        }
+    }
+
+    for name in options.map.keys() {
+        if !module.types.iter().any(|(_, ty)| ty.name.as_ref() == Some(name)) {
+            return Err(format!("mapped struct {} not found in {}", name, shader.filename));
+        }
+    }
+
+    Ok(items.join("\n"))
+}
+
+/// Generates compile-time assertions that a Rust struct matches the layout of a WGSL struct.
+///
+/// Every WGSL member needs a Rust field of the same name at the same offset,
+/// and the sizes must match. Extra Rust fields are only allowed for padding,
+/// i.e. when their name starts with an underscore.
+pub fn check_struct(
+    shader: &Shader,
+    wgsl_name: &str,
+    rust_name: &str,
+    rust_fields: &[String],
+) -> Result<String, String> {
+    let module = &shader.module;
+    let (members, span) = module
+        .types
+        .iter()
+        .find_map(|(_, ty)| match ty.inner {
+            TypeInner::Struct { ref members, span } if ty.name.as_deref() == Some(wgsl_name) => {
+                Some((members, span))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| format!("struct {} not found in {}", wgsl_name, shader.filename))?;
+
+    let mut assertions = vec![format!(
+        "assert!(::core::mem::size_of::<{rust}>() == {span}, \"size of {rust} doesn't match {wgsl} ({span} bytes)\");",
+        rust = rust_name,
+        wgsl = wgsl_name,
+        span = span
+    )];
+
+    for member in members.iter() {
+        let member_name = member.name.clone().unwrap_or_default();
+        if !rust_fields.contains(&member_name) {
+            return Err(format!(
+                "{} is missing field {} from {}",
+                rust_name, member_name, wgsl_name
+            ));
+        }
+
+        assertions.push(format!(
+            "assert!(::core::mem::offset_of!({rust}, {field}) == {offset}, \"offset of {rust}.{name} doesn't match {wgsl}.{name} ({offset} bytes)\");",
+            rust = rust_name,
+            field = rust_ident(&member_name),
+            name = member_name,
+            wgsl = wgsl_name,
+            offset = member.offset
+        ));
+    }
+
+    for field in rust_fields.iter() {
+        let in_wgsl = members
+            .iter()
+            .any(|member| member.name.as_ref() == Some(field));
+        if !in_wgsl && !field.starts_with('_') {
+            return Err(format!(
+                "{}.{} isn't in {}, padding fields should start with an underscore",
+                rust_name, field, wgsl_name
+            ));
+        }
+    }
+
+    Ok(format!("const _: () = {{ {} }};", assertions.join("\n")))
+}
+
+/// Converts `vs_main` to `VsMain`.
+pub fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Generates `WORKGROUP_SIZE_*` constants for the compute entry points of a shader.
+///
+/// When there's only one compute entry point, `WORKGROUP_SIZE` is generated too.
+pub fn workgroup_sizes(shader: &Shader) -> String {
+    let compute = shader
+        .module
+        .entry_points
+        .iter()
+        .filter(|entry_point| entry_point.stage == ShaderStage::Compute)
+        .collect::<Vec<_>>();
+
+    let mut consts = compute
+        .iter()
+        .map(|entry_point| {
+            format!(
+                "pub const WORKGROUP_SIZE_{}: [u32; 3] = {:?};",
+                entry_point.name.to_uppercase(),
+                entry_point.workgroup_size
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let [entry_point] = compute.as_slice() {
+        consts.push(format!(
+            "pub const WORKGROUP_SIZE: [u32; 3] = {:?};",
+            entry_point.workgroup_size
+        ));
+    }
+
+    consts.join("\n")
+}
+
+/// Returns a `const fn` dividing work items by `workgroup_size`, rounding up.
+fn dispatch_size_fn(name: &str, workgroup_size: [u32; 3]) -> String {
+    format!(
+        r#"
+        /// The workgroups to dispatch to cover `work_items`, rounding up.
+        pub const fn {name}(work_items: [u32; 3]) -> [u32; 3] {{
+            [
+                work_items[0].div_ceil({x}),
+                work_items[1].div_ceil({y}),
+                work_items[2].div_ceil({z}),
+            ]
+        }}
+        "#,
+        name = name,
+        x = workgroup_size[0],
+        y = workgroup_size[1],
+        z = workgroup_size[2],
+    )
+}
+
+/// Generates `dispatch_size_*` functions for the compute entry points of a
+/// shader, dividing work items by the workgroup size.
+///
+/// When there's only one compute entry point, `dispatch_size` is generated too.
+pub fn dispatch_sizes(shader: &Shader) -> String {
+    let compute = shader
+        .module
+        .entry_points
+        .iter()
+        .filter(|entry_point| entry_point.stage == ShaderStage::Compute)
+        .collect::<Vec<_>>();
+
+    let mut fns = compute
+        .iter()
+        .map(|entry_point| {
+            dispatch_size_fn(
+                &format!("dispatch_size_{}", entry_point.name.to_lowercase()),
+                entry_point.workgroup_size,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let [entry_point] = compute.as_slice() {
+        fns.push(dispatch_size_fn(
+            "dispatch_size",
+            entry_point.workgroup_size,
+        ));
+    }
+
+    fns.join("\n")
+}
+
+/// Generates `ENTRY_*` name constants and an `EntryPoint` enum for a shader.
+pub fn entry_points(shader: &Shader) -> String {
+    let module = &shader.module;
+
+    let mut consts = vec![];
+    let mut variants = vec![];
+    let mut names = vec![];
+    let mut labels = vec![];
+    let mut stages = vec![];
+    let mut sizes = vec![];
+    for entry_point in module.entry_points.iter() {
+        let variant = pascal_case(&entry_point.name);
+        let doc = doc_attrs(&shader.docs, &entry_point.name);
+        consts.push(format!(
+            "{} pub const ENTRY_{}: &str = {:?};",
+            doc,
+            entry_point.name.to_uppercase(),
+            entry_point.name
+        ));
+        variants.push(format!("{} {},", doc, variant));
+        names.push(format!("EntryPoint::{} => {:?},", variant, entry_point.name));
+        labels.push(format!(
+            "EntryPoint::{} => {:?},",
+            variant,
+            format!("{}:{}", shader.filename, entry_point.name)
+        ));
+        stages.push(format!(
+            "EntryPoint::{} => {},",
+            variant,
+            Stages::from_stage(entry_point.stage).to_rust()
+        ));
+        sizes.push(match entry_point.stage {
+            ShaderStage::Compute => format!(
+                "EntryPoint::{} => Some({:?}),",
+                variant, entry_point.workgroup_size
+            ),
+            _ => format!("EntryPoint::{} => None,", variant),
+        });
+    }
+
+    if module.entry_points.is_empty() {
+        return String::new();
+    }
+
+    format!(
+        r#"
+        {consts}
+        {workgroup_size_consts}
+        {dispatch_size_fns}
+
+        /// The entry points of the shader.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum EntryPoint {{
+            {variants}
+        }}
+
+        impl EntryPoint {{
+            pub const ALL: &'static [EntryPoint] = &[{all}];
+
+            /// The name of the entry point, as passed to pipeline creation.
+            pub const fn name(self) -> &'static str {{
+                match self {{
+                    {names}
+                }}
+            }}
+
+            /// A label for pipelines using the entry point, e.g. `shader.wgsl:vs_main`.
+            pub const fn label(self) -> &'static str {{
+                match self {{
+                    {labels}
+                }}
+            }}
+
+            /// The stage of the entry point.
+            pub const fn stage(self) -> ::wgpu::ShaderStages {{
+                match self {{
+                    {stages}
+                }}
+            }}
+
+            /// The `@workgroup_size` of compute entry points.
+            pub const fn workgroup_size(self) -> Option<[u32; 3]> {{
+                match self {{
+                    {workgroup_sizes}
+                }}
+            }}
+
+            /// The workgroups to dispatch to cover `work_items`, rounding up,
+            /// for compute entry points.
+            pub const fn dispatch_size(self, work_items: [u32; 3]) -> Option<[u32; 3]> {{
+                match self.workgroup_size() {{
+                    Some(size) => Some([
+                        work_items[0].div_ceil(size[0]),
+                        work_items[1].div_ceil(size[1]),
+                        work_items[2].div_ceil(size[2]),
+                    ]),
+                    None => None,
+                }}
+            }}
+        }}
+        "#,
+        consts = consts.join("\n"),
+        workgroup_size_consts = workgroup_sizes(shader),
+        dispatch_size_fns = dispatch_sizes(shader),
+        workgroup_sizes = sizes.join("\n"),
+        variants = variants.join("\n"),
+        all = module
+            .entry_points
+            .iter()
+            .map(|entry_point| format!("EntryPoint::{}", pascal_case(&entry_point.name)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        names = names.join("\n"),
+        labels = labels.join("\n"),
+        stages = stages.join("\n"),
+    )
+}
+
+/// Returns the default of an `override` as `f64`, if it's a literal.
+fn override_default(module: &Module, init: Option<Handle<naga::Expression>>) -> Option<f64> {
+    match module.global_expressions[init?] {
+        naga::Expression::Literal(literal) => match literal {
+            naga::Literal::F64(v) => Some(v),
+            naga::Literal::F32(v) => Some(v as f64),
+            naga::Literal::U32(v) => Some(v as f64),
+            naga::Literal::I32(v) => Some(v as f64),
+            naga::Literal::U64(v) => Some(v as f64),
+            naga::Literal::I64(v) => Some(v as f64),
+            naga::Literal::Bool(v) => Some(v as u8 as f64),
+            naga::Literal::AbstractInt(v) => Some(v as f64),
+            naga::Literal::AbstractFloat(v) => Some(v),
+        },
+        _ => None,
+    }
+}
+
+/// Generates an `OVERRIDES` table and a typed `Overrides` builder for the
+/// `override` declarations of a shader.
+///
+/// Overrides without a default are required arguments to `Overrides::new`, and
+/// the others get a setter each. `Overrides::build` returns the map passed as
+/// `constants` at pipeline creation, keyed by `@id` where there is one.
+pub fn overrides(shader: &Shader) -> Result<String, String> {
+    let module = &shader.module;
+
+    let mut table = vec![];
+    let mut required = vec![];
+    let mut inits = vec![];
+    let mut setters = vec![];
+    for (_, o) in module.overrides.iter() {
+        let name = o.name.clone().unwrap_or_default();
+        let key = match o.id {
+            Some(id) => id.to_string(),
+            None => name.clone(),
+        };
+
+        let ty = &module.types[o.ty];
+        let (wgsl_ty, rust_ty, to_f64) = match ty.inner {
+            TypeInner::Scalar(scalar) => match (scalar.kind, scalar.width) {
+                (ScalarKind::Bool, _) => ("bool", "bool", "if value { 1.0 } else { 0.0 }"),
+                (ScalarKind::Float, 4) => ("f32", "f32", "value as f64"),
+                (ScalarKind::Float, 8) => ("f64", "f64", "value"),
+                (ScalarKind::Sint, 4) => ("i32", "i32", "value as f64"),
+                (ScalarKind::Uint, 4) => ("u32", "u32", "value as f64"),
+                _ => return Err(format!("unsupported override type for {}", name)),
+            },
+            _ => return Err(format!("unsupported override type for {}", name)),
+        };
+
+        let default = override_default(module, o.init);
+        table.push(format!(
+            "OverrideDecl {{ name: {:?}, id: {:?}, ty: {:?}, default: {:?} }},",
+            name, o.id, wgsl_ty, default
+        ));
+
+        let ident = rust_ident(&name);
+        if o.init.is_none() {
+            required.push(format!("{}: {}", ident, rust_ty));
+            inits.push(format!(
+                "{{ let value = {}; values.insert({:?}.to_string(), {}); }}",
+                ident, key, to_f64
+            ));
+        }
+        setters.push(format!(
+            r#"
+            /// Sets `{name}`{default}.
+            {doc}
+            pub fn {ident}(mut self, value: {rust_ty}) -> Self {{
+                self.values.insert({key:?}.to_string(), {to_f64});
+                self
+            }}
+            "#,
+            name = name,
+            default = default
+                .map(|default| format!(", which defaults to `{}`", default))
+                .unwrap_or_default(),
+            doc = match shader.docs.contains_key(&name) {
+                true => format!("///\n{}", doc_attrs(&shader.docs, &name)),
+                false => String::new(),
+            },
+            ident = ident,
+            rust_ty = rust_ty,
+            key = key,
+            to_f64 = to_f64,
+        ));
+    }
+
+    if module.overrides.is_empty() {
+        return Ok(String::new());
+    }
+
+    Ok(format!(
+        r#"
+        /// An `override` declaration of the shader.
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub struct OverrideDecl {{
+            pub name: &'static str,
+            pub id: Option<u16>,
+            pub ty: &'static str,
+            pub default: Option<f64>,
+        }}
+
+        pub const OVERRIDES: &[OverrideDecl] = &[{table}];
+
+        /// Builds the `constants` map for pipeline creation.
+        #[derive(Clone, Debug)]
+        pub struct Overrides {{
+            values: ::std::collections::HashMap<::std::string::String, f64>,
+        }}
+
+        impl Overrides {{
+            /// Takes the overrides that don't have a default.
+            #[allow(clippy::new_without_default, clippy::too_many_arguments)]
+            pub fn new({required}) -> Self {{
+                #[allow(unused_mut)]
+                let mut values = ::std::collections::HashMap::new();
+                {inits}
+                Self {{ values }}
+            }}
+
+            {setters}
+
+            pub fn build(&self) -> ::std::collections::HashMap<::std::string::String, f64> {{
+                self.values.clone()
+            }}
+        }}
+        "#,
+        table = table.join("\n"),
+        required = required.join(", "),
+        inits = inits.join("\n"),
+        setters = setters.join("\n"),
+    ))
+}
+
+/// Generates `GROUP_*`, `BINDING_*` and `BINDING_TYPE_*` constants for the bindings of a shader.
+///
+/// The binding types carry texture dimensions, sample types, multisampling,
+/// storage formats and sampler types, so they can be checked against the
+/// resources being bound.
+pub fn binding_consts(shader: &Shader) -> Result<String, String> {
+    let consts = bindings(shader)?
+        .iter()
+        .filter(|binding| !binding.name.is_empty())
+        .map(|binding| {
+            let name = binding.name.to_uppercase();
+            format!(
+                "{doc} pub const GROUP_{name}: u32 = {};\n{doc} pub const BINDING_{name}: u32 = {};\n{doc} pub const BINDING_TYPE_{name}: ::wgpu::BindingType = {};",
+                binding.group,
+                binding.binding,
+                binding.ty,
+                doc = doc_attrs(&shader.docs, &binding.name),
+                name = name,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(consts)
+}
+
+/// Returns the name of a binding's field and method in its `BindGroup*`
+/// builder, in snake case like the rest of the Rust code, e.g. `albedoTex`
+/// becomes `albedo_tex`.
+fn binding_ident(name: &str) -> String {
+    rust_ident(&crate::derive::screaming_snake_case(name).to_lowercase())
+}
+
+/// Generates a `BindGroup*` builder for each group of a shader, with a method
+/// per binding taking the resource it binds, so bind groups are put together
+/// by name and type instead of by index.
+pub fn bind_groups(shader: &Shader) -> Result<String, String> {
+    let mut groups: BTreeMap<u32, Vec<Binding>> = BTreeMap::new();
+    for binding in bindings(shader)? {
+        if !binding.name.is_empty() {
+            groups.entry(binding.group).or_default().push(binding);
+        }
+    }
+
+    let mut builders = vec![];
+    for (group, mut bindings) in groups {
+        bindings.sort_by_key(|binding| binding.binding);
+        let mut fields = vec![];
+        let mut setters = vec![];
+        let mut entries = vec![];
+        let mut idents: Vec<(String, &str)> = vec![];
+        for binding in bindings.iter() {
+            let ident = binding_ident(&binding.name);
+            if let Some((_, other)) = idents.iter().find(|(other, _)| *other == ident) {
+                return Err(format!(
+                    "bindings `{}` and `{}` of group {} would both be `{}` in BindGroup{}",
+                    other, binding.name, group, ident, group
+                ));
+            }
+            idents.push((ident.clone(), &binding.name));
+            let doc = doc_attrs(&shader.docs, &binding.name);
+            fields.push(format!(
+                "{}: ::core::option::Option<::wgpu::BindingResource<'a>>,",
+                ident
+            ));
+            setters.push(format!(
+                "{doc} pub fn {ident}(mut self, resource: {param}) -> Self {{ self.{ident} = ::core::option::Option::Some({resource}); self }}",
+                doc = doc,
+                ident = ident,
+                param = binding.param,
+                resource = binding.resource,
+            ));
+            entries.push(format!(
+                "::wgpu::BindGroupEntry {{ binding: {}, resource: self.{}.clone().expect({:?}) }},",
+                binding.binding,
+                ident,
+                format!("binding `{}` of group {} isn't set", binding.name, group)
+            ));
+        }
+
+        builders.push(format!(
+            r#"
+            /// Builds bind group {group}, with a method per binding.
+            #[derive(Clone, Debug, Default)]
+            pub struct BindGroup{group}<'a> {{
+                {fields}
+            }}
+
+            impl<'a> BindGroup{group}<'a> {{
+                pub fn new() -> Self {{
+                    Self::default()
+                }}
+
+                {setters}
+
+                /// The entries of the bind group, in binding order.
+                ///
+                /// Panics if a binding wasn't set.
+                pub fn entries(&self) -> ::std::vec::Vec<::wgpu::BindGroupEntry<'a>> {{
+                    ::std::vec![{entries}]
+                }}
+
+                /// Creates the bind group with `layout`, labeled with the shader's path and the group.
+                ///
+                /// Panics if a binding wasn't set.
+                pub fn create(&self, device: &::wgpu::Device, layout: &::wgpu::BindGroupLayout) -> ::wgpu::BindGroup {{
+                    device.create_bind_group(&::wgpu::BindGroupDescriptor {{
+                        label: ::core::option::Option::Some({label:?}),
+                        layout,
+                        entries: &self.entries(),
+                    }})
+                }}
+            }}
+            "#,
+            group = group,
+            fields = fields.join("\n"),
+            setters = setters.join("\n"),
+            entries = entries.join("\n"),
+            label = format!("{} @group({})", shader.filename, group),
+        ));
+    }
+    Ok(builders.join("\n"))
+}
+
+/// Collects the `@location`s of an entry point argument or result, as
+/// `(name, location)`, looking into structs for their members.
+fn collect_locations(
+    module: &Module,
+    name: Option<&str>,
+    ty: Handle<naga::Type>,
+    binding: Option<&naga::Binding>,
+    locations: &mut Vec<(Option<String>, u32)>,
+) {
+    match (binding, &module.types[ty].inner) {
+        (Some(naga::Binding::Location { location, .. }), _) => {
+            locations.push((name.map(str::to_string), *location))
+        }
+        (None, TypeInner::Struct { members, .. }) => {
+            for member in members.iter() {
+                collect_locations(
+                    module,
+                    member.name.as_deref(),
+                    member.ty,
+                    member.binding.as_ref(),
+                    locations,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Generates `LOC_*` constants for the `@location`s of the entry points'
+/// inputs and outputs, i.e. vertex attributes, inter-stage varyings and color
+/// targets.
+///
+/// Constants are named after the argument or struct member. Names used at
+/// different locations, and results that aren't structs, get a constant per
+/// entry point instead, e.g. `LOC_VS_MAIN_IN_UV` or `LOC_FS_MAIN_OUT`.
+pub fn locations(shader: &Shader) -> String {
+    let module = &shader.module;
+    // (entry point, direction, name, location)
+    let mut all = vec![];
+    for entry_point in module.entry_points.iter() {
+        let function = &entry_point.function;
+        let mut inputs = vec![];
+        for argument in function.arguments.iter() {
+            collect_locations(
+                module,
+                argument.name.as_deref(),
+                argument.ty,
+                argument.binding.as_ref(),
+                &mut inputs,
+            );
+        }
+        let mut outputs = vec![];
+        if let Some(ref result) = function.result {
+            collect_locations(
+                module,
+                None,
+                result.ty,
+                result.binding.as_ref(),
+                &mut outputs,
+            );
+        }
+        for (direction, locations) in [("IN", inputs), ("OUT", outputs)] {
+            for (name, location) in locations {
+                all.push((entry_point.name.as_str(), direction, name, location));
+            }
+        }
+    }
+
+    let mut by_name: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (_, _, name, location) in all.iter() {
+        if let Some(name) = name {
+            by_name
+                .entry(name.to_uppercase())
+                .or_default()
+                .push(*location);
+        }
+    }
+
+    let mut consts = BTreeMap::new();
+    for (entry_point, direction, name, location) in all.iter() {
+        let name = name.as_ref().map(|name| name.to_uppercase());
+        let unambiguous = name
+            .as_ref()
+            .is_some_and(|name| by_name[name].iter().all(|other| other == location));
+        let const_name = match name {
+            Some(name) if unambiguous => format!("LOC_{}", name),
+            Some(name) => format!("LOC_{}_{}_{}", entry_point.to_uppercase(), direction, name),
+            None => format!("LOC_{}_{}", entry_point.to_uppercase(), direction),
+        };
+        consts.insert(const_name, *location);
+    }
+
+    consts
+        .iter()
+        .map(|(name, location)| format!("pub const {}: u32 = {};", name, location))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Generates `GROUP_OFFSETS`, mapping the groups of files included with
+/// `group_offset` to the groups they ended up in.
+pub fn group_offsets(shader: &Shader) -> String {
+    let offsets = shader
+        .group_offsets
+        .iter()
+        .map(|offset| {
+            let filename = forward_slashes(&offset.filename);
+            format!("({:?}, {}, {}),", filename, offset.group, offset.shifted)
+        })
+        .collect::<Vec<_>>();
+    format!(
+        r#"
+        /// `(file, group, shifted group)` for each group shifted by `#include "file" group_offset N`.
+        pub const GROUP_OFFSETS: &[(&str, u32, u32)] = &[{}];
+        "#,
+        offsets.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use wgpu_pp_runtime::preprocess_with_defines;
+
+    use super::*;
+    use crate::validate;
+
+    /// Loads a shader from the fixtures, without the depfile and manifest
+    /// bookkeeping of the macros.
+    fn shader(filename: &str) -> Shader {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let (source, source_info) = preprocess_with_defines(filename, &dir, &[]).unwrap();
+        let (module, info) = validate(&source).unwrap();
+        Shader {
+            filename: filename.to_string(),
+            source,
+            docs: source_info.docs,
+            group_offsets: source_info.group_offsets,
+            dependencies: source_info.dependencies,
+            define_values: source_info.define_values,
+            source_map: source_info.source_map,
+            module,
+            info,
+        }
+    }
+
+    #[test]
+    fn test_pipeline_layout() {
+        let layout = pipeline_layout(&[shader("reflection.wgsl")], Some("reflection")).unwrap();
+        assert!(layout.contains(r#"label: Some("reflection"),"#));
+        assert!(layout.contains(
+            r#"::wgpu::BindGroupLayoutDescriptor { label: Some("reflection @group(0)"), entries: &[::wgpu::BindGroupLayoutEntry { binding: 0, visibility: ::wgpu::ShaderStages::FRAGMENT, ty: ::wgpu::BindingType::Buffer { ty: ::wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: ::core::num::NonZeroU64::new(16) }, count: None }, "#
+        ));
+        assert!(layout.contains(
+            r#"::wgpu::BindGroupLayoutEntry { binding: 1, visibility: ::wgpu::ShaderStages::FRAGMENT, ty: ::wgpu::BindingType::Sampler(::wgpu::SamplerBindingType::Filtering), count: None }] }];"#
+        ));
+        assert!(layout.contains("range: 0..4 }];"));
+
+        let shaders = [
+            shader("reflection.wgsl"),
+            shader("reflection_conflict.wgsl"),
+        ];
+        let e = pipeline_layout(&shaders, None).unwrap_err();
+        assert!(
+            e.starts_with("@group(0) @binding(0) disagrees between shaders:"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_push_constant_consts() {
+        let consts = push_constant_consts(&shader("reflection.wgsl"));
+        assert!(consts.contains(
+            "pub const PUSH_CONSTANT_RANGES: &[::wgpu::PushConstantRange] = &[::wgpu::PushConstantRange { stages: ::wgpu::ShaderStages::VERTEX.union(::wgpu::ShaderStages::COMPUTE), range: 0..4 }];"
+        ));
+        assert!(consts.contains("pub const PUSH_CONSTANT_SIZE: u32 = 4;"));
+    }
+
+    #[test]
+    fn test_structs() {
+        let structs = structs(&shader("reflection.wgsl"), &StructOptions::default()).unwrap();
+        assert!(structs.contains("pub const fn new(position: [f32; 3], intensity: f32) -> Self {"));
+        assert!(structs.contains(r#"#[doc = " In lux."] pub intensity: f32,"#));
+        assert!(structs.contains("const _: () = assert!(::core::mem::size_of::<Light>() == 16);"));
+        // The vec2 is padded up to the struct's 16 byte alignment.
+        assert!(structs.contains("pub _pad2: [u8; 8],"));
+        assert!(structs
+            .contains("const _: () = assert!(::core::mem::size_of::<VertexOutput>() == 32);"));
+    }
+
+    #[test]
+    fn test_check_struct() {
+        let shader = shader("reflection.wgsl");
+        let fields = ["position".to_string(), "intensity".to_string()];
+        let checks = check_struct(&shader, "Light", "Light", &fields).unwrap();
+        assert!(checks.contains("assert!(::core::mem::offset_of!(Light, intensity) == 12,"));
+
+        let e = check_struct(&shader, "Light", "Light", &fields[..1]).unwrap_err();
+        assert_eq!(e, "Light is missing field intensity from Light");
+    }
+
+    #[test]
+    fn test_workgroup_sizes() {
+        assert_eq!(
+            workgroup_sizes(&shader("reflection.wgsl")),
+            "pub const WORKGROUP_SIZE_CS_MAIN: [u32; 3] = [64, 1, 1];\n\
+             pub const WORKGROUP_SIZE: [u32; 3] = [64, 1, 1];"
+        );
+        assert_eq!(
+            workgroup_sizes(&shader("reflection_conflict.wgsl")),
+            "pub const WORKGROUP_SIZE_MAIN: [u32; 3] = [8, 8, 1];\n\
+             pub const WORKGROUP_SIZE: [u32; 3] = [8, 8, 1];"
+        );
+    }
+
+    #[test]
+    fn test_dispatch_sizes() {
+        let dispatch_sizes = dispatch_sizes(&shader("reflection.wgsl"));
+        assert!(dispatch_sizes
+            .contains("pub const fn dispatch_size_cs_main(work_items: [u32; 3]) -> [u32; 3] {"));
+        assert!(dispatch_sizes
+            .contains("pub const fn dispatch_size(work_items: [u32; 3]) -> [u32; 3] {"));
+        assert!(dispatch_sizes.contains("work_items[0].div_ceil(64),"));
+    }
+
+    #[test]
+    fn test_entry_points() {
+        let entry_points = entry_points(&shader("reflection.wgsl"));
+        assert!(entry_points.contains(r#"pub const ENTRY_VS_MAIN: &str = "vs_main";"#));
+        assert!(entry_points.contains(
+            "pub const ALL: &'static [EntryPoint] = &[EntryPoint::VsMain, EntryPoint::FsMain, EntryPoint::CsMain];"
+        ));
+        assert!(entry_points.contains(r#"EntryPoint::FsMain => "reflection.wgsl:fs_main","#));
+        assert!(entry_points.contains("EntryPoint::CsMain => ::wgpu::ShaderStages::COMPUTE,"));
+        assert!(entry_points.contains("EntryPoint::CsMain => Some([64, 1, 1]),"));
+    }
+
+    #[test]
+    fn test_binding_consts() {
+        let consts = binding_consts(&shader("reflection.wgsl")).unwrap();
+        assert!(consts.contains(
+            r#"#[doc = " The light shading the mesh."] pub const GROUP_LIGHT: u32 = 0;"#
+        ));
+        assert!(consts.contains(
+            "pub const BINDING_TYPE_VALUES: ::wgpu::BindingType = ::wgpu::BindingType::Buffer { ty: ::wgpu::BufferBindingType::Storage { read_only: false }, has_dynamic_offset: false, min_binding_size: ::core::num::NonZeroU64::new(4) };"
+        ));
+        // Shifted by the include's group_offset.
+        assert!(consts.contains("pub const GROUP_ALBEDO: u32 = 1;"));
+        assert!(consts.contains(
+            "pub const BINDING_TYPE_ALBEDO: ::wgpu::BindingType = ::wgpu::BindingType::Texture { sample_type: ::wgpu::TextureSampleType::Float { filterable: true }, view_dimension: ::wgpu::TextureViewDimension::D2, multisampled: false };"
+        ));
+    }
+
+    #[test]
+    fn test_group_offsets() {
+        let offsets = group_offsets(&shader("reflection.wgsl"));
+        assert!(offsets.contains(
+            r#"pub const GROUP_OFFSETS: &[(&str, u32, u32)] = &[("material.wgsl", 0, 1),];"#
+        ));
+    }
+
+    #[test]
+    fn test_bind_groups() {
+        let bind_groups = bind_groups(&shader("reflection.wgsl")).unwrap();
+        assert!(bind_groups.contains("pub struct BindGroup0<'a> {"));
+        // Shifted by the include's group_offset.
+        assert!(bind_groups.contains("pub struct BindGroup1<'a> {"));
+        assert!(!bind_groups.contains("BindGroup2"));
+        assert!(bind_groups.contains(
+            r#"#[doc = " The light shading the mesh."] pub fn light(mut self, resource: &'a ::wgpu::Buffer) -> Self { self.light = ::core::option::Option::Some(resource.as_entire_binding()); self }"#
+        ));
+        assert!(bind_groups.contains(
+            "pub fn albedo(mut self, resource: &'a ::wgpu::TextureView) -> Self { self.albedo = ::core::option::Option::Some(::wgpu::BindingResource::TextureView(resource)); self }"
+        ));
+        assert!(bind_groups.contains(
+            "pub fn albedo_sampler(mut self, resource: &'a ::wgpu::Sampler) -> Self { self.albedo_sampler = ::core::option::Option::Some(::wgpu::BindingResource::Sampler(resource)); self }"
+        ));
+        assert!(bind_groups
+            .contains(r#"label: ::core::option::Option::Some("reflection.wgsl @group(1)"),"#));
+    }
+
+    #[test]
+    fn test_binding_ident() {
+        assert_eq!(binding_ident("albedo_sampler"), "albedo_sampler");
+        assert_eq!(binding_ident("albedoTex"), "albedo_tex");
+        assert_eq!(binding_ident("HDRTexture"), "hdr_texture");
+        assert_eq!(binding_ident("Type"), "r#type");
+    }
+
+    /// wgpu isn't a dependency, so the generated code can't be run, but
+    /// `entries` panics through `expect` with this message for each binding.
+    #[test]
+    fn test_bind_groups_unset_binding() {
+        let bind_groups = bind_groups(&shader("reflection.wgsl")).unwrap();
+        assert!(bind_groups.contains(
+            r#"::wgpu::BindGroupEntry { binding: 0, resource: self.light.clone().expect("binding `light` of group 0 isn't set") },"#
+        ));
+        assert!(bind_groups.contains(
+            r#"::wgpu::BindGroupEntry { binding: 1, resource: self.values.clone().expect("binding `values` of group 0 isn't set") },"#
+        ));
+        assert!(bind_groups.contains(
+            r#"::wgpu::BindGroupEntry { binding: 1, resource: self.albedo_sampler.clone().expect("binding `albedo_sampler` of group 1 isn't set") },"#
+        ));
+        assert_eq!(bind_groups.matches(".expect(").count(), 4);
+    }
+
+    #[test]
+    fn test_locations() {
+        // `position` is only at location 0, while `uv` is at 1 in the vertex
+        // attributes and at 0 in VertexOutput, so it falls back to a constant
+        // per entry point, like the fragment shader's result that isn't a struct.
+        // The builtin position in VertexOutput doesn't get a constant.
+        assert_eq!(
+            locations(&shader("reflection.wgsl")),
+            "pub const LOC_FS_MAIN_IN_UV: u32 = 0;\n\
+             pub const LOC_FS_MAIN_OUT: u32 = 0;\n\
+             pub const LOC_POSITION: u32 = 0;\n\
+             pub const LOC_VS_MAIN_IN_UV: u32 = 1;\n\
+             pub const LOC_VS_MAIN_OUT_UV: u32 = 0;"
+        );
+        assert_eq!(locations(&shader("reflection_conflict.wgsl")), "");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let generated = overrides(&shader("reflection.wgsl")).unwrap();
+        assert!(generated.contains(
+            r#"OverrideDecl { name: "gain", id: Some(7), ty: "f32", default: Some(1.0) },"#
+        ));
+        assert!(generated
+            .contains(r#"OverrideDecl { name: "count", id: None, ty: "u32", default: None },"#));
+
+        // Only overrides without a default are arguments of `new`, but every
+        // override gets a setter.
+        assert!(generated.contains("pub fn new(count: u32) -> Self {"));
+        assert!(generated.contains(
+            r#"{ let value = count; values.insert("count".to_string(), value as f64); }"#
+        ));
+        assert!(generated.contains("pub fn gain(mut self, value: f32) -> Self {"));
+        assert!(generated.contains("pub fn count(mut self, value: u32) -> Self {"));
+        assert!(generated.contains("/// Sets `gain`, which defaults to `1`."));
+
+        // Overrides with an `@id` are keyed by it instead of their name.
+        assert!(generated.contains(r#"self.values.insert("7".to_string(), value as f64);"#));
+        assert!(!generated.contains(r#"insert("gain""#));
+        assert!(generated.contains(r#"self.values.insert("count".to_string(), value as f64);"#));
+
+        assert_eq!(overrides(&shader("reflection_conflict.wgsl")).unwrap(), "");
+    }
+
+    #[test]
+    fn test_unsupported_binding_type() {
+        let mut module = Module::default();
+        let ty = module.types.insert(
+            naga::Type {
+                name: None,
+                inner: TypeInner::Scalar(naga::Scalar::F32),
+            },
+            naga::Span::UNDEFINED,
+        );
+        let e = binding_type(&module, AddressSpace::Handle, ty).unwrap_err();
+        assert!(e.starts_with("unsupported binding type Scalar"), "{}", e);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use naga::back::wgsl::{write_string, WriterFlags};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::{Block, Expression, Function, GlobalVariable, Handle, Module, Statement};
+
+/// Calls `f` for every block nested in `block`, including itself.
+fn visit_blocks(block: &mut Block, f: &mut impl FnMut(&mut Block)) {
+    f(block);
+    for statement in block.iter_mut() {
+        match statement {
+            Statement::Block(inner) => visit_blocks(inner, f),
+            Statement::If { accept, reject, .. } => {
+                visit_blocks(accept, f);
+                visit_blocks(reject, f);
+            }
+            Statement::Switch { cases, .. } => {
+                for case in cases.iter_mut() {
+                    visit_blocks(&mut case.body, f);
+                }
+            }
+            Statement::Loop {
+                body, continuing, ..
+            } => {
+                visit_blocks(body, f);
+                visit_blocks(continuing, f);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the functions a function calls directly.
+fn callees(function: &Function) -> Vec<Handle<Function>> {
+    let mut callees = vec![];
+    let mut body = function.body.clone();
+    visit_blocks(&mut body, &mut |block| {
+        for statement in block.iter() {
+            if let Statement::Call { function, .. } = statement {
+                callees.push(*function);
+            }
+        }
+    });
+    callees
+}
+
+/// Points the calls and global variables of a function at their new handles.
+fn remap(
+    function: &mut Function,
+    functions: &HashMap<Handle<Function>, Handle<Function>>,
+    globals: &HashMap<Handle<GlobalVariable>, Handle<GlobalVariable>>,
+) {
+    for (_, expression) in function.expressions.iter_mut() {
+        match expression {
+            Expression::CallResult(handle) => *handle = functions[handle],
+            Expression::GlobalVariable(handle) => *handle = globals[handle],
+            _ => {}
+        }
+    }
+    visit_blocks(&mut function.body, &mut |block| {
+        for statement in block.iter_mut() {
+            if let Statement::Call { function, .. } = statement {
+                *function = functions[function];
+            }
+        }
+    });
+}
+
+/// Returns a copy of `module` with only one entry point, and the functions and globals it uses.
+///
+/// Unused anonymous types and expressions are removed by `compact`, named
+/// types and constants are kept.
+fn entry_point_module(module: &Module, index: usize) -> Module {
+    let mut module = module.clone();
+    let entry_point = module.entry_points.swap_remove(index);
+
+    // Find the functions reachable from the entry point.
+    let mut reachable = vec![];
+    let mut stack = callees(&entry_point.function);
+    while let Some(handle) = stack.pop() {
+        if !reachable.contains(&handle) {
+            reachable.push(handle);
+            stack.extend(callees(&module.functions[handle]));
+        }
+    }
+
+    // Find the globals used by those functions.
+    let mut used_globals = vec![];
+    for function in reachable
+        .iter()
+        .map(|handle| &module.functions[*handle])
+        .chain([&entry_point.function])
+    {
+        for (_, expression) in function.expressions.iter() {
+            if let Expression::GlobalVariable(handle) = expression {
+                used_globals.push(*handle);
+            }
+        }
+    }
+
+    // Rebuild the arenas without the unused items, keeping their order.
+    let mut functions = HashMap::new();
+    for (handle, function, span) in std::mem::take(&mut module.functions).drain() {
+        if reachable.contains(&handle) {
+            functions.insert(handle, module.functions.append(function, span));
+        }
+    }
+    let mut globals = HashMap::new();
+    for (handle, global, span) in std::mem::take(&mut module.global_variables).drain() {
+        if used_globals.contains(&handle) {
+            globals.insert(handle, module.global_variables.append(global, span));
+        }
+    }
+
+    for (_, function) in module.functions.iter_mut() {
+        remap(function, &functions, &globals);
+    }
+    module.entry_points = vec![entry_point];
+    remap(&mut module.entry_points[0].function, &functions, &globals);
+
+    naga::compact::compact(&mut module);
+    module
+}
+
+/// Splits a shader into a WGSL source per entry point.
+///
+/// Each source only contains the entry point and the functions and globals it
+/// uses, so drivers don't compile unrelated entry points at pipeline creation.
+pub fn split(module: &Module) -> Result<Vec<(String, String)>, String> {
+    let capabilities = Capabilities::default() | Capabilities::PUSH_CONSTANT;
+    let mut sources = vec![];
+    for (i, entry_point) in module.entry_points.iter().enumerate() {
+        let module = entry_point_module(module, i);
+        let info = Validator::new(ValidationFlags::all(), capabilities)
+            .validate(&module)
+            .map_err(|e| format!("failed to split {}: {}", entry_point.name, e))?;
+        let source = write_string(&module, &info, WriterFlags::empty())
+            .map_err(|e| format!("failed to split {}: {}", entry_point.name, e))?;
+        sources.push((entry_point.name.clone(), source));
+    }
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    const SHADER: &str = "
+@group(0) @binding(0) var<uniform> offset: vec4<f32>;
+@group(0) @binding(1) var<storage, read_write> counts: array<u32>;
+
+fn shift(position: vec4<f32>) -> vec4<f32> { return position + offset; }
+fn scale(position: vec4<f32>) -> vec4<f32> { return shift(position) * 2.0; }
+fn count(i: u32) { counts[i] += 1u; }
+
+@vertex
+fn vs_main(@location(0) position: vec4<f32>) -> @builtin(position) vec4<f32> {
+    if position.x > 0.0 { return scale(position); }
+    return position;
+}
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> { return vec4<f32>(1.0); }
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) { count(id.x); }
+";
+
+    /// Splits `SHADER`, checking that each source is still valid.
+    fn sources() -> HashMap<String, String> {
+        let (module, _) = validate(SHADER).unwrap();
+        let sources = split(&module).unwrap();
+        for (_, source) in sources.iter() {
+            validate(source).unwrap();
+        }
+        sources.into_iter().collect()
+    }
+
+    #[test]
+    fn test_callees() {
+        let (module, _) = validate(SHADER).unwrap();
+        let name = |handle: &Handle<Function>| module.functions[*handle].name.clone().unwrap();
+
+        let vs_main = &module.entry_points[0].function;
+        assert_eq!(
+            callees(vs_main).iter().map(name).collect::<Vec<_>>(),
+            ["scale"]
+        );
+        let fs_main = &module.entry_points[1].function;
+        assert!(callees(fs_main).is_empty());
+    }
+
+    #[test]
+    fn test_split() {
+        let sources = sources();
+        assert_eq!(sources.len(), 3);
+
+        // Calls nested in blocks are followed, along with the globals they use.
+        let vertex = &sources["vs_main"];
+        assert!(vertex.contains("fn scale("));
+        assert!(vertex.contains("fn shift("));
+        assert!(vertex.contains("var<uniform> offset"));
+        assert!(!vertex.contains("fn count("));
+        assert!(!vertex.contains("counts"));
+        assert!(!vertex.contains("fn fs_main("));
+
+        let fragment = &sources["fs_main"];
+        assert!(fragment.contains("fn fs_main("));
+        assert!(!fragment.contains("fn shift("));
+        assert!(!fragment.contains("offset"));
+
+        let compute = &sources["cs_main"];
+        assert!(compute.contains("fn count("));
+        assert!(compute.contains("counts"));
+        assert!(!compute.contains("offset"));
+        assert!(!compute.contains("fn vs_main("));
+    }
+}
@@ -0,0 +1,162 @@
+//! Complexity statistics of a validated shader, generated by
+//! `include_wgsl_module!(..., stats)` so plain Rust tests can fail when a
+//! shader grows past a threshold.
+
+use std::collections::HashSet;
+
+use naga::{Block, Function, Handle, Module, Statement};
+
+use crate::Shader;
+
+/// Counts the statements in `block`, including nested ones, collecting the
+/// functions they call.
+fn count_statements(block: &Block, calls: &mut Vec<Handle<Function>>) -> usize {
+    let mut count = 0;
+    for statement in block.iter() {
+        count += 1;
+        match statement {
+            Statement::Block(block) => count += count_statements(block, calls),
+            Statement::If { accept, reject, .. } => {
+                count += count_statements(accept, calls) + count_statements(reject, calls)
+            }
+            Statement::Switch { cases, .. } => {
+                for case in cases.iter() {
+                    count += count_statements(&case.body, calls);
+                }
+            }
+            Statement::Loop {
+                body, continuing, ..
+            } => count += count_statements(body, calls) + count_statements(continuing, calls),
+            Statement::Call { function, .. } => calls.push(*function),
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Approximates the instructions of a function as its expressions and statements.
+fn function_size(function: &Function, calls: &mut Vec<Handle<Function>>) -> usize {
+    function.expressions.len() + count_statements(&function.body, calls)
+}
+
+/// Approximates the instructions of an entry point, along with every function
+/// it calls, directly or not, counted once each.
+fn instructions(module: &Module, function: &Function) -> usize {
+    let mut calls = vec![];
+    let mut size = function_size(function, &mut calls);
+    let mut counted = HashSet::new();
+    while let Some(called) = calls.pop() {
+        if counted.insert(called) {
+            size += function_size(&module.functions[called], &mut calls);
+        }
+    }
+    size
+}
+
+/// Generates a `stats` module with the statistics of a shader.
+pub fn stats_module(shader: &Shader) -> String {
+    let module = &shader.module;
+    let mut table = vec![];
+    let mut consts = vec![];
+    for entry_point in module.entry_points.iter() {
+        let size = instructions(module, &entry_point.function);
+        table.push(format!("({:?}, {}),", entry_point.name, size));
+        consts.push(format!(
+            "/// The approximate instructions of `{}`.\npub const INSTRUCTIONS_{}: usize = {};",
+            entry_point.name,
+            entry_point.name.to_uppercase(),
+            size
+        ));
+    }
+
+    format!(
+        r#"
+        /// Complexity statistics of the validated shader.
+        pub mod stats {{
+            /// Functions, not counting entry points.
+            pub const FUNCTIONS: usize = {functions};
+            pub const ENTRY_POINTS: usize = {entry_points};
+            /// Module scope `var`s, i.e. bindings, workgroup and private variables.
+            pub const GLOBAL_VARIABLES: usize = {globals};
+            /// The approximate instructions of each entry point, counted as Naga
+            /// expressions and statements, including the functions it calls.
+            pub const INSTRUCTIONS: &[(&str, usize)] = &[{table}];
+            {consts}
+        }}
+        "#,
+        functions = module.functions.len(),
+        entry_points = module.entry_points.len(),
+        globals = module.global_variables.len(),
+        table = table.join(" "),
+        consts = consts.join("\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    const SHADER: &str = "
+fn leaf(x: f32) -> f32 { return x * 2.0; }
+fn helper(x: f32) -> f32 { return leaf(x) + leaf(x); }
+
+@compute @workgroup_size(1)
+fn cs_main() {
+    var x = 1.0;
+    if x > 0.0 {
+        loop {
+            x = helper(x);
+            if x > 100.0 { break; }
+        }
+    }
+}
+
+@compute @workgroup_size(1)
+fn cs_empty() {}
+";
+
+    fn function<'a>(module: &'a Module, name: &str) -> (Handle<Function>, &'a Function) {
+        module
+            .functions
+            .iter()
+            .find(|(_, function)| function.name.as_deref() == Some(name))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_count_statements() {
+        let (module, _) = validate(SHADER).unwrap();
+        let cs_main = &module.entry_points[0].function;
+
+        // The call is nested in a loop in an `if`, and still counted.
+        let mut calls = vec![];
+        let nested = count_statements(&cs_main.body, &mut calls);
+        assert_eq!(calls, [function(&module, "helper").0]);
+        let top_level = cs_main.body.len();
+        assert!(nested > top_level + 3);
+
+        let (leaf, _) = function(&module, "leaf");
+        let mut calls = vec![];
+        count_statements(&function(&module, "helper").1.body, &mut calls);
+        assert_eq!(calls, [leaf, leaf]);
+    }
+
+    #[test]
+    fn test_instructions() {
+        let (module, _) = validate(SHADER).unwrap();
+        let size = |function: &Function| function_size(function, &mut vec![]);
+        let (_, helper) = function(&module, "helper");
+        let (_, leaf) = function(&module, "leaf");
+
+        // `leaf` is called twice, but counted once.
+        let cs_main = &module.entry_points[0].function;
+        assert_eq!(
+            instructions(&module, cs_main),
+            size(cs_main) + size(helper) + size(leaf)
+        );
+
+        let cs_empty = &module.entry_points[1].function;
+        assert_eq!(instructions(&module, cs_empty), size(cs_empty));
+    }
+}
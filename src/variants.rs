@@ -0,0 +1,548 @@
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use crate::args::MacroOption;
+use crate::compile_error;
+use crate::reflection::{pascal_case, rust_ident};
+
+/// Generating more variants than this is most likely a mistake, and slows down compilation.
+const MAX_VARIANTS: usize = 1024;
+
+/// Options of the other `include_wgsl` macros. They aren't defines here, so
+/// they're rejected rather than silently defined.
+const MACRO_OPTIONS: &[&str] = &[
+    "budget",
+    "expect",
+    "bounds_checks",
+    "defines",
+    "label",
+    "encase",
+    "map",
+    "stats",
+];
+
+/// A define the variants are generated over.
+pub struct Define {
+    pub name: String,
+    /// The possible values, `false` and `true` for boolean defines.
+    pub values: Vec<String>,
+}
+
+impl Define {
+    fn is_bool(&self) -> bool {
+        self.values == ["false", "true"]
+    }
+
+    /// The field of the define in `VariantKey`, e.g. `use_fog`.
+    fn field(&self) -> String {
+        rust_ident(&self.name.to_lowercase())
+    }
+
+    /// The enum generated for the values of non-boolean defines, e.g. `Quality`.
+    fn enum_name(&self) -> String {
+        pascal_case(&self.name.to_lowercase())
+    }
+
+    /// Names the enum variants after the values, e.g. `High` for `HIGH` and
+    /// `V2_5` for `2.5`, falling back to their index if those clash.
+    fn variant_names(&self) -> Vec<String> {
+        let names = self
+            .values
+            .iter()
+            .map(|value| {
+                let is_ident = value.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                    && value.chars().all(|c| c.is_alphanumeric() || c == '_');
+                match is_ident {
+                    true => pascal_case(&value.to_lowercase()),
+                    false => {
+                        let value = value
+                            .chars()
+                            .filter(|c| !c.is_whitespace())
+                            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                            .collect::<String>();
+                        format!("V{}", value)
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let unique = names
+            .iter()
+            .enumerate()
+            .all(|(i, name)| !name.is_empty() && !names[..i].contains(name));
+        match unique {
+            true => names,
+            false => (0..names.len()).map(|i| format!("V{}", i)).collect(),
+        }
+    }
+
+    /// The pattern or expression for a value of the define, e.g. `true` or `Quality::High`.
+    fn key_value(&self, i: usize) -> String {
+        match self.is_bool() {
+            true => self.values[i].clone(),
+            false => format!("{}::{}", self.enum_name(), self.variant_names()[i]),
+        }
+    }
+}
+
+/// The defines, and the value of each define for every variant.
+pub struct Variants {
+    pub defines: Vec<Define>,
+    /// Indices into the values of each define, in the order of `defines`.
+    pub combinations: Vec<Vec<usize>>,
+    /// Whether `combinations` holds every combination, rather than an explicit list.
+    pub exhaustive: bool,
+}
+
+impl Variants {
+    /// Returns the `(name, value)` pairs of a variant.
+    pub fn defines(&self, combination: &[usize]) -> Vec<(String, String)> {
+        self.defines
+            .iter()
+            .zip(combination.iter())
+            .map(|(define, i)| (define.name.clone(), define.values[*i].clone()))
+            .collect()
+    }
+
+    /// Returns the `VariantKey` of a variant, as Rust source.
+    fn key(&self, combination: &[usize]) -> String {
+        let fields = self
+            .defines
+            .iter()
+            .zip(combination.iter())
+            .map(|(define, i)| format!("{}: {}", define.field(), define.key_value(*i)))
+            .collect::<Vec<_>>();
+        format!("VariantKey {{ {} }}", fields.join(", "))
+    }
+
+    /// Describes a variant for error messages, e.g. `USE_FOG = true, QUALITY = 2`.
+    pub fn describe(&self, combination: &[usize]) -> String {
+        self.defines(combination)
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Returns the options `include_wgsl_variants!` allows: `base`, `variants`,
+/// and every other option, which is a define, unless another macro takes it.
+pub fn allowed_options(options: &[MacroOption]) -> Vec<&str> {
+    let mut allowed = vec!["base", "variants"];
+    allowed.extend(
+        options
+            .iter()
+            .map(|option| option.name.as_str())
+            .filter(|name| !MACRO_OPTIONS.contains(name)),
+    );
+    allowed
+}
+
+/// Returns how many combinations of `defines` there are, or `usize::MAX` if
+/// that overflows.
+fn count(defines: &[Define]) -> usize {
+    defines
+        .iter()
+        .try_fold(1usize, |count, define| {
+            count.checked_mul(define.values.len())
+        })
+        .unwrap_or(usize::MAX)
+}
+
+/// Returns every combination of `defines`, with the last define changing fastest.
+fn every_combination(defines: &[Define]) -> Vec<Vec<usize>> {
+    (0..count(defines))
+        .map(|mut n| {
+            let mut combination = vec![0; defines.len()];
+            for (i, define) in defines.iter().enumerate().rev() {
+                combination[i] = n % define.values.len();
+                n /= define.values.len();
+            }
+            combination
+        })
+        .collect()
+}
+
+fn split_commas(tokens: impl IntoIterator<Item = TokenTree>) -> Vec<Vec<TokenTree>> {
+    let mut items = vec![vec![]];
+    for token in tokens {
+        match token {
+            TokenTree::Punct(ref p) if p.as_char() == ',' => items.push(vec![]),
+            token => items.last_mut().unwrap().push(token),
+        }
+    }
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn to_string(tokens: &[TokenTree]) -> String {
+    tokens.iter().cloned().collect::<TokenStream>().to_string()
+}
+
+/// Parses one explicit variant, e.g. `[USE_FOG, QUALITY = 2]`.
+///
+/// Boolean defines that aren't listed are `false`, and other defines take their first value.
+fn parse_variant(defines: &[Define], tokens: &[TokenTree]) -> Result<Vec<usize>, TokenStream> {
+    let group = match tokens {
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Bracket => group,
+        _ => {
+            return Err(compile_error(
+                "expected variants as `[NAME, NAME = value, ...]`",
+            ))
+        }
+    };
+
+    let mut combination = vec![0; defines.len()];
+    for item in split_commas(group.stream()) {
+        let (name, value) = match item.as_slice() {
+            [TokenTree::Ident(name)] => (name.to_string(), "true".to_string()),
+            [TokenTree::Ident(name), TokenTree::Punct(p), value @ ..] if p.as_char() == '=' => {
+                (name.to_string(), to_string(value))
+            }
+            _ => {
+                let msg = format!(
+                    "expected `NAME` or `NAME = value`, got `{}`",
+                    to_string(&item)
+                );
+                return Err(compile_error(&msg));
+            }
+        };
+
+        let Some(i) = defines.iter().position(|define| define.name == name) else {
+            return Err(compile_error(&format!("unknown define: {}", name)));
+        };
+        let Some(value) = defines[i].values.iter().position(|v| *v == value) else {
+            let msg = format!("{} is not one of the values of {}", value, name);
+            return Err(compile_error(&msg));
+        };
+        combination[i] = value;
+    }
+    Ok(combination)
+}
+
+/// Parses the defines from the macro options.
+///
+/// Each define is either `NAME`, which is `false` or `true`, or
+/// `NAME = [values]`. By default every combination is generated, unless
+/// `variants([...], ...)` lists them. `base` is the option of the same name,
+/// not a define.
+pub fn parse(options: &[MacroOption]) -> Result<Variants, TokenStream> {
+    let mut defines = vec![];
+    let mut explicit = None;
+    for option in options.iter() {
+        if option.name == "variants" {
+            explicit = Some(&option.value);
+            continue;
+        }
+        // `base = "dir"` is where the shader is, see `base_dir`, so a define
+        // can't have its name.
+        if option.name == "base" {
+            if let [TokenTree::Literal(_)] = option.value.as_slice() {
+                continue;
+            }
+            return Err(compile_error(
+                "`base` is the `base = \"dir\"` option, so it can't be the name of a define",
+            ));
+        }
+
+        let values = match option.value.as_slice() {
+            [] => vec!["false".to_string(), "true".to_string()],
+            [TokenTree::Group(group)] if group.delimiter() == Delimiter::Bracket => {
+                split_commas(group.stream())
+                    .iter()
+                    .map(|value| to_string(value))
+                    .collect()
+            }
+            _ => {
+                let msg = format!("expected `{}` or `{} = [values]`", option.name, option.name);
+                return Err(compile_error(&msg));
+            }
+        };
+        if values.is_empty() {
+            let msg = format!("{} needs at least one value", option.name);
+            return Err(compile_error(&msg));
+        }
+        if defines
+            .iter()
+            .any(|define: &Define| define.name == option.name)
+        {
+            return Err(compile_error(&format!("duplicate define: {}", option.name)));
+        }
+        defines.push(Define {
+            name: option.name.clone(),
+            values,
+        });
+    }
+
+    let combinations = match explicit {
+        Some(tokens) => split_commas(tokens.iter().cloned())
+            .iter()
+            .map(|variant| parse_variant(&defines, variant))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => {
+            let count = count(&defines);
+            if count > MAX_VARIANTS {
+                let msg = format!(
+                    "{} variants is more than the maximum of {}, list the ones needed with `variants(...)`",
+                    count, MAX_VARIANTS
+                );
+                return Err(compile_error(&msg));
+            }
+
+            every_combination(&defines)
+        }
+    };
+
+    if combinations.is_empty() {
+        return Err(compile_error("expected at least one variant"));
+    }
+    Ok(Variants {
+        defines,
+        combinations,
+        exhaustive: explicit.is_none(),
+    })
+}
+
+/// Generates an enum for the values of a non-boolean define.
+fn value_enum(define: &Define) -> String {
+    let name = define.enum_name();
+    let variants = define.variant_names();
+    format!(
+        r#"
+        /// The values of `{define}`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum {name} {{
+            {variants}
+        }}
+
+        impl {name} {{
+            pub const ALL: &'static [{name}] = &[{all}];
+
+            /// The value `{define}` is defined as.
+            pub const fn value(self) -> &'static str {{
+                match self {{
+                    {values}
+                }}
+            }}
+        }}
+        "#,
+        define = define.name,
+        name = name,
+        variants = variants
+            .iter()
+            .map(|variant| format!("{},", variant))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        all = variants
+            .iter()
+            .map(|variant| format!("{}::{}", name, variant))
+            .collect::<Vec<_>>()
+            .join(", "),
+        values = variants
+            .iter()
+            .zip(define.values.iter())
+            .map(|(variant, value)| format!("{}::{} => {:?},", name, variant, value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+/// Generates the `VARIANTS` table, with the preprocessed source of each combination.
+///
+/// Each variant has a `VariantKey`, with a `bool` for boolean defines and an
+/// enum for the others, and `select` looks up the source for a key. When the
+/// variants are listed explicitly, `select` returns an `Option`.
+pub fn variants(variants: &Variants, sources: &[String]) -> String {
+    let table = variants
+        .combinations
+        .iter()
+        .zip(sources.iter())
+        .map(|(combination, source)| {
+            format!(
+                "Variant {{ key: {}, defines: &{:?}, source: {:?} }},",
+                variants.key(combination),
+                variants.defines(combination),
+                source
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let fields = variants
+        .defines
+        .iter()
+        .map(|define| {
+            let ty = match define.is_bool() {
+                true => "bool".to_string(),
+                false => define.enum_name(),
+            };
+            format!("pub {}: {},", define.field(), ty)
+        })
+        .collect::<Vec<_>>();
+
+    let enums = variants
+        .defines
+        .iter()
+        .filter(|define| !define.is_bool())
+        .map(value_enum)
+        .collect::<Vec<_>>();
+
+    let arms = variants
+        .combinations
+        .iter()
+        .enumerate()
+        .map(|(i, combination)| match variants.exhaustive {
+            true => format!("{} => VARIANTS[{}].source,", variants.key(combination), i),
+            false => format!(
+                "{} => Some(VARIANTS[{}].source),",
+                variants.key(combination),
+                i
+            ),
+        })
+        .collect::<Vec<_>>();
+    let (select_ty, fallback) = match variants.exhaustive {
+        true => ("&'static str", ""),
+        false => (
+            "Option<&'static str>",
+            "#[allow(unreachable_patterns)] _ => None,",
+        ),
+    };
+
+    format!(
+        r#"
+        {enums}
+
+        /// The defines of a variant.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct VariantKey {{
+            {fields}
+        }}
+
+        /// A preprocessed variant of the shader.
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub struct Variant {{
+            pub key: VariantKey,
+            /// The defines the variant was preprocessed with.
+            pub defines: &'static [(&'static str, &'static str)],
+            pub source: &'static str,
+        }}
+
+        pub const VARIANTS: &[Variant] = &[{table}];
+
+        /// Returns the preprocessed source of the variant with the given defines.
+        pub const fn select(key: VariantKey) -> {select_ty} {{
+            match key {{
+                {arms}
+                {fallback}
+            }}
+        }}
+        "#,
+        enums = enums.join("\n"),
+        fields = fields.join("\n"),
+        table = table.join("\n"),
+        select_ty = select_ty,
+        arms = arms.join("\n"),
+        fallback = fallback,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn define(name: &str, values: &[&str]) -> Define {
+        Define {
+            name: name.to_string(),
+            values: values.iter().map(|value| value.to_string()).collect(),
+        }
+    }
+
+    fn option(name: &str) -> MacroOption {
+        MacroOption {
+            name: name.to_string(),
+            value: vec![],
+        }
+    }
+
+    #[test]
+    fn test_every_combination() {
+        let defines = [
+            define("USE_FOG", &["false", "true"]),
+            define("QUALITY", &["1", "2", "3"]),
+        ];
+        assert_eq!(count(&defines), 6);
+        assert_eq!(
+            every_combination(&defines),
+            [[0, 0], [0, 1], [0, 2], [1, 0], [1, 1], [1, 2]]
+        );
+
+        // No defines is a single variant without any.
+        assert_eq!(every_combination(&[]), [Vec::<usize>::new()]);
+
+        let many = (0..70)
+            .map(|i| define(&format!("D{}", i), &["false", "true"]))
+            .collect::<Vec<_>>();
+        assert_eq!(count(&many), usize::MAX);
+    }
+
+    #[test]
+    fn test_names() {
+        let fog = define("USE_FOG", &["false", "true"]);
+        assert!(fog.is_bool());
+        assert_eq!(fog.field(), "use_fog");
+        assert_eq!(fog.key_value(1), "true");
+
+        let quality = define("QUALITY", &["LOW", "HIGH"]);
+        assert!(!quality.is_bool());
+        assert_eq!(quality.enum_name(), "Quality");
+        assert_eq!(quality.variant_names(), ["Low", "High"]);
+        assert_eq!(quality.key_value(1), "Quality::High");
+
+        assert_eq!(define("TYPE", &["1"]).field(), "r#type");
+        assert_eq!(
+            define("SCALE", &["1", "2.5", "vec2(1, 2)"]).variant_names(),
+            ["V1", "V2_5", "Vvec2_1_2_"]
+        );
+
+        // `1.5` and `1_5` would both be `V1_5`, so the index is used instead.
+        assert_eq!(
+            define("SCALE", &["1.5", "1_5"]).variant_names(),
+            ["V0", "V1"]
+        );
+    }
+
+    #[test]
+    fn test_variants() {
+        let variants = Variants {
+            defines: vec![
+                define("USE_FOG", &["false", "true"]),
+                define("QUALITY", &["LOW", "HIGH"]),
+            ],
+            combinations: vec![vec![1, 0]],
+            exhaustive: false,
+        };
+        assert_eq!(
+            variants.defines(&[1, 0]),
+            [
+                ("USE_FOG".to_string(), "true".to_string()),
+                ("QUALITY".to_string(), "LOW".to_string()),
+            ]
+        );
+        assert_eq!(variants.describe(&[1, 0]), "USE_FOG = true, QUALITY = LOW");
+        assert_eq!(
+            variants.key(&[1, 0]),
+            "VariantKey { use_fog: true, quality: Quality::Low }"
+        );
+
+        let generated = super::variants(&variants, &["fn main() {}".to_string()]);
+        assert!(generated.contains("pub enum Quality"));
+        assert!(generated.contains("-> Option<&'static str>"));
+    }
+
+    #[test]
+    fn test_allowed_options() {
+        let options = [option("base"), option("USE_FOG"), option("budget")];
+        assert_eq!(
+            allowed_options(&options),
+            ["base", "variants", "base", "USE_FOG"]
+        );
+    }
+}